@@ -0,0 +1,100 @@
+//! Compare memory allocated per message by `recv_msg` and `recv_msg_stream`.
+//!
+//! `recv_msg` allocates a new payload buffer for every message, while `recv_msg_stream` reuses one.
+//! Run with `cargo bench --features unstable`.
+#![feature(test)]
+
+extern crate bitcoin;
+extern crate futures;
+extern crate libyabitcoin;
+extern crate test;
+
+use std::{alloc::{GlobalAlloc, Layout, System}, io::Cursor, sync::atomic::{AtomicUsize, Ordering}};
+
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::{constants::Network, message::{NetworkMessage, RawNetworkMessage}, serialize::serialize};
+use futures::{Future, Stream};
+use test::{black_box, Bencher};
+
+use libyabitcoin::connection::socket::Socket;
+
+const NUM_MSGS: usize = 100;
+const NUM_TXS: usize = 1000;
+
+// Count bytes which are allocated by this process.
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8
+    {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+    {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+// `NUM_MSGS` block messages. Each block has `NUM_TXS` transactions.
+fn block_msgs() -> Vec<u8>
+{
+    let mut block = genesis_block(Network::Bitcoin);
+    let tx = block.txdata[0].clone();
+    block.txdata = vec![tx; NUM_TXS];
+    let msg = RawNetworkMessage {
+        magic: Network::Bitcoin.magic(),
+        payload: NetworkMessage::Block(block),
+    };
+    let bytes = serialize(&msg).unwrap();
+    bytes.iter().cloned().cycle().take(bytes.len() * NUM_MSGS).collect()
+}
+
+fn recv_each(bytes: &[u8])
+{
+    let mut socket = Socket::new(Cursor::new(bytes), Network::Bitcoin);
+    for _ in 0..NUM_MSGS {
+        let (msg, s) = socket.recv_msg().wait().unwrap();
+        black_box(msg);
+        socket = s;
+    }
+}
+
+fn recv_stream(bytes: &[u8])
+{
+    let socket = Socket::new(Cursor::new(bytes), Network::Bitcoin);
+    for msg in socket.recv_msg_stream().wait() {
+        black_box(msg.unwrap());
+    }
+}
+
+fn report_allocated_per_msg(name: &str, f: fn(&[u8]), bytes: &[u8])
+{
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    f(bytes);
+    let allocated = ALLOCATED.load(Ordering::Relaxed) - before;
+    eprintln!("{} : {} bytes allocated per message", name, allocated / NUM_MSGS);
+}
+
+#[bench]
+fn recv_msg(b: &mut Bencher)
+{
+    let bytes = block_msgs();
+    report_allocated_per_msg("recv_msg", recv_each, &bytes);
+    b.iter(|| recv_each(&bytes));
+}
+
+#[bench]
+fn recv_msg_stream(b: &mut Bencher)
+{
+    let bytes = block_msgs();
+    report_allocated_per_msg("recv_msg_stream", recv_stream, &bytes);
+    b.iter(|| recv_stream(&bytes));
+}
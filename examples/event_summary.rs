@@ -0,0 +1,107 @@
+extern crate actix;
+extern crate bitcoin;
+extern crate futures;
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+extern crate libyabitcoin;
+
+use std::{env, net::SocketAddr, process, sync::{Arc, Mutex}, time::Duration};
+
+use actix::prelude::*;
+use bitcoin::network::constants::Network;
+
+use libyabitcoin::blockchain::BlockChain;
+use libyabitcoin::connection::{connection_pool::ConnectionPool, socket::NODE_NETWORK};
+use libyabitcoin::events::{CountingSink, EventCounts};
+
+const USAGE: &str = "Usage: event_summary [bitcoin|testnet|regtest] [peer address...]";
+
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Keep connections with a `ConnectionPool`, and print a summary of its events every 10 seconds.
+///
+/// e.g. `cargo run --example event_summary -- regtest 127.0.0.1:18444`
+fn main()
+{
+    env_logger::init();
+
+    let (network, peers) = match parse_args() {
+        Some(args) => args,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        },
+    };
+
+    System::run(move || {
+        let sink = Arc::new(CountingSink::new());
+        let blockchain = Arc::new(Mutex::new(BlockChain::new(network)));
+        let mut pool = ConnectionPool::new(network, 0, NODE_NETWORK, false, blockchain);
+        if !peers.is_empty() {
+            pool = pool.with_bootstrap_addrs(peers).with_dns_seeds(Vec::new());
+        }
+        pool.set_event_sink(sink.clone());
+
+        Summary {
+            sink,
+            last: EventCounts::default(),
+            _pool: pool.start(),
+        }.start();
+    });
+}
+
+fn parse_args() -> Option<(Network, Vec<SocketAddr>)>
+{
+    let mut args = env::args().skip(1);
+    let network = match args.next().as_ref().map(String::as_str) {
+        None | Some("bitcoin") => Network::Bitcoin,
+        Some("testnet") => Network::Testnet,
+        Some("regtest") => Network::Regtest,
+        Some(_) => return None,
+    };
+    let peers = args.map(|arg| arg.parse().ok()).collect::<Option<Vec<_>>>()?;
+    Some((network, peers))
+}
+
+/// Print the number of events in the last interval.
+struct Summary
+{
+    sink: Arc<CountingSink>,
+    last: EventCounts,
+    // Keep the pool running.
+    _pool: Addr<ConnectionPool>,
+}
+
+impl Actor for Summary
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>)
+    {
+        ctx.run_interval(SUMMARY_INTERVAL, |actor, _ctx| actor.print());
+    }
+}
+
+impl Summary
+{
+    fn print(&mut self)
+    {
+        let now = self.sink.counts();
+        let last = self.last;
+        info!(
+            "handshakes +{} opened +{} closed +{} banned +{} misbehaviors +{} headers +{} reorgs +{} blocks +{}",
+            now.handshakes - last.handshakes,
+            now.connections_opened - last.connections_opened,
+            now.connections_closed - last.connections_closed,
+            now.connections_banned - last.connections_banned,
+            now.misbehaviors - last.misbehaviors,
+            now.headers_applied - last.headers_applied,
+            now.reorgs - last.reorgs,
+            now.blocks_stored - last.blocks_stored,
+        );
+        self.last = now;
+    }
+}
@@ -1,3 +1,4 @@
+extern crate actix;
 extern crate bitcoin;
 extern crate futures;
 
@@ -7,54 +8,74 @@ extern crate env_logger;
 
 extern crate libyabitcoin;
 
-use bitcoin::network::{constants::Network, serialize::BitcoinHash};
-use bitcoin::blockdata::block::{Block, BlockHeader};
-use bitcoin::util::hash::Sha256dHash;
+use std::{env, net::SocketAddr, process, sync::{Arc, Mutex}};
 
+use actix::prelude::*;
+use bitcoin::network::constants::Network;
 use futures::Future;
 
-use libyabitcoin::{blockchain::{BlockChain, BlockData}, peer::{Socket, Connection, Peer}};
+use libyabitcoin::blockchain::BlockChain;
+use libyabitcoin::connection::{socket::{HandshakeConfig, Socket}, Connection};
+use libyabitcoin::process::{initial_block_download::initial_block_download_with_progress,
+                            sync_blockchain::IbdProgress};
 
-const DEMO_PEER: &str = "172.105.194.235:8333";
-// const DEMO_PEER: &str = "35.187.215.241:8333";
-const LOCAL_PEER: &str = "10.0.1.16:8333";
+const USAGE: &str = "Usage: ibd <peer address> [bitcoin|testnet|regtest]";
 
+/// Download all block headers from a peer, then blocks of them.
+///
+/// e.g. `cargo run --example ibd -- 127.0.0.1:18444 regtest`
 fn main()
 {
     env_logger::init();
 
-    let ibd_fut = Socket::open(&DEMO_PEER.parse().unwrap(), Network::Bitcoin)
-        .and_then(|socket| Connection::initialize(socket, 0))
-        .and_then(|conn| {
-            info!("Connected");
-            let peer = Peer::new(conn);
-            let start_block = BlockData::new(start_block().header, 0);
-            let blockchain = BlockChain::with_start(start_block);
-            peer.sync_blockchain(blockchain)
-        });
-
-    ibd_fut.wait().unwrap();
+    let (peer, network) = match parse_args() {
+        Some(args) => args,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        },
+    };
+
+    System::run(move || {
+        let blockchain = Arc::new(Mutex::new(BlockChain::new(network)));
+        let f = Socket::connect(&peer, network)
+            .and_then(|socket| socket.begin_handshake_with_config(HandshakeConfig::default()))
+            .and_then(move |socket| {
+                info!("Connected to {}", peer);
+                let conn = Connection::start_actor(socket);
+                initial_block_download_with_progress(conn, blockchain, print_progress)
+            })
+            .then(|res| {
+                match res {
+                    Ok(progress) => info!("Complete : {:?}", progress),
+                    Err(e) => error!("Fail : {:?}", e),
+                }
+                System::current().stop();
+                Ok(())
+            });
+        Arbiter::spawn(f);
+    });
 }
 
-fn start_block() -> Block
+fn parse_args() -> Option<(SocketAddr, Network)>
 {
-    const START_BLOCK_HASH: &str = "000000000000000000376b62d61208a7e45a030c6b876e3516083bdd62be4097";
-    const START_BLOCK_PREV_HASH: &str = "0000000000000000001f5dee17110cb311de968496c0813918b15a9ff239c75e";
-    const START_BLOCK_MERKLE_ROOT: &str = "2c555f43f0588b73f23c806e821d39a0c035985917aaeb20e9ae4c993d730f9a";
-
-    let header = BlockHeader {
-        version: 536870912,
-        prev_blockhash: Sha256dHash::from_hex(START_BLOCK_PREV_HASH).unwrap(),
-        merkle_root: Sha256dHash::from_hex(START_BLOCK_MERKLE_ROOT).unwrap(),
-        time: 1530447144,
-        bits: 389508950,
-        nonce: 449341550,
+    let mut args = env::args().skip(1);
+    let peer = args.next()?.parse().ok()?;
+    let network = match args.next().as_ref().map(String::as_str) {
+        None | Some("bitcoin") => Network::Bitcoin,
+        Some("testnet") => Network::Testnet,
+        Some("regtest") => Network::Regtest,
+        Some(_) => return None,
     };
+    Some((peer, network))
+}
 
-    assert_eq!(Sha256dHash::from_hex(START_BLOCK_HASH).unwrap(), header.bitcoin_hash());
-
-    Block {
-        header,
-        txdata: Vec::new(),
+/// Print a line for each batch of headers, i.e. every 2000 headers, and every 100 blocks.
+fn print_progress(p: IbdProgress)
+{
+    if p.blocks_total == 0 {
+        info!("Synced headers {} / {}", p.current_height, p.best_known_height);
+    } else {
+        info!("Downloaded blocks {} / {} ({} bytes)", p.blocks_downloaded, p.blocks_total, p.bytes_downloaded);
     }
 }
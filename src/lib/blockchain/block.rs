@@ -1,6 +1,8 @@
 use bitcoin::blockdata::{block::{Block, BlockHeader}, constants::genesis_block};
 use bitcoin::network::{constants::Network, serialize::BitcoinHash};
-use bitcoin::util::hash::Sha256dHash;
+use bitcoin::util::{hash::Sha256dHash, uint::Uint256};
+
+use witness;
 
 #[derive(Copy, Debug, Clone, PartialEq, Eq)]
 pub struct BlockData
@@ -8,19 +10,44 @@ pub struct BlockData
     pub header: BlockHeader,
     pub height: u32,
     hash: Sha256dHash,
+    chain_work: Uint256,
 }
 
 impl BlockData
 {
+    /// Work of blocks before this block is unknown, so `chain_work` is the same as `work`.
     pub fn new(header: BlockHeader, height: u32) -> BlockData
     {
         BlockData {
             hash: header.bitcoin_hash(),
+            chain_work: header_work(&header),
             header,
             height,
         }
     }
 
+    /// Create a block which follows `prev`.
+    pub(super) fn with_prev(header: BlockHeader, prev: &BlockData) -> BlockData
+    {
+        BlockData {
+            hash: header.bitcoin_hash(),
+            chain_work: prev.chain_work + header_work(&header),
+            header,
+            height: prev.height + 1,
+        }
+    }
+
+    /// Same as `with_prev`, but `hash` and `work` of `header` are already calculated.
+    pub(super) fn with_prev_parts(header: BlockHeader, hash: Sha256dHash, work: Uint256, prev: &BlockData) -> BlockData
+    {
+        BlockData {
+            hash,
+            chain_work: prev.chain_work + work,
+            header,
+            height: prev.height + 1,
+        }
+    }
+
     pub fn genesis(network: Network) -> BlockData
     {
         BlockData::new(genesis_block(network).header, 0)
@@ -35,6 +62,49 @@ impl BlockData
     {
         self.height
     }
+
+    /// Expected number of hashes to find this block.
+    pub fn work(&self) -> Uint256
+    {
+        header_work(&self.header)
+    }
+
+    /// Total work of the chain from the start block of `BlockChain` up to this block.
+    pub fn chain_work(&self) -> Uint256
+    {
+        self.chain_work
+    }
+}
+
+/// `2^256 / (target + 1)`, which is calculated as `~target / (target + 1) + 1` to fit in 256 bits.
+/// A header with zero target is invalid and has no work.
+pub(super) fn header_work(header: &BlockHeader) -> Uint256
+{
+    let target = header.target();
+    let zero = Uint256::from_u64(0).unwrap();
+    if target == zero {
+        return zero;
+    }
+    let one = Uint256::from_u64(1).unwrap();
+    !target / (target + one) + one
+}
+
+/// Check that hash of `header` meets the target encoded in its `bits`.
+pub(super) fn has_valid_pow(header: &BlockHeader) -> bool
+{
+    let target = header.target();
+    target != Uint256::from_u64(0).unwrap() && header.bitcoin_hash().into_le() <= target
+}
+
+/// The easiest target which is allowed on `network`.
+pub(super) fn pow_limit(network: Network) -> Uint256
+{
+    match network {
+        // bits : 0x1d00ffff
+        Network::Bitcoin | Network::Testnet => Uint256::from_u64(0xffff).unwrap() << 208,
+        // bits : 0x207fffff
+        Network::Regtest => Uint256::from_u64(0x7f_ffff).unwrap() << 232,
+    }
 }
 
 impl BitcoinHash for BlockData
@@ -68,6 +138,12 @@ impl FullBlockData
     {
         FullBlockData::new(genesis_block(network), 0)
     }
+
+    /// Whether the block contains any witness data.
+    pub fn has_witness(&self) -> bool
+    {
+        witness::has_witness(&self.block)
+    }
 }
 
 impl BitcoinHash for FullBlockData
@@ -0,0 +1,29 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use bitcoin::blockdata::block::Block;
+use bitcoin::util::hash::Sha256dHash;
+
+/// Where a `Connection` finds block bodies which peer requests by `getdata`.
+///
+/// `BlockChain` only keeps headers, so bodies are provided by whoever stores them.
+pub trait BlockSource: Send + Sync
+{
+    fn get_block(&self, hash: &Sha256dHash) -> Option<Block>;
+}
+
+impl BlockSource for HashMap<Sha256dHash, Block>
+{
+    fn get_block(&self, hash: &Sha256dHash) -> Option<Block>
+    {
+        self.get(hash).cloned()
+    }
+}
+
+/// A source which keeps growing, e.g. while blocks are downloaded.
+impl<T: BlockSource> BlockSource for Mutex<T>
+{
+    fn get_block(&self, hash: &Sha256dHash) -> Option<Block>
+    {
+        self.lock().unwrap().get_block(hash)
+    }
+}
@@ -1,50 +1,284 @@
-use std::{cell::{Ref, RefCell}, rc::{Rc, Weak}};
+use std::{cmp, collections::HashMap, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
 
-use bitcoin::util::hash::Sha256dHash;
-use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::util::{hash::Sha256dHash, uint::Uint256};
+use bitcoin::blockdata::{block::{Block, BlockHeader}, transaction::Transaction};
 use bitcoin::network::{constants::Network, serialize::BitcoinHash};
 
-use super::{BlockData, NotFoundPrevBlock};
+use error::Error;
+use super::{BlockAddError, BlockAddResult, BlockChainSnapshot, BlockData, FullBlockData,
+            block::{has_valid_pow, pow_limit}, checkpoint::{checkpoints, is_checkpoint, Checkpoint},
+            difficulty::{compact_from_target, retarget_bits, RetargetParams},
+            orphan_pool::{OrphanPool, DEFAULT_MAX_ORPHANS}, snapshot::SNAPSHOT_CHUNK_LEN,
+            validate::validate_merkle_root};
 
+/// The number of blocks to calculate median time past.
+pub(super) const MEDIAN_TIME_SPAN: u32 = 11;
+
+/// How far a timestamp of block header can be ahead of our clock (2 hours).
+pub(super) const MAX_FUTURE_BLOCK_TIME: u32 = 2 * 60 * 60;
+
+/// The number of the latest blocks which are included in locator one by one.
+const LOCATOR_DENSE_SPAN: usize = 10;
+
+/// A function which returns current unix time.
+type TimeSource = Arc<Fn() -> u32 + Send + Sync>;
 
 /// A honest implementation of blockchain.
 pub struct BlockChain
 {
-    // Nodes of current active chain
-    active_nodes: Vec<Rc<RefCell<Node>>>,
+    network: Network,
+
+    // All nodes in the tree, including side branches. The first one is the start block.
+    nodes: Vec<Node>,
+
+    // Positions in `nodes` of current active chain
+    active: Vec<usize>,
+    // Full chunks of `SNAPSHOT_CHUNK_LEN` blocks from the start of the active chain, shared with snapshots.
+    frozen: Vec<Arc<Vec<BlockData>>>,
+
+    // Positions in `nodes` keyed by block hash.
+    index: HashMap<Sha256dHash, usize>,
+
+    // Headers which can not be connected to the tree yet.
+    orphans: OrphanPool,
+
+    // Transactions of blocks which are added by `try_add_full_block`.
+    bodies: HashMap<Sha256dHash, Vec<Transaction>>,
+    // Bodies of blocks deeper than this in the active chain are dropped. `None` keeps all.
+    body_retention: Option<u32>,
+
+    // A block at the height of a checkpoint must be the checkpoint block.
+    checkpoints: Vec<Checkpoint>,
+
+    retarget: RetargetParams,
+
+    time_source: TimeSource,
 }
 
 pub struct ActiveChain<'a>
 {
-    nodes: &'a Vec<Rc<RefCell<Node>>>,
+    nodes: &'a [Node],
+    active: &'a [usize],
+    index: &'a HashMap<Sha256dHash, usize>,
+    bodies: &'a HashMap<Sha256dHash, Vec<Transaction>>,
 }
 
 impl BlockChain
 {
     pub fn new(network: Network) -> BlockChain
     {
-        BlockChain::with_start(BlockData::genesis(network))
+        BlockChain::with_start(network, BlockData::genesis(network))
+    }
+
+    /// Start a blockchain from a trusted checkpoint of `network` instead of the genesis block.
+    /// `block_data` must be one of the compiled-in checkpoints, both in height and hash.
+    pub fn with_checkpoint(network: Network, block_data: BlockData) -> Result<BlockChain, Error>
+    {
+        if !is_checkpoint(network, &block_data) {
+            return Err(Error::UnknownCheckpoint(block_data.bitcoin_hash(), network));
+        }
+        Ok(BlockChain::with_start(network, block_data))
+    }
+
+    /// Start a blockchain from a trusted `header` at `height`, e.g. a recent block of testnet.
+    /// Unlike `with_checkpoint`, `header` does not have to be compiled-in,
+    /// but it must not contradict a built-in checkpoint of the same height.
+    pub fn from_checkpoint(network: Network, header: BlockHeader, height: u32) -> Result<BlockChain, Error>
+    {
+        let block_data = BlockData::new(header, height);
+        if checkpoints(network).iter().any(|c| c.conflicts_with(&block_data)) {
+            return Err(Error::CheckpointMismatch(block_data.bitcoin_hash()));
+        }
+        Ok(BlockChain::with_start(network, block_data))
+    }
+
+    /// Start a blockchain from an arbitrary block.
+    /// It is not checked whether `block_data` belongs to `network` or not.
+    pub fn with_start(network: Network, block_data: BlockData) -> BlockChain
+    {
+        let node = Node {
+            prev: None,
+            block: block_data,
+        };
+        let mut index = HashMap::new();
+        index.insert(block_data.bitcoin_hash(), 0);
+        BlockChain {
+            network,
+            nodes: vec![node],
+            active: vec![0],
+            frozen: Vec::new(),
+            index,
+            orphans: OrphanPool::new(DEFAULT_MAX_ORPHANS),
+            bodies: HashMap::new(),
+            body_retention: None,
+            checkpoints: checkpoints(network),
+            retarget: RetargetParams::new(network),
+            time_source: Arc::new(unix_time_now),
+        }
+    }
+
+    pub fn network(&self) -> Network
+    {
+        self.network
+    }
+
+    /// Replace the clock which is used to check timestamps of block headers.
+    /// Default is the system clock.
+    pub fn set_time_source<F>(&mut self, time_source: F)
+    where F: Fn() -> u32 + Send + Sync + 'static
+    {
+        self.time_source = Arc::new(time_source);
+    }
+
+    /// Replace the checkpoints which added headers must agree with.
+    /// Default is the built-in checkpoints of the network.
+    pub fn set_checkpoints(&mut self, checkpoints: Vec<Checkpoint>)
+    {
+        self.checkpoints = checkpoints;
+    }
+
+    /// Replace how difficulty is adjusted. Default is the rule of the network.
+    /// It is mainly for tests, which can not mine 2016 blocks of real difficulty.
+    pub fn set_retarget_params(&mut self, params: RetargetParams)
+    {
+        self.retarget = params;
+    }
+
+    /// Keep transactions of only the latest `depth` blocks of the active chain.
+    /// Older blocks are downgraded to headers. `None`, the default, keeps every body.
+    pub fn set_body_retention(&mut self, depth: Option<u32>)
+    {
+        self.body_retention = depth;
+        self.prune_bodies();
+    }
+
+    /// Change how many orphan headers are kept. Default is `DEFAULT_MAX_ORPHANS`.
+    /// The oldest orphans are evicted when there are more.
+    ///
+    /// With 0, a header whose prev block is not found is rejected by `BlockAddError::NotFoundPrevBlock`
+    /// instead of being kept, for consumers which require headers in order.
+    pub fn set_max_orphans(&mut self, max: usize)
+    {
+        self.orphans.set_capacity(max);
+    }
+
+    pub fn max_orphans(&self) -> usize
+    {
+        self.orphans.capacity()
+    }
+
+    /// Try to add a given block header.
+    ///
+    /// If prev block of given header is not found, the header is kept as an orphan and
+    /// `BlockAddResult::Orphaned` is returned, unless orphans are disabled by `set_max_orphans(0)`.
+    /// Orphans are connected automatically when their prev block is added.
+    /// If they make another branch active, `BlockAddResult::Reorganized` is returned as well.
+    ///
+    /// A header whose hash does not meet its target, or whose target is easier than
+    /// the minimum difficulty of the network, is rejected by `BlockAddError::InvalidPoW`.
+    /// A header whose `bits` does not follow difficulty adjustment is rejected by `BlockAddError::InvalidDifficulty`.
+    /// It is not checked when the start block is too recent to look back the last adjustment.
+    ///
+    /// A header whose timestamp is more than 2 hours ahead of our clock, or not later than
+    /// median time past of the previous 11 blocks, is rejected by `BlockAddError::InvalidTimestamp`.
+    ///
+    /// A header at the height of a checkpoint, which is not the checkpoint block,
+    /// is rejected by `BlockAddError::CheckpointMismatch`.
+    pub fn try_add(&mut self, block_header: BlockHeader) -> Result<BlockAddResult, BlockAddError>
+    {
+        if let Some(id) = self.index.get(&block_header.bitcoin_hash()) {
+            return Ok(BlockAddResult::AlreadyKnown(self.nodes[*id].block));
+        }
+        if block_header.target() > pow_limit(self.network) || !has_valid_pow(&block_header) {
+            return Err(BlockAddError::InvalidPoW(block_header));
+        }
+
+        let now = (self.time_source)();
+        if block_header.time > now.saturating_add(MAX_FUTURE_BLOCK_TIME) {
+            return Err(BlockAddError::InvalidTimestamp(block_header));
+        }
+
+        if !self.index.contains_key(&block_header.prev_blockhash) {
+            if self.orphans.capacity() == 0 {
+                return Err(BlockAddError::NotFoundPrevBlock(block_header));
+            }
+            self.orphans.insert(block_header);
+            return Ok(BlockAddResult::Orphaned);
+        }
+
+        let old_tip = *self.active_chain().latest_block();
+        let (block, mut disconnected) = self.try_add_inner(block_header)?;
+        disconnected.extend(self.connect_orphans(block.bitcoin_hash()));
+
+        let new_tip = *self.active_chain().latest_block();
+        if !disconnected.is_empty() {
+            Ok(BlockAddResult::Reorganized { new_tip, disconnected })
+        } else if new_tip == old_tip {
+            Ok(BlockAddResult::AddedToSideChain(block))
+        } else {
+            Ok(BlockAddResult::ExtendedActiveChain(block))
+        }
+    }
+
+    /// Try to add a given block with its transactions, which can be queried by
+    /// `ActiveChain::get_full_block` later.
+    ///
+    /// The header is checked in the same way as `try_add`. If the header is already in the tree,
+    /// e.g. after headers first sync, transactions are attached to it.
+    /// Transactions of an orphan are not kept, so the block must be added again after its prev block.
+    pub fn try_add_full_block(&mut self, block: Block) -> Result<BlockAddResult, BlockAddError>
+    {
+        if !validate_merkle_root(&block) {
+            return Err(BlockAddError::InvalidMerkleRoot(block.header));
+        }
+        let result = self.try_add(block.header)?;
+        if result != BlockAddResult::Orphaned {
+            self.bodies.insert(block.bitcoin_hash(), block.txdata);
+            self.prune_bodies();
+        }
+        Ok(result)
+    }
+
+    /// Check whether the tree contains a block of given hash or not.
+    /// Not only active chain but also side branches are searched.
+    pub fn contains(&self, hash: &Sha256dHash) -> bool
+    {
+        self.index.contains_key(hash)
     }
 
-    pub fn with_start(block_data: BlockData) -> BlockChain
+    /// Get the block of given hash.
+    /// Not only active chain but also side branches are searched.
+    pub fn find(&self, hash: &Sha256dHash) -> Option<&BlockData>
     {
-        let node = Node::new(block_data);
-        let mut vec = Vec::new();
-        vec.push(node);
-        BlockChain { active_nodes: vec }
+        self.index.get(hash).map(|id| &self.nodes[*id].block)
     }
 
-    pub fn try_add(&mut self, block_header: BlockHeader) -> Result<(), NotFoundPrevBlock>
+    /// The number of orphan headers which are waiting for their prev block.
+    pub fn orphan_count(&self) -> usize
     {
-        self.try_add_inner(block_header)
+        self.orphans.len()
     }
 
     pub fn active_chain(&self) -> ActiveChain
     {
         ActiveChain {
-            nodes: &self.active_nodes,
+            nodes: &self.nodes,
+            active: &self.active,
+            index: &self.index,
+            bodies: &self.bodies,
         }
     }
+
+    /// Take an immutable snapshot of current active chain.
+    /// A snapshot can be held by another thread without blocking further mutation.
+    ///
+    /// Blocks which are frozen in full chunks are shared, so only recent blocks are copied.
+    pub fn freeze(&self) -> BlockChainSnapshot
+    {
+        let frozen_len = self.frozen.len() * SNAPSHOT_CHUNK_LEN;
+        let tail = self.active[frozen_len..].iter().map(|id| self.nodes[*id].block).collect();
+        BlockChainSnapshot::new(self.network, self.frozen.clone(), tail)
+    }
 }
 
 impl Clone for BlockChain
@@ -53,63 +287,215 @@ impl Clone for BlockChain
     {
         let ac = self.active_chain();
         let mut blocks = ac.iter();
-        let mut blockchain = BlockChain::with_start(blocks.next().unwrap().clone());
+        let mut blockchain = BlockChain::with_start(self.network, blocks.next().unwrap().clone());
+        blockchain.time_source = self.time_source.clone();
+        blockchain.checkpoints = self.checkpoints.clone();
+        blockchain.retarget = self.retarget;
         for block_data in blocks {
-            let _never_err = blockchain.try_add(block_data.header().clone());
+            // These blocks are already checked.
+            let _never_err = blockchain.try_add_inner(block_data.header().clone());
         }
+        // Side branches are not cloned, neither are their bodies.
+        blockchain.bodies = self.bodies
+            .iter()
+            .filter(|&(hash, _)| blockchain.index.contains_key(hash))
+            .map(|(hash, txdata)| (*hash, txdata.clone()))
+            .collect();
+        blockchain.body_retention = self.body_retention;
         blockchain
     }
 }
 
-/// Since `Rc` is only used internally, `BlockChain` can implement `Send`.
-unsafe impl Send for BlockChain {}
-
 impl<'a> ActiveChain<'a>
 {
     pub fn len(&self) -> u32
     {
-        self.nodes.len() as u32
+        self.active.len() as u32
     }
 
     /// Get the latest block
     ///
     /// Note that there always be latest block.
-    pub fn latest_block<'b>(&'b self) -> Ref<'b, BlockData>
+    pub fn latest_block(&self) -> &'a BlockData
     {
         self.iter().rev().next().unwrap()
     }
 
+    /// Total work of active chain.
+    /// Work of blocks before the start block is not counted.
+    pub fn total_work(&self) -> Uint256
+    {
+        self.latest_block().chain_work()
+    }
+
+    /// Get the specified height block
+    pub fn get_block(&self, height: u32) -> Option<&'a BlockData>
+    {
+        self.get_by_height(height)
+    }
+
     /// Get the specified height block
-    pub fn get_block<'b>(&'b self, height: u32) -> Option<Ref<'b, BlockData>>
+    pub fn get_by_height(&self, height: u32) -> Option<&'a BlockData>
     {
         let start_height = self.iter().next().unwrap().height;
         if height < start_height {
             return None;
         }
-        self.nodes
-            .get((height - start_height) as usize)
-            .map(|node| Ref::map(node.as_ref().borrow(), |n| &n.block))
+        let (nodes, active) = (self.nodes, self.active);
+        active.get((height - start_height) as usize).map(|id| &nodes[*id].block)
+    }
+
+    /// Get the block whose hash is equal to given hash.
+    /// Blocks on side branches are not returned.
+    pub fn get_by_hash(&self, hash: &Sha256dHash) -> Option<&'a BlockData>
+    {
+        let nodes = self.nodes;
+        let block = &nodes[*self.index.get(hash)?].block;
+        if self.contains(block) {
+            Some(block)
+        } else {
+            None
+        }
+    }
+
+    /// Get the block of given hash with its transactions.
+    /// `None` if the block is not in the active chain, or its transactions are not kept.
+    pub fn get_full_block(&self, hash: &Sha256dHash) -> Option<FullBlockData>
+    {
+        let txdata = self.bodies.get(hash)?;
+        let block = self.get_by_hash(hash)?;
+        let full_block = Block {
+            header: block.header,
+            txdata: txdata.clone(),
+        };
+        Some(FullBlockData::new(full_block, block.height()))
+    }
+
+    /// Get the height of the block whose hash is equal to given hash.
+    pub fn height_of(&self, hash: &Sha256dHash) -> Option<u32>
+    {
+        self.get_by_hash(hash).map(|b| b.height())
     }
 
     /// Check whether active chain contains given block or not.
     pub fn contains(&self, block: &BlockData) -> bool
     {
-        match self.get_block(block.height) {
+        match self.get_by_height(block.height) {
             None => false,
             Some(b) => b.bitcoin_hash() == block.bitcoin_hash(),
         }
     }
 
-    pub fn iter<'b>(&'b self) -> impl Iterator<Item = Ref<'b, BlockData>> + DoubleEndedIterator
+    /// Check whether active chain contains a block whose hash is equal to given hash.
+    pub fn contains_hash(&self, hash: &Sha256dHash) -> bool
     {
-        self.nodes
-            .iter()
-            .map(|node| Ref::map(node.as_ref().borrow(), |n| &n.block))
+        self.get_by_hash(hash).is_some()
+    }
+
+    /// Find the last common block between active chain and the branch of `other_tip`.
+    /// `other_tip` may be on a side branch.
+    /// Returns `None` if `other_tip` is not in the tree.
+    pub fn find_fork_point(&self, other_tip: &BlockData) -> Option<BlockData>
+    {
+        let mut id = *self.index.get(&other_tip.bitcoin_hash())?;
+        loop {
+            let block = self.nodes[id].block;
+            if self.contains(&block) {
+                return Some(block);
+            }
+            id = self.nodes[id].prev?;
+        }
+    }
+
+    /// The number of blocks of the branch of `old_tip` which are not in active chain.
+    /// It is 0 if `old_tip` is still in active chain or not in the tree.
+    pub fn reorg_depth(&self, old_tip: &BlockData) -> u32
+    {
+        match self.find_fork_point(old_tip) {
+            Some(fork_point) => old_tip.height() - fork_point.height(),
+            None => 0,
+        }
+    }
+
+    /// Get the ancestor of `from` whose height is `height`.
+    /// `from` may be on a side branch.
+    pub fn ancestor_at_height(&self, from: &BlockData, height: u32) -> Option<BlockData>
+    {
+        if height > from.height() {
+            return None;
+        }
+        let fork_point = self.find_fork_point(from)?;
+        if height <= fork_point.height() {
+            return self.get_by_height(height).map(|b| *b);
+        }
+
+        // Walk back the side branch
+        let mut id = *self.index.get(&from.bitcoin_hash())?;
+        while self.nodes[id].block.height() > height {
+            id = self.nodes[id].prev?;
+        }
+        Some(self.nodes[id].block)
+    }
+
+    /// Median of timestamps of the last 11 blocks up to `height`.
+    pub fn median_time_past(&self, height: u32) -> Option<u32>
+    {
+        let start_height = self.iter().next().unwrap().height;
+        if height < start_height || height >= start_height + self.len() {
+            return None;
+        }
+        let from = cmp::max(start_height, height.saturating_sub(MEDIAN_TIME_SPAN - 1));
+        let mut times: Vec<u32> = (from..=height)
+            .map(|h| self.get_by_height(h).unwrap().header.time)
+            .collect();
+        times.sort();
+        Some(times[times.len() / 2])
+    }
+
+    /// Get the latest block whose median time past is not later than `timestamp`.
+    ///
+    /// # Note
+    /// Timestamps of headers are not monotonic, but median time past is.
+    /// So timestamp of returned block itself may be later than `timestamp`.
+    pub fn block_at_or_before_time(&self, timestamp: u32) -> Option<BlockData>
+    {
+        let mut low = self.iter().next().unwrap().height;
+        let mut high = self.latest_block().height;
+        if self.median_time_past(low).unwrap() > timestamp {
+            return None;
+        }
+
+        // Binary search keeping `median_time_past(low) <= timestamp`
+        while low < high {
+            let mid = low + (high - low + 1) / 2;
+            if self.median_time_past(mid).unwrap() <= timestamp {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        self.get_by_height(low).map(|b| *b)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'a BlockData> + DoubleEndedIterator + 'a
+    {
+        let (nodes, active) = (self.nodes, self.active);
+        active.iter().map(move |id| &nodes[*id].block)
+    }
+
+    /// Iterate blocks from `height` to the tip.
+    /// Iteration starts from the start block if `height` is before it, and is empty if `height` is beyond the tip.
+    pub fn iter_from(&self, height: u32) -> impl Iterator<Item = &'a BlockData> + DoubleEndedIterator + 'a
+    {
+        let start_height = self.iter().next().unwrap().height;
+        let skip = cmp::min(height.saturating_sub(start_height) as usize, self.active.len());
+        let (nodes, active) = (self.nodes, self.active);
+        active[skip..].iter().map(move |id| &nodes[*id].block)
     }
 
     pub fn into_vec(&self) -> Vec<BlockData>
     {
-        let mut vec = Vec::with_capacity(self.nodes.len());
+        let mut vec = Vec::with_capacity(self.active.len());
         for block in self.iter() {
             vec.push(block.clone());
         }
@@ -118,223 +504,350 @@ impl<'a> ActiveChain<'a>
 
     /// Get locator block's hash iterator.
     ///
-    /// # Note
-    /// Current implementation is **VERY** **VERY** simple.
-    /// It should be improved in future.
+    /// The latest 10 blocks are included one by one, then steps back double each time.
+    /// The start block is always included at the end, so that a peer which does not know
+    /// any recent block still finds the fork point in a few round trips.
     /// Bitcoin core's implementation is here.
     /// https://github.com/bitcoin/bitcoin/blob/master/src/chain.cpp#L23
     pub fn locator_hashes<'b>(&'b self) -> impl Iterator<Item = Sha256dHash> + 'b
     {
-        // TODO improve this algo
-        self.iter().rev().take(10).map(|b| b.bitcoin_hash())
+        let start_height = self.iter().next().unwrap().height;
+        let tip_height = self.latest_block().height;
+        locator_heights(start_height, tip_height)
+            .into_iter()
+            .map(move |height| self.get_by_height(height).unwrap().bitcoin_hash())
     }
 
     /// Get locator block's hash vec.
     pub fn locator_hashes_vec(&self) -> Vec<Sha256dHash>
     {
-        let mut vec = Vec::with_capacity(10);
-        for hash in self.locator_hashes() {
-            vec.push(hash);
+        self.locator_hashes().collect()
+    }
+}
+
+/// Heights of locator blocks from `tip` to `start`, in descending order.
+pub(super) fn locator_heights(start: u32, tip: u32) -> Vec<u32>
+{
+    let mut heights = Vec::new();
+    let mut height = tip;
+    let mut step = 1;
+    loop {
+        heights.push(height);
+        if height <= start {
+            return heights;
         }
-        vec
+        if heights.len() >= LOCATOR_DENSE_SPAN {
+            step *= 2;
+        }
+        height = cmp::max(height.saturating_sub(step), start);
     }
 }
 
 impl BlockChain
 {
-    fn try_add_inner(&mut self, block_header: BlockHeader) -> Result<(), NotFoundPrevBlock>
+    /// Returns `BlockData` of the added block and blocks which are disconnected from the active chain.
+    fn try_add_inner(&mut self, block_header: BlockHeader) -> Result<(BlockData, Vec<BlockData>), BlockAddError>
     {
         /* logic starts from here */
 
         // Search prev block of given block
-        let prev_node = match self.borrow_then_find_node(block_header.prev_blockhash) {
-            None => return Err(NotFoundPrevBlock(block_header)),
-            Some(node) => node,
+        let prev_id = match self.index.get(&block_header.prev_blockhash) {
+            None => return Err(BlockAddError::NotFoundPrevBlock(block_header)),
+            Some(id) => *id,
         };
 
-        // Generates `BlockData`.
-        let prev_block_height = {
-            // immutable borrow start
-            prev_node.borrow().block.height()
-            // immutable borrow end
-        };
-        let new_block_height = prev_block_height + 1;
-        let new_block_data = BlockData::new(block_header, new_block_height);
+        // Timestamp must be later than median time past.
+        if block_header.time <= self.median_time_past_of(prev_id) {
+            return Err(BlockAddError::InvalidTimestamp(block_header));
+        }
 
-        // Append a new block to back of `prev_node`.
-        let new_node = Node::borrow_mut_then_append_block(&prev_node, new_block_data);
+        if let Some(expected) = self.expected_bits(prev_id, &block_header) {
+            if block_header.bits != expected {
+                return Err(BlockAddError::InvalidDifficulty(block_header));
+            }
+        }
 
-        // If new_node is a new tip, replace
-        let tail_block_height = {
-            // immutable borrow start
-            self.active_nodes.last().unwrap().borrow().block.height()
-            // immutable borrow end
-        };
-        if tail_block_height < new_block_height {
+        // Append a new block to back of the prev node.
+        let new_block_data = BlockData::with_prev(block_header, &self.nodes[prev_id].block);
+        if self.checkpoints.iter().any(|c| c.conflicts_with(&new_block_data)) {
+            return Err(BlockAddError::CheckpointMismatch(block_header));
+        }
+        let new_id = self.nodes.len();
+        self.nodes.push(Node {
+            prev: Some(prev_id),
+            block: new_block_data,
+        });
+        self.index.insert(new_block_data.bitcoin_hash(), new_id);
+
+        // If new node has more work than current tip, replace.
+        // If both have the same work, the first seen one is kept.
+        let tail_chain_work = self.active_chain().latest_block().chain_work();
+        let mut disconnected = Vec::new();
+        if tail_chain_work < new_block_data.chain_work() {
             // Rewinds current active chain
-            let last_common_node = self.borrow_then_find_last_common(&new_node);
-            let rewind_height = {
-                // immutable borrow start
-                last_common_node.borrow().block.height()
-                // immutable borrow end
-            };
-            self.borrow_then_rewind_active_chain(rewind_height);
-            self.borrow_then_append_nodes(new_node);
+            let last_common_id = self.find_last_common(new_id);
+            let rewind_height = self.nodes[last_common_id].block.height();
+            disconnected = self.rewind_active_chain(rewind_height);
+            self.append_branch(new_id);
         }
 
-        Ok(())
+        Ok((new_block_data, disconnected))
     }
 
-    // Returns last common `Node` between `active_chain` and `node_ptr`'s branch.
-    fn borrow_then_find_last_common(&self, node_ptr: &Rc<RefCell<Node>>) -> Rc<RefCell<Node>>
+    // Connects all orphans which are descendants of a block of `hash`.
+    // Returns blocks which are disconnected from the active chain meanwhile.
+    fn connect_orphans(&mut self, hash: Sha256dHash) -> Vec<BlockData>
     {
-        fn inner(active_chain: ActiveChain, node_ptr: &Rc<RefCell<Node>>) -> Rc<RefCell<Node>>
-        {
-            let node = node_ptr.borrow();
-            if active_chain.contains(&node.block) {
-                return node_ptr.clone();
-            }
-            match Node::borrow_then_get_prev(node_ptr) {
-                None => unreachable!(), // because independent branch never exist.
-                Some(prev) => inner(active_chain, &prev),
+        let mut disconnected = Vec::new();
+        let mut connected = vec![hash];
+        while let Some(hash) = connected.pop() {
+            for orphan in self.orphans.take_children(&hash) {
+                if let Ok((block, blocks)) = self.try_add_inner(orphan) {
+                    disconnected.extend(blocks);
+                    connected.push(block.bitcoin_hash());
+                }
             }
         }
+        disconnected
+    }
 
-        inner(self.active_chain(), node_ptr)
+    // Returns the last common node between active chain and the branch of node `id`.
+    fn find_last_common(&self, mut id: usize) -> usize
+    {
+        let active_chain = self.active_chain();
+        loop {
+            if active_chain.contains(&self.nodes[id].block) {
+                return id;
+            }
+            // Independent branch never exists.
+            id = self.nodes[id].prev.unwrap();
+        }
     }
 
     // # Note
     // Rewinded `active_chain` contains a node whose height is `rewind_height`.
     // Length of `active_chain` **MUST** be long enough.
-    fn borrow_then_rewind_active_chain(&mut self, rewind_height: u32)
+    /// Returns removed blocks, from the old tip.
+    fn rewind_active_chain(&mut self, rewind_height: u32) -> Vec<BlockData>
     {
-        let start_height = self.active_nodes[0].borrow().block.height();
+        let start_height = self.nodes[self.active[0]].block.height();
         let rewind_idx = rewind_height - start_height + 1;
-        self.active_nodes.truncate(rewind_idx as usize);
+        let removed = self.active.split_off(rewind_idx as usize);
+        // Chunks which are not full anymore are dropped.
+        self.frozen.truncate(self.active.len() / SNAPSHOT_CHUNK_LEN);
+        removed.iter().rev().map(|id| self.nodes[*id].block).collect()
     }
 
-    /// Append nodes of given `node_ptr`'s branch.
+    /// Append nodes of the branch of node `id`.
     /// # Note
-    /// The last active node **MUST** be on `node_ptr`'s branch.
-    fn borrow_then_append_nodes(&mut self, node_ptr: Rc<RefCell<Node>>)
-    {
-        match Node::borrow_then_get_prev(&node_ptr) {
-            None => panic!("node_ptr must have prev node"),
-            Some(prev_node) => {
-                if !Rc::ptr_eq(&prev_node, self.active_nodes.last().unwrap()) {
-                    self.borrow_then_append_nodes(prev_node);
-                }
-                // Now, `prev_node == active_chain.back().unwrap()`
-                self.active_nodes.push(node_ptr);
-            },
+    /// The last active node **MUST** be on the branch.
+    fn append_branch(&mut self, id: usize)
+    {
+        let mut branch = vec![id];
+        loop {
+            let prev_id = self.nodes[*branch.last().unwrap()].prev.expect("node must have prev node");
+            if prev_id == *self.active.last().unwrap() {
+                break;
+            }
+            branch.push(prev_id);
         }
+        // Now, `branch.last().prev == active_chain.back().unwrap()`
+        self.active.extend(branch.into_iter().rev());
+        self.freeze_full_chunks();
     }
 
-    /// Find a block whose bitcoin_hash is equal to given hash
-    /// Depth first search.
-    fn borrow_then_find_node(&self, hash: Sha256dHash) -> Option<Rc<RefCell<Node>>>
+    // Move blocks of the active chain into shared chunks as soon as a chunk gets full.
+    fn freeze_full_chunks(&mut self)
     {
-        fn inner(node_ptr: &Rc<RefCell<Node>>, hash: Sha256dHash) -> Option<Rc<RefCell<Node>>>
-        {
-            let node = node_ptr.borrow();
+        while (self.frozen.len() + 1) * SNAPSHOT_CHUNK_LEN <= self.active.len() {
+            let start = self.frozen.len() * SNAPSHOT_CHUNK_LEN;
+            let chunk = self.active[start..start + SNAPSHOT_CHUNK_LEN]
+                .iter()
+                .map(|id| self.nodes[*id].block)
+                .collect();
+            self.frozen.push(Arc::new(chunk));
+        }
+    }
 
-            // Depth first search
-            for next in node.nexts.iter() {
-                if let Some(node) = inner(next, hash) {
-                    return Some(node);
-                }
-            }
+    // Drop bodies of blocks which are lower than the retention window from the tip.
+    // Bodies on side branches are dropped by height as well.
+    fn prune_bodies(&mut self)
+    {
+        let depth = match self.body_retention {
+            Some(depth) => depth,
+            None => return,
+        };
+        let tip_height = self.active_chain().latest_block().height();
+        let (index, nodes) = (&self.index, &self.nodes);
+        self.bodies.retain(|hash, _| match index.get(hash) {
+            Some(id) => tip_height.saturating_sub(nodes[*id].block.height()) < depth,
+            None => false,
+        });
+    }
+
+    /// `bits` which a child of node `prev_id` must have.
+    /// `None` if it can not be known because blocks before the start block are needed.
+    fn expected_bits(&self, prev_id: usize, header: &BlockHeader) -> Option<u32>
+    {
+        let params = self.retarget;
+        if params.no_retargeting {
+            return None;
+        }
+        let prev = &self.nodes[prev_id].block;
+        let height = prev.height() + 1;
+        let limit = pow_limit(self.network);
+
+        if params.is_retarget_height(height) {
+            let first = self.ancestor_of(prev_id, height - params.interval)?;
+            let first_time = self.nodes[first].block.header.time;
+            return Some(retarget_bits(first_time, prev.header.time, prev.header.bits, params.timespan, &limit));
+        }
+        if !params.allow_min_difficulty_blocks {
+            return Some(prev.header.bits);
+        }
 
-            if node.block.bitcoin_hash() == hash {
-                return Some(node_ptr.clone());
+        // No block is found for a while, so anyone can mine a block of the min difficulty.
+        let limit_bits = compact_from_target(&limit);
+        if header.time > prev.header.time.saturating_add(params.spacing() * 2) {
+            return Some(limit_bits);
+        }
+        // Otherwise difficulty is the same as the last block which is not of the min difficulty.
+        let mut id = prev_id;
+        loop {
+            let block = &self.nodes[id].block;
+            if params.is_retarget_height(block.height()) || block.header.bits != limit_bits {
+                return Some(block.header.bits);
             }
+            id = self.nodes[id].prev?;
+        }
+    }
 
+    // Position of the ancestor of node `id` at `height`. `None` if it is before the start block.
+    fn ancestor_of(&self, mut id: usize, height: u32) -> Option<usize>
+    {
+        while self.nodes[id].block.height() > height {
+            id = self.nodes[id].prev?;
+        }
+        if self.nodes[id].block.height() == height {
+            Some(id)
+        } else {
             None
         }
+    }
 
-        inner(&self.active_nodes[0], hash)
+    /// Median of timestamps of the last 11 blocks up to node `id`.
+    /// The node may be on a side branch.
+    fn median_time_past_of(&self, mut id: usize) -> u32
+    {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN as usize);
+        loop {
+            times.push(self.nodes[id].block.header.time);
+            if times.len() == MEDIAN_TIME_SPAN as usize {
+                break;
+            }
+            id = match self.nodes[id].prev {
+                None => break,
+                Some(prev) => prev,
+            };
+        }
+        times.sort();
+        times[times.len() / 2]
     }
 }
 
+/// A block in the tree. Nodes are owned by `BlockChain` and referred by their position in it,
+/// so that `BlockChain` is `Send + Sync` and dropping a long chain does not recurse.
 #[derive(Debug)]
-/// Node may be strongly referenced from
-///
-/// 1. parent node as `next` node
-/// 2. BlockChain as `active` node
-///
-/// During one of these reference alive, Node never be dropped.
-///
-/// So if `self.prev.unwrap().upgrade()` returns `None`,
-/// it means that above two reference does not alive,
-/// i.e. self is head node.
 struct Node
 {
-    prev: Weak<RefCell<Node>>,
-    nexts: Vec<Rc<RefCell<Node>>>,
+    // `None` only for the start block.
+    prev: Option<usize>,
     block: BlockData,
 }
 
-impl Node
+pub(super) fn unix_time_now() -> u32
+{
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() as u32
+}
+
+#[cfg(test)]
+mod tests
 {
-    fn new(block: BlockData) -> Rc<RefCell<Node>>
+    use super::*;
+    use std::cell::Cell;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::util::hash::MerkleRoot;
+
+    use testing::{mine, MIN_DIFFICULTY_BITS};
+
+    // Timestamps of dummy headers of a test.
+    // Each timestamp is later than previous ones, so that headers pass median time past check.
+    struct Clock(Cell<u32>);
+
+    impl Clock
     {
-        let new_node = Node {
-            prev: Weak::new(),
-            nexts: vec![],
-            block,
-        };
-        Rc::new(RefCell::new(new_node))
-    }
+        fn new() -> Clock
+        {
+            Clock(Cell::new(1))
+        }
 
-    /// # Note
-    /// Inside this function, `node.borrow_mut()` is called.
-    /// So caller **MUTS** take care of not calling `node.borrow_mut()` in parent scope.
-    fn borrow_mut_then_append_block(node: &Rc<RefCell<Node>>, block: BlockData) -> Rc<RefCell<Node>>
-    {
-        let new_node = Node {
-            prev: Rc::downgrade(node),
-            nexts: vec![],
-            block,
-        };
-        let new_node_ptr = Rc::new(RefCell::new(new_node));
+        fn tick(&self) -> u32
+        {
+            let time = self.0.get();
+            self.0.set(time + 1);
+            time
+        }
+    }
 
-        node.borrow_mut().nexts.push(new_node_ptr.clone());
+    fn dummy_block_header(clock: &Clock, prev_hash: Sha256dHash) -> BlockHeader
+    {
+        dummy_fork_block_header(clock, prev_hash, 0)
+    }
 
-        new_node_ptr
+    fn dummy_block_header_with_time(prev_hash: Sha256dHash, time: u32) -> BlockHeader
+    {
+        header_at(prev_hash, 0, time)
     }
 
-    fn borrow_then_get_prev(node: &Rc<RefCell<Node>>) -> Option<Rc<RefCell<Node>>>
+    fn assert_extended(result: BlockAddResult, header: BlockHeader)
     {
-        node.borrow().prev.upgrade()
+        match result {
+            BlockAddResult::ExtendedActiveChain(block) => assert_eq!(block.header, header),
+            other => panic!("Unexpected result : {:?}", other),
+        }
     }
-}
 
-/// TODO: Should test re-org case
-#[cfg(test)]
-mod tests
-{
-    use super::*;
+    // Different `fork` makes a different block on the same parent.
+    fn dummy_fork_block_header(clock: &Clock, prev_hash: Sha256dHash, fork: u32) -> BlockHeader
+    {
+        header_at(prev_hash, fork, clock.tick())
+    }
 
-    fn dummy_block_header(prev_hash: Sha256dHash) -> BlockHeader
+    // A mined header whose timestamp is `time`.
+    fn header_at(prev_hash: Sha256dHash, fork: u32, time: u32) -> BlockHeader
     {
-        let header = BlockHeader {
-            version: 1,
+        let mut header = BlockHeader {
+            version: 1 + fork,
             prev_blockhash: prev_hash,
             merkle_root: Sha256dHash::default(),
-            time: 0,
-            bits: 0,
+            time,
+            bits: MIN_DIFFICULTY_BITS,
             nonce: 0,
         };
+        mine(&mut header);
         header
     }
 
     #[test]
     fn blocktree_try_add()
     {
-        let start_block_header = dummy_block_header(Sha256dHash::default());
-        let next_block_header = dummy_block_header(start_block_header.bitcoin_hash());
+        let clock = Clock::new();
+        let start_block_header = dummy_block_header(&clock, Sha256dHash::default());
+        let next_block_header = dummy_block_header(&clock, start_block_header.bitcoin_hash());
         let start_block = BlockData::new(start_block_header, 0);
-        let mut blocktree = BlockChain::with_start(start_block);
+        let mut blocktree = BlockChain::with_start(Network::Regtest, start_block);
 
         assert_eq!(blocktree.active_chain().len(), 1);
 
@@ -346,4 +859,988 @@ mod tests
         let headers: Vec<_> = active_chain.iter().map(|block| block.header).collect();
         assert_eq!(headers, vec![start_block_header, next_block_header]);
     }
+
+    #[test]
+    fn redelivered_batch_is_already_known()
+    {
+        let clock = Clock::new();
+        let start_block_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_block_header, 0));
+        let mut headers = vec![dummy_block_header(&clock, start_block_header.bitcoin_hash())];
+        while headers.len() < 100 {
+            let next = dummy_block_header(&clock, headers.last().unwrap().bitcoin_hash());
+            headers.push(next);
+        }
+        for header in headers.iter() {
+            assert_extended(blocktree.try_add(*header).unwrap(), *header);
+        }
+        let num_nodes = blocktree.nodes.len();
+
+        for header in headers.iter() {
+            match blocktree.try_add(*header).unwrap() {
+                BlockAddResult::AlreadyKnown(block) => assert_eq!(block.header, *header),
+                other => panic!("Unexpected result : {:?}", other),
+            }
+        }
+        assert_eq!(blocktree.nodes.len(), num_nodes);
+        assert_eq!(blocktree.active_chain().len(), 101);
+        assert_eq!(blocktree.active_chain().latest_block().header, headers[99]);
+    }
+
+    #[test]
+    fn blocktree_long_chain_does_not_overflow_stack()
+    {
+        let clock = Clock::new();
+        let start_block_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_block_header, 0));
+
+        let mut prev_hash = start_block_header.bitcoin_hash();
+        for _ in 0..200_000 {
+            let header = dummy_block_header(&clock, prev_hash);
+            prev_hash = header.bitcoin_hash();
+            blocktree.try_add(header).unwrap();
+        }
+
+        assert_eq!(blocktree.active_chain().len(), 200_001);
+        assert_eq!(blocktree.active_chain().latest_block().bitcoin_hash(), prev_hash);
+
+        drop(blocktree);
+    }
+
+    #[test]
+    fn active_chain_iter_from_height()
+    {
+        let clock = Clock::new();
+        let start_block_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_block_header, 100));
+        let mut prev_hash = start_block_header.bitcoin_hash();
+        for _ in 0..5 {
+            let header = dummy_block_header(&clock, prev_hash);
+            prev_hash = header.bitcoin_hash();
+            blocktree.try_add(header).unwrap();
+        }
+
+        let active_chain = blocktree.active_chain();
+        let heights = |from| active_chain.iter_from(from).map(|b| b.height()).collect::<Vec<_>>();
+        assert_eq!(heights(103), vec![103, 104, 105]);
+        assert_eq!(heights(0), (100..=105).collect::<Vec<_>>());
+        assert!(heights(106).is_empty());
+    }
+
+    #[test]
+    fn active_chain_query_by_hash_in_long_chain()
+    {
+        let clock = Clock::new();
+        let start_block_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_block_header, 0));
+        let mut hashes = vec![start_block_header.bitcoin_hash()];
+        for _ in 0..100_000 {
+            let header = dummy_block_header(&clock, *hashes.last().unwrap());
+            hashes.push(header.bitcoin_hash());
+            blocktree.try_add(header).unwrap();
+        }
+
+        let active_chain = blocktree.active_chain();
+        for &height in [0, 1, 50_000, 99_999, 100_000].iter() {
+            let hash = hashes[height as usize];
+            assert_eq!(active_chain.height_of(&hash), Some(height));
+            assert_eq!(active_chain.get_by_hash(&hash).unwrap().bitcoin_hash(), hash);
+            assert!(active_chain.contains_hash(&hash));
+        }
+        assert!(!active_chain.contains_hash(&Sha256dHash::default()));
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn blockchain_is_send_and_sync()
+    {
+        assert_send_sync::<BlockChain>();
+        assert_send_sync::<ActiveChain>();
+    }
+
+    #[test]
+    fn read_blockchain_from_two_threads()
+    {
+        let clock = Clock::new();
+        let start_block_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_block_header, 0));
+        let mut prev_hash = start_block_header.bitcoin_hash();
+        for _ in 0..1000 {
+            let header = dummy_block_header(&clock, prev_hash);
+            prev_hash = header.bitcoin_hash();
+            blocktree.try_add(header).unwrap();
+        }
+
+        let blocktree = Arc::new(blocktree);
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let blocktree = blocktree.clone();
+                ::std::thread::spawn(move || {
+                    let active_chain = blocktree.active_chain();
+                    for block in active_chain.iter() {
+                        assert_eq!(active_chain.height_of(&block.bitcoin_hash()), Some(block.height()));
+                    }
+                    active_chain.latest_block().bitcoin_hash()
+                })
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), prev_hash);
+        }
+    }
+
+    #[test]
+    fn active_chain_query_by_hash_follows_reorg()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+
+        // Main branch : start - a1 - a2
+        let a1 = dummy_fork_block_header(&clock, start_header.bitcoin_hash(), 0);
+        let a2 = dummy_fork_block_header(&clock, a1.bitcoin_hash(), 0);
+        blocktree.try_add(a1).unwrap();
+        blocktree.try_add(a2).unwrap();
+
+        // Side branch : start - b1 - b2 - b3
+        let b1 = dummy_fork_block_header(&clock, start_header.bitcoin_hash(), 1);
+        let b2 = dummy_fork_block_header(&clock, b1.bitcoin_hash(), 1);
+        let b3 = dummy_fork_block_header(&clock, b2.bitcoin_hash(), 1);
+
+        blocktree.try_add(b1).unwrap();
+        blocktree.try_add(b2).unwrap();
+        {
+            let active_chain = blocktree.active_chain();
+            assert_eq!(active_chain.height_of(&a2.bitcoin_hash()), Some(2));
+            assert!(!active_chain.contains_hash(&b2.bitcoin_hash()));
+        }
+
+        blocktree.try_add(b3).unwrap();
+        let active_chain = blocktree.active_chain();
+        assert!(!active_chain.contains_hash(&a1.bitcoin_hash()));
+        assert!(!active_chain.contains_hash(&a2.bitcoin_hash()));
+        assert_eq!(active_chain.height_of(&b1.bitcoin_hash()), Some(1));
+        assert_eq!(active_chain.height_of(&b3.bitcoin_hash()), Some(3));
+        assert_eq!(active_chain.get_by_hash(&b2.bitcoin_hash()).unwrap().header, b2);
+        assert_eq!(active_chain.get_by_height(2).unwrap().header, b2);
+    }
+
+    #[test]
+    fn reorganize_to_longer_branch_and_back()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+
+        // Branch A : start - a1 - a2 - a3 - a4
+        let mut a = vec![start_header];
+        for _ in 0..4 {
+            let header = dummy_fork_block_header(&clock, a.last().unwrap().bitcoin_hash(), 0);
+            blocktree.try_add(header).unwrap();
+            a.push(header);
+        }
+
+        // Branch B : start - a1 - a2 - b3 - b4 - b5
+        let mut b = a[..3].to_vec();
+        for _ in 0..3 {
+            let header = dummy_fork_block_header(&clock, b.last().unwrap().bitcoin_hash(), 1);
+            blocktree.try_add(header).unwrap();
+            b.push(header);
+        }
+        {
+            let active_chain = blocktree.active_chain();
+            let headers: Vec<_> = active_chain.iter().map(|block| block.header).collect();
+            assert_eq!(headers, b);
+            for (height, header) in b.iter().enumerate() {
+                assert_eq!(active_chain.height_of(&header.bitcoin_hash()), Some(height as u32));
+            }
+            assert!(!active_chain.contains_hash(&a[3].bitcoin_hash()));
+            assert!(!active_chain.contains_hash(&a[4].bitcoin_hash()));
+        }
+
+        // The same length does not switch back. The first seen branch is kept.
+        let a5 = dummy_fork_block_header(&clock, a[4].bitcoin_hash(), 0);
+        blocktree.try_add(a5).unwrap();
+        a.push(a5);
+        assert_eq!(blocktree.active_chain().latest_block().header, b[5]);
+
+        let a6 = dummy_fork_block_header(&clock, a5.bitcoin_hash(), 0);
+        match blocktree.try_add(a6).unwrap() {
+            BlockAddResult::Reorganized { new_tip, disconnected } => {
+                assert_eq!(new_tip.header, a6);
+                let disconnected: Vec<_> = disconnected.iter().map(|block| block.header).collect();
+                assert_eq!(disconnected, vec![b[5], b[4], b[3]]);
+            },
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        a.push(a6);
+        let active_chain = blocktree.active_chain();
+        let headers: Vec<_> = active_chain.iter().map(|block| block.header).collect();
+        assert_eq!(headers, a);
+        assert_eq!(active_chain.get_by_height(6).unwrap().height(), 6);
+        assert!(!active_chain.contains_hash(&b[3].bitcoin_hash()));
+    }
+
+    #[test]
+    fn blocktree_connects_orphans_in_reverse_order()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+
+        let mut headers = Vec::new();
+        let mut prev_hash = start_header.bitcoin_hash();
+        for _ in 0..10 {
+            let header = dummy_block_header(&clock, prev_hash);
+            prev_hash = header.bitcoin_hash();
+            headers.push(header);
+        }
+
+        let (first, rest) = headers.split_first().unwrap();
+        for header in rest.iter().rev() {
+            assert_eq!(blocktree.try_add(*header).unwrap(), BlockAddResult::Orphaned);
+        }
+        assert_eq!(blocktree.orphan_count(), 9);
+        assert_eq!(blocktree.active_chain().len(), 1);
+
+        assert_extended(blocktree.try_add(*first).unwrap(), *first);
+        assert_eq!(blocktree.orphan_count(), 0);
+        assert_eq!(blocktree.active_chain().len(), 11);
+        assert_eq!(blocktree.active_chain().latest_block().bitcoin_hash(), prev_hash);
+    }
+
+    #[test]
+    fn reject_orphans_when_they_are_disabled()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+        let header1 = dummy_block_header(&clock, start_header.bitcoin_hash());
+        let header2 = dummy_block_header(&clock, header1.bitcoin_hash());
+        let header3 = dummy_block_header(&clock, header2.bitcoin_hash());
+
+        blocktree.try_add(header3).unwrap();
+        blocktree.try_add(header2).unwrap();
+        assert_eq!(blocktree.orphan_count(), 2);
+
+        // The oldest orphan is evicted.
+        blocktree.set_max_orphans(1);
+        assert_eq!(blocktree.orphan_count(), 1);
+
+        blocktree.set_max_orphans(0);
+        assert_eq!(blocktree.orphan_count(), 0);
+        match blocktree.try_add(header2) {
+            Err(BlockAddError::NotFoundPrevBlock(header)) => assert_eq!(header, header2),
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        assert_extended(blocktree.try_add(header1).unwrap(), header1);
+    }
+
+    #[test]
+    fn snapshot_is_not_affected_by_later_mutation()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+
+        let a1 = dummy_fork_block_header(&clock, start_header.bitcoin_hash(), 0);
+        blocktree.try_add(a1).unwrap();
+        let snapshot = blocktree.freeze();
+        let cloned = snapshot.clone();
+
+        // Extend the chain, then re-org to another branch.
+        let a2 = dummy_fork_block_header(&clock, a1.bitcoin_hash(), 0);
+        blocktree.try_add(a2).unwrap();
+        let b1 = dummy_fork_block_header(&clock, start_header.bitcoin_hash(), 1);
+        let b2 = dummy_fork_block_header(&clock, b1.bitcoin_hash(), 1);
+        let b3 = dummy_fork_block_header(&clock, b2.bitcoin_hash(), 1);
+        for header in vec![b1, b2, b3] {
+            blocktree.try_add(header).unwrap();
+        }
+        assert_eq!(blocktree.freeze().latest_block().header, b3);
+
+        for s in vec![snapshot, cloned] {
+            assert_eq!(s.len(), 2);
+            assert_eq!(s.latest_block().header, a1);
+            assert_eq!(s.get_by_height(1).unwrap().header, a1);
+            assert!(s.get_by_hash(&b1.bitcoin_hash()).is_none());
+        }
+    }
+
+    #[test]
+    fn snapshot_shares_full_chunks_and_survives_reorg_across_them()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+        let mut headers = vec![start_header];
+        for _ in 0..SNAPSHOT_CHUNK_LEN + 4 {
+            let header = dummy_block_header(&clock, headers.last().unwrap().bitcoin_hash());
+            blocktree.try_add(header).unwrap();
+            headers.push(header);
+        }
+        let snapshot = blocktree.freeze();
+        {
+            let active_chain = blocktree.active_chain();
+            assert_eq!(snapshot.len(), active_chain.len());
+            assert!(snapshot.iter().eq(active_chain.iter()));
+            assert!(snapshot.locator_hashes().eq(active_chain.locator_hashes()));
+            for height in (SNAPSHOT_CHUNK_LEN - 1) as u32..(SNAPSHOT_CHUNK_LEN + 1) as u32 {
+                assert_eq!(snapshot.get_by_height(height), active_chain.get_by_height(height));
+            }
+        }
+
+        // Switch to a longer branch which forks before the end of the first chunk.
+        let fork_height = SNAPSHOT_CHUNK_LEN - 3;
+        let mut prev_hash = headers[fork_height].bitcoin_hash();
+        for _ in 0..10 {
+            let header = dummy_fork_block_header(&clock, prev_hash, 1);
+            blocktree.try_add(header).unwrap();
+            prev_hash = header.bitcoin_hash();
+        }
+
+        let active_chain = blocktree.active_chain();
+        assert_eq!(active_chain.latest_block().bitcoin_hash(), prev_hash);
+        let new_snapshot = blocktree.freeze();
+        assert!(new_snapshot.iter().eq(active_chain.iter()));
+        assert_eq!(new_snapshot.latest_block().bitcoin_hash(), prev_hash);
+
+        assert_eq!(snapshot.len() as usize, headers.len());
+        assert!(snapshot.iter().map(|b| b.header).eq(headers.iter().cloned()));
+        assert_eq!(snapshot.latest_block().header, *headers.last().unwrap());
+    }
+
+    #[test]
+    fn active_chain_find_fork_point_and_ancestor()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+
+        // Main branch : start - a1 - a2 - a3 - a4
+        let mut main = vec![start_header];
+        for _ in 0..4 {
+            let header = dummy_fork_block_header(&clock, main.last().unwrap().bitcoin_hash(), 0);
+            blocktree.try_add(header).unwrap();
+            main.push(header);
+        }
+
+        // Side branch : a2 - b3
+        let b3 = dummy_fork_block_header(&clock, main[2].bitcoin_hash(), 1);
+        blocktree.try_add(b3).unwrap();
+
+        let active_chain = blocktree.active_chain();
+        let b3_data = BlockData::new(b3, 3);
+        let a4_data = BlockData::new(main[4], 4);
+        assert_eq!(active_chain.find_fork_point(&b3_data).unwrap().header, main[2]);
+        assert_eq!(active_chain.find_fork_point(&a4_data).unwrap().header, main[4]);
+        let unknown = BlockData::new(dummy_block_header(&clock, b3.bitcoin_hash()), 4);
+        assert!(active_chain.find_fork_point(&unknown).is_none());
+
+        assert_eq!(active_chain.ancestor_at_height(&b3_data, 3).unwrap().header, b3);
+        assert_eq!(active_chain.ancestor_at_height(&b3_data, 1).unwrap().header, main[1]);
+        assert_eq!(active_chain.ancestor_at_height(&a4_data, 3).unwrap().header, main[3]);
+        assert!(active_chain.ancestor_at_height(&b3_data, 4).is_none());
+
+        assert_eq!(active_chain.reorg_depth(&b3_data), 1);
+        assert_eq!(active_chain.reorg_depth(&a4_data), 0);
+    }
+
+    #[test]
+    fn active_chain_block_at_or_before_time()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+
+        // Timestamps are 1, 10, 20, 30, 40, 50
+        let mut prev_hash = start_header.bitcoin_hash();
+        for i in 1..6 {
+            let header = dummy_block_header_with_time(prev_hash, i * 10);
+            prev_hash = header.bitcoin_hash();
+            blocktree.try_add(header).unwrap();
+        }
+
+        // Median time pasts are 1, 10, 10, 20, 20, 30
+        let active_chain = blocktree.active_chain();
+        assert_eq!(active_chain.median_time_past(3), Some(20));
+        assert_eq!(active_chain.median_time_past(6), None);
+        assert_eq!(active_chain.block_at_or_before_time(5).unwrap().height(), 0);
+        assert_eq!(active_chain.block_at_or_before_time(10).unwrap().height(), 2);
+        assert_eq!(active_chain.block_at_or_before_time(25).unwrap().height(), 4);
+        assert_eq!(active_chain.block_at_or_before_time(100).unwrap().height(), 5);
+    }
+
+    #[test]
+    fn blocktree_rejects_header_too_far_in_the_future()
+    {
+        let start_header = dummy_block_header_with_time(Sha256dHash::default(), 1000);
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+        blocktree.set_time_source(|| 1000);
+
+        let too_late = dummy_block_header_with_time(start_header.bitcoin_hash(), 1000 + 2 * 60 * 60 + 1);
+        match blocktree.try_add(too_late) {
+            Err(BlockAddError::InvalidTimestamp(header)) => assert_eq!(header, too_late),
+            other => panic!("Unexpected result : {:?}", other),
+        }
+
+        // Exactly 2 hours ahead is allowed.
+        let just = dummy_block_header_with_time(start_header.bitcoin_hash(), 1000 + 2 * 60 * 60);
+        assert_extended(blocktree.try_add(just).unwrap(), just);
+
+        // Orphans are checked as well.
+        let orphan = dummy_block_header_with_time(Sha256dHash::default(), 1000 + 2 * 60 * 60 + 1);
+        assert!(blocktree.try_add(orphan).is_err());
+        assert_eq!(blocktree.orphan_count(), 0);
+    }
+
+    #[test]
+    fn blocktree_rejects_header_not_later_than_median_time_past()
+    {
+        let start_header = dummy_block_header_with_time(Sha256dHash::default(), 100);
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+
+        // Timestamps are 100, 110, 120, ..., 200
+        let mut prev_hash = start_header.bitcoin_hash();
+        for i in 1..11 {
+            let header = dummy_block_header_with_time(prev_hash, 100 + i * 10);
+            prev_hash = header.bitcoin_hash();
+            blocktree.try_add(header).unwrap();
+        }
+        assert_eq!(blocktree.active_chain().median_time_past(10), Some(150));
+
+        let equal = dummy_block_header_with_time(prev_hash, 150);
+        match blocktree.try_add(equal) {
+            Err(BlockAddError::InvalidTimestamp(header)) => assert_eq!(header, equal),
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        assert_eq!(blocktree.active_chain().len(), 11);
+
+        // Earlier than the prev block, but later than median time past.
+        let later = dummy_block_header_with_time(prev_hash, 151);
+        assert_extended(blocktree.try_add(later).unwrap(), later);
+        assert_eq!(blocktree.active_chain().len(), 12);
+    }
+
+    #[test]
+    fn median_time_past_uses_only_the_last_11_blocks()
+    {
+        let start_header = dummy_block_header_with_time(Sha256dHash::default(), 1000);
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+        blocktree.set_time_source(|| 2000);
+
+        // Timestamps are 1010, 1020, ..., 1150, except the 13th header.
+        // Median of the last 11 blocks before the 13th header is 1070, while median of all is 1060.
+        let mut prev_hash = start_header.bitcoin_hash();
+        let mut headers = Vec::new();
+        for i in 1..16 {
+            let time = if i == 13 { 1070 } else { 1000 + i * 10 };
+            let header = dummy_block_header_with_time(prev_hash, time);
+            prev_hash = header.bitcoin_hash();
+            headers.push(header);
+        }
+
+        for header in &headers[..12] {
+            assert_extended(blocktree.try_add(*header).unwrap(), *header);
+        }
+        match blocktree.try_add(headers[12]) {
+            Err(BlockAddError::InvalidTimestamp(header)) => assert_eq!(header, headers[12]),
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        // Descendants of the invalid header never connect.
+        for header in &headers[13..] {
+            assert_eq!(blocktree.try_add(*header).unwrap(), BlockAddResult::Orphaned);
+        }
+        assert_eq!(blocktree.active_chain().len(), 13);
+        assert_eq!(blocktree.active_chain().median_time_past(12), Some(1070));
+    }
+
+    #[test]
+    fn heavier_branch_wins_over_the_same_length_branch()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+
+        // Main branch : start - a1 - a2
+        let a1 = dummy_fork_block_header(&clock, start_header.bitcoin_hash(), 0);
+        let a2 = dummy_fork_block_header(&clock, a1.bitcoin_hash(), 0);
+        blocktree.try_add(a1).unwrap();
+        blocktree.try_add(a2).unwrap();
+        let main_work = blocktree.active_chain().total_work();
+
+        // Side branch with a half target : start - b1 - b2
+        let mut b1 = dummy_fork_block_header(&clock, start_header.bitcoin_hash(), 1);
+        b1.bits = MIN_DIFFICULTY_BITS - 0x0040_0000;
+        mine(&mut b1);
+        let mut b2 = dummy_fork_block_header(&clock, b1.bitcoin_hash(), 1);
+        b2.bits = b1.bits;
+        mine(&mut b2);
+
+        blocktree.try_add(b1).unwrap();
+        assert_eq!(blocktree.active_chain().latest_block().header, a2);
+
+        blocktree.try_add(b2).unwrap();
+        let active_chain = blocktree.active_chain();
+        assert_eq!(active_chain.len(), 3);
+        assert_eq!(active_chain.latest_block().header, b2);
+        assert!(active_chain.total_work() > main_work);
+
+        let b2_data = active_chain.latest_block();
+        let b1_data = active_chain.get_by_height(1).unwrap();
+        assert_eq!(b2_data.chain_work(), b1_data.chain_work() + b2_data.work());
+    }
+
+    #[test]
+    fn shorter_branch_with_more_work_wins()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+
+        // Main branch of minimum difficulty : start - a1 - a2 - a3 - a4
+        let mut prev_hash = start_header.bitcoin_hash();
+        for _ in 0..4 {
+            let header = dummy_fork_block_header(&clock, prev_hash, 0);
+            prev_hash = header.bitcoin_hash();
+            blocktree.try_add(header).unwrap();
+        }
+        let a4 = *blocktree.active_chain().latest_block();
+
+        // Side branch with a half target, so each block has double work : start - b1 - b2 - b3
+        let mut b = Vec::new();
+        let mut prev_hash = start_header.bitcoin_hash();
+        for _ in 0..3 {
+            let mut header = dummy_fork_block_header(&clock, prev_hash, 1);
+            header.bits = MIN_DIFFICULTY_BITS - 0x0040_0000;
+            mine(&mut header);
+            prev_hash = header.bitcoin_hash();
+            b.push(header);
+        }
+
+        blocktree.try_add(b[0]).unwrap();
+        blocktree.try_add(b[1]).unwrap();
+        // b2 has the same work as a4. The first seen tip is kept.
+        assert_eq!(blocktree.active_chain().latest_block(), &a4);
+
+        blocktree.try_add(b[2]).unwrap();
+        let active_chain = blocktree.active_chain();
+        assert_eq!(active_chain.len(), 4);
+        assert_eq!(active_chain.latest_block().header, b[2]);
+        assert!(active_chain.total_work() > a4.chain_work());
+    }
+
+    #[test]
+    fn try_add_reports_how_the_active_chain_changes()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let start = BlockData::new(start_header, 0);
+        let mut blocktree = BlockChain::with_start(Network::Regtest, start);
+
+        // Main branch : start - a1 - a2
+        let a1 = dummy_fork_block_header(&clock, start_header.bitcoin_hash(), 0);
+        let a2 = dummy_fork_block_header(&clock, a1.bitcoin_hash(), 0);
+        let a1_data = BlockData::with_prev(a1, &start);
+        let a2_data = BlockData::with_prev(a2, &a1_data);
+        assert_eq!(blocktree.try_add(a1).unwrap(), BlockAddResult::ExtendedActiveChain(a1_data));
+        assert_eq!(blocktree.try_add(a2).unwrap(), BlockAddResult::ExtendedActiveChain(a2_data));
+
+        // Side branch : start - b1 - b2 - b3
+        // The same work as the main branch does not switch branches.
+        let b1 = dummy_fork_block_header(&clock, start_header.bitcoin_hash(), 1);
+        let b2 = dummy_fork_block_header(&clock, b1.bitcoin_hash(), 1);
+        let b3 = dummy_fork_block_header(&clock, b2.bitcoin_hash(), 1);
+        let b1_data = BlockData::with_prev(b1, &start);
+        let b2_data = BlockData::with_prev(b2, &b1_data);
+        let b3_data = BlockData::with_prev(b3, &b2_data);
+        assert_eq!(blocktree.try_add(b1).unwrap(), BlockAddResult::AddedToSideChain(b1_data));
+        assert_eq!(blocktree.try_add(b2).unwrap(), BlockAddResult::AddedToSideChain(b2_data));
+        assert_eq!(
+            blocktree.try_add(b3).unwrap(),
+            BlockAddResult::Reorganized {
+                new_tip: b3_data,
+                disconnected: vec![a2_data, a1_data],
+            }
+        );
+
+        // Another branch whose descendants are orphans : start - c1 - c2 - c3 - c4
+        let mut c_headers = Vec::new();
+        let mut prev_hash = start_header.bitcoin_hash();
+        for _ in 0..4 {
+            let header = dummy_fork_block_header(&clock, prev_hash, 2);
+            prev_hash = header.bitcoin_hash();
+            c_headers.push(header);
+        }
+        for header in &c_headers[1..] {
+            assert_eq!(blocktree.try_add(*header).unwrap(), BlockAddResult::Orphaned);
+        }
+        match blocktree.try_add(c_headers[0]).unwrap() {
+            BlockAddResult::Reorganized { new_tip, disconnected } => {
+                assert_eq!(new_tip.header, c_headers[3]);
+                assert_eq!(new_tip.height(), 4);
+                assert_eq!(disconnected, vec![b3_data, b2_data, b1_data]);
+            },
+            other => panic!("Unexpected result : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn work_of_min_difficulty_block()
+    {
+        let clock = Clock::new();
+        // Target of regtest is about 2^255, so the work is 2.
+        let header = dummy_block_header(&clock, Sha256dHash::default());
+        assert_eq!(BlockData::new(header, 0).work(), Uint256::from_u64(2).unwrap());
+
+        let mut header = header;
+        header.bits = 0;
+        assert_eq!(BlockData::new(header, 0).work(), Uint256::from_u64(0).unwrap());
+    }
+
+    #[test]
+    fn with_checkpoint_rejects_block_of_another_network()
+    {
+        let testnet_genesis = BlockData::genesis(Network::Testnet);
+        match BlockChain::with_checkpoint(Network::Bitcoin, testnet_genesis) {
+            Err(Error::UnknownCheckpoint(hash, Network::Bitcoin)) => assert_eq!(hash, testnet_genesis.bitcoin_hash()),
+            _ => panic!("Testnet genesis should not be a checkpoint of mainnet"),
+        }
+
+        let blockchain = BlockChain::with_checkpoint(Network::Testnet, testnet_genesis).unwrap();
+        assert_eq!(blockchain.network(), Network::Testnet);
+        assert_eq!(blockchain.freeze().network(), Network::Testnet);
+        assert_eq!(BlockChain::new(Network::Regtest).clone().network(), Network::Regtest);
+    }
+
+    #[test]
+    fn start_from_checkpoint_at_non_zero_height()
+    {
+        let clock = Clock::new();
+        let start = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::from_checkpoint(Network::Regtest, start, 1000).unwrap();
+        let next = dummy_block_header(&clock, start.bitcoin_hash());
+        assert_extended(blocktree.try_add(next).unwrap(), next);
+        assert_eq!(blocktree.active_chain().latest_block().height(), 1001);
+        assert_eq!(blocktree.active_chain().height_of(&start.bitcoin_hash()), Some(1000));
+
+        // Regtest genesis is not the mainnet genesis.
+        let regtest_genesis = genesis_block(Network::Regtest).header;
+        match BlockChain::from_checkpoint(Network::Bitcoin, regtest_genesis, 0) {
+            Err(Error::CheckpointMismatch(hash)) => assert_eq!(hash, regtest_genesis.bitcoin_hash()),
+            _ => panic!("Regtest genesis should contradict the mainnet genesis"),
+        }
+    }
+
+    #[test]
+    fn reject_headers_which_contradict_checkpoint()
+    {
+        let clock = Clock::new();
+        let start = BlockData::genesis(Network::Regtest);
+        let b1 = dummy_block_header(&clock, start.bitcoin_hash());
+        let b2 = dummy_block_header(&clock, b1.bitcoin_hash());
+        let fork_b2 = dummy_fork_block_header(&clock, b1.bitcoin_hash(), 1);
+
+        let mut blocktree = BlockChain::with_start(Network::Regtest, start);
+        blocktree.set_checkpoints(vec![Checkpoint::new(2, b2.bitcoin_hash())]);
+        blocktree.try_add(b1).unwrap();
+        match blocktree.try_add(fork_b2) {
+            Err(BlockAddError::CheckpointMismatch(header)) => assert_eq!(header, fork_b2),
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        assert!(!blocktree.contains(&fork_b2.bitcoin_hash()));
+        assert_extended(blocktree.try_add(b2).unwrap(), b2);
+
+        // Checkpoints are kept by clone.
+        let mut cloned = blocktree.clone();
+        assert!(cloned.try_add(dummy_fork_block_header(&clock, b1.bitcoin_hash(), 2)).is_err());
+    }
+
+    #[test]
+    fn locator_heights_are_exponential_and_end_with_start()
+    {
+        assert_eq!(locator_heights(0, 0), vec![0]);
+        assert_eq!(locator_heights(5, 8), vec![8, 7, 6, 5]);
+        assert_eq!(
+            locator_heights(0, 600),
+            vec![600, 599, 598, 597, 596, 595, 594, 593, 592, 591, 589, 585, 577, 561, 529, 465, 337, 81, 0]
+        );
+        assert_eq!(*locator_heights(100, 200_000).last().unwrap(), 100);
+    }
+
+    #[test]
+    fn locator_hashes_span_to_start_block()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 100));
+        let mut prev_hash = start_header.bitcoin_hash();
+        for _ in 0..600 {
+            let header = dummy_block_header(&clock, prev_hash);
+            prev_hash = header.bitcoin_hash();
+            blocktree.try_add(header).unwrap();
+        }
+
+        let active_chain = blocktree.active_chain();
+        let locator = active_chain.locator_hashes_vec();
+        let heights: Vec<_> = locator.iter().map(|h| active_chain.height_of(h).unwrap()).collect();
+        assert_eq!(heights, locator_heights(100, 700));
+        assert_eq!(locator[0], prev_hash);
+        assert_eq!(*locator.last().unwrap(), start_header.bitcoin_hash());
+    }
+
+    fn mainnet_block_1_header() -> BlockHeader
+    {
+        BlockHeader {
+            version: 1,
+            prev_blockhash: genesis_block(Network::Bitcoin).bitcoin_hash(),
+            merkle_root: Sha256dHash::from_hex("0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098")
+                .unwrap(),
+            time: 1231469665,
+            bits: 0x1d00_ffff,
+            nonce: 2573394689,
+        }
+    }
+
+    #[test]
+    fn accept_valid_mainnet_header()
+    {
+        let header = mainnet_block_1_header();
+        assert_eq!(
+            header.bitcoin_hash(),
+            Sha256dHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap()
+        );
+
+        let mut blockchain = BlockChain::new(Network::Bitcoin);
+        assert_extended(blockchain.try_add(header).unwrap(), header);
+        assert_eq!(blockchain.active_chain().latest_block().height(), 1);
+    }
+
+    #[test]
+    fn reject_header_with_insufficient_work()
+    {
+        let clock = Clock::new();
+        let mut blockchain = BlockChain::new(Network::Bitcoin);
+
+        let mut header = mainnet_block_1_header();
+        header.nonce += 1;
+        match blockchain.try_add(header) {
+            Err(BlockAddError::InvalidPoW(h)) => assert_eq!(h, header),
+            other => panic!("Header with wrong nonce should be rejected : {:?}", other),
+        }
+
+        // Target of regtest is below the minimum difficulty of mainnet, even if the hash meets it.
+        let mut easy = mainnet_block_1_header();
+        easy.bits = MIN_DIFFICULTY_BITS;
+        mine(&mut easy);
+        match blockchain.try_add(easy) {
+            Err(BlockAddError::InvalidPoW(h)) => assert_eq!(h, easy),
+            other => panic!("Header easier than pow limit should be rejected : {:?}", other),
+        }
+
+        // Orphans are checked before they are kept.
+        let mut orphan = dummy_block_header(&clock, Sha256dHash::default());
+        orphan.bits = 0x1d00_ffff;
+        assert!(blockchain.try_add(orphan).is_err());
+        assert_eq!(blockchain.orphan_count(), 0);
+        assert_eq!(blockchain.active_chain().len(), 1);
+    }
+
+    #[test]
+    fn locator_of_5000_blocks_has_22_entries()
+    {
+        let clock = Clock::new();
+        let start_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_header, 0));
+        let mut prev_hash = start_header.bitcoin_hash();
+        for _ in 1..5000 {
+            let header = dummy_block_header(&clock, prev_hash);
+            prev_hash = header.bitcoin_hash();
+            blocktree.try_add(header).unwrap();
+        }
+
+        let active_chain = blocktree.active_chain();
+        let locator = active_chain.locator_hashes_vec();
+        let heights: Vec<_> = locator.iter().map(|h| active_chain.height_of(h).unwrap()).collect();
+        assert_eq!(
+            heights,
+            vec![
+                4999, 4998, 4997, 4996, 4995, 4994, 4993, 4992, 4991, 4990, 4988, 4984, 4976, 4960, 4928, 4864, 4736,
+                4480, 3968, 2944, 896, 0,
+            ]
+        );
+    }
+
+    // A block with a distinct coinbase, whose header commits to its transactions.
+    fn dummy_full_block(clock: &Clock, prev_hash: Sha256dHash, lock_time: u32) -> Block
+    {
+        let mut coinbase = genesis_block(Network::Regtest).txdata[0].clone();
+        coinbase.lock_time = lock_time;
+        let mut block = Block {
+            header: dummy_block_header(clock, prev_hash),
+            txdata: vec![coinbase],
+        };
+        block.header.merkle_root = block.merkle_root();
+        mine(&mut block.header);
+        block
+    }
+
+    #[test]
+    fn keep_transactions_of_full_blocks()
+    {
+        let clock = Clock::new();
+        let start_block_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_block_header, 0));
+        let block1 = dummy_full_block(&clock, start_block_header.bitcoin_hash(), 1);
+        let block2 = dummy_full_block(&clock, block1.bitcoin_hash(), 2);
+
+        assert_extended(blocktree.try_add_full_block(block1.clone()).unwrap(), block1.header);
+        // Transactions are attached to a header which is already added.
+        assert_extended(blocktree.try_add(block2.header).unwrap(), block2.header);
+        assert!(blocktree.active_chain().get_full_block(&block2.bitcoin_hash()).is_none());
+        match blocktree.try_add_full_block(block2.clone()).unwrap() {
+            BlockAddResult::AlreadyKnown(block) => assert_eq!(block.header, block2.header),
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        assert_eq!(blocktree.active_chain().len(), 3);
+
+        let full_block = blocktree.active_chain().get_full_block(&block1.bitcoin_hash()).unwrap();
+        assert_eq!(full_block, FullBlockData::new(block1, 1));
+        let full_block = blocktree.active_chain().get_full_block(&block2.bitcoin_hash()).unwrap();
+        assert_eq!(full_block, FullBlockData::new(block2, 2));
+    }
+
+    #[test]
+    fn reject_full_block_whose_transactions_do_not_match_merkle_root()
+    {
+        let clock = Clock::new();
+        let start_block_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_block_header, 0));
+        let mut block = dummy_full_block(&clock, start_block_header.bitcoin_hash(), 1);
+        block.txdata[0].lock_time = 100;
+
+        match blocktree.try_add_full_block(block) {
+            Err(BlockAddError::InvalidMerkleRoot(_)) => {},
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        assert_eq!(blocktree.active_chain().len(), 1);
+    }
+
+    #[test]
+    fn downgrade_buried_full_blocks_to_headers()
+    {
+        let clock = Clock::new();
+        let start_block_header = dummy_block_header(&clock, Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start_block_header, 0));
+        blocktree.set_body_retention(Some(2));
+        let mut hashes = Vec::new();
+        let mut prev_hash = start_block_header.bitcoin_hash();
+        for i in 0..4 {
+            let block = dummy_full_block(&clock, prev_hash, i);
+            prev_hash = block.bitcoin_hash();
+            hashes.push(prev_hash);
+            blocktree.try_add_full_block(block).unwrap();
+        }
+
+        let active_chain = blocktree.active_chain();
+        assert!(active_chain.get_full_block(&hashes[0]).is_none());
+        assert!(active_chain.get_full_block(&hashes[1]).is_none());
+        assert!(active_chain.get_full_block(&hashes[2]).is_some());
+        assert!(active_chain.get_full_block(&hashes[3]).is_some());
+        // Headers are still there.
+        assert_eq!(active_chain.len(), 5);
+    }
+
+    // Regtest headers whose difficulty is adjusted every 4 blocks, which should take 10 minutes each.
+    fn retarget_test_chain() -> (BlockChain, BlockHeader)
+    {
+        let start = header_at(Sha256dHash::default(), 0, 1_000_000);
+        let mut blocktree = BlockChain::with_start(Network::Regtest, BlockData::new(start, 0));
+        blocktree.set_retarget_params(RetargetParams {
+            interval: 4,
+            timespan: 4 * 600,
+            allow_min_difficulty_blocks: false,
+            no_retargeting: false,
+        });
+        (blocktree, start)
+    }
+
+    fn header_with(prev: &BlockHeader, time_delta: u32, bits: u32) -> BlockHeader
+    {
+        let mut header = header_at(prev.bitcoin_hash(), 0, prev.time + time_delta);
+        header.bits = bits;
+        mine(&mut header);
+        header
+    }
+
+    #[test]
+    fn reject_headers_which_do_not_follow_retarget()
+    {
+        let (mut blocktree, start) = retarget_test_chain();
+        // Blocks are found 4 times faster than expected.
+        let mut prev = start;
+        for _ in 0..3 {
+            prev = header_with(&prev, 150, MIN_DIFFICULTY_BITS);
+            blocktree.try_add(prev).unwrap();
+        }
+
+        // The target gets 1/4 at the retarget height.
+        let harder_bits = 0x201f_ffff;
+        let unchanged = header_with(&prev, 150, MIN_DIFFICULTY_BITS);
+        match blocktree.try_add(unchanged) {
+            Err(BlockAddError::InvalidDifficulty(header)) => assert_eq!(header, unchanged),
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        let retargeted = header_with(&prev, 150, harder_bits);
+        assert_extended(blocktree.try_add(retargeted).unwrap(), retargeted);
+
+        // Difficulty does not change until the next retarget height.
+        let reset = header_with(&retargeted, 150, MIN_DIFFICULTY_BITS);
+        assert!(blocktree.try_add(reset).is_err());
+        let next = header_with(&retargeted, 150, harder_bits);
+        assert_extended(blocktree.try_add(next).unwrap(), next);
+    }
+
+    #[test]
+    fn allow_min_difficulty_block_after_long_gap_on_testnet()
+    {
+        let (mut blocktree, start) = retarget_test_chain();
+        let mut params = RetargetParams::new(Network::Testnet);
+        params.interval = 4;
+        params.timespan = 4 * 600;
+        blocktree.set_retarget_params(params);
+
+        let mut prev = start;
+        for _ in 0..3 {
+            prev = header_with(&prev, 150, MIN_DIFFICULTY_BITS);
+            blocktree.try_add(prev).unwrap();
+        }
+        let harder_bits = 0x201f_ffff;
+        let retargeted = header_with(&prev, 150, harder_bits);
+        blocktree.try_add(retargeted).unwrap();
+
+        // A block of the min difficulty is not allowed within 20 minutes.
+        assert!(blocktree.try_add(header_with(&retargeted, 20 * 60, MIN_DIFFICULTY_BITS)).is_err());
+        let easy = header_with(&retargeted, 20 * 60 + 1, MIN_DIFFICULTY_BITS);
+        assert_extended(blocktree.try_add(easy).unwrap(), easy);
+
+        // The next block goes back to the difficulty before the min difficulty block.
+        assert!(blocktree.try_add(header_with(&easy, 60, MIN_DIFFICULTY_BITS)).is_err());
+        let next = header_with(&easy, 60, harder_bits);
+        assert_extended(blocktree.try_add(next).unwrap(), next);
+    }
+
+    #[test]
+    fn regtest_does_not_retarget()
+    {
+        let (mut blocktree, start) = retarget_test_chain();
+        blocktree.set_retarget_params(RetargetParams::new(Network::Regtest));
+        let mut prev = start;
+        for _ in 0..8 {
+            prev = header_with(&prev, 1, MIN_DIFFICULTY_BITS);
+            blocktree.try_add(prev).unwrap();
+        }
+        assert_eq!(blocktree.active_chain().latest_block().height(), 8);
+    }
 }
@@ -0,0 +1,110 @@
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::{constants::Network, serialize::BitcoinHash};
+use bitcoin::util::hash::Sha256dHash;
+
+use super::BlockData;
+
+/// Well known blocks of mainnet, from Bitcoin Core.
+const BITCOIN_CHECKPOINTS: &[(u32, &str)] = &[
+    (11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+    (33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+    (74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+    (105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+    (134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+    (168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+    (193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+    (210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+    (216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+    (225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+    (250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+    (279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+    (295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983"),
+];
+
+/// Well known blocks of testnet, from Bitcoin Core.
+const TESTNET_CHECKPOINTS: &[(u32, &str)] = &[
+    (546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70"),
+];
+
+/// A block which is known to be in the chain at `height`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint
+{
+    pub height: u32,
+    pub hash: Sha256dHash,
+}
+
+impl Checkpoint
+{
+    pub fn new(height: u32, hash: Sha256dHash) -> Checkpoint
+    {
+        Checkpoint { height, hash }
+    }
+
+    /// Whether `block` is at the height of this checkpoint but is a different block.
+    pub fn conflicts_with(&self, block: &BlockData) -> bool
+    {
+        self.height == block.height() && self.hash != block.bitcoin_hash()
+    }
+}
+
+/// Blocks which are known to be in the chain of `network`, sorted by height.
+/// The genesis block is always included.
+pub fn checkpoints(network: Network) -> Vec<Checkpoint>
+{
+    let known = match network {
+        Network::Bitcoin => BITCOIN_CHECKPOINTS,
+        Network::Testnet => TESTNET_CHECKPOINTS,
+        Network::Regtest => &[],
+    };
+    let mut vec = vec![Checkpoint::new(0, genesis_block(network).bitcoin_hash())];
+    vec.extend(known.iter().map(|&(height, hex)| Checkpoint::new(height, Sha256dHash::from_hex(hex).unwrap())));
+    vec
+}
+
+/// Whether `block` is one of the checkpoints of `network`. Both height and hash must match.
+pub fn is_checkpoint(network: Network, block: &BlockData) -> bool
+{
+    let hash = block.bitcoin_hash();
+    checkpoints(network)
+        .into_iter()
+        .any(|checkpoint| checkpoint.height == block.height() && checkpoint.hash == hash)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn genesis_is_checkpoint_of_its_network_only()
+    {
+        let regtest_genesis = BlockData::genesis(Network::Regtest);
+        assert!(is_checkpoint(Network::Regtest, &regtest_genesis));
+        assert!(!is_checkpoint(Network::Bitcoin, &regtest_genesis));
+        assert!(!is_checkpoint(Network::Testnet, &regtest_genesis));
+
+        assert!(is_checkpoint(Network::Bitcoin, &BlockData::genesis(Network::Bitcoin)));
+    }
+
+    #[test]
+    fn checkpoints_are_sorted_by_height()
+    {
+        for network in &[Network::Bitcoin, Network::Testnet, Network::Regtest] {
+            let heights: Vec<_> = checkpoints(*network).into_iter().map(|c| c.height).collect();
+            let mut sorted = heights.clone();
+            sorted.sort();
+            assert_eq!(heights, sorted);
+        }
+    }
+
+    #[test]
+    fn block_conflicts_with_checkpoint_only_at_the_same_height()
+    {
+        let genesis = BlockData::genesis(Network::Bitcoin);
+        let checkpoint = Checkpoint::new(0, genesis.bitcoin_hash());
+        assert!(!checkpoint.conflicts_with(&genesis));
+        assert!(checkpoint.conflicts_with(&BlockData::genesis(Network::Testnet)));
+        assert!(!checkpoint.conflicts_with(&BlockData::new(genesis_block(Network::Testnet).header, 1)));
+    }
+}
@@ -0,0 +1,134 @@
+use bitcoin::network::constants::Network;
+use bitcoin::util::uint::Uint256;
+
+/// The number of blocks between difficulty adjustments.
+pub const RETARGET_INTERVAL: u32 = 2016;
+
+/// Expected seconds which `RETARGET_INTERVAL` blocks take (2 weeks).
+pub const TARGET_TIMESPAN: u32 = 14 * 24 * 60 * 60;
+
+/// How difficulty is adjusted on a network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetargetParams
+{
+    /// The number of blocks between difficulty adjustments.
+    pub interval: u32,
+    /// Expected seconds which `interval` blocks take.
+    pub timespan: u32,
+    /// Testnet allows a block of the min difficulty if no block is found for twice the target spacing.
+    pub allow_min_difficulty_blocks: bool,
+    /// Regtest never adjusts difficulty.
+    pub no_retargeting: bool,
+}
+
+impl RetargetParams
+{
+    pub fn new(network: Network) -> RetargetParams
+    {
+        RetargetParams {
+            interval: RETARGET_INTERVAL,
+            timespan: TARGET_TIMESPAN,
+            allow_min_difficulty_blocks: network != Network::Bitcoin,
+            no_retargeting: network == Network::Regtest,
+        }
+    }
+
+    /// Expected seconds between two blocks.
+    pub fn spacing(&self) -> u32
+    {
+        self.timespan / self.interval
+    }
+
+    pub fn is_retarget_height(&self, height: u32) -> bool
+    {
+        height % self.interval == 0
+    }
+}
+
+/// `bits` of the first block of a new interval.
+/// `first_time` is the timestamp of the first block of the last interval,
+/// and `last_time` and `last_bits` are of the last block of it.
+///
+/// The actual timespan is clamped to 1/4 - 4 times of the expected one.
+pub fn retarget_bits(first_time: u32, last_time: u32, last_bits: u32, timespan: u32, pow_limit: &Uint256) -> u32
+{
+    let actual = (last_time as i64 - first_time as i64)
+        .max(timespan as i64 / 4)
+        .min(timespan as i64 * 4) as u32;
+    let target = target_from_compact(last_bits);
+    // A target near 2^256 (e.g. regtest) is divided first so that it does not overflow.
+    let new_target = if target.bits() <= 256 - 32 {
+        target.mul_u32(actual) / Uint256::from_u64(timespan as u64).unwrap()
+    } else {
+        (target / Uint256::from_u64(timespan as u64).unwrap()).mul_u32(actual)
+    };
+    if new_target > *pow_limit {
+        compact_from_target(pow_limit)
+    } else {
+        compact_from_target(&new_target)
+    }
+}
+
+/// Decode compact `bits` into a target. A negative target is treated as zero.
+pub fn target_from_compact(bits: u32) -> Uint256
+{
+    let size = (bits >> 24) as usize;
+    let word = bits & 0x007f_ffff;
+    if bits & 0x0080_0000 != 0 {
+        return Uint256::from_u64(0).unwrap();
+    }
+    if size <= 3 {
+        Uint256::from_u64((word >> (8 * (3 - size))) as u64).unwrap()
+    } else {
+        Uint256::from_u64(word as u64).unwrap() << (8 * (size - 3))
+    }
+}
+
+/// Encode a target into compact `bits`. Precision beyond 3 bytes is truncated.
+pub fn compact_from_target(target: &Uint256) -> u32
+{
+    let mut size = (target.bits() + 7) / 8;
+    let mut compact = if size <= 3 {
+        (target.low_u64() << (8 * (3 - size))) as u32
+    } else {
+        (*target >> (8 * (size - 3))).low_u64() as u32
+    };
+    // The sign bit must not be set.
+    if compact & 0x0080_0000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | (size as u32) << 24
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use blockchain::block::pow_limit;
+
+    #[test]
+    fn compact_round_trip_of_pow_limits()
+    {
+        assert_eq!(compact_from_target(&pow_limit(Network::Bitcoin)), 0x1d00_ffff);
+        assert_eq!(compact_from_target(&pow_limit(Network::Regtest)), 0x207f_ffff);
+        assert_eq!(target_from_compact(0x1d00_ffff), pow_limit(Network::Bitcoin));
+        assert_eq!(target_from_compact(0x207f_ffff), pow_limit(Network::Regtest));
+        assert_eq!(compact_from_target(&target_from_compact(0x1b04_04cb)), 0x1b04_04cb);
+    }
+
+    // Same cases as `pow_tests.cpp` of Bitcoin Core.
+    #[test]
+    fn retarget_mainnet()
+    {
+        let limit = pow_limit(Network::Bitcoin);
+        // Blocks of the interval took a little more than expected.
+        assert_eq!(retarget_bits(1261130161, 1262152739, 0x1d00_ffff, TARGET_TIMESPAN, &limit), 0x1d00_d86a);
+        // Difficulty never gets lower than the pow limit.
+        assert_eq!(retarget_bits(1231006505, 1233061996, 0x1d00_ffff, TARGET_TIMESPAN, &limit), 0x1d00_ffff);
+        // Too fast interval is clamped to 1/4.
+        assert_eq!(retarget_bits(1279008237, 1279297671, 0x1c05_a3f4, TARGET_TIMESPAN, &limit), 0x1c01_68fd);
+        // Too slow interval is clamped to 4 times.
+        assert_eq!(retarget_bits(1263163443, 1269211443, 0x1c38_7f6f, TARGET_TIMESPAN, &limit), 0x1d00_e1fd);
+    }
+}
@@ -1,10 +1,59 @@
 mod blockchain;
 mod block;
+mod block_source;
+mod checkpoint;
+mod difficulty;
+mod orphan_pool;
+mod snapshot;
+mod validate;
 
 pub use self::blockchain::BlockChain;
 pub use self::block::{BlockData, BlockDataLike, FullBlockData};
+pub use self::block_source::BlockSource;
+pub use self::checkpoint::{checkpoints, is_checkpoint, Checkpoint};
+pub use self::difficulty::{RetargetParams, RETARGET_INTERVAL, TARGET_TIMESPAN};
+pub use self::orphan_pool::DEFAULT_MAX_ORPHANS;
+pub use self::snapshot::BlockChainSnapshot;
+pub use self::validate::{validate_headers, validate_headers_parallel, validate_merkle_root, ValidationError,
+                         MIN_PARALLEL_BATCH};
 
 use bitcoin::blockdata::block::BlockHeader;
 
 #[derive(Debug)]
-pub struct NotFoundPrevBlock(pub BlockHeader);
+pub enum BlockAddError
+{
+    NotFoundPrevBlock(BlockHeader),
+    /// Hash of given block does not meet its target,
+    /// or the target is easier than the minimum difficulty of the network.
+    InvalidPoW(BlockHeader),
+    /// Timestamp of given block is more than 2 hours ahead of our clock,
+    /// or not later than median time past of its prev block.
+    InvalidTimestamp(BlockHeader),
+    /// Transactions of given full block do not hash up to the merkle root of its header.
+    InvalidMerkleRoot(BlockHeader),
+    /// `bits` of given block does not follow difficulty adjustment.
+    InvalidDifficulty(BlockHeader),
+    /// Given block is at the height of a checkpoint, but is not the checkpoint block.
+    CheckpointMismatch(BlockHeader),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockAddResult
+{
+    /// Given block is connected to the tip of the active chain.
+    ExtendedActiveChain(BlockData),
+    /// Given block is connected to a side branch, which does not have more work than the active chain.
+    AddedToSideChain(BlockData),
+    /// The branch of given block (or its descendant orphans) gets more work than the active chain.
+    /// `disconnected` blocks are removed from the active chain, from the old tip.
+    Reorganized
+    {
+        new_tip: BlockData,
+        disconnected: Vec<BlockData>,
+    },
+    /// Prev block of given block is not found yet. Given block is kept until its prev block comes.
+    Orphaned,
+    /// Given block is already in the tree, so nothing changes.
+    /// Transactions of a full block are still attached to it.
+    AlreadyKnown(BlockData),
+}
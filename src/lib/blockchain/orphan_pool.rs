@@ -0,0 +1,167 @@
+use std::collections::{HashMap, VecDeque};
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+
+pub const DEFAULT_MAX_ORPHANS: usize = 1000;
+
+/// A bounded pool of headers whose prev block is not known yet.
+/// When the pool is full, the oldest header is evicted.
+#[derive(Debug)]
+pub struct OrphanPool
+{
+    // Orphan headers keyed by their hashes.
+    headers: HashMap<Sha256dHash, BlockHeader>,
+    // Hashes of orphan headers keyed by their `prev_blockhash`.
+    children: HashMap<Sha256dHash, Vec<Sha256dHash>>,
+    // Hashes of orphan headers in insertion order.
+    // Hashes of taken orphans are left here and skipped on eviction, so that taking orphans does not
+    // scan the whole queue. They are swept once the queue gets twice as long as `capacity`.
+    order: VecDeque<Sha256dHash>,
+    capacity: usize,
+}
+
+impl OrphanPool
+{
+    pub fn new(capacity: usize) -> OrphanPool
+    {
+        OrphanPool {
+            headers: HashMap::new(),
+            children: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.headers.len()
+    }
+
+    pub fn capacity(&self) -> usize
+    {
+        self.capacity
+    }
+
+    /// Oldest headers are evicted if the pool has more than `capacity`.
+    pub fn set_capacity(&mut self, capacity: usize)
+    {
+        self.capacity = capacity;
+        while self.headers.len() > capacity {
+            self.remove_oldest();
+        }
+    }
+
+    pub fn contains(&self, header: &BlockHeader) -> bool
+    {
+        self.headers.contains_key(&header.bitcoin_hash())
+    }
+
+    pub fn insert(&mut self, header: BlockHeader)
+    {
+        let hash = header.bitcoin_hash();
+        if self.capacity == 0 || self.headers.contains_key(&hash) {
+            return;
+        }
+        while self.headers.len() >= self.capacity {
+            self.remove_oldest();
+        }
+        self.headers.insert(hash, header);
+        self.children
+            .entry(header.prev_blockhash)
+            .or_insert_with(Vec::new)
+            .push(hash);
+        self.order.push_back(hash);
+
+        if self.order.len() > self.capacity * 2 {
+            let headers = &self.headers;
+            self.order.retain(|hash| headers.contains_key(hash));
+        }
+    }
+
+    /// Remove and return all orphans whose prev block is `prev_hash`.
+    pub fn take_children(&mut self, prev_hash: &Sha256dHash) -> Vec<BlockHeader>
+    {
+        let hashes = self.children.remove(prev_hash).unwrap_or_default();
+        hashes.iter().filter_map(|hash| self.headers.remove(hash)).collect()
+    }
+
+    fn remove_oldest(&mut self)
+    {
+        while let Some(hash) = self.order.pop_front() {
+            // Already taken.
+            let header = match self.headers.remove(&hash) {
+                None => continue,
+                Some(header) => header,
+            };
+            let is_empty = match self.children.get_mut(&header.prev_blockhash) {
+                None => false,
+                Some(siblings) => {
+                    siblings.retain(|h| *h != hash);
+                    siblings.is_empty()
+                },
+            };
+            if is_empty {
+                self.children.remove(&header.prev_blockhash);
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn dummy_header(prev_blockhash: Sha256dHash, nonce: u32) -> BlockHeader
+    {
+        BlockHeader {
+            version: 1,
+            prev_blockhash,
+            merkle_root: Sha256dHash::default(),
+            time: 0,
+            bits: 0,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn evict_oldest_orphan_which_is_not_taken_yet()
+    {
+        let parent = Sha256dHash::from_data(b"parent");
+        let other = Sha256dHash::from_data(b"other");
+        let mut pool = OrphanPool::new(3);
+
+        let taken = dummy_header(parent, 0);
+        let old = dummy_header(other, 1);
+        pool.insert(taken);
+        pool.insert(old);
+        assert_eq!(pool.take_children(&parent), vec![taken]);
+        assert_eq!(pool.len(), 1);
+
+        // The taken one does not count, so `old` is evicted only by the 4th orphan.
+        let new: Vec<_> = (2..5).map(|nonce| dummy_header(other, nonce)).collect();
+        pool.insert(new[0]);
+        pool.insert(new[1]);
+        assert!(pool.contains(&old));
+        pool.insert(new[2]);
+        assert!(!pool.contains(&old));
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.take_children(&other), new);
+    }
+
+    #[test]
+    fn sweep_taken_orphans_from_queue()
+    {
+        let mut pool = OrphanPool::new(2);
+        for nonce in 0..100 {
+            let header = dummy_header(Sha256dHash::default(), nonce);
+            pool.insert(header);
+            pool.take_children(&Sha256dHash::default());
+        }
+        assert_eq!(pool.len(), 0);
+        assert!(pool.order.len() <= 4);
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use bitcoin::network::{constants::Network, serialize::BitcoinHash};
+use bitcoin::util::hash::Sha256dHash;
+
+use super::{BlockData, blockchain::locator_heights};
+
+/// The number of blocks in a chunk which `BlockChain` shares with its snapshots.
+pub(super) const SNAPSHOT_CHUNK_LEN: usize = 2016;
+
+/// An immutable view of the active chain at some instant.
+///
+/// Cloning a snapshot is cheap, and it can be sent to another thread.
+/// Later mutations of `BlockChain` never change a snapshot.
+///
+/// Blocks are held in chunks of `SNAPSHOT_CHUNK_LEN` which are shared with `BlockChain`,
+/// so taking a snapshot copies only blocks after the last full chunk.
+#[derive(Debug, Clone)]
+pub struct BlockChainSnapshot
+{
+    network: Network,
+    chunks: Vec<Arc<Vec<BlockData>>>,
+    // Blocks after `chunks` up to the tip.
+    tail: Arc<Vec<BlockData>>,
+}
+
+impl BlockChainSnapshot
+{
+    pub(super) fn new(network: Network, chunks: Vec<Arc<Vec<BlockData>>>, tail: Vec<BlockData>) -> BlockChainSnapshot
+    {
+        assert!(!chunks.is_empty() || !tail.is_empty());
+        BlockChainSnapshot {
+            network,
+            chunks,
+            tail: Arc::new(tail),
+        }
+    }
+
+    pub fn network(&self) -> Network
+    {
+        self.network
+    }
+
+    pub fn len(&self) -> u32
+    {
+        (self.chunks.len() * SNAPSHOT_CHUNK_LEN + self.tail.len()) as u32
+    }
+
+    /// Get the latest block
+    ///
+    /// Note that there always be latest block.
+    pub fn latest_block(&self) -> &BlockData
+    {
+        self.iter().next_back().unwrap()
+    }
+
+    /// Get the specified height block
+    pub fn get_by_height(&self, height: u32) -> Option<&BlockData>
+    {
+        let start_height = self.iter().next().unwrap().height;
+        if height < start_height {
+            return None;
+        }
+        let idx = (height - start_height) as usize;
+        match self.chunks.get(idx / SNAPSHOT_CHUNK_LEN) {
+            Some(chunk) => chunk.get(idx % SNAPSHOT_CHUNK_LEN),
+            None => self.tail.get(idx - self.chunks.len() * SNAPSHOT_CHUNK_LEN),
+        }
+    }
+
+    /// Get the block whose hash is equal to given hash.
+    pub fn get_by_hash(&self, hash: &Sha256dHash) -> Option<&BlockData>
+    {
+        self.iter().rev().find(|b| b.bitcoin_hash() == *hash)
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &'a BlockData> + DoubleEndedIterator + 'a
+    {
+        self.chunks.iter().flat_map(|chunk| chunk.iter()).chain(self.tail.iter())
+    }
+
+    /// Get locator block's hash iterator, in the same way as `ActiveChain::locator_hashes`.
+    pub fn locator_hashes<'a>(&'a self) -> impl Iterator<Item = Sha256dHash> + 'a
+    {
+        let start_height = self.iter().next().unwrap().height;
+        let tip_height = self.latest_block().height;
+        locator_heights(start_height, tip_height)
+            .into_iter()
+            .map(move |height| self.get_by_height(height).unwrap().bitcoin_hash())
+    }
+}
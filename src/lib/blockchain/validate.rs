@@ -0,0 +1,330 @@
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::{hash::Sha256dHash, uint::Uint256};
+use futures::{future, Future, sync::oneshot};
+use tokio_threadpool::ThreadPool;
+
+use super::{block::header_work, blockchain::{unix_time_now, MAX_FUTURE_BLOCK_TIME, MEDIAN_TIME_SPAN}, BlockData};
+
+/// Batches smaller than this are validated on the current thread.
+/// Sending them to workers costs more than hashing them.
+pub const MIN_PARALLEL_BATCH: usize = 1000;
+
+/// The number of headers which a worker validates at once.
+const CHUNK_SIZE: usize = 250;
+
+/// A reason why a batch of headers is rejected, with the index of the first invalid header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationError
+{
+    /// Header does not follow the previous one.
+    Disconnected(usize),
+    /// Hash of header does not meet its target.
+    InvalidProofOfWork(usize),
+    /// Timestamp is more than 2 hours ahead of our clock,
+    /// or not later than median time past of the previous headers.
+    InvalidTimestamp(usize),
+}
+
+impl ValidationError
+{
+    pub fn index(&self) -> usize
+    {
+        match *self {
+            ValidationError::Disconnected(i) => i,
+            ValidationError::InvalidProofOfWork(i) => i,
+            ValidationError::InvalidTimestamp(i) => i,
+        }
+    }
+}
+
+/// Validate `headers` which follow `prev` on the current thread,
+/// and prepare `BlockData` with heights and chain work filled in.
+///
+/// # Note
+/// Median time past is calculated only from `prev` and `headers`, since blocks before `prev` are unknown here.
+/// `BlockChain::try_add` checks it again against the full chain.
+/// Whether `bits` follows difficulty adjustment is not checked.
+pub fn validate_headers(headers: &[BlockHeader], prev: &BlockData) -> Result<Vec<BlockData>, ValidationError>
+{
+    let checked = headers
+        .iter()
+        .enumerate()
+        .map(|(i, header)| check_proof_of_work(header, i))
+        .collect();
+    connect(headers, checked, prev, unix_time_now())
+}
+
+/// Same as `validate_headers`, but hashing and PoW checks are done by workers of `pool`.
+/// Linkage and timestamps are checked on the current thread after workers finish.
+///
+/// A batch smaller than `MIN_PARALLEL_BATCH` is validated on the current thread.
+/// The number of workers is configured by `pool` (`ThreadPool::new` uses the number of CPUs).
+pub fn validate_headers_parallel(
+    headers: &[BlockHeader],
+    prev: &BlockData,
+    pool: &ThreadPool,
+) -> Result<Vec<BlockData>, ValidationError>
+{
+    if headers.len() < MIN_PARALLEL_BATCH {
+        return validate_headers(headers, prev);
+    }
+
+    let receivers: Vec<_> = headers
+        .chunks(CHUNK_SIZE)
+        .enumerate()
+        .map(|(n, chunk)| {
+            let chunk = chunk.to_vec();
+            let offset = n * CHUNK_SIZE;
+            let (tx, rx) = oneshot::channel();
+            pool.spawn(future::lazy(move || {
+                let checked: Vec<_> = chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(i, header)| check_proof_of_work(header, offset + i))
+                    .collect();
+                let _ = tx.send(checked);
+                Ok(())
+            }));
+            rx
+        })
+        .collect();
+
+    let mut checked = Vec::with_capacity(headers.len());
+    for rx in receivers {
+        checked.extend(rx.wait().expect("Thread pool is shut down while validating headers"));
+    }
+    connect(headers, checked, prev, unix_time_now())
+}
+
+/// Returns hash and work of `header`, which is `index`-th in a batch.
+fn check_proof_of_work(header: &BlockHeader, index: usize) -> Result<(Sha256dHash, Uint256), ValidationError>
+{
+    let target = header.target();
+    let hash = header.bitcoin_hash();
+    if target == Uint256::from_u64(0).unwrap() || hash.into_le() > target {
+        return Err(ValidationError::InvalidProofOfWork(index));
+    }
+    Ok((hash, header_work(header)))
+}
+
+/// Check linkage and timestamps in order, and build `BlockData` from pre-calculated hashes and works.
+fn connect(
+    headers: &[BlockHeader],
+    checked: Vec<Result<(Sha256dHash, Uint256), ValidationError>>,
+    prev: &BlockData,
+    now: u32,
+) -> Result<Vec<BlockData>, ValidationError>
+{
+    let mut blocks: Vec<BlockData> = Vec::with_capacity(headers.len());
+    // Timestamps of the last `MEDIAN_TIME_SPAN` blocks.
+    let mut times = vec![prev.header.time];
+
+    for (i, (header, res)) in headers.iter().zip(checked).enumerate() {
+        let (hash, work) = res?;
+        let prev = *blocks.last().unwrap_or(prev);
+        if header.prev_blockhash != prev.bitcoin_hash() {
+            return Err(ValidationError::Disconnected(i));
+        }
+        if header.time > now.saturating_add(MAX_FUTURE_BLOCK_TIME) || header.time <= median(&times) {
+            return Err(ValidationError::InvalidTimestamp(i));
+        }
+
+        blocks.push(BlockData::with_prev_parts(*header, hash, work, &prev));
+        times.push(header.time);
+        if times.len() > MEDIAN_TIME_SPAN as usize {
+            times.remove(0);
+        }
+    }
+    Ok(blocks)
+}
+
+/// Whether transactions of `block` hash up to the merkle root of its header.
+///
+/// The tree is built over txids, and the last hash of a level is paired with itself if the level has odd length.
+/// Since it lets a block with duplicated transactions have the same root (CVE-2012-2459),
+/// a level which has such a pair of identical hashes is rejected.
+pub fn validate_merkle_root(block: &Block) -> bool
+{
+    let mut hashes: Vec<_> = block.txdata.iter().map(|tx| tx.bitcoin_hash()).collect();
+    if hashes.is_empty() {
+        return false;
+    }
+    while hashes.len() > 1 {
+        let is_mutated = hashes.chunks(2).any(|pair| pair.len() == 2 && pair[0] == pair[1]);
+        if is_mutated {
+            return false;
+        }
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| {
+                let right = pair.get(1).unwrap_or(&pair[0]);
+                let mut data = Vec::with_capacity(64);
+                data.extend_from_slice(&pair[0][..]);
+                data.extend_from_slice(&right[..]);
+                Sha256dHash::from_data(&data)
+            })
+            .collect();
+    }
+    hashes[0] == block.header.merkle_root
+}
+
+fn median(times: &[u32]) -> u32
+{
+    let mut times = times.to_vec();
+    times.sort();
+    times[times.len() / 2]
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::MerkleRoot;
+
+    use testing::{mine, mined_headers, MIN_DIFFICULTY_BITS};
+
+    fn start_block() -> BlockData
+    {
+        let header = BlockHeader {
+            version: 1,
+            prev_blockhash: Sha256dHash::default(),
+            merkle_root: Sha256dHash::default(),
+            time: 1_000_000,
+            bits: MIN_DIFFICULTY_BITS,
+            nonce: 0,
+        };
+        BlockData::new(header, 100)
+    }
+
+    #[test]
+    fn prepare_block_data_following_prev()
+    {
+        let start = start_block();
+        let headers = mined_headers(start.bitcoin_hash(), start.header.time + 1, 20);
+
+        let blocks = validate_headers(&headers, &start).unwrap();
+        assert_eq!(blocks[0], BlockData::with_prev(headers[0], &start));
+        for i in 1..blocks.len() {
+            assert_eq!(blocks[i], BlockData::with_prev(headers[i], &blocks[i - 1]));
+        }
+        assert_eq!(blocks.last().unwrap().height(), 120);
+    }
+
+    #[test]
+    fn parallel_and_sequential_paths_agree_on_10k_headers()
+    {
+        let start = start_block();
+        let headers = mined_headers(start.bitcoin_hash(), start.header.time + 1, 10_000);
+        let pool = ThreadPool::new();
+
+        let sequential = validate_headers(&headers, &start).unwrap();
+        let parallel = validate_headers_parallel(&headers, &start, &pool).unwrap();
+        assert_eq!(sequential, parallel);
+    }
+
+    #[test]
+    fn single_bad_header_fails_whole_batch_with_its_index()
+    {
+        let start = start_block();
+        let headers = mined_headers(start.bitcoin_hash(), start.header.time + 1, 10_000);
+        let pool = ThreadPool::new();
+        let bad_index = 5_000;
+
+        let mut invalid_pow = headers.clone();
+        while check_proof_of_work(&invalid_pow[bad_index], 0).is_ok() {
+            invalid_pow[bad_index].nonce += 1;
+        }
+
+        let mut disconnected = headers.clone();
+        disconnected[bad_index].prev_blockhash = Sha256dHash::default();
+        mine(&mut disconnected[bad_index]);
+
+        let mut invalid_time = headers.clone();
+        invalid_time[bad_index].time = start.header.time;
+        mine(&mut invalid_time[bad_index]);
+
+        let cases = vec![
+            (invalid_pow, ValidationError::InvalidProofOfWork(bad_index)),
+            (disconnected, ValidationError::Disconnected(bad_index)),
+            (invalid_time, ValidationError::InvalidTimestamp(bad_index)),
+        ];
+        for (headers, expected) in cases {
+            assert_eq!(validate_headers(&headers, &start).unwrap_err(), expected);
+            assert_eq!(validate_headers_parallel(&headers, &start, &pool).unwrap_err(), expected);
+            assert_eq!(expected.index(), bad_index);
+        }
+    }
+
+    #[test]
+    fn reject_header_too_far_in_the_future()
+    {
+        let start = start_block();
+        let mut headers = mined_headers(start.bitcoin_hash(), start.header.time + 1, 3);
+        let checked = headers.iter().enumerate().map(|(i, h)| check_proof_of_work(h, i)).collect();
+        let now = headers[2].time - MAX_FUTURE_BLOCK_TIME - 1;
+        assert_eq!(
+            connect(&headers, checked, &start, now).unwrap_err(),
+            ValidationError::InvalidTimestamp(2)
+        );
+
+        // Small batch on the parallel path is validated on the current thread.
+        headers.truncate(2);
+        let pool = ThreadPool::new();
+        assert_eq!(validate_headers_parallel(&headers, &start, &pool).unwrap().len(), 2);
+    }
+
+    // A block of `n` transactions whose header commits to them.
+    fn block_with_txs(n: u32) -> Block
+    {
+        let coinbase = genesis_block(Network::Regtest).txdata[0].clone();
+        let txdata: Vec<_> = (0..n)
+            .map(|i| {
+                let mut tx = coinbase.clone();
+                tx.lock_time = i;
+                tx
+            })
+            .collect();
+        let mut block = Block {
+            header: start_block().header,
+            txdata,
+        };
+        block.header.merkle_root = block.merkle_root();
+        block
+    }
+
+    #[test]
+    fn validate_merkle_root_of_two_transactions()
+    {
+        let block = block_with_txs(2);
+        let mut data = block.txdata[0].bitcoin_hash()[..].to_vec();
+        data.extend_from_slice(&block.txdata[1].bitcoin_hash()[..]);
+        assert_eq!(block.header.merkle_root, Sha256dHash::from_data(&data));
+        assert!(validate_merkle_root(&block));
+
+        let mut tampered = block.clone();
+        tampered.txdata[1].lock_time = 100;
+        assert!(!validate_merkle_root(&tampered));
+
+        let mut empty = block;
+        empty.txdata.clear();
+        assert!(!validate_merkle_root(&empty));
+    }
+
+    #[test]
+    fn validate_merkle_root_with_odd_number_of_transactions()
+    {
+        let block = block_with_txs(3);
+        assert!(validate_merkle_root(&block));
+
+        // Duplicating the last transaction keeps the root, but the block is invalid.
+        let mut mutated = block.clone();
+        let last = mutated.txdata[2].clone();
+        mutated.txdata.push(last);
+        assert_eq!(mutated.merkle_root(), block.header.merkle_root);
+        assert!(!validate_merkle_root(&mutated));
+    }
+}
@@ -0,0 +1,426 @@
+use std::cmp;
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::network::{encodable::{ConsensusDecodable, ConsensusEncodable},
+                       serialize::{BitcoinHash, SimpleDecoder, SimpleEncoder}};
+use bitcoin::util::hash::Sha256dHash;
+
+use witness::{MAX_BLOCK_WEIGHT, MIN_TRANSACTION_WEIGHT};
+
+/// Max size of a bloom filter in bytes (BIP 37).
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+/// Max number of hash functions of a bloom filter (BIP 37).
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+const LN2SQUARED: f64 = 0.480_453_013_918_201_4;
+const LN2: f64 = 0.693_147_180_559_945_3;
+
+/// How the remote peer updates a filter when a transaction matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BloomFlags
+{
+    None = 0,
+    All = 1,
+    PubkeyOnly = 2,
+}
+
+/// A bloom filter which is sent to remote peer via `filterload` message (BIP 37).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter
+{
+    content: Vec<u8>,
+    hash_funcs: u32,
+    tweak: u32,
+    flags: BloomFlags,
+}
+
+impl BloomFilter
+{
+    /// Create a filter which holds `elements` elements with `fp_rate` false positive rate.
+    pub fn new(elements: u32, fp_rate: f64, tweak: u32, flags: BloomFlags) -> BloomFilter
+    {
+        let elements = cmp::max(elements, 1);
+        let size_bits = (-1.0 / LN2SQUARED * elements as f64 * fp_rate.ln()) as usize;
+        let size = cmp::min(size_bits, MAX_BLOOM_FILTER_SIZE * 8) / 8;
+        let hash_funcs = ((size * 8 / elements as usize) as f64 * LN2) as u32;
+        BloomFilter {
+            content: vec![0; size],
+            hash_funcs: cmp::min(hash_funcs, MAX_HASH_FUNCS),
+            tweak,
+            flags,
+        }
+    }
+
+    pub fn insert(&mut self, data: &[u8])
+    {
+        if self.content.is_empty() {
+            return;
+        }
+        for n in 0..self.hash_funcs {
+            let idx = self.hash(n, data);
+            self.content[idx >> 3] |= 1 << (7 & idx);
+        }
+    }
+
+    pub fn contains(&self, data: &[u8]) -> bool
+    {
+        if self.content.is_empty() {
+            return true;
+        }
+        (0..self.hash_funcs).all(|n| {
+            let idx = self.hash(n, data);
+            self.content[idx >> 3] & (1 << (7 & idx)) != 0
+        })
+    }
+
+    fn hash(&self, n: u32, data: &[u8]) -> usize
+    {
+        let seed = n.wrapping_mul(0xFBA4_C795).wrapping_add(self.tweak);
+        murmur3(seed, data) as usize % (self.content.len() * 8)
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for BloomFilter
+{
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error>
+    {
+        self.content.consensus_encode(s)?;
+        self.hash_funcs.consensus_encode(s)?;
+        self.tweak.consensus_encode(s)?;
+        (self.flags as u8).consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for BloomFilter
+{
+    fn consensus_decode(d: &mut D) -> Result<BloomFilter, D::Error>
+    {
+        let content = ConsensusDecodable::consensus_decode(d)?;
+        let hash_funcs = ConsensusDecodable::consensus_decode(d)?;
+        let tweak = ConsensusDecodable::consensus_decode(d)?;
+        let flags = match u8::consensus_decode(d)? {
+            0 => BloomFlags::None,
+            1 => BloomFlags::All,
+            2 => BloomFlags::PubkeyOnly,
+            n => return Err(d.error(format!("unknown bloom filter flags {}", n))),
+        };
+        Ok(BloomFilter {
+            content,
+            hash_funcs,
+            tweak,
+            flags,
+        })
+    }
+}
+
+/// 32bit MurmurHash3.
+fn murmur3(seed: u32, data: &[u8]) -> u32
+{
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut h = seed;
+    let mut chunks = data.chunks(4);
+    let mut tail: &[u8] = &[];
+    for chunk in &mut chunks {
+        if chunk.len() < 4 {
+            tail = chunk;
+            break;
+        }
+        let mut k = u32::from(chunk[0]) | u32::from(chunk[1]) << 8 | u32::from(chunk[2]) << 16
+            | u32::from(chunk[3]) << 24;
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+
+    let mut k = 0u32;
+    if tail.len() >= 3 {
+        k ^= u32::from(tail[2]) << 16;
+    }
+    if tail.len() >= 2 {
+        k ^= u32::from(tail[1]) << 8;
+    }
+    if !tail.is_empty() {
+        k ^= u32::from(tail[0]);
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
+
+/// A `merkleblock` message.
+/// It contains a block header and a partial merkle tree of transactions which match a filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleBlock
+{
+    pub header: BlockHeader,
+    pub total_transactions: u32,
+    pub hashes: Vec<Sha256dHash>,
+    pub flags: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MerkleBlockError
+{
+    /// Partial merkle tree is malformed.
+    BadEncoding,
+    /// Partial merkle tree does not hash up to the merkle root of header.
+    MerkleRootMismatch,
+}
+
+impl MerkleBlock
+{
+    /// Verify the partial merkle tree against the header's merkle root and extract matched txids.
+    pub fn extract_matches(&self) -> Result<Vec<Sha256dHash>, MerkleBlockError>
+    {
+        // Same checks as Bitcoin core's `CPartialMerkleTree::ExtractMatches`.
+        // No block can have more transactions than the smallest ones which fill max block weight.
+        if self.total_transactions == 0 || self.total_transactions > MAX_BLOCK_WEIGHT / MIN_TRANSACTION_WEIGHT
+            || self.hashes.len() > self.total_transactions as usize
+            || self.flags.len() * 8 < self.hashes.len()
+        {
+            return Err(MerkleBlockError::BadEncoding);
+        }
+
+        let mut height = 0;
+        while self.tree_width(height) > 1 {
+            height += 1;
+        }
+
+        let mut bits_used = 0;
+        let mut hashes_used = 0;
+        let mut matches = Vec::new();
+        let root = self.traverse_and_extract(height, 0, &mut bits_used, &mut hashes_used, &mut matches)?;
+
+        // All bits and hashes must be consumed.
+        if (bits_used + 7) / 8 != self.flags.len() || hashes_used != self.hashes.len() {
+            return Err(MerkleBlockError::BadEncoding);
+        }
+        if root != self.header.merkle_root {
+            return Err(MerkleBlockError::MerkleRootMismatch);
+        }
+        Ok(matches)
+    }
+
+    fn tree_width(&self, height: u32) -> u32
+    {
+        // Computed in u64 so that it never overflows even if `total_transactions` is not checked.
+        ((self.total_transactions as u64 + (1 << height) - 1) >> height) as u32
+    }
+
+    fn bit(&self, idx: usize) -> Option<bool>
+    {
+        self.flags.get(idx / 8).map(|byte| byte & (1 << (idx % 8)) != 0)
+    }
+
+    fn traverse_and_extract(
+        &self,
+        height: u32,
+        pos: u32,
+        bits_used: &mut usize,
+        hashes_used: &mut usize,
+        matches: &mut Vec<Sha256dHash>,
+    ) -> Result<Sha256dHash, MerkleBlockError>
+    {
+        let parent_of_match = self.bit(*bits_used).ok_or(MerkleBlockError::BadEncoding)?;
+        *bits_used += 1;
+
+        if height == 0 || !parent_of_match {
+            let hash = *self.hashes
+                .get(*hashes_used)
+                .ok_or(MerkleBlockError::BadEncoding)?;
+            *hashes_used += 1;
+            if height == 0 && parent_of_match {
+                matches.push(hash);
+            }
+            return Ok(hash);
+        }
+
+        let left = self.traverse_and_extract(height - 1, pos * 2, bits_used, hashes_used, matches)?;
+        let right = if pos * 2 + 1 < self.tree_width(height - 1) {
+            let right = self.traverse_and_extract(height - 1, pos * 2 + 1, bits_used, hashes_used, matches)?;
+            // Identical children makes the tree ambiguous (CVE-2012-2459).
+            if right == left {
+                return Err(MerkleBlockError::BadEncoding);
+            }
+            right
+        } else {
+            left
+        };
+
+        let mut data = Vec::with_capacity(64);
+        data.extend_from_slice(&left[..]);
+        data.extend_from_slice(&right[..]);
+        Ok(Sha256dHash::from_data(&data))
+    }
+}
+
+impl BitcoinHash for MerkleBlock
+{
+    fn bitcoin_hash(&self) -> Sha256dHash
+    {
+        self.header.bitcoin_hash()
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for MerkleBlock
+{
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error>
+    {
+        self.header.consensus_encode(s)?;
+        self.total_transactions.consensus_encode(s)?;
+        self.hashes.consensus_encode(s)?;
+        self.flags.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for MerkleBlock
+{
+    fn consensus_decode(d: &mut D) -> Result<MerkleBlock, D::Error>
+    {
+        Ok(MerkleBlock {
+            header: ConsensusDecodable::consensus_decode(d)?,
+            total_transactions: ConsensusDecodable::consensus_decode(d)?,
+            hashes: ConsensusDecodable::consensus_decode(d)?,
+            flags: ConsensusDecodable::consensus_decode(d)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bitcoin::network::serialize::{deserialize, serialize};
+
+    fn hex(bytes: &[u8]) -> String
+    {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn from_hex(s: &str) -> Vec<u8>
+    {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn filter_with_test_elements(tweak: u32) -> BloomFilter
+    {
+        let mut filter = BloomFilter::new(3, 0.01, tweak, BloomFlags::All);
+        filter.insert(&from_hex("99108ad8ed9bb6274d3980bab5a85c048f0950c8"));
+        filter.insert(&from_hex("b5a2c786d9ef4658287ced5914b37a1b4aa32eee"));
+        filter.insert(&from_hex("b9300670b4c5366e95b2699e8b18bc75e5f729c5"));
+        filter
+    }
+
+    // Test vectors are taken from Bitcoin core's `bloom_tests.cpp`.
+    #[test]
+    fn bloom_filter_serialize()
+    {
+        let filter = filter_with_test_elements(0);
+        assert_eq!(hex(&serialize(&filter).unwrap()), "03614e9b050000000000000001");
+        assert!(filter.contains(&from_hex("99108ad8ed9bb6274d3980bab5a85c048f0950c8")));
+        assert!(!filter.contains(&from_hex("19108ad8ed9bb6274d3980bab5a85c048f0950c8")));
+
+        let filter = filter_with_test_elements(2147483649);
+        assert_eq!(hex(&serialize(&filter).unwrap()), "03ce4299050000000100008001");
+    }
+
+    fn merkle_parent(left: &Sha256dHash, right: &Sha256dHash) -> Sha256dHash
+    {
+        let mut data = Vec::new();
+        data.extend_from_slice(&left[..]);
+        data.extend_from_slice(&right[..]);
+        Sha256dHash::from_data(&data)
+    }
+
+    fn dummy_merkle_block(merkle_root: Sha256dHash, total: u32, hashes: Vec<Sha256dHash>, flags: u8) -> MerkleBlock
+    {
+        let header = BlockHeader {
+            version: 1,
+            prev_blockhash: Sha256dHash::default(),
+            merkle_root,
+            time: 0,
+            bits: 0,
+            nonce: 0,
+        };
+        MerkleBlock {
+            header,
+            total_transactions: total,
+            hashes,
+            flags: vec![flags],
+        }
+    }
+
+    #[test]
+    fn merkle_block_extract_matches()
+    {
+        let txids: Vec<_> = (0u8..3).map(|i| Sha256dHash::from_data(&[i])).collect();
+        let h01 = merkle_parent(&txids[0], &txids[1]);
+        let h22 = merkle_parent(&txids[2], &txids[2]);
+        let root = merkle_parent(&h01, &h22);
+
+        // Only the 3rd transaction matches.
+        // Traverse order : root(1), h01(0), h22(1), tx2(1)
+        let merkle_block = dummy_merkle_block(root, 3, vec![h01, txids[2]], 0b1101);
+        assert_eq!(merkle_block.extract_matches(), Ok(vec![txids[2]]));
+
+        // Tampered merkle root
+        let merkle_block = dummy_merkle_block(h01, 3, vec![h01, txids[2]], 0b1101);
+        assert_eq!(merkle_block.extract_matches(), Err(MerkleBlockError::MerkleRootMismatch));
+
+        // Not enough hashes
+        let merkle_block = dummy_merkle_block(root, 3, vec![h01], 0b1101);
+        assert_eq!(merkle_block.extract_matches(), Err(MerkleBlockError::BadEncoding));
+    }
+
+    // `merkle_block_1` of Bitcoin core's `bloom_tests.cpp`, a merkleblock of a mainnet block with 9 transactions.
+    const MAINNET_MERKLE_BLOCK: &str = "0100000090f0a9f110702f808219ebea1173056042a714bad51b916cb680000000000000\
+                                        5275289558f51c9966699404ae2294730c3c9f9bda53523ce50e9b95e558da2fdb261b4d\
+                                        4c86041b1ab1bf930900000005fac7708a6e81b2a986dea60db2663840ed141130848162\
+                                        eb1bd1dee54f309a1b2ee1e12587e497ada70d9bd10d31e83f0a924825b96cb8d04e8936\
+                                        d793fb60db7ad8b910d0c7ba2369bc7f18bb53d80e1869ba2c32274996cebe1ae264bc0e\
+                                        2289189ff0316cdc10511da71da757e553cada9f3b5b1434f3923673adb57d83caac392c\
+                                        38af156d6fc30b55fad4112df2b95531e68114e9ad10011e72f7b7cfdb025700";
+
+    #[test]
+    fn extract_matches_of_mainnet_merkle_block()
+    {
+        let merkle_block: MerkleBlock = deserialize(&from_hex(MAINNET_MERKLE_BLOCK)).unwrap();
+        assert_eq!(
+            merkle_block.bitcoin_hash(),
+            Sha256dHash::from_hex("0000000000013b8ab2cd513b0261a14096412195a72a0c4827d229dcc7e0f7af").unwrap()
+        );
+        assert_eq!(merkle_block.total_transactions, 9);
+
+        // Only one transaction of the block matches the filter of the test.
+        let matched = "220ebc64e21abece964927322cba69180ed853bb187fbc6923bac7d010b9d87a";
+        assert_eq!(merkle_block.extract_matches(), Ok(vec![Sha256dHash::from_hex(matched).unwrap()]));
+        assert_eq!(hex(&serialize(&merkle_block).unwrap()), MAINNET_MERKLE_BLOCK);
+    }
+
+    #[test]
+    fn reject_too_many_transactions_without_overflow()
+    {
+        let txid = Sha256dHash::from_data(&[0]);
+        let merkle_block = dummy_merkle_block(txid, u32::max_value(), vec![txid], 0b1);
+        assert_eq!(merkle_block.extract_matches(), Err(MerkleBlockError::BadEncoding));
+
+        let merkle_block = MerkleBlock {
+            total_transactions: u32::max_value(),
+            ..merkle_block
+        };
+        assert_eq!(merkle_block.tree_width(0), u32::max_value());
+        assert_eq!(merkle_block.tree_width(1), 1 << 31);
+        assert_eq!(merkle_block.tree_width(32), 1);
+    }
+}
@@ -0,0 +1,411 @@
+use std::{cmp, collections::HashMap, fs::{self, File}, io::{self, BufRead, BufReader, BufWriter, Write},
+          net::SocketAddr, path::Path};
+
+use rand::Rng;
+
+/// An address which succeeded within this many seconds is preferred over the others.
+pub const RECENT_SUCCESS_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Known peer addresses with their connection history.
+///
+/// Unlike `ConnectionPool`'s backoff, the history is kept across restarts by `save` and `load`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrManager
+{
+    entries: HashMap<SocketAddr, AddrEntry>,
+    capacity: usize,
+    // Whether `entries` changed since the last `save` or `load`.
+    modified: bool,
+}
+
+/// Connection history of an address. Timestamps are UNIX time in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddrEntry
+{
+    /// Services which the address advertises.
+    pub services: u64,
+    /// When we learned the address most recently.
+    pub last_seen: u64,
+    /// When we completed handshake with the address most recently. `0` if never.
+    pub last_success: u64,
+    pub successes: u32,
+    pub failures: u32,
+}
+
+impl AddrEntry
+{
+    /// Quality of the address. Addresses which we never try have `0`.
+    pub fn score(&self) -> i64
+    {
+        self.successes as i64 - self.failures as i64
+    }
+
+    fn is_tried(&self) -> bool
+    {
+        self.successes > 0 || self.failures > 0
+    }
+
+    /// `0` for addresses which succeeded recently, `1` for addresses which we never try, `2` for the others.
+    fn preference(&self, now: u64) -> u8
+    {
+        if self.last_success > 0 && now.saturating_sub(self.last_success) < RECENT_SUCCESS_SECS {
+            0
+        } else if !self.is_tried() {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+impl AddrManager
+{
+    /// Create an empty manager which keeps up to `capacity` addresses.
+    pub fn new(capacity: usize) -> AddrManager
+    {
+        AddrManager {
+            entries: HashMap::new(),
+            capacity,
+            modified: false,
+        }
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddrEntry>
+    {
+        self.entries.get(addr)
+    }
+
+    /// Whether any address is added, updated or evicted since the last `save` or `load`.
+    pub fn is_modified(&self) -> bool
+    {
+        self.modified
+    }
+
+    /// The number of addresses which do not fail more than they succeed.
+    pub fn num_viable(&self) -> usize
+    {
+        self.entries.values().filter(|e| e.score() >= 0).count()
+    }
+
+    /// Add a learned address, or refresh it if it is already known.
+    ///
+    /// When the manager is full, the worst scoring address is evicted for it.
+    /// If every known address has a better score than a new one, the new one is dropped.
+    pub fn add(&mut self, addr: SocketAddr, services: u64, now: u64)
+    {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            let updated = AddrEntry {
+                services: entry.services | services,
+                last_seen: cmp::max(entry.last_seen, now),
+                ..*entry
+            };
+            if updated != *entry {
+                *entry = updated;
+                self.modified = true;
+            }
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.evict_worst() {
+            return;
+        }
+        self.modified = true;
+        self.entries.insert(addr, AddrEntry {
+            services,
+            last_seen: now,
+            last_success: 0,
+            successes: 0,
+            failures: 0,
+        });
+    }
+
+    /// Record a successful handshake. An unknown address is added.
+    pub fn record_success(&mut self, addr: SocketAddr, services: u64, now: u64)
+    {
+        self.add(addr, services, now);
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.successes = entry.successes.saturating_add(1);
+            entry.last_success = now;
+            self.modified = true;
+        }
+    }
+
+    /// Record a failed connection attempt. An unknown address is ignored.
+    pub fn record_failure(&mut self, addr: SocketAddr)
+    {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.failures = entry.failures.saturating_add(1);
+            self.modified = true;
+        }
+    }
+
+    /// Addresses which pass `filter` and we should dial first.
+    /// Addresses which succeeded recently are preferred, then addresses which we never try.
+    pub fn preferred<F>(&self, now: u64, filter: F) -> Vec<SocketAddr>
+    where
+        F: Fn(&SocketAddr) -> bool,
+    {
+        let candidates: Vec<_> = self.entries
+            .iter()
+            .filter(|&(addr, _)| filter(addr))
+            .map(|(addr, entry)| (*addr, entry.preference(now)))
+            .collect();
+        let best = match candidates.iter().map(|&(_, pref)| pref).min() {
+            Some(best) => best,
+            None => return Vec::new(),
+        };
+        candidates.into_iter().filter(|&(_, pref)| pref == best).map(|(addr, _)| addr).collect()
+    }
+
+    /// Up to `max` addresses chosen at random, e.g. to respond `getaddr`.
+    /// Addresses which fail more than they succeed are never chosen.
+    pub fn sample<R: Rng>(&self, max: usize, rng: &mut R) -> Vec<(SocketAddr, AddrEntry)>
+    {
+        let mut viable: Vec<_> = self.entries
+            .iter()
+            .filter(|&(_, entry)| entry.score() >= 0)
+            .map(|(addr, entry)| (*addr, *entry))
+            .collect();
+        rng.shuffle(&mut viable);
+        viable.truncate(max);
+        viable
+    }
+
+    // Evict the lowest scoring address, oldest first. Addresses with positive score are never evicted.
+    fn evict_worst(&mut self) -> bool
+    {
+        let worst = self.entries
+            .iter()
+            .filter(|&(_, entry)| entry.score() <= 0)
+            .min_by_key(|&(_, entry)| (entry.score(), entry.last_seen))
+            .map(|(addr, _)| *addr);
+        match worst {
+            Some(addr) => {
+                self.entries.remove(&addr);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Write every address to `path`, one per line as
+    /// `<addr> <services> <last_seen> <last_success> <successes> <failures>`.
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()>
+    {
+        // Write to a temporary file first so that a crash does not leave a truncated file.
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            for (addr, e) in self.entries.iter() {
+                writeln!(
+                    writer,
+                    "{} {} {} {} {} {}",
+                    addr, e.services, e.last_seen, e.last_success, e.successes, e.failures
+                )?;
+            }
+            writer.flush()?;
+        }
+        fs::rename(tmp, path)?;
+        self.modified = false;
+        Ok(())
+    }
+
+    /// Read addresses written by `save`. Addresses over `capacity` are evicted as `add` does.
+    pub fn load<P: AsRef<Path>>(path: P, capacity: usize) -> io::Result<AddrManager>
+    {
+        let mut manager = AddrManager::new(capacity);
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (addr, entry) = parse_line(&line)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid line : {}", line)))?;
+            if manager.entries.len() >= capacity && !manager.evict_worst() {
+                continue;
+            }
+            manager.entries.insert(addr, entry);
+        }
+        Ok(manager)
+    }
+}
+
+fn parse_line(line: &str) -> Option<(SocketAddr, AddrEntry)>
+{
+    let mut fields = line.split_whitespace();
+    let addr = fields.next()?.parse().ok()?;
+    let entry = AddrEntry {
+        services: fields.next()?.parse().ok()?,
+        last_seen: fields.next()?.parse().ok()?,
+        last_success: fields.next()?.parse().ok()?,
+        successes: fields.next()?.parse().ok()?,
+        failures: fields.next()?.parse().ok()?,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some((addr, entry))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::env;
+
+    fn addr(s: &str) -> SocketAddr
+    {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn update_score_after_success_and_failure()
+    {
+        let mut manager = AddrManager::new(8);
+        let a = addr("1.2.3.4:8333");
+        manager.add(a, 1, 100);
+        assert_eq!(manager.get(&a).unwrap().score(), 0);
+
+        manager.record_failure(a);
+        manager.record_failure(a);
+        assert_eq!(manager.get(&a).unwrap().score(), -2);
+        assert_eq!(manager.num_viable(), 0);
+
+        manager.record_success(a, 1, 200);
+        manager.record_success(a, 1, 300);
+        manager.record_success(a, 1, 400);
+        let entry = manager.get(&a).unwrap();
+        assert_eq!(entry.score(), 1);
+        assert_eq!(entry.last_success, 400);
+        assert_eq!(manager.num_viable(), 1);
+
+        // A failure of an unknown address is not recorded.
+        manager.record_failure(addr("5.6.7.8:8333"));
+        assert_eq!(manager.len(), 1);
+    }
+
+    #[test]
+    fn prefer_recent_success_then_never_tried()
+    {
+        let now = 10 * RECENT_SUCCESS_SECS;
+        let (succeeded, fresh, failed) = (addr("1.1.1.1:8333"), addr("2.2.2.2:8333"), addr("3.3.3.3:8333"));
+        let mut manager = AddrManager::new(8);
+        manager.add(fresh, 1, now);
+        manager.add(failed, 1, now);
+        manager.record_failure(failed);
+        manager.record_success(succeeded, 1, now - 10);
+
+        assert_eq!(manager.preferred(now, |_| true), vec![succeeded]);
+        assert_eq!(manager.preferred(now, |a| *a != succeeded), vec![fresh]);
+        assert_eq!(manager.preferred(now, |a| *a == failed), vec![failed]);
+        assert!(manager.preferred(now, |_| false).is_empty());
+
+        // An old success is not preferred over a never tried address.
+        let later = now + RECENT_SUCCESS_SECS;
+        assert_eq!(manager.preferred(later, |a| *a != failed), vec![fresh]);
+    }
+
+    #[test]
+    fn evict_worst_scoring_address_when_full()
+    {
+        let mut manager = AddrManager::new(2);
+        let (good, bad) = (addr("1.1.1.1:8333"), addr("2.2.2.2:8333"));
+        manager.record_success(good, 1, 100);
+        manager.add(bad, 1, 100);
+        manager.record_failure(bad);
+
+        let new = addr("3.3.3.3:8333");
+        manager.add(new, 1, 200);
+        assert!(manager.get(&bad).is_none());
+        assert!(manager.get(&new).is_some());
+
+        // Never tried addresses are replaced from the oldest.
+        let newer = addr("4.4.4.4:8333");
+        manager.add(newer, 1, 300);
+        assert!(manager.get(&new).is_none());
+        assert!(manager.get(&newer).is_some());
+
+        // Addresses which succeed are kept.
+        manager.record_success(newer, 1, 300);
+        manager.add(addr("5.5.5.5:8333"), 1, 400);
+        assert_eq!(manager.len(), 2);
+        assert!(manager.get(&good).is_some());
+        assert!(manager.get(&newer).is_some());
+    }
+
+    #[test]
+    fn track_modification_since_save()
+    {
+        let mut manager = AddrManager::new(8);
+        let a = addr("1.2.3.4:8333");
+        assert!(!manager.is_modified());
+        manager.add(a, 1, 100);
+        assert!(manager.is_modified());
+
+        let path = env::temp_dir().join(format!("yabitcoin-modified-{}.txt", ::std::process::id()));
+        manager.save(&path).unwrap();
+        assert!(!manager.is_modified());
+        assert!(!AddrManager::load(&path, 8).unwrap().is_modified());
+        fs::remove_file(&path).unwrap();
+
+        // Learning the same address again changes nothing.
+        manager.add(a, 1, 100);
+        manager.record_failure(addr("5.6.7.8:8333"));
+        assert!(!manager.is_modified());
+
+        manager.add(a, 1, 200);
+        assert!(manager.is_modified());
+    }
+
+    #[test]
+    fn round_trip_file()
+    {
+        let mut manager = AddrManager::new(8);
+        manager.add(addr("1.2.3.4:8333"), 1, 100);
+        manager.record_success(addr("[2001:db8::1]:18333"), 9, 200);
+        manager.add(addr("5.6.7.8:8333"), 1, 300);
+        manager.record_failure(addr("5.6.7.8:8333"));
+
+        let path = env::temp_dir().join(format!("yabitcoin-addrs-{}.txt", ::std::process::id()));
+        manager.save(&path).unwrap();
+        let loaded = AddrManager::load(&path, 8).unwrap();
+        assert_eq!(loaded, manager);
+
+        fs::write(&path, "1.2.3.4:8333 1 100\n").unwrap();
+        let err = AddrManager::load(&path, 8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sample_at_most_max_viable_addresses()
+    {
+        let mut manager = AddrManager::new(2000);
+        for i in 0..1500u32 {
+            let addr = SocketAddr::new([10, 0, (i / 256) as u8, (i % 256) as u8].into(), 8333);
+            manager.add(addr, 1, 100);
+        }
+        let failed = addr("10.0.0.0:8333");
+        manager.record_failure(failed);
+
+        let mut rng = ::rand::thread_rng();
+        let sampled = manager.sample(1000, &mut rng);
+        assert_eq!(sampled.len(), 1000);
+        let distinct: ::std::collections::HashSet<_> = sampled.iter().map(|&(a, _)| a).collect();
+        assert_eq!(distinct.len(), 1000);
+
+        let all = manager.sample(2000, &mut rng);
+        assert_eq!(all.len(), 1499);
+        assert!(all.iter().all(|&(a, _)| a != failed));
+    }
+}
@@ -0,0 +1,635 @@
+use std::io::Cursor;
+use bitcoin::network::{constants::Network, encodable::{ConsensusDecodable, VarInt},
+                       message::{CommandString, NetworkMessage, RawNetworkMessage},
+                       serialize::{serialize, Error as BitcoinSerializeError, RawDecoder}};
+use bitcoin::util::hash::Sha256dHash;
+
+use tokio::codec::{Decoder, Encoder};
+use bytes::BytesMut;
+use connection::message::Message;
+use error::Error;
+
+/// Serialize `msg` with a header.
+pub fn encode(msg: Message, network: Network) -> Vec<u8>
+{
+    // Never fail
+    match msg {
+        Message::Network(msg) => {
+            let msg = RawNetworkMessage {
+                magic: network.magic(),
+                payload: msg,
+            };
+            serialize(&msg).unwrap()
+        },
+        Message::GetDataRaw(invs) => encode_raw("getdata", serialize(&invs).unwrap(), network),
+        Message::FilterLoad(filter) => encode_raw("filterload", serialize(&filter).unwrap(), network),
+        Message::FilterAdd(data) => encode_raw("filteradd", serialize(&data).unwrap(), network),
+        Message::FilterClear => encode_raw("filterclear", Vec::new(), network),
+        Message::MerkleBlock(block) => encode_raw("merkleblock", serialize(&block).unwrap(), network),
+        Message::SendCmpct(msg) => encode_raw("sendcmpct", serialize(&msg).unwrap(), network),
+        Message::CmpctBlock(block) => encode_raw("cmpctblock", serialize(&block).unwrap(), network),
+        Message::GetBlockTxn(req) => encode_raw("getblocktxn", serialize(&req).unwrap(), network),
+        Message::BlockTxn(txs) => encode_raw("blocktxn", serialize(&txs).unwrap(), network),
+        Message::Reject(reject) => encode_raw("reject", serialize(&reject).unwrap(), network),
+        Message::SendHeaders => encode_raw("sendheaders", Vec::new(), network),
+        Message::FeeFilter(rate) => encode_raw("feefilter", serialize(&rate).unwrap(), network),
+        // The payload is not kept.
+        Message::Unknown(command) => encode_raw(&command, Vec::new(), network),
+    }
+}
+
+/// Encode a message which `bitcoin` crate does not support.
+fn encode_raw(command: &str, payload: Vec<u8>, network: Network) -> Vec<u8>
+{
+    let mut buf = Vec::with_capacity(RAW_NETWORK_MESSAGE_HEADER_SIZE + payload.len());
+    buf.extend(serialize(&network.magic()).unwrap());
+    buf.extend(serialize(&CommandString(command.into())).unwrap());
+    buf.extend(serialize(&(payload.len() as u32)).unwrap());
+    buf.extend_from_slice(&sha2_checksum(&payload));
+    buf.extend(payload);
+    buf
+}
+
+/// Frames `Message`s on a byte stream, e.g. by `tokio::codec::Framed`.
+/// Decoded messages come with their size in bytes including a header.
+#[derive(Debug, Clone)]
+pub struct BtcCodec
+{
+    network: Network,
+}
+
+impl BtcCodec
+{
+    pub fn new(network: Network) -> BtcCodec
+    {
+        BtcCodec { network }
+    }
+
+    /// Size of a whole message which starts with `header`.
+    /// Useful to read exactly one message from a stream.
+    ///
+    /// # Panic
+    /// If length of `header` is not `RAW_NETWORK_MESSAGE_HEADER_SIZE`.
+    pub fn frame_size(&self, header: &[u8]) -> Result<usize, Error>
+    {
+        let header = decode_msg_header(header, &self.network)?;
+        Ok(RAW_NETWORK_MESSAGE_HEADER_SIZE + header.payload_size as usize)
+    }
+}
+
+impl Encoder for BtcCodec
+{
+    type Item = Message;
+    type Error = Error;
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error>
+    {
+        let encoded = encode(item, self.network.clone());
+        dst.extend_from_slice(encoded.as_slice());
+        Ok(())
+    }
+}
+
+impl Decoder for BtcCodec
+{
+    type Item = (Message, usize);
+    type Error = Error;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error>
+    {
+        if src.len() < RAW_NETWORK_MESSAGE_HEADER_SIZE {
+            return Ok(None);
+        }
+        let header = decode_msg_header(&src[..RAW_NETWORK_MESSAGE_HEADER_SIZE], &self.network)?;
+        let size = RAW_NETWORK_MESSAGE_HEADER_SIZE + header.payload_size as usize;
+        if src.len() < size {
+            // Grow the buffer at once rather than on every read.
+            src.reserve(size - src.len());
+            return Ok(None);
+        }
+
+        // Memory of decoded frame is reused by next messages after it is dropped.
+        let frame = src.split_to(size);
+        let msg = decode_and_check_msg_payload(&frame[RAW_NETWORK_MESSAGE_HEADER_SIZE..], &header)?;
+        Ok(Some((msg, size)))
+    }
+}
+
+pub const RAW_NETWORK_MESSAGE_HEADER_SIZE: usize = 24;
+
+/// Max size of message payload. It is the same as `MAX_PROTOCOL_MESSAGE_LENGTH` of bitcoin core.
+/// A peer can not make us allocate a larger buffer.
+pub const MAX_PAYLOAD_SIZE: u32 = 4_000_000;
+
+/// Protocol limits of the number of entries in a message.
+pub const MAX_HEADERS_IN_MSG: u64 = 2000;
+pub const MAX_INV_IN_MSG: u64 = 50_000;
+pub const MAX_ADDR_IN_MSG: u64 = 1000;
+
+struct RawNetworkMessageHeader
+{
+    command_name: CommandString,
+    payload_size: u32,
+    checksum: [u8; 4],
+}
+
+/// Max number of entries of a message, and size of each entry.
+/// Messages which are not listed here have no limit other than `MAX_PAYLOAD_SIZE`.
+fn entry_limit(command: &str) -> Option<(u64, u32)>
+{
+    match command {
+        // A header and an empty transaction count.
+        "headers" => Some((MAX_HEADERS_IN_MSG, 81)),
+        "inv" | "getdata" => Some((MAX_INV_IN_MSG, 36)),
+        // A timestamp and an address.
+        "addr" => Some((MAX_ADDR_IN_MSG, 30)),
+        _ => None,
+    }
+}
+
+/// Reject a payload whose size is impossible for the command, before it is read.
+fn check_payload_size(command: &str, size: u32) -> Result<(), Error>
+{
+    let is_valid = match command {
+        "ping" | "pong" | "feefilter" => size == 8,
+        cmd => {
+            match entry_limit(cmd) {
+                // Count prefix takes at most 9 bytes.
+                Some((max, entry_size)) => size as u64 <= 9 + max * entry_size as u64,
+                None => true,
+            }
+        },
+    };
+    if is_valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidPayloadSize {
+            command: command.into(),
+            size,
+        })
+    }
+}
+
+/// Whether `msg` has almost as many entries as the protocol allows (90% or more).
+/// Note that a full `headers` message is usual while syncing.
+pub fn is_near_limit(msg: &Message) -> bool
+{
+    let (count, max) = match *msg {
+        Message::Network(NetworkMessage::Headers(ref headers)) => (headers.len(), MAX_HEADERS_IN_MSG),
+        Message::Network(NetworkMessage::Inv(ref invs)) => (invs.len(), MAX_INV_IN_MSG),
+        Message::Network(NetworkMessage::GetData(ref invs)) => (invs.len(), MAX_INV_IN_MSG),
+        Message::Network(NetworkMessage::Addr(ref addrs)) => (addrs.len(), MAX_ADDR_IN_MSG),
+        _ => return false,
+    };
+    count as u64 * 10 >= max * 9
+}
+
+/// # Panic
+/// If length of `src` is not 24 bytes.
+fn decode_msg_header(src: &[u8], network: &Network) -> Result<RawNetworkMessageHeader, Error>
+{
+    assert!(src.len() == RAW_NETWORK_MESSAGE_HEADER_SIZE);
+
+    debug!("Decode message header");
+
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+
+    let magic = u32::consensus_decode(&mut decoder)?;
+    if magic != network.magic() {
+        return Err(Error::from(BitcoinSerializeError::UnexpectedNetworkMagic {
+            expected: network.magic(),
+            actual: magic,
+        }));
+    }
+
+    let command_name = CommandString::consensus_decode(&mut decoder)?;
+    let payload_size = u32::consensus_decode(&mut decoder)?;
+    if payload_size > MAX_PAYLOAD_SIZE {
+        return Err(Error::OversizedPayload {
+            command: command_name.0,
+            size: payload_size,
+            max: MAX_PAYLOAD_SIZE,
+        });
+    }
+    check_payload_size(&command_name.0, payload_size)?;
+    let checksum = <[u8; 4]>::consensus_decode(&mut decoder)?;
+
+    Ok(RawNetworkMessageHeader {
+        command_name,
+        payload_size,
+        checksum,
+    })
+}
+
+/// # Panic
+/// If length of `src` is not `header.payload_size`.
+fn decode_and_check_msg_payload(src: &[u8], header: &RawNetworkMessageHeader) -> Result<Message, Error>
+{
+    assert!(src.len() as u32 == header.payload_size);
+
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+
+    // Check a checksum
+    let expected_checksum = sha2_checksum(&src);
+    if expected_checksum != header.checksum {
+        warn!("bad checksum");
+        return Err(Error::from(BitcoinSerializeError::InvalidChecksum {
+            expected: expected_checksum,
+            actual: header.checksum,
+        }));
+    }
+
+    // Check the number of entries before a vector is allocated.
+    let command = &header.command_name.0[..];
+    if let Some((max, _)) = entry_limit(command) {
+        let count = VarInt::consensus_decode(&mut RawDecoder::new(Cursor::new(src)))?.0;
+        if count > max {
+            return Err(Error::TooManyEntries {
+                command: command.into(),
+                count,
+                max,
+            });
+        }
+    }
+
+    let msg = match command {
+        "filterload" => Message::FilterLoad(ConsensusDecodable::consensus_decode(&mut decoder)?),
+        "filteradd" => Message::FilterAdd(ConsensusDecodable::consensus_decode(&mut decoder)?),
+        "filterclear" => Message::FilterClear,
+        "merkleblock" => Message::MerkleBlock(ConsensusDecodable::consensus_decode(&mut decoder)?),
+        "sendcmpct" => Message::SendCmpct(ConsensusDecodable::consensus_decode(&mut decoder)?),
+        "cmpctblock" => Message::CmpctBlock(ConsensusDecodable::consensus_decode(&mut decoder)?),
+        "getblocktxn" => Message::GetBlockTxn(ConsensusDecodable::consensus_decode(&mut decoder)?),
+        "blocktxn" => Message::BlockTxn(ConsensusDecodable::consensus_decode(&mut decoder)?),
+        "reject" => Message::Reject(ConsensusDecodable::consensus_decode(&mut decoder)?),
+        "sendheaders" => Message::SendHeaders,
+        "feefilter" => Message::FeeFilter(ConsensusDecodable::consensus_decode(&mut decoder)?),
+        cmd => {
+            match decode_network_msg_payload(cmd, &mut decoder)? {
+                Some(msg) => Message::Network(msg),
+                None => Message::Unknown(cmd.into()),
+            }
+        },
+    };
+
+    Ok(msg)
+}
+
+/// Decode a payload of a message which `bitcoin` crate supports.
+/// `None` if `bitcoin` crate does not know `cmd` either.
+fn decode_network_msg_payload(
+    cmd: &str,
+    decoder: &mut RawDecoder<Cursor<&[u8]>>,
+) -> Result<Option<NetworkMessage>, Error>
+{
+    let msg = match cmd {
+        "version" => NetworkMessage::Version(ConsensusDecodable::consensus_decode(decoder)?),
+        "verack" => NetworkMessage::Verack,
+        "addr" => NetworkMessage::Addr(ConsensusDecodable::consensus_decode(decoder)?),
+        "inv" => NetworkMessage::Inv(ConsensusDecodable::consensus_decode(decoder)?),
+        "getdata" => NetworkMessage::GetData(ConsensusDecodable::consensus_decode(decoder)?),
+        "notfound" => NetworkMessage::NotFound(ConsensusDecodable::consensus_decode(decoder)?),
+        "getblocks" => NetworkMessage::GetBlocks(ConsensusDecodable::consensus_decode(decoder)?),
+        "getheaders" => NetworkMessage::GetHeaders(ConsensusDecodable::consensus_decode(decoder)?),
+        "mempool" => NetworkMessage::MemPool,
+        "block" => NetworkMessage::Block(ConsensusDecodable::consensus_decode(decoder)?),
+        "headers" => NetworkMessage::Headers(ConsensusDecodable::consensus_decode(decoder)?),
+        "getaddr" => NetworkMessage::GetAddr,
+        "ping" => NetworkMessage::Ping(ConsensusDecodable::consensus_decode(decoder)?),
+        "pong" => NetworkMessage::Pong(ConsensusDecodable::consensus_decode(decoder)?),
+        "tx" => NetworkMessage::Tx(ConsensusDecodable::consensus_decode(decoder)?),
+        "alert" => NetworkMessage::Alert(ConsensusDecodable::consensus_decode(decoder)?),
+        cmd => {
+            // Newer peers send messages which we do not need, e.g. `wtxidrelay` or `sendaddrv2`.
+            debug!("Skip unrecognized network command : {}", cmd);
+            return Ok(None);
+        },
+    };
+
+    Ok(Some(msg))
+}
+
+fn sha2_checksum(data: &[u8]) -> [u8; 4]
+{
+    let checksum = Sha256dHash::from_data(data);
+    [checksum[0], checksum[1], checksum[2], checksum[3]]
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bitcoin::blockdata::{block::LoneBlockHeader, constants::genesis_block};
+    use bitcoin::network::{address::Address,
+                           message_blockdata::{GetBlocksMessage, GetHeadersMessage, InvType, Inventory}};
+    use bloom::{BloomFilter, BloomFlags, MerkleBlock};
+    use connection::compact::{BlockTransactions, BlockTransactionsRequest, HeaderAndShortIds, PrefilledTransaction,
+                              SendCmpct};
+    use connection::message::{RawInventory, Reject};
+    use testing::dummy_version_msg;
+
+    #[test]
+    fn decode_messages_split_across_reads()
+    {
+        let mut bytes = encode(NetworkMessage::Ping(1).into(), Network::Bitcoin);
+        bytes.extend(encode(NetworkMessage::Pong(2).into(), Network::Bitcoin));
+        let mut decoder = BtcCodec::new(Network::Bitcoin);
+        let mut buf = BytesMut::new();
+
+        // A half of the header.
+        buf.extend_from_slice(&bytes[..12]);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+
+        // The header without the payload.
+        buf.extend_from_slice(&bytes[12..24]);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        assert!(buf.capacity() >= 32);
+
+        // The rest of `ping` and a part of `pong`.
+        buf.extend_from_slice(&bytes[24..40]);
+        match decoder.decode(&mut buf).unwrap() {
+            Some((Message::Network(NetworkMessage::Ping(1)), 32)) => {},
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&bytes[40..]);
+        match decoder.decode(&mut buf).unwrap() {
+            Some((Message::Network(NetworkMessage::Pong(2)), 32)) => {},
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn reject_oversized_payload()
+    {
+        let mut bytes = encode(NetworkMessage::Ping(1).into(), Network::Bitcoin);
+        // Overwrite payload size in the header.
+        bytes[16..20].copy_from_slice(&serialize(&(MAX_PAYLOAD_SIZE + 1)).unwrap());
+        let mut decoder = BtcCodec::new(Network::Bitcoin);
+        let mut buf = BytesMut::from(bytes);
+
+        match decoder.decode(&mut buf) {
+            Err(Error::OversizedPayload { size, max, .. }) => {
+                assert_eq!((size, max), (MAX_PAYLOAD_SIZE + 1, 4_000_000))
+            },
+            other => panic!("Unexpected result : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reject_huge_payload_without_allocating()
+    {
+        // Only a header which claims 100MB payload.
+        let mut bytes = encode_raw("block", Vec::new(), Network::Bitcoin);
+        bytes[16..20].copy_from_slice(&serialize(&(100 * 1000 * 1000u32)).unwrap());
+        let mut decoder = BtcCodec::new(Network::Bitcoin);
+        let mut buf = BytesMut::with_capacity(64);
+        buf.extend_from_slice(&bytes);
+
+        match decoder.decode(&mut buf) {
+            Err(Error::OversizedPayload { ref command, size, .. }) => {
+                assert_eq!((command.as_str(), size), ("block", 100 * 1000 * 1000))
+            },
+            other => panic!("Unexpected result : {:?}", other),
+        }
+        assert!(buf.capacity() < 1024);
+    }
+
+    #[test]
+    fn decode_messages_split_at_every_boundary()
+    {
+        let mut bytes = encode(NetworkMessage::Ping(1).into(), Network::Bitcoin);
+        bytes.extend(encode(NetworkMessage::Headers(vec![dummy_header(); 3]).into(), Network::Bitcoin));
+
+        for split in 0..bytes.len() + 1 {
+            let mut decoder = BtcCodec::new(Network::Bitcoin);
+            let mut buf = BytesMut::new();
+            let mut decoded = Vec::new();
+            for chunk in &[&bytes[..split], &bytes[split..]] {
+                buf.extend_from_slice(chunk);
+                while let Some((msg, _)) = decoder.decode(&mut buf).unwrap() {
+                    decoded.push(msg.command());
+                }
+            }
+            assert_eq!(decoded, vec!["ping", "headers"], "split at {}", split);
+            assert!(buf.is_empty());
+        }
+    }
+
+    fn decode_frame(bytes: Vec<u8>) -> Result<Option<(Message, usize)>, Error>
+    {
+        let mut decoder = BtcCodec::new(Network::Bitcoin);
+        decoder.decode(&mut BytesMut::from(bytes))
+    }
+
+    fn dummy_inv() -> Inventory
+    {
+        Inventory {
+            inv_type: InvType::Block,
+            hash: Sha256dHash::default(),
+        }
+    }
+
+    fn dummy_header() -> LoneBlockHeader
+    {
+        LoneBlockHeader {
+            header: genesis_block(Network::Bitcoin).header,
+            tx_count: VarInt(0),
+        }
+    }
+
+    fn dummy_addr() -> (u32, Address)
+    {
+        (0, Address::new(&"127.0.0.1:8333".parse().unwrap(), 0))
+    }
+
+    #[test]
+    fn enforce_entry_limits_per_command()
+    {
+        let cases: Vec<(&str, u64, fn(usize) -> NetworkMessage)> = vec![
+            ("headers", MAX_HEADERS_IN_MSG, |n| NetworkMessage::Headers(vec![dummy_header(); n])),
+            ("inv", MAX_INV_IN_MSG, |n| NetworkMessage::Inv(vec![dummy_inv(); n])),
+            ("getdata", MAX_INV_IN_MSG, |n| NetworkMessage::GetData(vec![dummy_inv(); n])),
+            ("addr", MAX_ADDR_IN_MSG, |n| NetworkMessage::Addr(vec![dummy_addr(); n])),
+        ];
+
+        for (command, max, make_msg) in cases {
+            let at_limit = encode(make_msg(max as usize).into(), Network::Bitcoin);
+            match decode_frame(at_limit) {
+                Ok(Some((ref msg, _))) => assert!(is_near_limit(msg), "{} at the limit", command),
+                other => panic!("{} at the limit should pass : {:?}", command, other.map(|_| ())),
+            }
+
+            let above_limit = encode(make_msg(max as usize + 1).into(), Network::Bitcoin);
+            match decode_frame(above_limit) {
+                Err(Error::InvalidPayloadSize { command: ref cmd, .. }) => assert_eq!(cmd, command),
+                other => panic!("{} above the limit should fail : {:?}", command, other.map(|_| ())),
+            }
+
+            // A small frame which claims too many entries.
+            let lying = encode_raw(command, serialize(&VarInt(max + 1)).unwrap(), Network::Bitcoin);
+            match decode_frame(lying) {
+                Err(Error::TooManyEntries { count, max: m, .. }) => assert_eq!((count, m), (max + 1, max)),
+                other => panic!("{} with a lying count should fail : {:?}", command, other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn ping_and_pong_must_have_exactly_8_bytes()
+    {
+        for command in &["ping", "pong"] {
+            for size in &[0, 7, 9, 16] {
+                match decode_frame(encode_raw(command, vec![0; *size], Network::Bitcoin)) {
+                    Err(Error::InvalidPayloadSize { size: s, .. }) => assert_eq!(s, *size as u32),
+                    other => panic!("{} of {} bytes should fail : {:?}", command, size, other.map(|_| ())),
+                }
+            }
+            assert!(decode_frame(encode_raw(command, vec![0; 8], Network::Bitcoin)).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn small_messages_are_not_near_limit()
+    {
+        assert!(!is_near_limit(&NetworkMessage::Inv(vec![dummy_inv(); 10]).into()));
+        assert!(!is_near_limit(&NetworkMessage::Addr(vec![dummy_addr(); 899]).into()));
+        assert!(is_near_limit(&NetworkMessage::Addr(vec![dummy_addr(); 900]).into()));
+        assert!(!is_near_limit(&NetworkMessage::Ping(1).into()));
+    }
+
+    // Decoding `msg` and encoding it again must give the same bytes.
+    fn assert_round_trip(msg: Message)
+    {
+        let command = msg.command();
+        let mut codec = BtcCodec::new(Network::Bitcoin);
+        let mut buf = BytesMut::new();
+        codec.encode(msg, &mut buf).unwrap();
+        let bytes = buf.to_vec();
+
+        let (decoded, size) = match codec.decode(&mut buf) {
+            Ok(Some(decoded)) => decoded,
+            other => panic!("{} should be decoded : {:?}", command, other.map(|_| ())),
+        };
+        assert!(buf.is_empty(), "{}", command);
+        assert_eq!(size, bytes.len(), "{}", command);
+        assert_eq!(decoded.command(), command);
+        assert_eq!(encode(decoded, Network::Bitcoin), bytes, "{}", command);
+    }
+
+    #[test]
+    fn round_trip_every_message()
+    {
+        let block = genesis_block(Network::Bitcoin);
+        let header = block.header;
+        let tx = block.txdata[0].clone();
+        let hash = header.merkle_root;
+
+        let network_msgs = vec![
+            NetworkMessage::Version(dummy_version_msg(42)),
+            NetworkMessage::Verack,
+            NetworkMessage::Addr(vec![dummy_addr()]),
+            NetworkMessage::Inv(vec![dummy_inv()]),
+            NetworkMessage::GetData(vec![dummy_inv()]),
+            NetworkMessage::NotFound(vec![dummy_inv()]),
+            NetworkMessage::GetBlocks(GetBlocksMessage::new(vec![hash], Sha256dHash::default())),
+            NetworkMessage::GetHeaders(GetHeadersMessage::new(vec![hash], Sha256dHash::default())),
+            NetworkMessage::MemPool,
+            NetworkMessage::Block(block.clone()),
+            NetworkMessage::Headers(vec![dummy_header()]),
+            NetworkMessage::GetAddr,
+            NetworkMessage::Ping(1),
+            NetworkMessage::Pong(2),
+            NetworkMessage::Tx(tx.clone()),
+            NetworkMessage::Alert(vec![1, 2, 3]),
+        ];
+        for msg in network_msgs {
+            assert_round_trip(msg.into());
+        }
+
+        let msgs = vec![
+            // It is decoded as `getdata` of `bitcoin` crate, which knows block inventories.
+            Message::GetDataRaw(vec![RawInventory { inv_type: 2, hash }]),
+            Message::FilterLoad(BloomFilter::new(10, 0.01, 0, BloomFlags::None)),
+            Message::FilterAdd(vec![1; 20]),
+            Message::FilterClear,
+            Message::MerkleBlock(MerkleBlock {
+                header,
+                total_transactions: 1,
+                hashes: vec![hash],
+                flags: vec![1],
+            }),
+            Message::SendCmpct(SendCmpct {
+                high_bandwidth: true,
+                version: 1,
+            }),
+            Message::CmpctBlock(HeaderAndShortIds {
+                header,
+                nonce: 7,
+                short_ids: vec![1, 2],
+                prefilled_txs: vec![PrefilledTransaction { index: 0, tx: tx.clone() }],
+            }),
+            Message::GetBlockTxn(BlockTransactionsRequest {
+                block_hash: hash,
+                indexes: vec![1, 3],
+            }),
+            Message::BlockTxn(BlockTransactions {
+                block_hash: hash,
+                txs: vec![tx],
+            }),
+            Message::Reject(Reject {
+                message: "tx".into(),
+                ccode: 0x10,
+                reason: "bad-txns".into(),
+                data: Some(hash),
+            }),
+            Message::SendHeaders,
+            Message::FeeFilter(1000),
+            Message::Unknown("wtxidrelay".into()),
+        ];
+        for msg in msgs {
+            assert_round_trip(msg);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_checksum()
+    {
+        let mut bytes = encode(NetworkMessage::Ping(1).into(), Network::Bitcoin);
+        // Corrupt the payload.
+        bytes[RAW_NETWORK_MESSAGE_HEADER_SIZE] ^= 1;
+
+        match decode_frame(bytes) {
+            Err(Error::Decode(BitcoinSerializeError::InvalidChecksum { .. })) => {},
+            other => panic!("Unexpected result : {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn skip_payload_of_unknown_command()
+    {
+        let mut bytes = encode_raw("unknown", vec![1, 2, 3], Network::Bitcoin);
+        bytes.extend(encode(NetworkMessage::Ping(1).into(), Network::Bitcoin));
+        let mut codec = BtcCodec::new(Network::Bitcoin);
+        let mut buf = BytesMut::from(bytes);
+
+        match codec.decode(&mut buf) {
+            Ok(Some((Message::Unknown(ref cmd), size))) => assert_eq!((cmd.as_str(), size), ("unknown", 24 + 3)),
+            other => panic!("Unexpected result : {:?}", other.map(|_| ())),
+        }
+        match codec.decode(&mut buf) {
+            Ok(Some((Message::Network(NetworkMessage::Ping(1)), _))) => {},
+            other => panic!("Unexpected result : {:?}", other.map(|_| ())),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn feefilter_must_have_exactly_8_bytes()
+    {
+        match decode_frame(encode_raw("feefilter", vec![0; 4], Network::Bitcoin)) {
+            Err(Error::InvalidPayloadSize { size, .. }) => assert_eq!(size, 4),
+            other => panic!("Unexpected result : {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,499 @@
+//! Compact block relay (BIP 152).
+//!
+//! We only support the receive side of low-bandwidth mode.
+
+use bitcoin::blockdata::{block::{Block, BlockHeader}, transaction::Transaction};
+use bitcoin::network::{encodable::{ConsensusDecodable, ConsensusEncodable, VarInt},
+                       serialize::{serialize, BitcoinHash, SimpleDecoder, SimpleEncoder}};
+use bitcoin::util::hash::{MerkleRoot, Sha256dHash};
+use crypto::{digest::Digest, sha2::Sha256};
+
+/// `MSG_CMPCT_BLOCK` inventory type (BIP 152).
+pub const MSG_CMPCT_BLOCK: u32 = 4;
+
+/// Version of compact block protocol which we support.
+/// Version 1 computes short ids from txids, not wtxids.
+pub const COMPACT_BLOCK_VERSION: u64 = 1;
+
+/// Peers whose protocol version is lower than this do not understand `sendcmpct` message.
+pub const COMPACT_BLOCK_MIN_PROTOCOL_VERSION: u32 = 70014;
+
+/// `sendcmpct` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendCmpct
+{
+    /// If true, peer announces new blocks by `cmpctblock` directly.
+    pub high_bandwidth: bool,
+    pub version: u64,
+}
+
+/// A transaction which is sent in a `cmpctblock` message as it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefilledTransaction
+{
+    /// Absolute index in the block. Differential encoding is resolved while decoding.
+    pub index: usize,
+    pub tx: Transaction,
+}
+
+/// `cmpctblock` message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderAndShortIds
+{
+    pub header: BlockHeader,
+    pub nonce: u64,
+    pub short_ids: Vec<u64>,
+    pub prefilled_txs: Vec<PrefilledTransaction>,
+}
+
+/// `getblocktxn` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockTransactionsRequest
+{
+    pub block_hash: Sha256dHash,
+    /// Absolute indexes of requested transactions in ascending order.
+    pub indexes: Vec<usize>,
+}
+
+/// `blocktxn` message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockTransactions
+{
+    pub block_hash: Sha256dHash,
+    pub txs: Vec<Transaction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactBlockError
+{
+    /// Index of a prefilled transaction is out of range or duplicated.
+    BadIndex,
+    /// The number of received transactions is different from the number of missing ones.
+    TxCountMismatch,
+    /// Reconstructed transactions do not hash up to the merkle root of header.
+    MerkleRootMismatch,
+}
+
+impl HeaderAndShortIds
+{
+    /// SipHash keys to compute short ids of this block.
+    pub fn short_id_keys(&self) -> (u64, u64)
+    {
+        let mut data = serialize(&self.header).unwrap(); // Never fail
+        data.extend(serialize(&self.nonce).unwrap());
+
+        let mut hasher = Sha256::new();
+        hasher.input(&data);
+        let mut hash = [0; 32];
+        hasher.result(&mut hash);
+
+        (read_u64_le(&hash[0..8]), read_u64_le(&hash[8..16]))
+    }
+
+    /// Compute a short id of a given txid.
+    pub fn short_id(&self, txid: &Sha256dHash) -> u64
+    {
+        let (k0, k1) = self.short_id_keys();
+        siphash24(k0, k1, &txid[..]) & 0xffff_ffff_ffff
+    }
+
+    pub fn total_transactions(&self) -> usize
+    {
+        self.short_ids.len() + self.prefilled_txs.len()
+    }
+}
+
+/// A block which is being reconstructed from a `cmpctblock` message.
+///
+/// # Note
+/// We do not have a mempool, so only prefilled transactions are known at first.
+/// The other transactions are requested by `getblocktxn` message.
+#[derive(Debug)]
+pub struct PartialBlock
+{
+    header: BlockHeader,
+    txs: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock
+{
+    pub fn new(cmpct: &HeaderAndShortIds) -> Result<PartialBlock, CompactBlockError>
+    {
+        let mut txs = vec![None; cmpct.total_transactions()];
+        for prefilled in cmpct.prefilled_txs.iter() {
+            let slot = txs.get_mut(prefilled.index).ok_or(CompactBlockError::BadIndex)?;
+            if slot.is_some() {
+                return Err(CompactBlockError::BadIndex);
+            }
+            *slot = Some(prefilled.tx.clone());
+        }
+        Ok(PartialBlock {
+            header: cmpct.header,
+            txs,
+        })
+    }
+
+    pub fn block_hash(&self) -> Sha256dHash
+    {
+        self.header.bitcoin_hash()
+    }
+
+    /// Indexes of transactions which we do not have yet.
+    pub fn missing_indexes(&self) -> Vec<usize>
+    {
+        self.txs
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Fill missing transactions in order, then verify the merkle root.
+    pub fn fill(self, missing_txs: Vec<Transaction>) -> Result<Block, CompactBlockError>
+    {
+        let PartialBlock { header, txs } = self;
+        let mut missing_txs = missing_txs.into_iter();
+        let mut txdata = Vec::with_capacity(txs.len());
+        for tx in txs {
+            match tx.or_else(|| missing_txs.next()) {
+                Some(tx) => txdata.push(tx),
+                None => return Err(CompactBlockError::TxCountMismatch),
+            }
+        }
+        if missing_txs.next().is_some() {
+            return Err(CompactBlockError::TxCountMismatch);
+        }
+
+        let block = Block { header, txdata };
+        if block.merkle_root() != block.header.merkle_root {
+            return Err(CompactBlockError::MerkleRootMismatch);
+        }
+        Ok(block)
+    }
+}
+
+fn read_u64_le(bytes: &[u8]) -> u64
+{
+    bytes.iter().rev().fold(0, |acc, b| (acc << 8) | u64::from(*b))
+}
+
+/// SipHash-2-4.
+pub fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64
+{
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+
+    let mut chunks = data.chunks(8);
+    let last = if data.len() % 8 == 0 { &[][..] } else { chunks.next_back().unwrap() };
+    for chunk in chunks {
+        let m = read_u64_le(chunk);
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+
+    let b = ((data.len() as u64 & 0xff) << 56) | read_u64_le(last);
+    v[3] ^= b;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= b;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_round(v: &mut [u64; 4])
+{
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+/* Encode and decode */
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for SendCmpct
+{
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error>
+    {
+        self.high_bandwidth.consensus_encode(s)?;
+        self.version.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for SendCmpct
+{
+    fn consensus_decode(d: &mut D) -> Result<SendCmpct, D::Error>
+    {
+        Ok(SendCmpct {
+            high_bandwidth: ConsensusDecodable::consensus_decode(d)?,
+            version: ConsensusDecodable::consensus_decode(d)?,
+        })
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for HeaderAndShortIds
+{
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error>
+    {
+        self.header.consensus_encode(s)?;
+        self.nonce.consensus_encode(s)?;
+
+        VarInt(self.short_ids.len() as u64).consensus_encode(s)?;
+        for id in self.short_ids.iter() {
+            // A short id is 6 bytes little endian.
+            for i in 0..6 {
+                s.emit_u8((id >> (8 * i)) as u8)?;
+            }
+        }
+
+        VarInt(self.prefilled_txs.len() as u64).consensus_encode(s)?;
+        let indexes: Vec<_> = self.prefilled_txs.iter().map(|p| p.index).collect();
+        for (diff, prefilled) in differential(&indexes).into_iter().zip(self.prefilled_txs.iter()) {
+            VarInt(diff).consensus_encode(s)?;
+            prefilled.tx.consensus_encode(s)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for HeaderAndShortIds
+{
+    fn consensus_decode(d: &mut D) -> Result<HeaderAndShortIds, D::Error>
+    {
+        let header = ConsensusDecodable::consensus_decode(d)?;
+        let nonce = ConsensusDecodable::consensus_decode(d)?;
+
+        let VarInt(len) = ConsensusDecodable::consensus_decode(d)?;
+        let mut short_ids = Vec::new();
+        for _ in 0..len {
+            let mut id = 0;
+            for i in 0..6 {
+                id |= u64::from(d.read_u8()?) << (8 * i);
+            }
+            short_ids.push(id);
+        }
+
+        let VarInt(len) = ConsensusDecodable::consensus_decode(d)?;
+        let mut prefilled_txs = Vec::new();
+        let mut next_index = 0;
+        for _ in 0..len {
+            let VarInt(diff) = ConsensusDecodable::consensus_decode(d)?;
+            let index = match next_index.checked_add(diff) {
+                Some(index) if index <= u64::from(u16::max_value()) => index,
+                _ => return Err(d.error("compact block index overflow".into())),
+            };
+            prefilled_txs.push(PrefilledTransaction {
+                index: index as usize,
+                tx: ConsensusDecodable::consensus_decode(d)?,
+            });
+            next_index = index + 1;
+        }
+
+        Ok(HeaderAndShortIds {
+            header,
+            nonce,
+            short_ids,
+            prefilled_txs,
+        })
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for BlockTransactionsRequest
+{
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error>
+    {
+        self.block_hash.consensus_encode(s)?;
+        VarInt(self.indexes.len() as u64).consensus_encode(s)?;
+        for diff in differential(&self.indexes) {
+            VarInt(diff).consensus_encode(s)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for BlockTransactionsRequest
+{
+    fn consensus_decode(d: &mut D) -> Result<BlockTransactionsRequest, D::Error>
+    {
+        let block_hash = ConsensusDecodable::consensus_decode(d)?;
+        let VarInt(len) = ConsensusDecodable::consensus_decode(d)?;
+        let mut indexes = Vec::new();
+        let mut next_index = 0;
+        for _ in 0..len {
+            let VarInt(diff) = ConsensusDecodable::consensus_decode(d)?;
+            let index = match next_index.checked_add(diff) {
+                Some(index) if index <= u64::from(u16::max_value()) => index,
+                _ => return Err(d.error("compact block index overflow".into())),
+            };
+            indexes.push(index as usize);
+            next_index = index + 1;
+        }
+        Ok(BlockTransactionsRequest { block_hash, indexes })
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for BlockTransactions
+{
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error>
+    {
+        self.block_hash.consensus_encode(s)?;
+        self.txs.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for BlockTransactions
+{
+    fn consensus_decode(d: &mut D) -> Result<BlockTransactions, D::Error>
+    {
+        Ok(BlockTransactions {
+            block_hash: ConsensusDecodable::consensus_decode(d)?,
+            txs: ConsensusDecodable::consensus_decode(d)?,
+        })
+    }
+}
+
+/// Convert ascending absolute indexes into differential ones.
+fn differential(indexes: &[usize]) -> Vec<u64>
+{
+    let mut next_index = 0;
+    indexes
+        .iter()
+        .map(|i| {
+            let diff = (*i - next_index) as u64;
+            next_index = *i + 1;
+            diff
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::{constants::Network, serialize::deserialize};
+
+    // Test vectors are taken from the reference implementation of SipHash.
+    #[test]
+    fn siphash24_test_vectors()
+    {
+        let k0 = 0x0706_0504_0302_0100;
+        let k1 = 0x0f0e_0d0c_0b0a_0908;
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash24(k0, k1, &[]), 0x726f_db47_dd0e_0e31);
+        assert_eq!(siphash24(k0, k1, &data[..8]), 0x93f5_f579_9a93_2462);
+        assert_eq!(siphash24(k0, k1, &data), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn short_id_of_genesis_coinbase()
+    {
+        let genesis = genesis_block(Network::Bitcoin);
+        let cmpct = HeaderAndShortIds {
+            header: genesis.header,
+            nonce: 42,
+            short_ids: Vec::new(),
+            prefilled_txs: Vec::new(),
+        };
+        assert_eq!(cmpct.short_id_keys(), (0xcdcb_a171_49bf_0fe4, 0x92d1_b7da_ad4c_b395));
+        assert_eq!(cmpct.short_id(&genesis.header.merkle_root), 0xdf07_102c_66b9);
+    }
+
+    #[test]
+    fn reconstruct_block_from_compact_block()
+    {
+        let genesis = genesis_block(Network::Bitcoin);
+        let coinbase = genesis.txdata[0].clone();
+        let mut cmpct = HeaderAndShortIds {
+            header: genesis.header,
+            nonce: 42,
+            short_ids: Vec::new(),
+            prefilled_txs: vec![
+                PrefilledTransaction {
+                    index: 0,
+                    tx: coinbase.clone(),
+                },
+            ],
+        };
+
+        // All transactions are prefilled.
+        let decoded: HeaderAndShortIds = deserialize(&serialize(&cmpct).unwrap()).unwrap();
+        assert_eq!(decoded, cmpct);
+        let partial = PartialBlock::new(&decoded).unwrap();
+        assert!(partial.missing_indexes().is_empty());
+        assert_eq!(partial.fill(Vec::new()).unwrap(), genesis);
+
+        // Coinbase is requested by `getblocktxn`.
+        cmpct.prefilled_txs.clear();
+        cmpct.short_ids.push(cmpct.short_id(&coinbase.bitcoin_hash()));
+        let partial = PartialBlock::new(&cmpct).unwrap();
+        assert_eq!(partial.missing_indexes(), vec![0]);
+        assert_eq!(partial.fill(vec![coinbase.clone()]).unwrap(), genesis);
+
+        let partial = PartialBlock::new(&cmpct).unwrap();
+        assert_eq!(partial.fill(Vec::new()).unwrap_err(), CompactBlockError::TxCountMismatch);
+
+        let mut another = coinbase.clone();
+        another.lock_time += 1;
+        let partial = PartialBlock::new(&cmpct).unwrap();
+        assert_eq!(partial.fill(vec![another]).unwrap_err(), CompactBlockError::MerkleRootMismatch);
+    }
+
+    #[test]
+    fn getblocktxn_uses_differential_indexes()
+    {
+        let req = BlockTransactionsRequest {
+            block_hash: Sha256dHash::default(),
+            indexes: vec![1, 2, 5],
+        };
+        let encoded = serialize(&req).unwrap();
+        // hash, count, then 1 - 0, 2 - 2, 5 - 3
+        assert_eq!(&encoded[32..], &[3, 1, 0, 2]);
+        let decoded: BlockTransactionsRequest = deserialize(&encoded).unwrap();
+        assert_eq!(decoded, req);
+    }
+    #[test]
+    fn reject_differential_index_overflow()
+    {
+        // `getblocktxn` with indexes 0 and 0 + 1 + u64::MAX.
+        let mut encoded = vec![0u8; 32];
+        encoded.extend_from_slice(&[2, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert!(deserialize::<BlockTransactionsRequest>(&encoded).is_err());
+
+        // `cmpctblock` whose second prefilled transaction has the same differential index.
+        let genesis = genesis_block(Network::Bitcoin);
+        let cmpct = HeaderAndShortIds {
+            header: genesis.header,
+            nonce: 42,
+            short_ids: Vec::new(),
+            prefilled_txs: vec![
+                PrefilledTransaction {
+                    index: 0,
+                    tx: genesis.txdata[0].clone(),
+                },
+            ],
+        };
+        let mut encoded = serialize(&cmpct).unwrap();
+        // header, nonce and the count of short ids come before the count of prefilled transactions.
+        assert_eq!(encoded[89], 1);
+        encoded[89] = 2;
+        encoded.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert!(deserialize::<HeaderAndShortIds>(&encoded).is_err());
+    }
+}
@@ -1,27 +1,73 @@
-use std::time::Duration;
+use std::{collections::{HashMap, HashSet, VecDeque}, net::SocketAddr,
+          sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, time::{Duration, Instant}};
 
-use bitcoin::network::{address::Address, message::NetworkMessage,
-                       message_blockdata::{GetHeadersMessage, InvType, Inventory}};
-use bitcoin::blockdata::block::{Block, LoneBlockHeader};
+use bitcoin::network::{address::Address, constants::Network, message::NetworkMessage,
+                       message_blockdata::{GetBlocksMessage, GetHeadersMessage, InvType, Inventory},
+                       message_network::VersionMessage};
+use bitcoin::blockdata::{block::{Block, BlockHeader, LoneBlockHeader}, transaction::Transaction};
+use bitcoin::network::encodable::VarInt;
+use bitcoin::network::serialize::Error as BitcoinSerializeError;
 use bitcoin::util::hash::Sha256dHash;
 use bitcoin::BitcoinHash;
 
-use futures::{Future, Stream};
-use tokio::{io::WriteHalf, net::TcpStream};
+use futures::{Future, Stream, sync::mpsc};
+use tokio::io::{AsyncRead, AsyncWrite};
 use actix::{msgs::StartActor, prelude::*};
-use failure::Error;
+use rand::random;
 
-use connection::socket::HandshakedSocket;
+use blockchain::{BlockChain, BlockSource};
+use bloom::{BloomFilter, MerkleBlock};
+use connection::{codec::is_near_limit,
+                 compact::{BlockTransactions, BlockTransactionsRequest, HeaderAndShortIds, PartialBlock, SendCmpct,
+                           COMPACT_BLOCK_MIN_PROTOCOL_VERSION, COMPACT_BLOCK_VERSION, MSG_CMPCT_BLOCK},
+                 connection_pool::BanConnection,
+                 message::{Message, RawInventory, Reject, MSG_FILTERED_BLOCK, SEND_HEADERS_MIN_PROTOCOL_VERSION},
+                 misbehavior::{MisbehaviorPolicy, MisbehaviorScore, Violation, Violations},
+                 rate_limit::{RateLimitedMessages, RateLimiter, RateLimits},
+                 socket::HandshakedSocket};
+use error::Error;
+use events::{noop_sink, Event, EventSink};
+use witness::{check_witness_commitment, MSG_WITNESS_BLOCK, NODE_WITNESS};
 
 const SEND_TIMEOUT: Duration = Duration::from_secs(2);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+// How long we wait for peer to request a transaction which we announced.
+const BROADCAST_TIMEOUT: Duration = Duration::from_secs(30);
+// How long we wait for `reject` message after peer receives a transaction.
+const REJECT_WINDOW: Duration = Duration::from_secs(5);
+// The number of recent round trip times which are used to calculate median ping.
+const NUM_PING_SAMPLES: usize = 8;
+
+/// At most this number of compact blocks wait for `blocktxn` at a time.
+/// Other compact blocks are requested again as full blocks.
+const MAX_PARTIAL_BLOCKS: usize = 16;
+/// How long incoming inventories are buffered before they are published to subscribers.
+pub const DEFAULT_INV_BATCH_INTERVAL: Duration = Duration::from_millis(100);
+/// How often we send `ping` to check that peer is alive.
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+/// If peer does not respond `pong` in this duration, we close the connection.
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long we wait for peer to respond all of requested headers or blocks.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// A transaction which is announced again within this duration is not requested again.
+const TX_DEDUP_WINDOW: Duration = Duration::from_secs(60);
+/// The max number of headers in a `headers` message.
+pub const MAX_HEADERS_IN_MSG: usize = 2000;
+/// Max number of addresses in an `addr` message.
+pub const MAX_ADDRS_IN_MSG: usize = 1000;
+/// Default max number of outgoing messages which wait to be written to the socket.
+pub const DEFAULT_SEND_QUEUE_SIZE: usize = 1000;
 
 #[derive(Message, Debug)]
-pub struct P2PMessage(NetworkMessage);
+/// A message from peer with its size in bytes.
+pub struct P2PMessage(Message, usize);
 
 #[derive(Message)]
 /// This message corresponds to `getdata` message in bitcoin protocol.
-/// If peer does not have requested block data, peer does not respond anything.
-/// In Bitcoin core implementation, it wait up to two seconds and then disconnect.
+/// If peer does not have requested block data, peer responds `notfound` message.
+/// But old peer may not respond anything.
+/// Several requests can be in flight at once. A block which is requested by several requests is
+/// delivered to all of them.
 pub struct GetBlocksRequest
 {
     pub block_hashes: Vec<Sha256dHash>,
@@ -30,14 +76,21 @@ pub struct GetBlocksRequest
 
 #[derive(Message)]
 /// A response message to GetBlocksRequest.
-/// Sender probably receive same number of `BlockResponse` with request hashes.
-/// But sometime that number is less (see `GetBlocksRequest` document).
-/// Sender **SHOULD** set timeout.
-/// 2 seconds are recommended.
-pub struct BlockResponse(pub Block);
+/// Sender receives the same number of `BlockResponse` with request hashes.
+/// If peer does not respond some of them within the request timeout (`DEFAULT_REQUEST_TIMEOUT` by default),
+/// `Timeout` is sent for each of them.
+pub enum BlockResponse
+{
+    Found(Block),
+    /// Peer does not have a requested block.
+    NotFound(Sha256dHash),
+    /// Peer does not respond a requested block in time.
+    Timeout(Sha256dHash),
+}
 
 #[derive(Message)]
 /// This message corresponds to `getheaders` message in bitcoin protocol.
+/// Requests are sent to peer one by one in the order they arrive.
 pub struct GetHeadersRequest
 {
     pub locator_hashes: Vec<Sha256dHash>,
@@ -45,20 +98,127 @@ pub struct GetHeadersRequest
 }
 
 #[derive(Message)]
-/// This message corresponds to `headers` message in bitcoin protocol.
-pub struct HeadersResponse(pub Vec<LoneBlockHeader>);
+/// A response message to GetHeadersRequest.
+pub enum HeadersResponse
+{
+    /// This corresponds to `headers` message in bitcoin protocol.
+    Headers(Vec<LoneBlockHeader>),
+    /// Peer does not respond within the request timeout.
+    Timeout,
+}
+
+#[derive(Message)]
+/// This message corresponds to `getblocks` message in bitcoin protocol.
+/// Peer responds hashes of up to 500 blocks after the fork point of `locator_hashes` by `inv` message.
+/// The first `inv` of blocks after `getblocks` is taken as the response, and is not published to subscribers.
+/// Requests are sent to peer one by one in the order they arrive.
+pub struct GetBlockInvsRequest
+{
+    pub locator_hashes: Vec<Sha256dHash>,
+    pub addr: Recipient<BlockInvsResponse>,
+}
+
+#[derive(Message)]
+/// A response message to GetBlockInvsRequest.
+pub enum BlockInvsResponse
+{
+    /// Hashes of blocks in the order peer sends.
+    Invs(Vec<Sha256dHash>),
+    /// Peer does not respond within the request timeout.
+    /// Peer which has no block after the locator does not respond at all, so it ends up here as well.
+    Timeout,
+}
 
 #[derive(Message)]
 /// Start to subscribe incoming `inv` message.
-/// Sender may receive a lot of `PublishInv` message.
+/// Inventories which arrive within a batch interval are de-duplicated and published at once.
+/// Multiple subscribers can subscribe the same connection.
 pub struct SubscribeInv
 {
     pub addr: Recipient<PublishInv>,
+    pub filter: InvFilter,
+}
+
+/// Kinds of inventories which a subscriber of `SubscribeInv` receives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvFilter
+{
+    Block,
+    Tx,
+    All,
+}
+
+impl InvFilter
+{
+    pub fn matches(&self, inv: &Inventory) -> bool
+    {
+        match *self {
+            InvFilter::Block => inv.inv_type == InvType::Block,
+            InvFilter::Tx => inv.inv_type == InvType::Transaction,
+            InvFilter::All => true,
+        }
+    }
+}
+
+#[derive(Message)]
+/// Inventories which peer announced by `inv` messages, filtered by `InvFilter`.
+pub struct PublishInv
+{
+    /// It is never empty.
+    pub invs: Vec<Inventory>,
+    /// The connection to the peer which announced them, e.g. to request announced blocks from it.
+    pub conn: Addr<Connection>,
+}
+
+#[derive(Message)]
+/// Start to subscribe transactions which peer announces by `inv` messages.
+/// Connection requests announced transactions by `getdata` while there is any subscriber,
+/// and publishes them as they arrive.
+pub struct SubscribeTx
+{
+    pub addr: Recipient<PublishTx>,
+}
+
+#[derive(Message)]
+/// A transaction which peer announced and sent.
+pub struct PublishTx(pub Transaction);
+
+#[derive(Message)]
+/// Start to subscribe `headers` messages which peer sends without our request.
+/// Peer announces new blocks by them after we send `sendheaders` (BIP 130).
+/// If no one subscribes them, they are treated as unsolicited messages.
+pub struct SubscribeHeaders
+{
+    pub addr: Recipient<PublishHeaders>,
+}
+
+#[derive(Message)]
+/// Headers which peer announced by `headers` message.
+pub struct PublishHeaders
+{
+    pub headers: Vec<LoneBlockHeader>,
+    /// The connection to the peer which announced them.
+    pub conn: Addr<Connection>,
 }
 
 #[derive(Message)]
-/// This message corresponds to `inv` message in bitcoin protocol.
-pub struct PublishInv(pub Vec<Inventory>);
+/// Change how long incoming inventories are buffered before they are published.
+/// Default is `DEFAULT_INV_BATCH_INTERVAL`.
+pub struct SetInvBatchInterval(pub Duration);
+
+#[derive(Message)]
+/// Change how long we wait for responses of `GetHeadersRequest`, `GetBlockInvsRequest` and `GetBlocksRequest`.
+/// Default is `DEFAULT_REQUEST_TIMEOUT`. It applies to requests which are sent after this.
+pub struct SetRequestTimeout(pub Duration);
+
+#[derive(Message)]
+/// Change how often we send `ping`, and how long we wait for the matching `pong`.
+/// Defaults are `DEFAULT_PING_INTERVAL` and `DEFAULT_PING_TIMEOUT`.
+pub struct SetPingInterval
+{
+    pub interval: Duration,
+    pub timeout: Duration,
+}
 
 #[derive(Message)]
 /// This message corresponds to `getaddr` message in bitcoin protocol.
@@ -68,12 +228,194 @@ pub struct GetAddrsRequest
 }
 
 #[derive(Message)]
+/// Addresses with their timestamps which peer sent by `addr` message.
 pub struct AddrsResponse(pub Vec<(u32, Address)>);
 
+#[derive(Message)]
+/// Start to subscribe addresses which peer announces by `addr` messages without our `GetAddrsRequest`.
+pub struct SubscribeAddrs
+{
+    pub addr: Recipient<AddrsResponse>,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<(u32, Address)>")]
+/// Ask for known addresses with their timestamps to respond `getaddr` message from peer.
+pub struct SampleAddrs
+{
+    pub max: usize,
+}
+
+#[derive(Message)]
+/// This message corresponds to `filterload` message in bitcoin protocol (BIP 37).
+pub struct LoadBloomFilter(pub BloomFilter);
+
+#[derive(Message)]
+/// This message corresponds to `filteradd` message in bitcoin protocol (BIP 37).
+pub struct AddToBloomFilter(pub Vec<u8>);
+
+#[derive(Message)]
+/// This message corresponds to `filterclear` message in bitcoin protocol (BIP 37).
+pub struct ClearBloomFilter;
+
+#[derive(Message)]
+/// This message corresponds to `getdata` message with `MSG_FILTERED_BLOCK` inventories.
+/// A bloom filter should be loaded by `LoadBloomFilter` before.
+/// Peer responds `merkleblock` message followed by `tx` messages which match the filter.
+pub struct GetFilteredBlocksRequest
+{
+    pub block_hashes: Vec<Sha256dHash>,
+    pub addr: Recipient<FilteredBlockResponse>,
+}
+
+#[derive(Message)]
+/// A response message to GetFilteredBlocksRequest.
+/// `header` is verified against the partial merkle tree, and `txs` are transactions
+/// which matched the bloom filter.
+pub struct FilteredBlockResponse
+{
+    pub header: BlockHeader,
+    pub txs: Vec<Transaction>,
+}
+
+#[derive(Message)]
+/// Announce a transaction to peer by `inv` message, and send it when peer requests it by `getdata`.
+/// The result is reported to `addr`.
+pub struct BroadcastTx
+{
+    pub tx: Transaction,
+    pub addr: Recipient<BroadcastResult>,
+}
+
+#[derive(Message, Debug)]
+/// A response message to BroadcastTx.
+pub enum BroadcastResult
+{
+    /// Peer requested and received the transaction, and did not reject it.
+    Accepted(Sha256dHash),
+    /// Peer sent `reject` message referencing the transaction.
+    Rejected(Sha256dHash, Reject),
+    /// Peer did not request the transaction.
+    Ignored(Sha256dHash),
+    /// The transaction is not announced since too many messages wait to be sent to peer.
+    QueueFull(Sha256dHash),
+}
+
+#[derive(Message)]
+/// Request blocks with witness data by `MSG_WITNESS_BLOCK` inventories (BIP 144).
+/// It is ignored if peer does not advertise `NODE_WITNESS`.
+pub struct DownloadWitnessBlocks(pub bool);
+
 #[derive(Message)]
 /// Force to gracefully shutdown connection.
 pub struct Disconnect();
 
+#[derive(Message)]
+#[rtype(result = "i32")]
+/// Get `start_height` which remote peer advertised while handshake.
+pub struct GetPeerStartHeight;
+
+#[derive(Message)]
+#[rtype(result = "Network")]
+/// Get the network which the connection is on.
+pub struct GetNetwork;
+
+#[derive(Message)]
+#[rtype(result = "VersionMessage")]
+/// Get `version` message which remote peer sent while handshake.
+pub struct GetRemoteVersion;
+
+#[derive(Message)]
+#[rtype(result = "ConnectionStats")]
+pub struct GetConnectionStats;
+
+#[derive(Message)]
+/// Report a violation of peer which is found outside of `Connection`.
+/// When misbehavior score reaches the threshold, connection is closed.
+pub struct ReportMisbehavior(pub Violation);
+
+#[derive(Message)]
+/// Replace the policy of misbehavior scoring.
+/// When the score reaches the threshold, `BanConnection` is sent to `ban` before closing connection.
+pub struct SetMisbehaviorPolicy
+{
+    pub policy: MisbehaviorPolicy,
+    pub ban: Recipient<BanConnection>,
+}
+
+#[derive(Message)]
+/// Report `Event`s of this connection to given sink.
+pub struct SetEventSink(pub Arc<EventSink>);
+
+#[derive(Message)]
+/// Respond `getheaders` messages from peer with headers of active chain of given blockchain.
+/// Until it is set, `getheaders` messages are ignored.
+pub struct SetHeaderSource(pub Arc<Mutex<BlockChain>>);
+
+#[derive(Message)]
+/// Respond `getaddr` messages from peer with up to `MAX_ADDRS_IN_MSG` addresses of given source.
+/// Until it is set, `getaddr` messages are ignored.
+pub struct SetAddrSource(pub Recipient<SampleAddrs>);
+
+#[derive(Message)]
+/// Respond `getdata` messages for blocks from peer with blocks of given source.
+/// Until it is set, every requested block is responded by `notfound` message.
+pub struct SetBlockSource(pub Arc<BlockSource>);
+
+#[derive(Message)]
+/// Change how many outgoing messages can wait to be written to the socket.
+/// When the queue is full, responses to peer's requests and our announcements are dropped.
+/// Default is `DEFAULT_SEND_QUEUE_SIZE`.
+pub struct SetSendQueueSize(pub usize);
+
+#[derive(Message)]
+/// Replace rate limits of `addr` and `inv` messages which peer sends without our request.
+/// Messages over the limits are dropped and reported as `Violation::RateLimited`.
+/// Default is `RateLimits::default()`.
+pub struct SetRateLimits(pub RateLimits);
+
+/// Statistics of a connection to identify slow peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats
+{
+    pub bytes_received: u64,
+    pub messages_received: u64,
+    /// When peer sent the last message of any kind.
+    pub last_msg: Option<Instant>,
+    /// The number of requested blocks which peer sent.
+    pub blocks_served: u64,
+    pub last_block_msg: Option<Instant>,
+    pub last_headers_msg: Option<Instant>,
+    /// Median round trip time of recent `ping` messages.
+    pub median_ping: Option<Duration>,
+    /// Current misbehavior score, which decays over time.
+    pub misbehavior_score: u32,
+    pub violations: Violations,
+    /// Since when peer has not sent any of requested blocks.
+    /// `None` if there is no outstanding request.
+    pub blocks_waiting_since: Option<Instant>,
+    /// The number of outgoing messages which wait to be written to the socket.
+    pub send_queue_len: usize,
+    /// The number of outgoing messages which are dropped since the send queue is full.
+    pub dropped_messages: u64,
+    /// Min fee rate of transactions which peer wants announced, in satoshis per 1000 bytes (BIP 133).
+    pub fee_filter: Option<u64>,
+    pub rate_limits: RateLimits,
+    /// The number of messages which are dropped since peer sends them too fast.
+    pub rate_limited: RateLimitedMessages,
+}
+
+impl ConnectionStats
+{
+    /// Whether peer has not sent any of requested blocks for `timeout`.
+    pub fn is_stalled(&self, now: Instant, timeout: Duration) -> bool
+    {
+        self.blocks_waiting_since
+            .map(|since| since + timeout <= now)
+            .unwrap_or(false)
+    }
+}
+
 /// # Note
 /// The behavior of `Connection` follows bitcoin protocol.
 /// e.g. after GetBlocksRequest is sent, if connecting peer couldn't find requested block peer does
@@ -81,75 +423,282 @@ pub struct Disconnect();
 /// So `Connection` is also response nothing.
 pub struct Connection
 {
-    // it should not be None except during waiting to complete sending
-    write_socket: Option<HandshakedSocket<WriteHalf<TcpStream>>>,
+    // `None` after `Disconnect`.
+    send_queue: Option<SendQueue>,
+    send_queue_size: usize,
     socket_stream_handle: SpawnHandle,
+    remote_version: VersionMessage,
+    peer_addr: SocketAddr,
+    network: Network,
 
-    waiting_blocks: Option<WaitingBlocks>,
+    // Requested blocks and ids of requests which wait for them.
+    waiting_blocks: HashMap<Sha256dHash, Vec<u64>>,
+    block_requests: HashMap<u64, WaitingBlocks>,
+    next_block_request_id: u64,
+    // Whether peer can send blocks as `cmpctblock` or not.
+    compact_blocks: bool,
+    // Compact blocks which wait for `blocktxn` message.
+    partial_blocks: HashMap<Sha256dHash, PartialBlock>,
+    // Whether we request blocks with witness data.
+    witness_blocks: bool,
+    waiting_filtered_blocks: Option<WaitingFilteredBlocks>,
     waiting_headers: Option<WaitingHeaders>,
-    subscribe_invs: Option<Recipient<PublishInv>>,
+    // `getheaders` is sent one by one, since `headers` message does not tell which request it responds.
+    queued_headers_requests: VecDeque<GetHeadersRequest>,
+    waiting_block_invs: Option<WaitingBlockInvs>,
+    // `getblocks` is sent one by one for the same reason.
+    queued_block_invs_requests: VecDeque<GetBlockInvsRequest>,
+    request_timeout: Duration,
+    inv_subscribers: Vec<(Recipient<PublishInv>, InvFilter)>,
+    headers_subscribers: Vec<Recipient<PublishHeaders>>,
+    tx_subscribers: Vec<Recipient<PublishTx>>,
+    addr_subscribers: Vec<Recipient<AddrsResponse>>,
+    // Announced transactions which we requested and which do not arrive yet.
+    waiting_txs: HashSet<Sha256dHash>,
+    // Transactions which we requested within `TX_DEDUP_WINDOW`.
+    recent_txids: HashSet<Sha256dHash>,
+    // Inventories which wait to be published, and their hashes to de-duplicate them.
+    pending_invs: Vec<Inventory>,
+    pending_inv_hashes: HashSet<Sha256dHash>,
+    inv_batch_interval: Duration,
     waiting_addrs: Option<Recipient<AddrsResponse>>,
+    broadcasting_txs: HashMap<Sha256dHash, BroadcastingTx>,
+
+    stats: ConnectionStats,
+    // The last time when a block request is sent or a requested block is received.
+    last_block_progress: Instant,
+    // Nonce of `ping` which waits for `pong` and when it is sent.
+    waiting_pong: Option<(u64, Instant)>,
+    ping_samples: VecDeque<Duration>,
+    ping_interval_handle: SpawnHandle,
+    ping_timeout: Duration,
+
+    misbehavior: MisbehaviorScore,
+    misbehavior_policy: MisbehaviorPolicy,
+    rate_limiter: RateLimiter,
+    ban_addr: Option<Recipient<BanConnection>>,
+    events: Arc<EventSink>,
+    header_source: Option<Arc<Mutex<BlockChain>>>,
+    block_source: Option<Arc<BlockSource>>,
+    addr_source: Option<Recipient<SampleAddrs>>,
 }
 
 impl Actor for Connection
 {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context)
+    {
+        // Ask peer to announce new blocks by `headers` message.
+        if self.remote_version.version >= SEND_HEADERS_MIN_PROTOCOL_VERSION {
+            self.send_p2p_msg(Message::SendHeaders, ctx);
+        }
+        // Ask peer to send blocks as `cmpctblock` in low-bandwidth mode (BIP 152).
+        if self.remote_version.version >= COMPACT_BLOCK_MIN_PROTOCOL_VERSION {
+            let sendcmpct = SendCmpct {
+                high_bandwidth: false,
+                version: COMPACT_BLOCK_VERSION,
+            };
+            self.send_p2p_msg(Message::SendCmpct(sendcmpct), ctx);
+        }
+        self.ping_interval_handle = ctx.run_interval(DEFAULT_PING_INTERVAL, |actor, ctx| actor.send_ping(ctx));
+    }
 }
 
 impl Connection
 {
-    pub fn start_actor(socket: HandshakedSocket<TcpStream>) -> Addr<Self>
+    pub fn start_actor<S>(socket: HandshakedSocket<S>) -> Addr<Self>
+    where S: AsyncRead + AsyncWrite + 'static
     {
         <Connection as Actor>::create(move |ctx| Connection::create(socket, ctx))
     }
 
-    pub fn start_actor_on(
-        socket: HandshakedSocket<TcpStream>,
+    /// Start `Connection` actor on the given arbiter.
+    ///
+    /// Returned future must not be blocked on from an actor context running on the
+    /// same arbiter, otherwise it never resolves.
+    pub fn start_actor_on<S>(
+        socket: HandshakedSocket<S>,
         arbiter: Addr<Arbiter>,
-    ) -> Result<Addr<Self>, MailboxError>
+    ) -> impl Future<Item = Addr<Self>, Error = MailboxError>
+    where S: AsyncRead + AsyncWrite + Send + 'static
     {
         let start_actor = StartActor::new(move |ctx| Connection::create(socket, ctx));
-        arbiter.send(start_actor).wait()
+        arbiter.send(start_actor)
     }
 
-    pub fn create(socket: HandshakedSocket<TcpStream>, ctx: &mut Context<Self>) -> Connection
+    pub fn create<S>(socket: HandshakedSocket<S>, ctx: &mut Context<Self>) -> Connection
+    where S: AsyncRead + AsyncWrite + 'static
     {
+        let remote_version = socket.remote_version().clone();
         let (read_socket, write_socket) = socket.split();
 
-        let msg_stream = read_socket.recv_msg_stream().map(|m| P2PMessage(m));
+        let msg_stream = read_socket
+            .recv_sized_msg_stream()
+            .map(|(m, size)| P2PMessage(m, size));
         let socket_stream_handle = ctx.add_stream(msg_stream);
 
-        Connection::new(write_socket, socket_stream_handle)
+        let (network, peer_addr) = (write_socket.network(), write_socket.peer_addr());
+        let (send_queue, writer) = SendQueue::new(write_socket);
+        let conn = Connection::new(network, peer_addr, send_queue, socket_stream_handle, remote_version);
+
+        // Messages are written one after another without blocking the actor.
+        // The writer finishes after `Disconnect`, when queued messages are flushed and the socket is shut down.
+        let f = writer
+            .into_actor(&conn)
+            .map(|(), _actor, ctx| ctx.stop())
+            .map_err(|e, _actor, ctx| {
+                info!("Socket is closed : {:?}", e);
+                info!("Close connection as well");
+                ctx.stop();
+            });
+        ctx.spawn(f);
+        conn
     }
 
-    fn new(write_socket: HandshakedSocket<WriteHalf<TcpStream>>, socket_stream_handle: SpawnHandle) -> Connection
+    fn new(
+        network: Network,
+        peer_addr: SocketAddr,
+        send_queue: SendQueue,
+        socket_stream_handle: SpawnHandle,
+        remote_version: VersionMessage,
+    ) -> Connection
     {
         Connection {
-            write_socket: Some(write_socket),
+            network,
+            peer_addr,
+            send_queue: Some(send_queue),
+            send_queue_size: DEFAULT_SEND_QUEUE_SIZE,
             socket_stream_handle,
+            remote_version,
 
-            waiting_blocks: None,
+            waiting_blocks: HashMap::new(),
+            block_requests: HashMap::new(),
+            next_block_request_id: 0,
+            compact_blocks: false,
+            partial_blocks: HashMap::new(),
+            witness_blocks: false,
+            waiting_filtered_blocks: None,
             waiting_headers: None,
-            subscribe_invs: None,
+            queued_headers_requests: VecDeque::new(),
+            waiting_block_invs: None,
+            queued_block_invs_requests: VecDeque::new(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            inv_subscribers: Vec::new(),
+            headers_subscribers: Vec::new(),
+            tx_subscribers: Vec::new(),
+            addr_subscribers: Vec::new(),
+            waiting_txs: HashSet::new(),
+            recent_txids: HashSet::new(),
+            pending_invs: Vec::new(),
+            pending_inv_hashes: HashSet::new(),
+            inv_batch_interval: DEFAULT_INV_BATCH_INTERVAL,
             waiting_addrs: None,
+            broadcasting_txs: HashMap::new(),
+
+            stats: ConnectionStats::default(),
+            last_block_progress: Instant::now(),
+            waiting_pong: None,
+            ping_samples: VecDeque::with_capacity(NUM_PING_SAMPLES),
+            ping_interval_handle: SpawnHandle::default(),
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+
+            misbehavior: MisbehaviorScore::new(Instant::now()),
+            misbehavior_policy: MisbehaviorPolicy::default(),
+            rate_limiter: RateLimiter::new(RateLimits::default(), Instant::now()),
+            ban_addr: None,
+            events: noop_sink(),
+            header_source: None,
+            block_source: None,
+            addr_source: None,
         }
     }
 
-    fn send_p2p_msg(&mut self, msg: NetworkMessage, ctx: &mut Context<Self>)
+    /// Queue a message which we need to send, e.g. our request.
+    /// It is queued even if the send queue is full.
+    fn send_p2p_msg<M: Into<Message>>(&mut self, msg: M, ctx: &mut Context<Self>)
     {
-        let write_socket = self.write_socket.take().expect("BUG!!");
-        let f = write_socket
-            .send_msg(msg)
-            .into_actor(self)
-            .map(|socket, actor, _ctx| {
-                actor.write_socket = Some(socket);
-            })
-            .map_err(|e, _actor, ctx| {
-                info!("Socket is closed : {:?}", e);
-                info!("Close connection as well");
-                ctx.stop();
-            });
-        ctx.wait(f);
+        let msg = msg.into();
+        let is_sent = match self.send_queue {
+            None => {
+                debug!("Connection is closing. Drop {} message", msg.command());
+                return;
+            },
+            Some(ref queue) => queue.push(msg),
+        };
+        if !is_sent {
+            info!("Writer of socket is already stopped. Close connection");
+            ctx.stop();
+        }
+    }
+
+    /// Queue a message which only peer needs, e.g. a response to `getdata`.
+    /// It is dropped if the send queue is full, and then `false` is returned.
+    fn send_p2p_response<M: Into<Message>>(&mut self, msg: M, ctx: &mut Context<Self>) -> bool
+    {
+        let queued = self.send_queue.as_ref().map(|queue| queue.len()).unwrap_or(0);
+        if queued >= self.send_queue_size {
+            let msg = msg.into();
+            debug!("Send queue is full. Drop {} message", msg.command());
+            self.stats.dropped_messages += 1;
+            return false;
+        }
+        self.send_p2p_msg(msg, ctx);
+        true
+    }
+
+    /// `start_height` which remote peer advertised while handshake.
+    /// It is an estimation of the height of remote chain.
+    pub fn remote_start_height(&self) -> i32
+    {
+        self.remote_version.start_height
+    }
+
+    pub fn remote_user_agent(&self) -> &str
+    {
+        &self.remote_version.user_agent
+    }
+
+    /// Services which remote peer advertised while handshake.
+    pub fn remote_services(&self) -> u64
+    {
+        self.remote_version.services
+    }
+
+    fn stats(&self) -> ConnectionStats
+    {
+        let mut samples: Vec<_> = self.ping_samples.iter().cloned().collect();
+        samples.sort();
+        let mut misbehavior = self.misbehavior;
+        ConnectionStats {
+            median_ping: samples.get(samples.len() / 2).cloned(),
+            misbehavior_score: misbehavior.score(&self.misbehavior_policy, Instant::now()),
+            violations: misbehavior.violations(),
+            blocks_waiting_since: if self.waiting_blocks.is_empty() {
+                None
+            } else {
+                Some(self.last_block_progress)
+            },
+            send_queue_len: self.send_queue.as_ref().map(|queue| queue.len()).unwrap_or(0),
+            rate_limits: self.rate_limiter.limits(),
+            rate_limited: self.rate_limiter.dropped(),
+            ..self.stats
+        }
+    }
+
+    fn requests_witness_blocks(&self) -> bool
+    {
+        self.witness_blocks && self.remote_version.services & NODE_WITNESS != 0
+    }
+
+    /// Whether `msg` may be a response to our request. Responses are never rate limited.
+    fn may_be_response(&self, msg: &Message) -> bool
+    {
+        match *msg {
+            Message::Network(NetworkMessage::Addr(_)) => self.waiting_addrs.is_some(),
+            Message::Network(NetworkMessage::Inv(_)) => self.waiting_block_invs.is_some(),
+            _ => false,
+        }
     }
 }
 
@@ -159,9 +708,56 @@ impl Handler<Disconnect> for Connection
 
     fn handle(&mut self, _msg: Disconnect, ctx: &mut Self::Context)
     {
-        let _ = self.write_socket.take().unwrap().shutdown().wait();
         ctx.cancel_future(self.socket_stream_handle);
-        ctx.stop();
+
+        // Closing the queue lets the writer flush queued messages, shut down the socket and stop the actor.
+        if self.send_queue.take().is_none() {
+            ctx.stop();
+            return;
+        }
+
+        // Never block the arbiter on shutdown. Stop anyway if peer does not respond in time.
+        ctx.run_later(SHUTDOWN_TIMEOUT, |_actor, ctx| ctx.stop());
+    }
+}
+
+impl Handler<GetRemoteVersion> for Connection
+{
+    type Result = MessageResult<GetRemoteVersion>;
+
+    fn handle(&mut self, _msg: GetRemoteVersion, _ctx: &mut Self::Context) -> MessageResult<GetRemoteVersion>
+    {
+        MessageResult(self.remote_version.clone())
+    }
+}
+
+impl Handler<GetConnectionStats> for Connection
+{
+    type Result = MessageResult<GetConnectionStats>;
+
+    fn handle(&mut self, _msg: GetConnectionStats, _ctx: &mut Self::Context) -> MessageResult<GetConnectionStats>
+    {
+        MessageResult(self.stats())
+    }
+}
+
+impl Handler<GetNetwork> for Connection
+{
+    type Result = MessageResult<GetNetwork>;
+
+    fn handle(&mut self, _msg: GetNetwork, _ctx: &mut Self::Context) -> MessageResult<GetNetwork>
+    {
+        MessageResult(self.network)
+    }
+}
+
+impl Handler<GetPeerStartHeight> for Connection
+{
+    type Result = i32;
+
+    fn handle(&mut self, _msg: GetPeerStartHeight, _ctx: &mut Self::Context) -> i32
+    {
+        self.remote_start_height()
     }
 }
 
@@ -173,21 +769,56 @@ impl StreamHandler<P2PMessage, Error> for Connection
     fn handle(&mut self, msg: P2PMessage, ctx: &mut Self::Context)
     {
         use self::NetworkMessage::*;
+        self.stats.bytes_received += msg.1 as u64;
+        self.stats.messages_received += 1;
+        self.stats.last_msg = Some(Instant::now());
+        if is_near_limit(&msg.0) {
+            self.report_misbehavior(Violation::NearLimitMessage, ctx);
+        }
+        if !self.may_be_response(&msg.0) && !self.rate_limiter.allow(&msg.0, Instant::now()) {
+            debug!("Peer sends {} messages too fast. Drop it", msg.0.command());
+            return self.report_misbehavior(Violation::RateLimited, ctx);
+        }
         match msg.0 {
-            Addr(addrs) => self.handle_addr_msg(addrs, ctx),
-            Inv(invs) => self.handle_invs_msg(invs, ctx),
-            Block(block) => self.handle_block_msg(block, ctx),
-            Headers(headers) => self.handle_headers_msg(headers, ctx),
-            Ping(nonce) => self.handle_ping_msg(nonce, ctx),
+            Message::Network(Addr(addrs)) => self.handle_addr_msg(addrs, ctx),
+            Message::Network(Inv(invs)) => self.handle_invs_msg(invs, ctx),
+            Message::Network(Block(block)) => self.handle_block_msg(block, ctx),
+            Message::Network(NotFound(invs)) => self.handle_notfound_msg(invs, ctx),
+            Message::Network(Headers(headers)) => self.handle_headers_msg(headers, ctx),
+            Message::Network(GetHeaders(msg)) => self.handle_getheaders_msg(msg, ctx),
+            Message::Network(Ping(nonce)) => self.handle_ping_msg(nonce, ctx),
+            Message::Network(Pong(nonce)) => self.handle_pong_msg(nonce, ctx),
+            Message::Network(Tx(tx)) => self.handle_tx_msg(tx, ctx),
+            Message::Network(GetData(invs)) => self.handle_getdata_msg(invs, ctx),
+            Message::Network(GetAddr) => self.handle_getaddr_msg(ctx),
+            Message::Reject(reject) => self.handle_reject_msg(reject, ctx),
+            Message::MerkleBlock(block) => self.handle_merkleblock_msg(block, ctx),
+            Message::SendCmpct(msg) => self.handle_sendcmpct_msg(msg, ctx),
+            Message::CmpctBlock(block) => self.handle_cmpctblock_msg(block, ctx),
+            Message::BlockTxn(txs) => self.handle_blocktxn_msg(txs, ctx),
+            // We never announce blocks, so it does not matter how peer wants them.
+            Message::SendHeaders => {},
+            // We never announce transactions either, but it may be useful to know.
+            Message::FeeFilter(rate) => self.stats.fee_filter = Some(rate),
+            Message::Unknown(command) => debug!("Ignore unknown {} message", command),
             another => {
                 info!("Receive unexpected network msg. {:?}", another);
             },
         }
     }
 
-    fn error(&mut self, err: Error, _ctx: &mut Self::Context) -> Running
+    fn error(&mut self, err: Error, ctx: &mut Self::Context) -> Running
     {
         info!("Catch error on socket : {:?}", err);
+        match err {
+            Error::Decode(BitcoinSerializeError::InvalidChecksum { .. }) => {
+                self.report_misbehavior(Violation::ChecksumFailure, ctx)
+            },
+            Error::OversizedPayload { .. } | Error::InvalidPayloadSize { .. } | Error::TooManyEntries { .. } => {
+                self.report_misbehavior(Violation::InvalidMessageSize, ctx)
+            },
+            _ => {},
+        }
         Running::Stop
     }
 }
@@ -195,20 +826,122 @@ impl StreamHandler<P2PMessage, Error> for Connection
 struct WaitingBlocks
 {
     addr: Recipient<BlockResponse>,
+    // Blocks which are not delivered yet.
     block_hashes: Vec<Sha256dHash>,
+    timeout: SpawnHandle,
+}
+
+struct WaitingFilteredBlocks
+{
+    addr: Recipient<FilteredBlockResponse>,
+    block_hashes: Vec<Sha256dHash>,
+    // A block whose matched transactions are not received yet.
+    current: Option<PendingFilteredBlock>,
+}
+
+struct PendingFilteredBlock
+{
+    header: BlockHeader,
+    matched: Vec<Sha256dHash>,
+    txs: Vec<Transaction>,
 }
 
 struct WaitingHeaders
 {
     addr: Recipient<HeadersResponse>,
+    timeout: SpawnHandle,
+}
+
+struct WaitingBlockInvs
+{
+    addr: Recipient<BlockInvsResponse>,
+    timeout: SpawnHandle,
+}
+
+struct BroadcastingTx
+{
+    tx: Transaction,
+    addr: Recipient<BroadcastResult>,
+    // Whether peer already requested the transaction.
+    requested: bool,
+}
+
+/// Outgoing messages which wait to be written by a writer future.
+struct SendQueue
+{
+    normal: mpsc::UnboundedSender<Message>,
+    // `ping` and `pong` overtake other messages so that a long queue does not look like a dead connection.
+    urgent: mpsc::UnboundedSender<Message>,
+    // The number of messages in `normal`. The writer decrements it.
+    len: Arc<AtomicUsize>,
+}
+
+impl SendQueue
+{
+    /// The returned future writes queued messages until the queue is dropped.
+    fn new<W>(socket: HandshakedSocket<W>) -> (SendQueue, impl Future<Item = (), Error = Error>)
+    where W: AsyncWrite + 'static
+    {
+        let (normal, normal_rx) = mpsc::unbounded();
+        let (urgent, urgent_rx) = mpsc::unbounded();
+        let len = Arc::new(AtomicUsize::new(0));
+        let len2 = len.clone();
+        let normal_rx = normal_rx.inspect(move |_| {
+            len2.fetch_sub(1, Ordering::SeqCst);
+        });
+        let writer = urgent_rx
+            .select(normal_rx)
+            .map_err(|()| -> Error { unreachable!("Receiver never fails") })
+            .forward(socket.send_msg_sink())
+            .map(|_| ());
+        let queue = SendQueue { normal, urgent, len };
+        (queue, writer)
+    }
+
+    /// Returns false if the writer is already stopped.
+    fn push(&self, msg: Message) -> bool
+    {
+        match msg {
+            Message::Network(NetworkMessage::Ping(_)) | Message::Network(NetworkMessage::Pong(_)) => {
+                self.urgent.unbounded_send(msg).is_ok()
+            },
+            msg => {
+                if self.normal.unbounded_send(msg).is_err() {
+                    return false;
+                }
+                self.len.fetch_add(1, Ordering::SeqCst);
+                true
+            },
+        }
+    }
+
+    fn len(&self) -> usize
+    {
+        self.len.load(Ordering::SeqCst)
+    }
 }
 
 impl Connection
 {
-    fn stop_misbehaving_connection(&mut self, ctx: &mut Context<Self>)
+    /// Add a violation to misbehavior score.
+    /// Connection is closed and banned only when the score reaches the threshold.
+    fn report_misbehavior(&mut self, violation: Violation, ctx: &mut Context<Self>)
     {
-        info!("Peer misbehaves. Close connection");
-        ctx.stop();
+        info!("Peer misbehaves : {:?}", violation);
+        // Violations which weigh nothing, e.g. near limit messages, are too common to be events.
+        if self.misbehavior_policy.weight(violation) > 0 {
+            self.events.on_event(Event::Misbehavior {
+                peer: self.peer_addr,
+                violation,
+            });
+        }
+        if self.misbehavior.report(violation, &self.misbehavior_policy, Instant::now()) {
+            info!("Misbehavior score reaches the threshold. Close connection");
+            if let Some(ref ban) = self.ban_addr {
+                let _ = ban.do_send(BanConnection { conn: ctx.address() });
+            }
+            ctx.stop();
+        }
     }
 
     fn handle_addr_msg(&mut self, addrs: Vec<(u32, Address)>, ctx: &mut Context<Self>)
@@ -220,65 +953,507 @@ impl Connection
                 .map_err(|_e| ())
                 .into_actor(self);
             let _ = ctx.spawn(f);
-        } else {
+        } else if self.addr_subscribers.is_empty() {
             debug!("Discard Addr msg");
+        } else {
+            // Peer announces addresses on its own, e.g. when it relays a new node.
+            self.addr_subscribers
+                .retain(|addr| addr.do_send(AddrsResponse(addrs.clone())).is_ok());
         }
     }
 
-    fn handle_block_msg(&mut self, block: Block, ctx: &mut Context<Connection>)
+    fn handle_getaddr_msg(&mut self, ctx: &mut Context<Self>)
     {
-        if let Some(mut waiting) = self.waiting_blocks.take() {
-            let block_hash = block.bitcoin_hash();
-            let maybe_idx = waiting.block_hashes.iter().position(|h| *h == block_hash);
-            match maybe_idx {
-                None => {
-                    self.stop_misbehaving_connection(ctx);
-                    return;
-                },
-                Some(idx) => waiting.block_hashes.remove(idx),
-            };
-            let send_f = waiting.addr.send(BlockResponse(block)).timeout(SEND_TIMEOUT);
-            let f = send_f.into_actor(self).map_err(|e, _actor, _ctx| {
-                debug!("Fail to send msg : {:?}", e);
-            });
-            let _ = ctx.spawn(f);
+        let f = match self.addr_source {
+            None => {
+                debug!("Peer requests addresses but we don't serve them.");
+                return;
+            },
+            Some(ref source) => source.send(SampleAddrs { max: MAX_ADDRS_IN_MSG }),
+        };
+        let f = f.into_actor(self)
+            .map(|mut addrs, actor, ctx| {
+                addrs.truncate(MAX_ADDRS_IN_MSG);
+                actor.send_p2p_response(NetworkMessage::Addr(addrs), ctx);
+            })
+            .map_err(|e, _actor, _ctx| debug!("Address source is already dropped : {:?}", e));
+        ctx.spawn(f);
+    }
 
-            if !waiting.block_hashes.is_empty() {
-                self.waiting_blocks = Some(waiting);
+    fn handle_block_msg(&mut self, block: Block, ctx: &mut Context<Connection>)
+    {
+        let block_hash = block.bitcoin_hash();
+        if !self.waiting_blocks.contains_key(&block_hash) {
+            self.report_misbehavior(Violation::UnsolicitedMessage, ctx);
+            return;
+        }
+        let now = Instant::now();
+        self.stats.blocks_served += 1;
+        self.stats.last_block_msg = Some(now);
+        self.last_block_progress = now;
+        if self.requests_witness_blocks() {
+            if let Err(e) = check_witness_commitment(&block) {
+                // The request is kept, and it will be timed out.
+                info!("Invalid witness block {} : {:?}", block_hash, e);
+                self.report_misbehavior(Violation::InvalidBlock, ctx);
+                return;
             }
         }
+
+        let mut requesters = self.take_waiting_block(&block_hash, ctx);
+        // The last requester takes the block itself.
+        let last = requesters.pop();
+        for addr in requesters {
+            self.send_block_response(&addr, BlockResponse::Found(block.clone()), ctx);
+        }
+        if let Some(addr) = last {
+            self.send_block_response(&addr, BlockResponse::Found(block), ctx);
+        }
     }
 
-    fn handle_invs_msg(&mut self, invs: Vec<Inventory>, ctx: &mut Context<Self>)
+    /// Remove `hash` from waiting blocks, and returns requesters which wait for it.
+    /// A request is finished when all of its blocks are taken.
+    fn take_waiting_block(&mut self, hash: &Sha256dHash, ctx: &mut Context<Self>) -> Vec<Recipient<BlockResponse>>
     {
-        if let Some(ref subscriber) = self.subscribe_invs.as_ref() {
-            let send_f = subscriber.send(PublishInv(invs)).timeout(SEND_TIMEOUT);
-            let f = send_f.into_actor(self).map_err(|e, actor, _ctx| {
-                debug!("Fail to send msg : {:?}", e);
-                actor.subscribe_invs = None;
-            });
-            ctx.spawn(f);
+        self.partial_blocks.remove(hash);
+        let ids = self.waiting_blocks.remove(hash).unwrap_or_default();
+        let mut requesters = Vec::with_capacity(ids.len());
+        for id in ids {
+            let is_complete = match self.block_requests.get_mut(&id) {
+                None => continue,
+                Some(req) => {
+                    req.block_hashes.retain(|h| h != hash);
+                    requesters.push(req.addr.clone());
+                    req.block_hashes.is_empty()
+                },
+            };
+            if is_complete {
+                let req = self.block_requests.remove(&id).unwrap();
+                ctx.cancel_future(req.timeout);
+            }
+        }
+        requesters
+    }
+
+    fn handle_sendcmpct_msg(&mut self, msg: SendCmpct, _ctx: &mut Context<Self>)
+    {
+        // We can not reconstruct a block from wtxid based short ids.
+        if msg.version == COMPACT_BLOCK_VERSION {
+            self.compact_blocks = true;
+        }
+    }
+
+    fn handle_cmpctblock_msg(&mut self, block: HeaderAndShortIds, ctx: &mut Context<Self>)
+    {
+        let block_hash = block.header.bitcoin_hash();
+        if !self.waiting_blocks.contains_key(&block_hash) {
+            self.report_misbehavior(Violation::UnsolicitedMessage, ctx);
+            return;
+        }
+
+        let partial = match PartialBlock::new(&block) {
+            Ok(partial) => partial,
+            Err(e) => {
+                info!("Invalid cmpctblock : {:?}", e);
+                self.report_misbehavior(Violation::InvalidBlock, ctx);
+                return;
+            },
+        };
+
+        let indexes = partial.missing_indexes();
+        if indexes.is_empty() {
+            self.complete_partial_block(partial, Vec::new(), ctx);
+        } else if self.partial_blocks.len() >= MAX_PARTIAL_BLOCKS && !self.partial_blocks.contains_key(&block_hash) {
+            let inv = Inventory {
+                inv_type: InvType::Block,
+                hash: block_hash,
+            };
+            self.send_p2p_msg(NetworkMessage::GetData(vec![inv]), ctx);
+        } else {
+            let req = BlockTransactionsRequest { block_hash, indexes };
+            self.send_p2p_msg(Message::GetBlockTxn(req), ctx);
+            self.partial_blocks.insert(block_hash, partial);
+        }
+    }
+
+    fn handle_blocktxn_msg(&mut self, txs: BlockTransactions, ctx: &mut Context<Self>)
+    {
+        match self.partial_blocks.remove(&txs.block_hash) {
+            None => self.report_misbehavior(Violation::UnsolicitedMessage, ctx),
+            Some(partial) => self.complete_partial_block(partial, txs.txs, ctx),
+        }
+    }
+
+    /// Deliver a reconstructed block.
+    /// If reconstruction fails, fall back to request a full block.
+    fn complete_partial_block(&mut self, partial: PartialBlock, txs: Vec<Transaction>, ctx: &mut Context<Self>)
+    {
+        let block_hash = partial.block_hash();
+        match partial.fill(txs) {
+            Ok(block) => self.handle_block_msg(block, ctx),
+            Err(e) => {
+                info!("Fail to reconstruct compact block {} : {:?}", block_hash, e);
+                let inv = Inventory {
+                    inv_type: InvType::Block,
+                    hash: block_hash,
+                };
+                self.send_p2p_msg(NetworkMessage::GetData(vec![inv]), ctx);
+            },
+        }
+    }
+
+    /// Notify requester of missing blocks.
+    /// `notfound` of blocks which we are not waiting for is unsolicited.
+    fn handle_notfound_msg(&mut self, invs: Vec<Inventory>, ctx: &mut Context<Self>)
+    {
+        let mut unsolicited = false;
+        for inv in invs.iter().filter(|inv| inv.inv_type == InvType::Block) {
+            let requesters = self.take_waiting_block(&inv.hash, ctx);
+            if requesters.is_empty() {
+                unsolicited = true;
+            }
+            for addr in requesters {
+                self.send_block_response(&addr, BlockResponse::NotFound(inv.hash), ctx);
+            }
+        }
+        if unsolicited {
+            self.report_misbehavior(Violation::UnsolicitedMessage, ctx);
+        }
+    }
+
+    /// Give up blocks of request `id` which peer does not respond yet.
+    /// The same blocks requested by other requests are still waited.
+    fn timeout_blocks(&mut self, id: u64, ctx: &mut Context<Self>)
+    {
+        let req = match self.block_requests.remove(&id) {
+            None => return,
+            Some(req) => req,
+        };
+        info!("Peer does not respond {} blocks in time", req.block_hashes.len());
+        for hash in req.block_hashes {
+            let is_empty = match self.waiting_blocks.get_mut(&hash) {
+                None => continue,
+                Some(ids) => {
+                    ids.retain(|i| *i != id);
+                    ids.is_empty()
+                },
+            };
+            if is_empty {
+                self.waiting_blocks.remove(&hash);
+                self.partial_blocks.remove(&hash);
+            }
+            self.send_block_response(&req.addr, BlockResponse::Timeout(hash), ctx);
+        }
+    }
+
+    fn send_block_response(&mut self, addr: &Recipient<BlockResponse>, res: BlockResponse, ctx: &mut Context<Self>)
+    {
+        let send_f = addr.send(res).timeout(SEND_TIMEOUT);
+        let f = send_f.into_actor(self).map_err(|e, _actor, _ctx| {
+            debug!("Fail to send msg : {:?}", e);
+        });
+        let _ = ctx.spawn(f);
+    }
+
+    fn handle_merkleblock_msg(&mut self, block: MerkleBlock, ctx: &mut Context<Self>)
+    {
+        let mut waiting = match self.waiting_filtered_blocks.take() {
+            None => {
+                debug!("Discard MerkleBlock msg");
+                return;
+            },
+            Some(waiting) => waiting,
+        };
+
+        let block_hash = block.header.bitcoin_hash();
+        match waiting.block_hashes.iter().position(|h| *h == block_hash) {
+            None => {
+                self.waiting_filtered_blocks = Some(waiting);
+                self.report_misbehavior(Violation::UnsolicitedMessage, ctx);
+                return;
+            },
+            Some(idx) => waiting.block_hashes.remove(idx),
+        };
+
+        let matched = match block.extract_matches() {
+            Ok(matched) => matched,
+            Err(e) => {
+                info!("Invalid merkleblock : {:?}", e);
+                self.report_misbehavior(Violation::InvalidBlock, ctx);
+                return;
+            },
+        };
+
+        // Peer does not send transactions which we already know, so a previous block may be incomplete.
+        if let Some(prev) = waiting.current.take() {
+            self.send_filtered_block_response(&waiting.addr, prev, ctx);
+        }
+
+        let pending = PendingFilteredBlock {
+            header: block.header,
+            matched,
+            txs: Vec::new(),
+        };
+        if pending.matched.is_empty() {
+            self.send_filtered_block_response(&waiting.addr, pending, ctx);
         } else {
+            waiting.current = Some(pending);
+        }
+
+        if !waiting.block_hashes.is_empty() || waiting.current.is_some() {
+            self.waiting_filtered_blocks = Some(waiting);
+        }
+    }
+
+    fn handle_tx_msg(&mut self, tx: Transaction, ctx: &mut Context<Self>)
+    {
+        if self.waiting_txs.remove(&tx.bitcoin_hash()) {
+            self.tx_subscribers
+                .retain(|addr| addr.do_send(PublishTx(tx.clone())).is_ok());
+            return;
+        }
+
+        let mut waiting = match self.waiting_filtered_blocks.take() {
+            None => {
+                debug!("Discard Tx msg");
+                return;
+            },
+            Some(waiting) => waiting,
+        };
+
+        let is_complete = match waiting.current.as_mut() {
+            None => {
+                debug!("Discard Tx msg");
+                false
+            },
+            Some(pending) => {
+                let txid = tx.bitcoin_hash();
+                if pending.matched.contains(&txid) {
+                    pending.txs.push(tx);
+                } else {
+                    debug!("Discard Tx msg which is not a part of filtered block");
+                }
+                pending.txs.len() == pending.matched.len()
+            },
+        };
+
+        if is_complete {
+            let pending = waiting.current.take().unwrap();
+            self.send_filtered_block_response(&waiting.addr, pending, ctx);
+        }
+
+        if !waiting.block_hashes.is_empty() || waiting.current.is_some() {
+            self.waiting_filtered_blocks = Some(waiting);
+        }
+    }
+
+    fn send_filtered_block_response(
+        &mut self,
+        addr: &Recipient<FilteredBlockResponse>,
+        block: PendingFilteredBlock,
+        ctx: &mut Context<Self>,
+    )
+    {
+        let res = FilteredBlockResponse {
+            header: block.header,
+            txs: block.txs,
+        };
+        let send_f = addr.send(res).timeout(SEND_TIMEOUT);
+        let f = send_f.into_actor(self).map_err(|e, _actor, _ctx| {
+            debug!("Fail to send msg : {:?}", e);
+        });
+        let _ = ctx.spawn(f);
+    }
+
+    fn handle_invs_msg(&mut self, invs: Vec<Inventory>, ctx: &mut Context<Self>)
+    {
+        let invs = self.take_block_invs_response(invs, ctx);
+        if invs.is_empty() {
+            return;
+        }
+
+        if !self.tx_subscribers.is_empty() {
+            self.request_announced_txs(&invs, ctx);
+        }
+
+        if self.inv_subscribers.is_empty() {
             debug!("Peer sends Inv message but no subscriber is set, so discard it.");
+            return;
+        }
+
+        // The first inventory of a batch schedules publishing.
+        if self.pending_invs.is_empty() {
+            ctx.run_later(self.inv_batch_interval, |actor, ctx| actor.publish_invs(ctx));
+        }
+        for inv in invs {
+            if self.pending_inv_hashes.insert(inv.hash) {
+                self.pending_invs.push(inv);
+            }
+        }
+    }
+
+    /// Request announced transactions which are not requested within `TX_DEDUP_WINDOW`.
+    fn request_announced_txs(&mut self, invs: &[Inventory], ctx: &mut Context<Self>)
+    {
+        let mut requests = Vec::new();
+        for inv in invs {
+            if inv.inv_type != InvType::Transaction || !self.recent_txids.insert(inv.hash) {
+                continue;
+            }
+            self.waiting_txs.insert(inv.hash);
+            requests.push(inv.clone());
+
+            let txid = inv.hash;
+            ctx.run_later(TX_DEDUP_WINDOW, move |actor, _ctx| {
+                actor.recent_txids.remove(&txid);
+                actor.waiting_txs.remove(&txid);
+            });
         }
+        if !requests.is_empty() {
+            self.send_p2p_msg(NetworkMessage::GetData(requests), ctx);
+        }
+    }
+
+    fn publish_invs(&mut self, ctx: &mut Context<Self>)
+    {
+        let invs: Vec<_> = self.pending_invs.drain(..).collect();
+        self.pending_inv_hashes.clear();
+        let conn = ctx.address();
+        self.inv_subscribers.retain(|&(ref addr, filter)| {
+            let matched: Vec<_> = invs.iter().filter(|inv| filter.matches(inv)).cloned().collect();
+            if matched.is_empty() {
+                return true;
+            }
+            let publish = PublishInv {
+                invs: matched,
+                conn: conn.clone(),
+            };
+            // Subscriber is dropped when it is already stopped.
+            addr.do_send(publish).is_ok()
+        });
     }
 
     fn handle_headers_msg(&mut self, headers: Vec<LoneBlockHeader>, ctx: &mut Context<Self>)
     {
+        self.stats.last_headers_msg = Some(Instant::now());
         let maybe_waiting_headers = self.waiting_headers.take();
         match maybe_waiting_headers {
-            None => {
+            None if self.headers_subscribers.is_empty() => {
                 info!("We don't wait headers but received.");
-                self.stop_misbehaving_connection(ctx);
+                self.report_misbehavior(Violation::UnsolicitedMessage, ctx);
+            },
+            None => {
+                // Peer announces new blocks.
+                let conn = ctx.address();
+                self.headers_subscribers.retain(|addr| {
+                    let publish = PublishHeaders {
+                        headers: headers.clone(),
+                        conn: conn.clone(),
+                    };
+                    addr.do_send(publish).is_ok()
+                });
             },
             Some(waiting_headers) => {
+                ctx.cancel_future(waiting_headers.timeout);
                 let f = waiting_headers
                     .addr
-                    .send(HeadersResponse(headers))
+                    .send(HeadersResponse::Headers(headers))
                     .map_err(|_e| ())
                     .into_actor(self);
                 ctx.wait(f);
+                self.send_queued_getheaders(ctx);
+            },
+        }
+    }
+
+    fn handle_getheaders_msg(&mut self, msg: GetHeadersMessage, ctx: &mut Context<Self>)
+    {
+        let headers = match self.header_source {
+            None => {
+                debug!("Peer requests headers but we don't serve them.");
+                return;
+            },
+            Some(ref blockchain) => headers_after_locator(&blockchain.lock().unwrap(), &msg),
+        };
+        self.send_p2p_response(NetworkMessage::Headers(headers), ctx);
+    }
+
+    fn send_getheaders(&mut self, req: GetHeadersRequest, ctx: &mut Context<Self>)
+    {
+        // Send GetHeaders message to peer
+        let getheaders = GetHeadersMessage::new(req.locator_hashes, Sha256dHash::default());
+        let msg = NetworkMessage::GetHeaders(getheaders);
+        self.send_p2p_msg(msg, ctx);
+
+        let timeout = ctx.run_later(self.request_timeout, |actor, ctx| actor.timeout_headers(ctx));
+        let waiting_headers = WaitingHeaders {
+            addr: req.addr,
+            timeout,
+        };
+        self.waiting_headers = Some(waiting_headers);
+    }
+
+    fn timeout_headers(&mut self, ctx: &mut Context<Self>)
+    {
+        if let Some(waiting_headers) = self.waiting_headers.take() {
+            info!("Peer does not respond headers in time");
+            let _ = waiting_headers.addr.do_send(HeadersResponse::Timeout);
+            self.send_queued_getheaders(ctx);
+        }
+    }
+
+    fn send_queued_getheaders(&mut self, ctx: &mut Context<Self>)
+    {
+        if let Some(req) = self.queued_headers_requests.pop_front() {
+            self.send_getheaders(req, ctx);
+        }
+    }
+
+    fn send_getblocks(&mut self, req: GetBlockInvsRequest, ctx: &mut Context<Self>)
+    {
+        let getblocks = GetBlocksMessage::new(req.locator_hashes, Sha256dHash::default());
+        self.send_p2p_msg(NetworkMessage::GetBlocks(getblocks), ctx);
+
+        let timeout = ctx.run_later(self.request_timeout, |actor, ctx| actor.timeout_block_invs(ctx));
+        self.waiting_block_invs = Some(WaitingBlockInvs {
+            addr: req.addr,
+            timeout,
+        });
+    }
+
+    /// If we wait a response of `getblocks`, hand block inventories of `invs` to the requester.
+    /// Returns the other inventories.
+    fn take_block_invs_response(&mut self, invs: Vec<Inventory>, ctx: &mut Context<Self>) -> Vec<Inventory>
+    {
+        let has_block = invs.iter().any(|inv| inv.inv_type == InvType::Block);
+        let waiting = match self.waiting_block_invs.take() {
+            Some(waiting) if has_block => waiting,
+            other => {
+                self.waiting_block_invs = other;
+                return invs;
             },
+        };
+        ctx.cancel_future(waiting.timeout);
+        let (blocks, others): (Vec<_>, Vec<_>) = invs.into_iter().partition(|inv| inv.inv_type == InvType::Block);
+        let hashes = blocks.into_iter().map(|inv| inv.hash).collect();
+        let _ = waiting.addr.do_send(BlockInvsResponse::Invs(hashes));
+        self.send_queued_getblocks(ctx);
+        others
+    }
+
+    fn timeout_block_invs(&mut self, ctx: &mut Context<Self>)
+    {
+        if let Some(waiting) = self.waiting_block_invs.take() {
+            info!("Peer does not respond inv of blocks in time");
+            let _ = waiting.addr.do_send(BlockInvsResponse::Timeout);
+            self.send_queued_getblocks(ctx);
+        }
+    }
+
+    fn send_queued_getblocks(&mut self, ctx: &mut Context<Self>)
+    {
+        if let Some(req) = self.queued_block_invs_requests.pop_front() {
+            self.send_getblocks(req, ctx);
         }
     }
 
@@ -287,6 +1462,129 @@ impl Connection
         let pong = NetworkMessage::Pong(nonce);
         self.send_p2p_msg(pong, ctx);
     }
+
+    fn send_ping(&mut self, ctx: &mut Context<Self>)
+    {
+        // The outstanding `ping` is checked by its own timeout.
+        if self.waiting_pong.is_some() {
+            return;
+        }
+        let nonce = random();
+        self.waiting_pong = Some((nonce, Instant::now()));
+        self.send_p2p_msg(NetworkMessage::Ping(nonce), ctx);
+
+        ctx.run_later(self.ping_timeout, move |actor, ctx| {
+            if let Some((expected, _)) = actor.waiting_pong {
+                if expected == nonce {
+                    info!("Peer does not respond ping in time. Close connection");
+                    ctx.stop();
+                }
+            }
+        });
+    }
+
+    fn handle_pong_msg(&mut self, nonce: u64, ctx: &mut Context<Self>)
+    {
+        match self.waiting_pong {
+            Some((expected, sent_at)) if expected == nonce => {
+                self.waiting_pong = None;
+                if self.ping_samples.len() == NUM_PING_SAMPLES {
+                    self.ping_samples.pop_front();
+                }
+                self.ping_samples.push_back(sent_at.elapsed());
+            },
+            _ => {
+                info!("Peer sends pong which does not match our ping.");
+                self.report_misbehavior(Violation::UnsolicitedMessage, ctx);
+            },
+        }
+    }
+
+    /// Send requested blocks and transactions which we have,
+    /// and then a single `notfound` message for the rest of them.
+    fn handle_getdata_msg(&mut self, invs: Vec<Inventory>, ctx: &mut Context<Self>)
+    {
+        let mut not_found = Vec::new();
+        for inv in invs {
+            let is_served = match inv.inv_type {
+                InvType::Transaction => self.serve_tx(inv.hash, ctx),
+                InvType::Block => self.serve_block(&inv.hash, ctx),
+                _ => false,
+            };
+            if !is_served {
+                debug!("Respond notfound to GetData of {:?}", inv.inv_type);
+                not_found.push(inv);
+            }
+        }
+        if !not_found.is_empty() {
+            self.send_p2p_response(NetworkMessage::NotFound(not_found), ctx);
+        }
+    }
+
+    /// Send a transaction which we are broadcasting. Returns false if we do not serve it.
+    fn serve_tx(&mut self, txid: Sha256dHash, ctx: &mut Context<Self>) -> bool
+    {
+        let tx = match self.broadcasting_txs.get_mut(&txid) {
+            // We only serve transactions which we are broadcasting.
+            None => return false,
+            Some(broadcasting) => {
+                if broadcasting.requested {
+                    return false;
+                }
+                broadcasting.requested = true;
+                broadcasting.tx.clone()
+            },
+        };
+        self.send_p2p_msg(NetworkMessage::Tx(tx), ctx);
+
+        // If peer does not reject it in a while, we regard it as accepted.
+        ctx.run_later(REJECT_WINDOW, move |actor, ctx| {
+            if let Some(broadcasting) = actor.broadcasting_txs.remove(&txid) {
+                actor.send_broadcast_result(&broadcasting.addr, BroadcastResult::Accepted(txid), ctx);
+            }
+        });
+        true
+    }
+
+    /// Send a block of our block source. Returns false if we do not have it.
+    fn serve_block(&mut self, hash: &Sha256dHash, ctx: &mut Context<Self>) -> bool
+    {
+        let block = self.block_source.as_ref().and_then(|source| source.get_block(hash));
+        match block {
+            None => false,
+            Some(block) => {
+                // Even if it is dropped, peer can request it again.
+                self.send_p2p_response(NetworkMessage::Block(block), ctx);
+                true
+            },
+        }
+    }
+
+    fn handle_reject_msg(&mut self, reject: Reject, ctx: &mut Context<Self>)
+    {
+        info!("Peer rejects {} message : {}", reject.message, reject.reason);
+        let txid = match reject.data {
+            Some(hash) if reject.message == "tx" => hash,
+            _ => return,
+        };
+        if let Some(broadcasting) = self.broadcasting_txs.remove(&txid) {
+            self.send_broadcast_result(&broadcasting.addr, BroadcastResult::Rejected(txid, reject), ctx);
+        }
+    }
+
+    fn send_broadcast_result(
+        &mut self,
+        addr: &Recipient<BroadcastResult>,
+        res: BroadcastResult,
+        ctx: &mut Context<Self>,
+    )
+    {
+        let send_f = addr.send(res).timeout(SEND_TIMEOUT);
+        let f = send_f.into_actor(self).map_err(|e, _actor, _ctx| {
+            debug!("Fail to send msg : {:?}", e);
+        });
+        let _ = ctx.spawn(f);
+    }
 }
 
 /* Handle GetBlocksRequest */
@@ -297,65 +1595,358 @@ impl Handler<GetBlocksRequest> for Connection
 
     fn handle(&mut self, req: GetBlocksRequest, ctx: &mut Context<Connection>)
     {
-        if self.waiting_blocks.is_some() {
-            info!("Can not request GetBlockRequest in parallel. A new request is dropped.");
+        if req.block_hashes.is_empty() {
+            return;
+        }
+        if self.waiting_blocks.is_empty() {
+            self.last_block_progress = Instant::now();
+        }
+
+        // Blocks which are already requested by another request are not requested again.
+        let new_hashes: Vec<_> = req.block_hashes
+            .iter()
+            .filter(|hash| !self.waiting_blocks.contains_key(*hash))
+            .cloned()
+            .collect();
+        let id = self.next_block_request_id;
+        self.next_block_request_id += 1;
+        for hash in req.block_hashes.iter() {
+            self.waiting_blocks.entry(*hash).or_insert_with(Vec::new).push(id);
+        }
+        let timeout = ctx.run_later(self.request_timeout, move |actor, ctx| actor.timeout_blocks(id, ctx));
+        let waiting_blocks = WaitingBlocks {
+            addr: req.addr,
+            block_hashes: req.block_hashes,
+            timeout,
+        };
+        self.block_requests.insert(id, waiting_blocks);
+
+        if new_hashes.is_empty() {
+            return;
+        }
+
+        // Send GetData message to peer.
+        let msg = getdata_blocks_msg(new_hashes, self.requests_witness_blocks(), self.compact_blocks);
+        self.send_p2p_msg(msg, ctx);
+    }
+}
+
+/* Handle GetFilteredBlocksRequest */
+
+impl Handler<GetFilteredBlocksRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetFilteredBlocksRequest, ctx: &mut Context<Self>)
+    {
+        if self.waiting_filtered_blocks.is_some() {
+            info!("Can not request GetFilteredBlocksRequest in parallel. A new request is dropped.");
             return;
         }
 
-        // Send Inv message to peer
         let invs: Vec<_> = req.block_hashes
             .iter()
             .map(|hash| {
-                Inventory {
-                    inv_type: InvType::Block,
+                RawInventory {
+                    inv_type: MSG_FILTERED_BLOCK,
                     hash: *hash,
                 }
             })
             .collect();
-        let msg = NetworkMessage::GetData(invs);
-        self.send_p2p_msg(msg, ctx);
+        self.send_p2p_msg(Message::GetDataRaw(invs), ctx);
 
-        let waiting_blocks = WaitingBlocks {
+        let waiting = WaitingFilteredBlocks {
             addr: req.addr,
             block_hashes: req.block_hashes,
+            current: None,
         };
-        self.waiting_blocks = Some(waiting_blocks);
+        self.waiting_filtered_blocks = Some(waiting);
     }
 }
 
-/* Handle GetHeadersRequest */
+/* Handle BroadcastTx */
 
-impl Handler<GetHeadersRequest> for Connection
+impl Handler<BroadcastTx> for Connection
 {
     type Result = ();
 
-    fn handle(&mut self, req: GetHeadersRequest, ctx: &mut Context<Self>)
+    fn handle(&mut self, req: BroadcastTx, ctx: &mut Context<Self>)
     {
-        if self.waiting_headers.is_some() {
-            info!("Can not request GetHeadersRequest in parallel. A new request is dropped.");
+        let txid = req.tx.bitcoin_hash();
+        if self.broadcasting_txs.contains_key(&txid) {
+            info!("Transaction {} is already being broadcasted. A new request is dropped.", txid);
             return;
         }
 
-        // Send GetHeaders message to peer
-        let getheaders = GetHeadersMessage::new(req.locator_hashes, Sha256dHash::default());
-        let msg = NetworkMessage::GetHeaders(getheaders);
-        self.send_p2p_msg(msg, ctx);
+        let inv = Inventory {
+            inv_type: InvType::Transaction,
+            hash: txid,
+        };
+        if !self.send_p2p_response(NetworkMessage::Inv(vec![inv]), ctx) {
+            self.send_broadcast_result(&req.addr, BroadcastResult::QueueFull(txid), ctx);
+            return;
+        }
 
-        let waiting_headers = WaitingHeaders { addr: req.addr };
-        self.waiting_headers = Some(waiting_headers);
+        let broadcasting = BroadcastingTx {
+            tx: req.tx,
+            addr: req.addr,
+            requested: false,
+        };
+        self.broadcasting_txs.insert(txid, broadcasting);
+
+        ctx.run_later(BROADCAST_TIMEOUT, move |actor, ctx| {
+            let is_ignored = actor.broadcasting_txs.get(&txid).map(|b| !b.requested).unwrap_or(false);
+            if is_ignored {
+                let broadcasting = actor.broadcasting_txs.remove(&txid).unwrap();
+                actor.send_broadcast_result(&broadcasting.addr, BroadcastResult::Ignored(txid), ctx);
+            }
+        });
     }
 }
 
-/* Handle GetAddrsRequest */
+/* Handle DownloadWitnessBlocks */
 
-impl Handler<GetAddrsRequest> for Connection
+impl Handler<DownloadWitnessBlocks> for Connection
 {
     type Result = ();
 
-    fn handle(&mut self, req: GetAddrsRequest, ctx: &mut Context<Self>)
+    fn handle(&mut self, msg: DownloadWitnessBlocks, _ctx: &mut Context<Self>)
     {
-        if self.waiting_addrs.is_some() {
-            info!("Can not request GetAddrsRequest in parallel. A new request is dropped.");
+        self.witness_blocks = msg.0;
+    }
+}
+
+/* Handle misbehavior messages */
+
+impl Handler<ReportMisbehavior> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: ReportMisbehavior, ctx: &mut Context<Self>)
+    {
+        self.report_misbehavior(msg.0, ctx);
+    }
+}
+
+impl Handler<SetMisbehaviorPolicy> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SetMisbehaviorPolicy, _ctx: &mut Context<Self>)
+    {
+        self.misbehavior_policy = msg.policy;
+        self.ban_addr = Some(msg.ban);
+    }
+}
+
+/* Handle configuration and subscription messages */
+
+impl Handler<SetEventSink> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SetEventSink, _ctx: &mut Context<Self>)
+    {
+        self.events = msg.0;
+    }
+}
+
+impl Handler<SetHeaderSource> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SetHeaderSource, _ctx: &mut Context<Self>)
+    {
+        self.header_source = Some(msg.0);
+    }
+}
+
+impl Handler<SetBlockSource> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SetBlockSource, _ctx: &mut Context<Self>)
+    {
+        self.block_source = Some(msg.0);
+    }
+}
+
+impl Handler<SetSendQueueSize> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SetSendQueueSize, _ctx: &mut Context<Self>)
+    {
+        self.send_queue_size = msg.0;
+    }
+}
+
+impl Handler<SetRateLimits> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SetRateLimits, _ctx: &mut Context<Self>)
+    {
+        self.rate_limiter.set_limits(msg.0, Instant::now());
+    }
+}
+
+impl Handler<SubscribeInv> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeInv, _ctx: &mut Context<Self>)
+    {
+        self.inv_subscribers.push((msg.addr, msg.filter));
+    }
+}
+
+impl Handler<SubscribeTx> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeTx, _ctx: &mut Context<Self>)
+    {
+        self.tx_subscribers.push(msg.addr);
+    }
+}
+
+impl Handler<SubscribeAddrs> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeAddrs, _ctx: &mut Context<Self>)
+    {
+        self.addr_subscribers.push(msg.addr);
+    }
+}
+
+impl Handler<SetAddrSource> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SetAddrSource, _ctx: &mut Context<Self>)
+    {
+        self.addr_source = Some(msg.0);
+    }
+}
+
+impl Handler<SubscribeHeaders> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeHeaders, _ctx: &mut Context<Self>)
+    {
+        self.headers_subscribers.push(msg.addr);
+    }
+}
+
+impl Handler<SetPingInterval> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SetPingInterval, ctx: &mut Context<Self>)
+    {
+        ctx.cancel_future(self.ping_interval_handle);
+        self.ping_interval_handle = ctx.run_interval(msg.interval, |actor, ctx| actor.send_ping(ctx));
+        self.ping_timeout = msg.timeout;
+    }
+}
+
+impl Handler<SetInvBatchInterval> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SetInvBatchInterval, _ctx: &mut Context<Self>)
+    {
+        self.inv_batch_interval = msg.0;
+    }
+}
+
+impl Handler<SetRequestTimeout> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SetRequestTimeout, _ctx: &mut Context<Self>)
+    {
+        self.request_timeout = msg.0;
+    }
+}
+
+/* Handle bloom filter messages */
+
+impl Handler<LoadBloomFilter> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: LoadBloomFilter, ctx: &mut Context<Self>)
+    {
+        self.send_p2p_msg(Message::FilterLoad(msg.0), ctx);
+    }
+}
+
+impl Handler<AddToBloomFilter> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: AddToBloomFilter, ctx: &mut Context<Self>)
+    {
+        self.send_p2p_msg(Message::FilterAdd(msg.0), ctx);
+    }
+}
+
+impl Handler<ClearBloomFilter> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, _msg: ClearBloomFilter, ctx: &mut Context<Self>)
+    {
+        self.send_p2p_msg(Message::FilterClear, ctx);
+    }
+}
+
+/* Handle GetHeadersRequest */
+
+impl Handler<GetHeadersRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetHeadersRequest, ctx: &mut Context<Self>)
+    {
+        if self.waiting_headers.is_some() {
+            self.queued_headers_requests.push_back(req);
+            return;
+        }
+        self.send_getheaders(req, ctx);
+    }
+}
+
+/* Handle GetBlockInvsRequest */
+
+impl Handler<GetBlockInvsRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetBlockInvsRequest, ctx: &mut Context<Self>)
+    {
+        if self.waiting_block_invs.is_some() {
+            self.queued_block_invs_requests.push_back(req);
+            return;
+        }
+        self.send_getblocks(req, ctx);
+    }
+}
+
+/* Handle GetAddrsRequest */
+
+impl Handler<GetAddrsRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetAddrsRequest, ctx: &mut Context<Self>)
+    {
+        if self.waiting_addrs.is_some() {
+            info!("Can not request GetAddrsRequest in parallel. A new request is dropped.");
         }
 
         let msg = NetworkMessage::GetAddr;
@@ -364,3 +1955,1226 @@ impl Handler<GetAddrsRequest> for Connection
         self.waiting_addrs = Some(req.addr);
     }
 }
+
+/// `getdata` message which requests `hashes` as witness blocks, compact blocks or legacy blocks.
+/// Compact blocks do not carry witness data, so full witness blocks are preferred.
+fn getdata_blocks_msg(hashes: Vec<Sha256dHash>, witness: bool, compact: bool) -> Message
+{
+    let raw_inv_type = if witness {
+        MSG_WITNESS_BLOCK
+    } else if compact {
+        MSG_CMPCT_BLOCK
+    } else {
+        let invs = hashes
+            .into_iter()
+            .map(|hash| {
+                Inventory {
+                    inv_type: InvType::Block,
+                    hash,
+                }
+            })
+            .collect();
+        return NetworkMessage::GetData(invs).into();
+    };
+    let invs = hashes
+        .into_iter()
+        .map(|hash| {
+            RawInventory {
+                inv_type: raw_inv_type,
+                hash,
+            }
+        })
+        .collect();
+    Message::GetDataRaw(invs)
+}
+
+/// Headers of active chain after the first locator hash which we know, up to `MAX_HEADERS_IN_MSG`.
+/// If we know none of them, headers after our start block are returned.
+fn headers_after_locator(blockchain: &BlockChain, msg: &GetHeadersMessage) -> Vec<LoneBlockHeader>
+{
+    let active_chain = blockchain.active_chain();
+    let fork_height = msg.locator_hashes
+        .iter()
+        .filter_map(|hash| active_chain.height_of(hash))
+        .next()
+        .unwrap_or_else(|| active_chain.iter().next().unwrap().height());
+
+    let mut headers = Vec::new();
+    for block in active_chain.iter_from(fork_height + 1).take(MAX_HEADERS_IN_MSG) {
+        headers.push(LoneBlockHeader {
+            header: block.header,
+            tx_count: VarInt(0),
+        });
+        if block.bitcoin_hash() == msg.stop_hash {
+            break;
+        }
+    }
+    headers
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::{cell::{Cell, RefCell}, rc::Rc};
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::{constants::Network, serialize::serialize};
+    use futures::{future::{self, Loop}, sync::oneshot};
+
+    use connection::rate_limit::RateLimit;
+    use connection::socket::{begin_handshake_on, HandshakeConfig, Socket};
+    use testing::{duplex, duplex_with_capacity, dummy_addrs, MemoryStream, ScriptedPeer};
+
+    // Handshake with a scripted peer over `stream`, then start `Connection` actor.
+    fn start_connection(stream: MemoryStream) -> impl Future<Item = Addr<Connection>, Error = ()>
+    {
+        let (local_addr, peer_addr) = dummy_addrs();
+        let socket = Socket::new(stream, Network::Bitcoin);
+        begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr)
+            .map(|socket| Connection::start_actor(socket))
+            .map_err(|e| panic!("Fail to handshake : {:?}", e))
+    }
+
+    #[test]
+    fn disconnect_completes_on_the_same_arbiter()
+    {
+        let (local, remote) = duplex();
+        let disconnected = Rc::new(Cell::new(false));
+        let peer_closed = Rc::new(Cell::new(false));
+        let (disconnected2, peer_closed2) = (disconnected.clone(), peer_closed.clone());
+
+        // Stop the system when both of `Disconnect` response and EOF are observed.
+        let (d, p) = (disconnected.clone(), peer_closed.clone());
+        let stop_if_done = Rc::new(move || {
+            if d.get() && p.get() {
+                System::current().stop();
+            }
+        });
+        let stop_if_done2 = stop_if_done.clone();
+
+        System::run(move || {
+            // Peer finishes serving when `Connection` shuts down the stream.
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .run_and_serve(|_msg| Vec::new())
+                .map(move |()| {
+                    peer_closed2.set(true);
+                    stop_if_done();
+                })
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let f = start_connection(local).and_then(move |conn| {
+                conn.send(Disconnect())
+                    .map(move |()| {
+                        disconnected2.set(true);
+                        stop_if_done2();
+                    })
+                    .map_err(|e| panic!("Fail to send Disconnect : {:?}", e))
+            });
+            Arbiter::spawn(f);
+        });
+
+        assert!(disconnected.get());
+        assert!(peer_closed.get());
+    }
+
+    // Collect `num` messages, then stop the system.
+    struct Collector<M>
+    {
+        results: Rc<RefCell<Vec<M>>>,
+        num: usize,
+    }
+
+    impl<M: 'static> Actor for Collector<M>
+    {
+        type Context = Context<Self>;
+    }
+
+    impl<M> Handler<M> for Collector<M>
+    where M: ::actix::Message<Result = ()> + 'static
+    {
+        type Result = ();
+
+        fn handle(&mut self, msg: M, _ctx: &mut Context<Self>)
+        {
+            let mut results = self.results.borrow_mut();
+            results.push(msg);
+            if results.len() == self.num {
+                System::current().stop();
+            }
+        }
+    }
+
+    #[test]
+    fn broadcast_tx_reports_reject()
+    {
+        let tx = genesis_block(Network::Bitcoin).txdata[0].clone();
+        let txid = tx.bitcoin_hash();
+        let reject = Reject {
+            message: "tx".into(),
+            ccode: 0x10,
+            reason: "bad-txns-inputs-missingorspent".into(),
+            data: Some(txid),
+        };
+        let getdata = NetworkMessage::GetData(vec![
+            Inventory {
+                inv_type: InvType::Transaction,
+                hash: txid,
+            },
+        ]);
+
+        let (local, remote) = duplex();
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let results2 = results.clone();
+        let expected_reject = reject.clone();
+
+        System::run(move || {
+            // Peer requests the transaction, and then rejects it.
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("inv")
+                .send(getdata)
+                .expect("tx")
+                .send(Message::Reject(reject))
+                .run_and_serve(|_msg| Vec::new())
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let collector = Collector {
+                results: results2,
+                num: 1,
+            }.start();
+            let f = start_connection(local).map(move |conn| {
+                let req = BroadcastTx {
+                    tx,
+                    addr: collector.recipient(),
+                };
+                conn.do_send(req);
+            });
+            Arbiter::spawn(f);
+        });
+
+        let results = results.borrow();
+        assert_eq!(results.len(), 1);
+        match results[0] {
+            BroadcastResult::Rejected(hash, ref reject) => {
+                assert_eq!(hash, txid);
+                assert_eq!(*reject, expected_reject);
+            },
+            ref other => panic!("Unexpected result : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn inv_subscribers_receive_filtered_batches()
+    {
+        let block_inv = |block: Block| {
+            Inventory {
+                inv_type: InvType::Block,
+                hash: block.bitcoin_hash(),
+            }
+        };
+        let block1 = block_inv(genesis_block(Network::Bitcoin));
+        let block2 = block_inv(genesis_block(Network::Testnet));
+        let tx = Inventory {
+            inv_type: InvType::Transaction,
+            hash: genesis_block(Network::Bitcoin).txdata[0].bitcoin_hash(),
+        };
+
+        let (local, remote) = duplex();
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let results2 = results.clone();
+        let (b1, b2, t) = (block1.clone(), block2.clone(), tx.clone());
+
+        System::run(move || {
+            // Peer announces duplicated inventories after both subscribers are registered.
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("filterclear")
+                .send(NetworkMessage::Inv(vec![b1.clone(), t.clone()]))
+                .send(NetworkMessage::Inv(vec![b1, b2]))
+                .send(NetworkMessage::Inv(vec![t]))
+                .run_and_serve(|_msg| Vec::new())
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            // Both collectors share results, so the system stops after two batches in total.
+            let new_collector = || {
+                Collector {
+                    results: results2.clone(),
+                    num: 2,
+                }.start()
+            };
+            let (blocks_collector, txs_collector) = (new_collector(), new_collector());
+            let f = start_connection(local).map(move |conn| {
+                conn.do_send(SubscribeInv {
+                    addr: blocks_collector.recipient(),
+                    filter: InvFilter::Block,
+                });
+                conn.do_send(SubscribeInv {
+                    addr: txs_collector.recipient(),
+                    filter: InvFilter::Tx,
+                });
+                conn.do_send(ClearBloomFilter);
+            });
+            Arbiter::spawn(f);
+        });
+
+        let results = results.borrow();
+        let batches: Vec<_> = results.iter().map(|res: &PublishInv| res.invs.clone()).collect();
+        assert_eq!(batches.len(), 2);
+        assert!(batches.contains(&vec![block1, block2]));
+        assert!(batches.contains(&vec![tx]));
+    }
+
+    #[test]
+    fn get_blocks_tolerates_interleaved_messages()
+    {
+        let block1 = genesis_block(Network::Bitcoin);
+        let block2 = genesis_block(Network::Testnet);
+        let hashes = vec![block1.bitcoin_hash(), block2.bitcoin_hash()];
+        let inv = Inventory {
+            inv_type: InvType::Block,
+            hash: Sha256dHash::default(),
+        };
+
+        let (local, remote) = duplex();
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let results2 = results.clone();
+        let block_hashes = hashes.clone();
+
+        System::run(move || {
+            // Peer announces a new block and gossips addresses between requested blocks.
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("getdata")
+                .send(NetworkMessage::Block(block1))
+                .send(NetworkMessage::Inv(vec![inv]))
+                .send(NetworkMessage::Addr(Vec::new()))
+                .send(NetworkMessage::Ping(42))
+                .send(NetworkMessage::Block(block2))
+                .run_and_serve(|_msg| Vec::new())
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let collector = Collector {
+                results: results2,
+                num: 2,
+            }.start();
+            let f = start_connection(local).map(move |conn| {
+                let req = GetBlocksRequest {
+                    block_hashes,
+                    addr: collector.recipient(),
+                };
+                conn.do_send(req);
+            });
+            Arbiter::spawn(f);
+        });
+
+        let found: Vec<_> = results
+            .borrow()
+            .iter()
+            .map(|res| match *res {
+                BlockResponse::Found(ref block) => block.bitcoin_hash(),
+                BlockResponse::NotFound(hash) => panic!("Block {} is not found", hash),
+                BlockResponse::Timeout(hash) => panic!("Block {} is timed out", hash),
+            })
+            .collect();
+        assert_eq!(found, hashes);
+    }
+
+    #[test]
+    fn deliver_found_blocks_and_report_notfound_one()
+    {
+        let mut modified = genesis_block(Network::Bitcoin);
+        modified.header.nonce += 1;
+        let blocks = vec![genesis_block(Network::Bitcoin), genesis_block(Network::Testnet), modified];
+        let hashes: Vec<_> = blocks.iter().map(|b| b.bitcoin_hash()).collect();
+        let missing = Inventory {
+            inv_type: InvType::Block,
+            hash: hashes[1],
+        };
+
+        let (local, remote) = duplex();
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let results2 = results.clone();
+        let block_hashes = hashes.clone();
+
+        System::run(move || {
+            // Peer does not have the second block.
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("getdata")
+                .send(NetworkMessage::Block(blocks[0].clone()))
+                .send(NetworkMessage::NotFound(vec![missing]))
+                .send(NetworkMessage::Block(blocks[2].clone()))
+                .run_and_serve(|_msg| Vec::new())
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let collector = Collector {
+                results: results2,
+                num: 3,
+            }.start();
+            let f = start_connection(local).map(move |conn| {
+                conn.do_send(GetBlocksRequest {
+                    block_hashes,
+                    addr: collector.recipient(),
+                });
+            });
+            Arbiter::spawn(f);
+        });
+
+        let mut found = Vec::new();
+        let mut not_found = Vec::new();
+        for res in results.borrow().iter() {
+            match *res {
+                BlockResponse::Found(ref block) => found.push(block.bitcoin_hash()),
+                BlockResponse::NotFound(hash) => not_found.push(hash),
+                BlockResponse::Timeout(hash) => panic!("Block {} is timed out", hash),
+            }
+        }
+        assert_eq!(found, vec![hashes[0], hashes[2]]);
+        assert_eq!(not_found, vec![hashes[1]]);
+    }
+
+    #[test]
+    fn stream_handler_updates_stats()
+    {
+        let block = genesis_block(Network::Bitcoin);
+        let block_hash = block.bitcoin_hash();
+        // Each message has a 24 bytes header, and `ping` has an 8 bytes nonce.
+        let expected_bytes = (24 + serialize(&block).unwrap().len() + 24 + 8) as u64;
+
+        let (local, remote) = duplex();
+        let stats = Rc::new(Cell::new(None));
+        let stats2 = stats.clone();
+
+        System::run(move || {
+            // When peer receives `pong`, `Connection` has already handled `block` and `ping`.
+            let (tx, rx) = oneshot::channel();
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("getdata")
+                .send(NetworkMessage::Block(block))
+                .send(NetworkMessage::Ping(42))
+                .expect("pong")
+                .run()
+                .map(move |socket| {
+                    let _ = tx.send(socket);
+                })
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let collector = Collector {
+                results: Rc::new(RefCell::new(Vec::new())),
+                num: 2,
+            }.start();
+            let f = start_connection(local)
+                .and_then(move |conn| {
+                    let req = GetBlocksRequest {
+                        block_hashes: vec![block_hash],
+                        addr: collector.recipient(),
+                    };
+                    conn.do_send(req);
+                    rx.map_err(|e| panic!("Peer is dropped : {:?}", e))
+                        .and_then(move |socket| {
+                            // Keep the socket open until we get stats.
+                            conn.send(GetConnectionStats).map(move |stats| (stats, socket))
+                        })
+                        .map_err(|e| panic!("Fail to get stats : {:?}", e))
+                })
+                .map(move |(stats, _socket)| {
+                    stats2.set(Some(stats));
+                    System::current().stop();
+                });
+            Arbiter::spawn(f);
+        });
+
+        let stats = stats.get().unwrap();
+        assert_eq!(stats.messages_received, 2);
+        assert_eq!(stats.bytes_received, expected_bytes);
+        assert_eq!(stats.blocks_served, 1);
+        assert!(stats.last_block_msg.is_some());
+        assert_eq!(stats.last_headers_msg, None);
+        assert_eq!(stats.blocks_waiting_since, None);
+    }
+
+    #[test]
+    fn detect_stalled_peer()
+    {
+        let now = Instant::now();
+        let timeout = Duration::from_secs(30);
+        let mut stats = ConnectionStats::default();
+        assert!(!stats.is_stalled(now, timeout));
+
+        stats.blocks_waiting_since = Some(now);
+        assert!(!stats.is_stalled(now + Duration::from_secs(29), timeout));
+        assert!(stats.is_stalled(now + Duration::from_secs(30), timeout));
+    }
+
+    #[test]
+    fn ban_peer_only_after_repeated_violations()
+    {
+        let (local, remote) = duplex();
+        let bans = Rc::new(RefCell::new(Vec::new()));
+        let bans2 = bans.clone();
+        let stats = Rc::new(RefCell::new(None));
+        let stats2 = stats.clone();
+        let conn_cell = Rc::new(RefCell::new(None));
+        let conn_cell2 = conn_cell.clone();
+
+        System::run(move || {
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .run_and_serve(|_msg| Vec::new())
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let collector = Collector {
+                results: bans2,
+                num: 1,
+            }.start();
+            let f = start_connection(local).and_then(move |conn| {
+                *conn_cell2.borrow_mut() = Some(conn.clone());
+                conn.do_send(SetMisbehaviorPolicy {
+                    policy: MisbehaviorPolicy::default(),
+                    ban: collector.recipient(),
+                });
+
+                // A benign violation keeps connection alive.
+                conn.do_send(ReportMisbehavior(Violation::UnsolicitedMessage));
+                conn.send(GetConnectionStats)
+                    .map(move |s| {
+                        *stats2.borrow_mut() = Some(s);
+                        for _ in 0..5 {
+                            conn.do_send(ReportMisbehavior(Violation::InvalidHeader));
+                        }
+                    })
+                    .map_err(|e| panic!("Fail to get stats : {:?}", e))
+            });
+            Arbiter::spawn(f);
+        });
+
+        let stats = stats.borrow().unwrap();
+        assert_eq!(stats.misbehavior_score, 1);
+        assert_eq!(stats.violations.unsolicited_message, 1);
+
+        let bans: &Vec<BanConnection> = &bans.borrow();
+        assert_eq!(bans.len(), 1);
+        assert!(Some(&bans[0].conn) == conn_cell.borrow().as_ref());
+    }
+
+    #[test]
+    fn notfound_of_unrequested_block_is_misbehavior()
+    {
+        let requested = genesis_block(Network::Bitcoin).bitcoin_hash();
+        let unrequested = genesis_block(Network::Testnet).bitcoin_hash();
+        let invs = vec![requested, unrequested]
+            .into_iter()
+            .map(|hash| {
+                Inventory {
+                    inv_type: InvType::Block,
+                    hash,
+                }
+            })
+            .collect();
+
+        let (local, remote) = duplex();
+        let bans = Rc::new(RefCell::new(Vec::new()));
+        let bans2 = bans.clone();
+
+        System::run(move || {
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("getdata")
+                .send(NetworkMessage::NotFound(invs))
+                .run_and_serve(|_msg| Vec::new())
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let ban_collector = Collector {
+                results: bans2,
+                num: 1,
+            }.start();
+            // Never stops the system by itself.
+            let block_collector: Addr<Collector<BlockResponse>> = Collector {
+                results: Rc::new(RefCell::new(Vec::new())),
+                num: 2,
+            }.start();
+            let f = start_connection(local).map(move |conn| {
+                // A single unsolicited message is enough to ban.
+                let policy = MisbehaviorPolicy {
+                    unsolicited_message: 100,
+                    ..MisbehaviorPolicy::default()
+                };
+                conn.do_send(SetMisbehaviorPolicy {
+                    policy,
+                    ban: ban_collector.recipient(),
+                });
+                conn.do_send(GetBlocksRequest {
+                    block_hashes: vec![requested],
+                    addr: block_collector.recipient(),
+                });
+            });
+            Arbiter::spawn(f);
+        });
+
+        assert_eq!(bans.borrow().len(), 1);
+    }
+
+    #[test]
+    fn notify_requester_when_peer_does_not_respond_blocks()
+    {
+        let hashes = vec![
+            genesis_block(Network::Bitcoin).bitcoin_hash(),
+            genesis_block(Network::Testnet).bitcoin_hash(),
+        ];
+
+        let (local, remote) = duplex();
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let results2 = results.clone();
+        let block_hashes = hashes.clone();
+
+        System::run(move || {
+            // Peer receives `getdata` but stays silent.
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .run_and_serve(|_msg| Vec::new())
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let collector = Collector {
+                results: results2,
+                num: 2,
+            }.start();
+            let f = start_connection(local).map(move |conn| {
+                conn.do_send(SetRequestTimeout(Duration::from_millis(100)));
+                conn.do_send(GetBlocksRequest {
+                    block_hashes,
+                    addr: collector.recipient(),
+                });
+            });
+            Arbiter::spawn(f);
+        });
+
+        let timed_out: Vec<_> = results
+            .borrow()
+            .iter()
+            .map(|res| match *res {
+                BlockResponse::Timeout(hash) => hash,
+                _ => panic!("Peer should not respond anything"),
+            })
+            .collect();
+        assert_eq!(timed_out, hashes);
+    }
+
+    #[test]
+    fn forget_compact_block_when_its_request_times_out()
+    {
+        let genesis = genesis_block(Network::Bitcoin);
+        let coinbase = genesis.txdata[0].clone();
+        let mut cmpct = HeaderAndShortIds {
+            header: genesis.header,
+            nonce: 42,
+            short_ids: Vec::new(),
+            prefilled_txs: Vec::new(),
+        };
+        cmpct.short_ids.push(cmpct.short_id(&coinbase.bitcoin_hash()));
+        let txs = BlockTransactions {
+            block_hash: genesis.bitcoin_hash(),
+            txs: vec![coinbase],
+        };
+
+        let (local, remote) = duplex();
+        let (bans, results) = (Rc::new(RefCell::new(Vec::new())), Rc::new(RefCell::new(Vec::new())));
+        let (bans2, results2) = (bans.clone(), results.clone());
+
+        System::run(move || {
+            // Peer answers `getblocktxn` only after the request times out.
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("getdata")
+                .send(Message::CmpctBlock(cmpct))
+                .expect("getblocktxn")
+                .wait(Duration::from_millis(300))
+                .send(Message::BlockTxn(txs))
+                .run_and_serve(|_msg| Vec::new())
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let ban_collector = Collector {
+                results: bans2,
+                num: 1,
+            }.start();
+            // Never stops the system by itself.
+            let block_collector: Addr<Collector<BlockResponse>> = Collector {
+                results: results2,
+                num: 2,
+            }.start();
+            let f = start_connection(local).map(move |conn| {
+                // `blocktxn` of a forgotten compact block is unsolicited, and bans peer at once.
+                let policy = MisbehaviorPolicy {
+                    unsolicited_message: 100,
+                    ..MisbehaviorPolicy::default()
+                };
+                conn.do_send(SetMisbehaviorPolicy {
+                    policy,
+                    ban: ban_collector.recipient(),
+                });
+                conn.do_send(SetRequestTimeout(Duration::from_millis(100)));
+                conn.do_send(GetBlocksRequest {
+                    block_hashes: vec![genesis.bitcoin_hash()],
+                    addr: block_collector.recipient(),
+                });
+            });
+            Arbiter::spawn(f);
+        });
+
+        assert_eq!(bans.borrow().len(), 1);
+        let timed_out: Vec<_> = results
+            .borrow()
+            .iter()
+            .map(|res| match *res {
+                BlockResponse::Timeout(hash) => hash,
+                _ => panic!("The request should time out"),
+            })
+            .collect();
+        assert_eq!(timed_out, vec![genesis_block(Network::Bitcoin).bitcoin_hash()]);
+    }
+
+    // Collect hashes of found blocks with the name of requester.
+    // Stop the system when `total` blocks are collected by all requesters.
+    struct NamedBlockCollector
+    {
+        name: &'static str,
+        results: Rc<RefCell<Vec<(&'static str, Sha256dHash)>>>,
+        total: usize,
+    }
+
+    impl Actor for NamedBlockCollector
+    {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<BlockResponse> for NamedBlockCollector
+    {
+        type Result = ();
+
+        fn handle(&mut self, msg: BlockResponse, _ctx: &mut Context<Self>)
+        {
+            let hash = match msg {
+                BlockResponse::Found(block) => block.bitcoin_hash(),
+                BlockResponse::NotFound(hash) => panic!("Block {} is not found", hash),
+                BlockResponse::Timeout(hash) => panic!("Block {} is timed out", hash),
+            };
+            let mut results = self.results.borrow_mut();
+            results.push((self.name, hash));
+            if results.len() == self.total {
+                System::current().stop();
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_block_requests_receive_their_own_blocks()
+    {
+        let mut modified = genesis_block(Network::Bitcoin);
+        modified.header.nonce += 1;
+        let blocks = vec![
+            genesis_block(Network::Bitcoin),
+            genesis_block(Network::Testnet),
+            genesis_block(Network::Regtest),
+            modified,
+        ];
+        let hashes: Vec<_> = blocks.iter().map(|b| b.bitcoin_hash()).collect();
+        let (hashes_a, hashes_b) = (vec![hashes[0], hashes[1]], vec![hashes[2], hashes[3]]);
+
+        let (local, remote) = duplex();
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let results2 = results.clone();
+        let (req_a, req_b) = (hashes_a.clone(), hashes_b.clone());
+
+        System::run(move || {
+            // Peer responds blocks of both requests alternately.
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("getdata")
+                .expect("getdata")
+                .send(NetworkMessage::Block(blocks[0].clone()))
+                .send(NetworkMessage::Block(blocks[2].clone()))
+                .send(NetworkMessage::Block(blocks[1].clone()))
+                .send(NetworkMessage::Block(blocks[3].clone()))
+                .run_and_serve(|_msg| Vec::new())
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let collector_a = NamedBlockCollector {
+                name: "a",
+                results: results2.clone(),
+                total: 4,
+            }.start();
+            let collector_b = NamedBlockCollector {
+                name: "b",
+                results: results2,
+                total: 4,
+            }.start();
+            let f = start_connection(local).map(move |conn| {
+                conn.do_send(GetBlocksRequest {
+                    block_hashes: req_a,
+                    addr: collector_a.recipient(),
+                });
+                conn.do_send(GetBlocksRequest {
+                    block_hashes: req_b,
+                    addr: collector_b.recipient(),
+                });
+            });
+            Arbiter::spawn(f);
+        });
+
+        let results = results.borrow();
+        let received = |name| -> Vec<Sha256dHash> {
+            results.iter().filter(|&&(n, _)| n == name).map(|&(_, hash)| hash).collect()
+        };
+        assert_eq!(received("a"), hashes_a);
+        assert_eq!(received("b"), hashes_b);
+    }
+
+    #[test]
+    fn respond_getdata_with_blocks_and_a_single_notfound()
+    {
+        let block1 = genesis_block(Network::Bitcoin);
+        let block2 = genesis_block(Network::Testnet);
+        let missing = genesis_block(Network::Regtest).bitcoin_hash();
+        let source: HashMap<_, _> = vec![block1.clone(), block2.clone()]
+            .into_iter()
+            .map(|block| (block.bitcoin_hash(), block))
+            .collect();
+        let invs = vec![block1.bitcoin_hash(), missing, block2.bitcoin_hash()]
+            .into_iter()
+            .map(|hash| {
+                Inventory {
+                    inv_type: InvType::Block,
+                    hash,
+                }
+            })
+            .collect();
+
+        let (local, remote) = duplex();
+        let replies = Rc::new(RefCell::new(Vec::new()));
+        let replies2 = replies.clone();
+
+        System::run(move || {
+            // Peer requests blocks after we set the block source.
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("filterclear")
+                .send(NetworkMessage::GetData(invs))
+                .run()
+                .and_then(|socket| socket.recv_msg_stream().take(3).collect())
+                .map(move |msgs| {
+                    *replies2.borrow_mut() = msgs;
+                    System::current().stop();
+                })
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let f = start_connection(local).map(move |conn| {
+                conn.do_send(SetBlockSource(Arc::new(source)));
+                conn.do_send(ClearBloomFilter);
+            });
+            Arbiter::spawn(f);
+        });
+
+        let replies = replies.borrow();
+        assert_eq!(replies.len(), 3);
+        match (&replies[0], &replies[1], &replies[2]) {
+            (
+                &Message::Network(NetworkMessage::Block(ref first)),
+                &Message::Network(NetworkMessage::Block(ref second)),
+                &Message::Network(NetworkMessage::NotFound(ref not_found)),
+            ) => {
+                assert_eq!(*first, block1);
+                assert_eq!(*second, block2);
+                let not_found: Vec<_> = not_found.iter().map(|inv| inv.hash).collect();
+                assert_eq!(not_found, vec![missing]);
+            },
+            other => panic!("Unexpected replies : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tx_subscriber_receives_announced_txs_once()
+    {
+        let tx1 = genesis_block(Network::Bitcoin).txdata[0].clone();
+        let mut tx2 = tx1.clone();
+        tx2.lock_time = 1;
+        let tx_inv = |tx: &Transaction| {
+            Inventory {
+                inv_type: InvType::Transaction,
+                hash: tx.bitcoin_hash(),
+            }
+        };
+        let txs: HashMap<_, _> = vec![tx1.clone(), tx2.clone()]
+            .into_iter()
+            .map(|tx| (tx.bitcoin_hash(), tx))
+            .collect();
+
+        let (local, remote) = duplex();
+        let published = Rc::new(RefCell::new(Vec::new()));
+        let published2 = published.clone();
+        let requested = Rc::new(RefCell::new(Vec::new()));
+        let requested2 = requested.clone();
+
+        System::run(move || {
+            // `tx1` is announced twice, but requested only once.
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("filterclear")
+                .send(NetworkMessage::Inv(vec![tx_inv(&tx1)]))
+                .send(NetworkMessage::Inv(vec![tx_inv(&tx1), tx_inv(&tx2)]))
+                .run_and_serve(move |msg| match msg {
+                    Message::Network(NetworkMessage::GetData(invs)) => {
+                        invs.iter()
+                            .map(|inv| {
+                                requested2.borrow_mut().push(inv.hash);
+                                NetworkMessage::Tx(txs[&inv.hash].clone()).into()
+                            })
+                            .collect()
+                    },
+                    _ => Vec::new(),
+                })
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let collector = Collector {
+                results: published2,
+                num: 2,
+            }.start();
+            let f = start_connection(local).map(move |conn| {
+                conn.do_send(SubscribeTx {
+                    addr: collector.recipient(),
+                });
+                conn.do_send(ClearBloomFilter);
+            });
+            Arbiter::spawn(f);
+        });
+
+        let expected = vec![tx1.bitcoin_hash(), tx2.bitcoin_hash()];
+        assert_eq!(*requested.borrow(), expected);
+        let published: Vec<_> = published.borrow().iter().map(|p| p.0.bitcoin_hash()).collect();
+        assert_eq!(published, expected);
+    }
+
+    #[test]
+    fn pong_without_matching_ping_is_misbehavior()
+    {
+        let (local, remote) = duplex();
+        let bans = Rc::new(RefCell::new(Vec::new()));
+        let bans2 = bans.clone();
+
+        System::run(move || {
+            // We never send `ping` before the default interval passes.
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("filterclear")
+                .send(NetworkMessage::Pong(42))
+                .run_and_serve(|_msg| Vec::new())
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let ban_collector = Collector {
+                results: bans2,
+                num: 1,
+            }.start();
+            let f = start_connection(local).map(move |conn| {
+                let policy = MisbehaviorPolicy {
+                    unsolicited_message: 100,
+                    ..MisbehaviorPolicy::default()
+                };
+                conn.do_send(SetMisbehaviorPolicy {
+                    policy,
+                    ban: ban_collector.recipient(),
+                });
+                conn.do_send(ClearBloomFilter);
+            });
+            Arbiter::spawn(f);
+        });
+
+        assert_eq!(bans.borrow().len(), 1);
+    }
+
+    #[test]
+    fn close_connection_when_peer_does_not_respond_ping()
+    {
+        let (local, remote) = duplex();
+        let closed = Rc::new(Cell::new(false));
+        let closed2 = closed.clone();
+
+        System::run(move || {
+            // Peer receives `ping` but never responds `pong`.
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("ping")
+                .run_and_serve(|_msg| Vec::new())
+                .map(move |()| {
+                    closed2.set(true);
+                    System::current().stop();
+                })
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let f = start_connection(local).map(|conn| {
+                conn.do_send(SetPingInterval {
+                    interval: Duration::from_millis(10),
+                    timeout: Duration::from_millis(100),
+                });
+            });
+            Arbiter::spawn(f);
+        });
+
+        assert!(closed.get());
+    }
+
+    #[test]
+    fn getdata_blocks_inventory_type_per_mode()
+    {
+        let hashes = vec![genesis_block(Network::Bitcoin).bitcoin_hash()];
+        let raw_inv_types = |msg: Message| match msg {
+            Message::GetDataRaw(invs) => invs.iter().map(|inv| inv.inv_type).collect::<Vec<_>>(),
+            other => panic!("Unexpected message : {:?}", other),
+        };
+
+        assert_eq!(raw_inv_types(getdata_blocks_msg(hashes.clone(), true, false)), vec![MSG_WITNESS_BLOCK]);
+        // Witness blocks are preferred over compact blocks.
+        assert_eq!(raw_inv_types(getdata_blocks_msg(hashes.clone(), true, true)), vec![MSG_WITNESS_BLOCK]);
+        assert_eq!(raw_inv_types(getdata_blocks_msg(hashes.clone(), false, true)), vec![MSG_CMPCT_BLOCK]);
+        match getdata_blocks_msg(hashes.clone(), false, false) {
+            Message::Network(NetworkMessage::GetData(ref invs)) => {
+                assert_eq!(invs.len(), 1);
+                assert_eq!(invs[0].inv_type, InvType::Block);
+                assert_eq!(invs[0].hash, hashes[0]);
+            },
+            other => panic!("Unexpected message : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn survive_messages_of_newer_peers()
+    {
+        let (local, remote) = duplex();
+        let stats = Rc::new(Cell::new(None));
+        let stats2 = stats.clone();
+
+        System::run(move || {
+            let (tx, rx) = oneshot::channel();
+            let sendcmpct = SendCmpct {
+                high_bandwidth: false,
+                version: 2,
+            };
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .send(Message::SendCmpct(sendcmpct))
+                .send(Message::FeeFilter(1000))
+                .send(Message::Unknown("getcfilters".into()))
+                .send(NetworkMessage::Ping(42))
+                .expect("pong")
+                .run()
+                .map(move |socket| {
+                    let _ = tx.send(socket);
+                })
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let f = start_connection(local)
+                .and_then(move |conn| {
+                    rx.map_err(|e| panic!("Peer is dropped : {:?}", e))
+                        .and_then(move |socket| conn.send(GetConnectionStats).map(move |stats| (stats, socket)))
+                        .map_err(|e| panic!("Fail to get stats : {:?}", e))
+                })
+                .map(move |(stats, _socket)| {
+                    stats2.set(Some(stats));
+                    System::current().stop();
+                });
+            Arbiter::spawn(f);
+        });
+
+        let stats = stats.get().unwrap();
+        assert_eq!(stats.messages_received, 4);
+        assert_eq!(stats.fee_filter, Some(1000));
+    }
+
+    #[test]
+    fn answer_ping_while_many_blocks_wait_to_be_sent()
+    {
+        const NUM_REQUESTED: usize = 100;
+        const QUEUE_SIZE: usize = 64;
+        let block = genesis_block(Network::Bitcoin);
+        let inv = Inventory {
+            inv_type: InvType::Block,
+            hash: block.bitcoin_hash(),
+        };
+        let source: HashMap<_, _> = vec![(block.bitcoin_hash(), block)].into_iter().collect();
+
+        // Peer reads slowly, so that most of blocks wait in the send queue.
+        let (local, remote) = duplex_with_capacity(1024);
+        let result = Rc::new(Cell::new(None));
+        let result2 = result.clone();
+
+        System::run(move || {
+            let (tx, rx) = oneshot::channel();
+            let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("filterclear")
+                .send(NetworkMessage::GetData(vec![inv; NUM_REQUESTED]))
+                .send(NetworkMessage::Ping(42))
+                .run()
+                .and_then(|socket| {
+                    future::loop_fn((socket, 0), |(socket, blocks)| {
+                        socket.recv_msg().map(move |(msg, socket)| {
+                            match msg.command().as_str() {
+                                "pong" => Loop::Break((blocks, socket)),
+                                _ => Loop::Continue((socket, blocks + 1)),
+                            }
+                        })
+                    })
+                })
+                .map(move |res| {
+                    let _ = tx.send(res);
+                })
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let f = start_connection(local)
+                .and_then(move |conn| {
+                    conn.do_send(SetSendQueueSize(QUEUE_SIZE));
+                    conn.do_send(SetBlockSource(Arc::new(source)));
+                    conn.do_send(ClearBloomFilter);
+                    rx.map_err(|e| panic!("Peer is dropped : {:?}", e))
+                        .and_then(move |(blocks, socket)| {
+                            // Keep the socket open until we get stats.
+                            conn.send(GetConnectionStats).map(move |stats| (blocks, stats, socket))
+                        })
+                        .map_err(|e| panic!("Fail to get stats : {:?}", e))
+                })
+                .map(move |(blocks, stats, _socket)| {
+                    result2.set(Some((blocks, stats)));
+                    System::current().stop();
+                });
+            Arbiter::spawn(f);
+        });
+
+        let (blocks_before_pong, stats) = result.get().unwrap();
+        // `pong` overtakes blocks which wait in the queue.
+        assert!(blocks_before_pong < QUEUE_SIZE);
+        assert_eq!(stats.dropped_messages, (NUM_REQUESTED - QUEUE_SIZE) as u64);
+        assert!(stats.send_queue_len > 0);
+    }
+
+    #[test]
+    fn drop_unsolicited_addrs_over_rate_limit()
+    {
+        let (local, remote) = duplex();
+        let published = Rc::new(RefCell::new(Vec::new()));
+        let stats = Rc::new(Cell::new(None));
+        let (published2, stats2) = (published.clone(), stats.clone());
+
+        System::run(move || {
+            // The first `addr` responds our `getaddr`, and the others burst on their own.
+            let (tx, rx) = oneshot::channel();
+            let mut peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("getaddr");
+            for _ in 0..6 {
+                peer = peer.send(NetworkMessage::Addr(Vec::new()));
+            }
+            let peer = peer.send(NetworkMessage::Ping(42))
+                .expect("pong")
+                .run()
+                .map(move |socket| {
+                    let _ = tx.send(socket);
+                })
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let collector = Collector {
+                results: published2,
+                num: usize::max_value(),
+            }.start();
+            let f = start_connection(local)
+                .and_then(move |conn| {
+                    conn.do_send(SetRateLimits(RateLimits {
+                        addr: Some(RateLimit::per_minute(2)),
+                        inv: None,
+                    }));
+                    conn.do_send(SubscribeAddrs {
+                        addr: collector.clone().recipient(),
+                    });
+                    conn.do_send(GetAddrsRequest {
+                        addr: collector.recipient(),
+                    });
+                    rx.map_err(|e| panic!("Peer is dropped : {:?}", e))
+                        .and_then(move |socket| conn.send(GetConnectionStats).map(move |stats| (stats, socket)))
+                        .map_err(|e| panic!("Fail to get stats : {:?}", e))
+                })
+                .map(move |(stats, _socket)| {
+                    stats2.set(Some(stats));
+                    System::current().stop();
+                });
+            Arbiter::spawn(f);
+        });
+
+        let stats = stats.get().unwrap();
+        assert_eq!(stats.rate_limited, RateLimitedMessages { addr: 3, inv: 0 });
+        assert_eq!(stats.violations.rate_limited, 3);
+        assert_eq!(stats.misbehavior_score, 3);
+        assert_eq!(stats.rate_limits.addr, Some(RateLimit::per_minute(2)));
+        assert_eq!(published.borrow().len(), 3);
+    }
+
+    #[test]
+    fn disconnect_peer_which_keeps_flooding_invs()
+    {
+        let (local, remote) = duplex();
+        let bans = Rc::new(RefCell::new(Vec::new()));
+        let bans2 = bans.clone();
+
+        System::run(move || {
+            let mut peer = ScriptedPeer::new(remote, Network::Bitcoin)
+                .handshake(0)
+                .expect("filterclear");
+            for _ in 0..20 {
+                peer = peer.send(NetworkMessage::Inv(Vec::new()));
+            }
+            let peer = peer.run_and_serve(|_msg| Vec::new())
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let ban_collector = Collector {
+                results: bans2,
+                num: 1,
+            }.start();
+            let f = start_connection(local).map(move |conn| {
+                conn.do_send(SetRateLimits(RateLimits {
+                    addr: None,
+                    inv: Some(RateLimit::per_minute(5)),
+                }));
+                let policy = MisbehaviorPolicy {
+                    threshold: 10,
+                    ..MisbehaviorPolicy::default()
+                };
+                conn.do_send(SetMisbehaviorPolicy {
+                    policy,
+                    ban: ban_collector.recipient(),
+                });
+                conn.do_send(ClearBloomFilter);
+            });
+            Arbiter::spawn(f);
+        });
+
+        // 5 invs are allowed, and the 10th dropped one reaches the threshold.
+        assert_eq!(bans.borrow().len(), 1);
+    }
+}
@@ -1,22 +1,60 @@
-use std::{collections::HashSet, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::Duration};
+use std::{cmp, collections::{HashMap, HashSet}, io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, path::PathBuf,
+          sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use actix::prelude::*;
-use trust_dns_resolver::{ResolverFuture, config::{ResolverConfig, ResolverOpts}, error::ResolveError};
-use futures::Future;
-use bitcoin::network::constants::Network;
+use trust_dns_resolver::{ResolverFuture, config::{ResolverConfig, ResolverOpts}, error::ResolveError,
+                         system_conf::read_system_conf};
+use futures::{future::join_all, Future};
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::{address::Address, constants::{Network, PROTOCOL_VERSION}};
 
-use rand::{FromEntropy, RngCore, XorShiftRng, seq::sample_iter};
+use rand::{FromEntropy, Rng, RngCore, XorShiftRng};
+
+use tokio::{net::TcpStream, timer::Timeout};
 
 use blockchain::BlockChain;
-use connection::{socket::Socket, {AddrsResponse, Connection, Disconnect, GetAddrsRequest}};
+use error::Error;
+use events::{noop_sink, Event, EventSink};
+use connection::{addr_manager::AddrManager, misbehavior::MisbehaviorPolicy,
+                 socket::{HandshakeConfig, HandshakedSocket, LocalNonces, Socket, NODE_NETWORK, USER_AGENT},
+                 {AddrsResponse, BroadcastResult, BroadcastTx, Connection, Disconnect, GetAddrsRequest,
+                  GetConnectionStats, SampleAddrs, SetAddrSource, SetEventSink, SetHeaderSource,
+                  SetMisbehaviorPolicy, SubscribeAddrs, MAX_ADDRS_IN_MSG}};
 
 pub const DEFAULT_WATER_LINE: usize = 8;
+/// Bitcoin core also connects to at most one peer in each network group, which makes eclipse attacks harder.
+pub const DEFAULT_MAX_CONNECTIONS_PER_NETGROUP: usize = 1;
+pub const DEFAULT_MAX_INBOUND_CONNECTIONS: usize = 8;
 pub const ADDR_POOL_SIZE: usize = 64;
+/// DNS seeds are queried only when address pool has fewer viable addresses than this.
+pub const MIN_VIABLE_ADDRS: usize = 8;
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+/// A peer which does not send any of requested blocks for this duration is banned.
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+/// Addresses which peers saw longer ago than this are ignored.
+pub const DEFAULT_ADDR_HORIZON: Duration = Duration::from_secs(3 * 60 * 60);
+
+/// How long `Shutdown` waits for each connection to handle `Disconnect`.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A DNS seed which does not answer in this duration is skipped.
+const DNS_SEED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Backoff after the first failure. It doubles on every failure in a row.
+const MIN_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Connection histories are kept for at most this number of addresses.
+/// Addresses come from peers, so the oldest history is dropped to bound memory.
+const MAX_ADDR_HISTORY: usize = 1024;
+
+/// A subscriber is dropped when it fails to receive events this number of times in a row.
+const MAX_DELIVERY_FAILURES: u32 = 2;
 
 pub const BITCOIN_DNS_SEEDS: [&'static str; 6] = [
     "seed.bitcoin.sipa.be",
     "dnsseed.bluematt.me",
     "dnsseed.bitcoin.dashjr.org",
-    "seed.bitcoinstats,com",
+    "seed.bitcoinstats.com",
     "bitseed.xf2.org",
     "seed.bitcoin.jonasschnelli.ch",
 ];
@@ -30,27 +68,84 @@ pub const TESTNET_DNS_SEEDS: [&'static str; 4] = [
 
 pub const BITCOIN_PORT: u16 = 8333;
 pub const TESTNET_PORT: u16 = 18333;
+pub const REGTEST_PORT: u16 = 18444;
+
+/// Resolve hostnames of DNS seeds with given resolver config.
+type SeedQuery = fn(Vec<String>, ResolverConfig, ResolverOpts) -> Box<Future<Item = Vec<IpAddr>, Error = ResolveError>>;
 
 pub struct ConnectionPool
 {
-    connection_pool: HashSet<Addr<Connection>>,
-    water_line: usize, // The number of connections it needs to keep
-    addr_pool: Vec<SocketAddr>,
+    connection_pool: HashMap<Addr<Connection>, PoolEntry>,
+    water_line: usize, // The number of outbound connections it needs to keep
+    max_connections_per_netgroup: usize,
+    listen_addr: Option<SocketAddr>,
+    max_inbound_connections: usize,
+    addr_pool: AddrManager,
+    addr_file: Option<PathBuf>, // Where address pool is persisted
+    addr_horizon: Duration,
+    dialing: HashSet<SocketAddr>,
+    bootstrap_addrs: Vec<SocketAddr>, // Static peers which are dialed before any other address
+    dns_seeds: Vec<String>,
+    resolver_config: Option<(ResolverConfig, ResolverOpts)>, // `None` means the system config
+    seed_query: SeedQuery,
+    addr_history: HashMap<SocketAddr, AddrHistory>,
+    banned: HashMap<IpAddr, Instant>, // Banned addresses and when the ban expires
+    ban_duration: Duration,
+    stall_timeout: Duration,
+    misbehavior_policy: MisbehaviorPolicy,
+    allow_private_addrs: bool,
+    // Our IP addresses which outbound peers see.
+    external_ips: HashSet<IpAddr>,
+    querying_dns_seeds: bool,
+    shutting_down: bool,
+    health_check_handle: SpawnHandle,
 
     rng: XorShiftRng,
 
     network: Network,
     services: u64,
+    required_services: u64,
     relay: bool,
+    user_agent: String,
+    protocol_version: u32,
     blockchain: Arc<Mutex<BlockChain>>,
+    local_nonces: LocalNonces,
+
+    subscribers: Vec<Subscriber>,
+    events: Arc<EventSink>,
+}
+
+struct PoolEntry
+{
+    socket_addr: SocketAddr,
+    // Services which remote peer advertises in `version` message
+    services: u64,
+    // Whether remote peer connected to us or not.
+    inbound: bool,
+    // Median round trip time of `ping`, which is refreshed by health check.
+    median_ping: Option<Duration>,
+}
+
+/// Connection history of an address which we dial.
+/// An address which we never fail to connect to does not have a history.
+#[derive(Debug, Clone, Copy)]
+struct AddrHistory
+{
+    // The number of failures in a row.
+    failures: u32,
+    last_attempt: Instant,
 }
 
 #[derive(Message)]
 #[rtype(result = "Vec<Addr<Connection>>")]
+/// Get up to `num` connections. Connections whose peers respond `ping` faster are returned first.
 pub struct GetConnections
 {
     pub num: usize,
     pub except: Vec<Addr<Connection>>,
+    /// Only connections whose peer advertises all of these services are returned.
+    /// e.g. `NODE_NETWORK` for initial block download.
+    pub services: u64,
 }
 
 #[derive(Message)]
@@ -59,14 +154,64 @@ pub struct BanConnection
     pub conn: Addr<Connection>,
 }
 
+#[derive(Message)]
+#[rtype(result = "PoolStats")]
+/// Get statistics of `ConnectionPool` for monitoring.
+pub struct GetPoolStats;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats
+{
+    /// The number of established connections.
+    pub active: usize,
+    /// The number of addresses which wait for backoff to expire.
+    pub backoff: usize,
+    /// The number of banned addresses.
+    pub banned: usize,
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<(), ()>")]
+/// Disconnect all connections, then stop `ConnectionPool`.
+/// Response is sent after every connection handles `Disconnect`, or `SHUTDOWN_TIMEOUT` passes.
+pub struct Shutdown;
+
+#[derive(Message)]
+/// Start to subscribe `PoolEvent`s.
+pub struct SubscribePoolEvents
+{
+    pub addr: Recipient<PoolEvent>,
+}
+
+#[derive(Message, Clone)]
+/// Notify changes of `ConnectionPool` to subscribers.
+pub enum PoolEvent
+{
+    ConnectionEstablished(Addr<Connection>, SocketAddr),
+    /// Connection is closed by peer or by an error.
+    ConnectionLost(SocketAddr),
+    ConnectionBanned(SocketAddr),
+}
+
+struct Subscriber
+{
+    addr: Recipient<PoolEvent>,
+    // The number of delivery failures in a row.
+    failures: u32,
+}
+
 impl Actor for ConnectionPool
 {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>)
     {
-        self.feed_initial_addrs(ctx);
-        ctx.run_interval(Duration::from_secs(30), |actor, ctx| {
+        self.load_addrs();
+        if let Some(addr) = self.listen_addr {
+            ctx.add_stream(Socket::listen(addr, self.network));
+        }
+        self.health_check(ctx);
+        self.health_check_handle = ctx.run_interval(Duration::from_secs(30), |actor, ctx| {
             actor.health_check(ctx);
         });
     }
@@ -74,49 +219,279 @@ impl Actor for ConnectionPool
 
 impl ConnectionPool
 {
-    pub fn new(network: Network, services: u64, relay: bool, blockchain: Arc<Mutex<BlockChain>>) -> ConnectionPool
+    /// `required_services` is a set of services which every connecting peer **MUST** advertise.
+    pub fn new(
+        network: Network,
+        services: u64,
+        required_services: u64,
+        relay: bool,
+        blockchain: Arc<Mutex<BlockChain>>,
+    ) -> ConnectionPool
     {
         ConnectionPool {
-            connection_pool: HashSet::new(),
+            connection_pool: HashMap::new(),
             water_line: DEFAULT_WATER_LINE,
-            addr_pool: Vec::new(),
+            max_connections_per_netgroup: DEFAULT_MAX_CONNECTIONS_PER_NETGROUP,
+            listen_addr: None,
+            max_inbound_connections: DEFAULT_MAX_INBOUND_CONNECTIONS,
+            addr_pool: AddrManager::new(ADDR_POOL_SIZE),
+            addr_file: None,
+            addr_horizon: DEFAULT_ADDR_HORIZON,
+            dialing: HashSet::new(),
+            bootstrap_addrs: Vec::new(),
+            dns_seeds: default_dns_seeds(network),
+            resolver_config: None,
+            seed_query: query_dns_seeds,
+            addr_history: HashMap::new(),
+            banned: HashMap::new(),
+            ban_duration: DEFAULT_BAN_DURATION,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+            misbehavior_policy: MisbehaviorPolicy::default(),
+            allow_private_addrs: false,
+            external_ips: HashSet::new(),
+            querying_dns_seeds: false,
+            shutting_down: false,
+            health_check_handle: SpawnHandle::default(),
 
             rng: XorShiftRng::from_entropy(),
 
             network,
             services,
+            required_services,
             relay,
+            user_agent: USER_AGENT.into(),
+            protocol_version: PROTOCOL_VERSION,
             blockchain,
+            local_nonces: LocalNonces::default(),
+
+            subscribers: Vec::new(),
+            events: noop_sink(),
         }
     }
 
+    /// Send `Shutdown` to `pool` and wait for it to disconnect all connections.
+    /// It resolves immediately if `pool` is already stopped.
+    pub fn shutdown(pool: Addr<ConnectionPool>) -> impl Future<Item = (), Error = ()>
+    {
+        pool.send(Shutdown).then(|res| match res {
+            Ok(res) => res,
+            Err(MailboxError::Closed) => Ok(()),
+            Err(e) => {
+                info!("Fail to shutdown connection pool : {:?}", e);
+                Err(())
+            },
+        })
+    }
+
+    /// Persist address pool to `path`, so that learned addresses and their history survive restarts.
+    /// The file is loaded when `ConnectionPool` starts, and saved by every health check and on shutdown.
+    pub fn set_addr_file(&mut self, path: PathBuf)
+    {
+        self.addr_file = Some(path);
+    }
+
+    /// Static peers which are always dialed before addresses from DNS seeds or other peers.
+    /// It is required for regtest, which does not have any DNS seed.
+    pub fn with_bootstrap_addrs(mut self, addrs: Vec<SocketAddr>) -> ConnectionPool
+    {
+        self.bootstrap_addrs = addrs;
+        self
+    }
+
+    /// Query given DNS seeds instead of the default ones of the network.
+    /// An empty list disables DNS seeding, so that only bootstrap addresses and their peers are used.
+    pub fn with_dns_seeds(mut self, seeds: Vec<String>) -> ConnectionPool
+    {
+        self.dns_seeds = seeds;
+        self
+    }
+
+    /// Resolve DNS seeds with given config instead of the system config.
+    pub fn with_resolver_config(mut self, config: ResolverConfig, opts: ResolverOpts) -> ConnectionPool
+    {
+        self.resolver_config = Some((config, opts));
+        self
+    }
+
+    /// Accept inbound connections on `addr`, as `set_listen_addr` does.
+    /// Inbound peers are capped by `set_max_inbound_connections` and do not count toward `water_line`.
+    pub fn with_listener(mut self, addr: SocketAddr) -> ConnectionPool
+    {
+        self.set_listen_addr(addr);
+        self
+    }
+
+    /// Set the number of outbound connections which the pool keeps.
+    pub fn set_water_line(&mut self, water_line: usize)
+    {
+        self.water_line = water_line;
+    }
+
+    /// Set the max number of connections to peers in the same network group (/16 for IPv4).
+    /// It keeps diversity of peers.
+    pub fn set_max_connections_per_netgroup(&mut self, max: usize)
+    {
+        self.max_connections_per_netgroup = max;
+    }
+
+    /// Accept inbound connections on `addr`.
+    /// Listener starts when the actor starts.
+    pub fn set_listen_addr(&mut self, addr: SocketAddr)
+    {
+        self.listen_addr = Some(addr);
+    }
+
+    /// Set the max number of inbound connections.
+    /// Inbound connections are not counted for `water_line`.
+    pub fn set_max_inbound_connections(&mut self, max: usize)
+    {
+        self.max_inbound_connections = max;
+    }
+
+    /// Set how recently peers must have seen an address for us to learn it.
+    /// The timestamp of each address in `addr` messages is compared with our clock.
+    pub fn set_addr_horizon(&mut self, horizon: Duration)
+    {
+        self.addr_horizon = horizon;
+    }
+
+    /// Set how long a banned address is excluded from connections.
+    pub fn set_ban_duration(&mut self, duration: Duration)
+    {
+        self.ban_duration = duration;
+    }
+
+    /// Set how long we wait for a peer to send any of requested blocks.
+    /// A peer which is slower than that is banned.
+    pub fn set_stall_timeout(&mut self, timeout: Duration)
+    {
+        self.stall_timeout = timeout;
+    }
+
+    /// Set weights of violations and the threshold to ban a peer.
+    /// It is applied to connections which are established after this call.
+    pub fn set_misbehavior_policy(&mut self, policy: MisbehaviorPolicy)
+    {
+        self.misbehavior_policy = policy;
+    }
+
+    /// Report `Event`s of the pool and its connections to `sink`.
+    /// It is applied to connections which are established after this call.
+    pub fn set_event_sink(&mut self, sink: Arc<EventSink>)
+    {
+        self.events = sink;
+    }
+
+    /// Set the user agent of our `version` message, in BIP 14 format. e.g. "/bitcoinrs:0.0/"
+    /// It is applied to connections which are established after this call.
+    pub fn set_user_agent(&mut self, user_agent: String)
+    {
+        self.user_agent = user_agent;
+    }
+
+    /// Set the protocol version of our `version` message, e.g. a lower one for compatibility testing.
+    /// It is applied to connections which are established after this call.
+    pub fn set_protocol_version(&mut self, version: u32)
+    {
+        self.protocol_version = version;
+    }
+
+    /// Accept loopback, private and link-local addresses from DNS seeds and peers.
+    /// It is useful for regtest setups on a local network. Default is false.
+    ///
+    /// Such addresses are not limited by `set_max_connections_per_netgroup`, so that the pool can connect to
+    /// several nodes on the same host, e.g. `127.0.0.1:18444` and `127.0.0.1:18445`.
+    pub fn set_allow_private_addrs(&mut self, allow: bool)
+    {
+        self.allow_private_addrs = allow;
+    }
+
+    /// Broadcast `tx` to `n` randomly chosen connections.
+    /// Each connection reports a `BroadcastResult` to `addr`.
+    /// Returned future resolves to the number of connections which the transaction is announced to.
+    pub fn broadcast_to_n(
+        pool: &Addr<ConnectionPool>,
+        tx: Transaction,
+        n: usize,
+        addr: Recipient<BroadcastResult>,
+    ) -> impl Future<Item = usize, Error = MailboxError>
+    {
+        let req = GetConnections {
+            num: n,
+            except: Vec::new(),
+            services: 0,
+        };
+        pool.send(req).map(move |conns| {
+            for conn in conns.iter() {
+                let req = BroadcastTx {
+                    tx: tx.clone(),
+                    addr: addr.clone(),
+                };
+                conn.do_send(req);
+            }
+            conns.len()
+        })
+    }
+
     fn add_connection(&mut self, addr: &SocketAddr, ctx: &mut Context<Self>)
     {
+        let socket_addr = *addr;
+        self.record_attempt(socket_addr, Instant::now());
+        self.dialing.insert(socket_addr);
         let f = Socket::connect(addr, self.network)
             .into_actor(self)
             .and_then(|socket, actor, _ctx| {
-                let start_height = {
-                    let lock = actor.blockchain.lock().unwrap();
-                    let active_chain = lock.active_chain();
-                    let start_height = active_chain.latest_block().height();
-                    start_height
-                };
-                socket
-                    .begin_handshake(start_height as i32, actor.services, actor.relay)
-                    .into_actor(actor)
+                let config = actor.handshake_config();
+                socket.begin_handshake_with_config(config).into_actor(actor)
             })
-            .map(|socket, actor, ctx| {
+            .map(move |socket, actor, ctx| {
+                actor.dialing.remove(&socket_addr);
+                if actor.shutting_down {
+                    return;
+                }
+                // Another connection to the same peer may be established while handshake.
+                if !actor.is_dialable(&socket_addr) {
+                    info!("Already connected to the same network group. Drop connection");
+                    return;
+                }
+
+                // Peer tells how it sees us, so that we do not dial ourself later.
+                if let Ok(addr) = socket.remote_version().receiver.socket_addr() {
+                    actor.external_ips.insert(addr.ip());
+                }
+
+                let services = socket.remote_services();
+                if !has_services(services, actor.required_services) {
+                    info!("Peer does not have required services. Drop connection");
+                    return;
+                }
+
+                actor.report_handshake(&socket);
                 let conn = Connection::start_actor(socket);
+                actor.setup_connection(&conn, ctx);
+                // Successful handshake resets backoff.
+                actor.addr_history.remove(&socket_addr);
+                actor.addr_pool.record_success(socket_addr, services, unix_time());
 
                 // Try send a GetAddrsRequest
                 let me = ctx.address().recipient();
                 let req = GetAddrsRequest { addr: me };
                 conn.do_send(req);
 
-                let _ = actor.connection_pool.insert(conn);
+                let entry = PoolEntry {
+                    socket_addr,
+                    services,
+                    inbound: false,
+                    median_ping: None,
+                };
+                let _ = actor.connection_pool.insert(conn.clone(), entry);
+                actor.publish(PoolEvent::ConnectionEstablished(conn, socket_addr));
             })
-            .map_err(|err, _actor, _ctx| {
+            .map_err(move |err, actor, _ctx| {
                 info!("Fail to establish connection : {:?}", err);
+                actor.dialing.remove(&socket_addr);
+                actor.record_failure(socket_addr, Instant::now());
+                actor.addr_pool.record_failure(socket_addr);
             });
         ctx.spawn(f);
     }
@@ -125,52 +500,404 @@ impl ConnectionPool
     // So even if connection_pool gets empty, it does not invoke recovery process immediately.
     fn health_check(&mut self, ctx: &mut Context<Self>)
     {
-        // Remove all dropped connections
-        self.connection_pool.retain(|addr| addr.connected());
+        if self.shutting_down {
+            return;
+        }
+        let now = Instant::now();
+
+        // Remove all dropped connections.
+        // Outbound peers are dialed again after backoff.
+        let lost: Vec<_> = self.connection_pool
+            .iter()
+            .filter(|&(addr, _)| !addr.connected())
+            .map(|(_, entry)| (entry.socket_addr, entry.inbound))
+            .collect();
+        self.connection_pool.retain(|addr, _| addr.connected());
+        for (socket_addr, inbound) in lost {
+            if !inbound {
+                self.record_failure(socket_addr, now);
+            }
+            self.publish(PoolEvent::ConnectionLost(socket_addr));
+        }
+        self.banned.retain(|_, until| now < *until);
+        self.check_connection_stats(ctx);
 
-        // If address pool is empty, we feed addresses to address pool but not try to establish a
-        // new connection. It may happen in next cycle.
-        if self.addr_pool.is_empty() {
+        // If address pool runs short of viable addresses, we feed addresses to address pool.
+        // Addresses from DNS seeds are fed asynchronously, so they are dialed in next cycle.
+        if self.addr_pool.num_viable() < MIN_VIABLE_ADDRS {
             self.feed_initial_addrs(ctx);
+        }
 
         // If we does not have enough connection, we will try to establish a new connection.
         // Note that only one connection is tried to establish in one cycle.
-        } else if !self.has_enough_connection() {
-            let next_idx = self.rng.next_u32() as usize % self.addr_pool.len();
-            let addr = self.addr_pool.swap_remove(next_idx);
-            self.add_connection(&addr, ctx);
+        // Addresses in backoff are kept in address pool for later cycles.
+        if !self.has_enough_connection() {
+            if let Some(addr) = self.next_addr(now) {
+                self.add_connection(&addr, ctx);
+            }
+        }
+        self.save_addrs();
+    }
+
+    /// Pick an address to dial. Bootstrap addresses come first, then address pool's preference.
+    fn next_addr(&mut self, now: Instant) -> Option<SocketAddr>
+    {
+        let mut candidates: Vec<_> = self.bootstrap_addrs
+            .iter()
+            .cloned()
+            .filter(|addr| self.is_candidate(addr, now))
+            .collect();
+        if candidates.is_empty() {
+            candidates = self.addr_pool.preferred(unix_time(), |addr| self.is_candidate(addr, now));
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(candidates[self.rng.next_u32() as usize % candidates.len()])
+    }
+
+    fn is_candidate(&self, addr: &SocketAddr, now: Instant) -> bool
+    {
+        !self.dialing.contains(addr) && self.is_dialable(addr) && self.is_eligible(addr, now)
+    }
+
+    /// Ban connections which stall block download, and record latency of the others.
+    fn check_connection_stats(&mut self, ctx: &mut Context<Self>)
+    {
+        for conn in self.connection_pool.keys() {
+            let conn2 = conn.clone();
+            let f = conn.send(GetConnectionStats)
+                .into_actor(self)
+                .map(move |stats, actor, _ctx| {
+                    if stats.is_stalled(Instant::now(), actor.stall_timeout) {
+                        info!("Peer stalls block download. Ban connection");
+                        return actor.ban_connection(&conn2);
+                    }
+                    if let Some(entry) = actor.connection_pool.get_mut(&conn2) {
+                        entry.median_ping = stats.median_ping;
+                    }
+                })
+                .map_err(|_e, _actor, _ctx| debug!("Connection is already dropped"));
+            ctx.spawn(f);
+        }
+    }
+
+    fn ban_connection(&mut self, conn: &Addr<Connection>)
+    {
+        if let Some(entry) = self.connection_pool.remove(conn) {
+            // Even if it fail to send Disconnect message, if all Addr are dropped, underlying
+            // Connection will stop.
+            conn.do_send(Disconnect());
+            let until = Instant::now() + self.ban_duration;
+            self.banned.insert(entry.socket_addr.ip(), until);
+            self.publish(PoolEvent::ConnectionBanned(entry.socket_addr));
+        }
+    }
+
+    /// Let `conn` ask us to ban it when its misbehavior score reaches the threshold,
+    /// report its events to our sink, serve headers of our blockchain and exchange addresses with us.
+    fn setup_connection(&self, conn: &Addr<Connection>, ctx: &mut Context<Self>)
+    {
+        conn.do_send(SetMisbehaviorPolicy {
+            policy: self.misbehavior_policy,
+            ban: ctx.address().recipient(),
+        });
+        conn.do_send(SetEventSink(self.events.clone()));
+        conn.do_send(SetHeaderSource(self.blockchain.clone()));
+        conn.do_send(SubscribeAddrs {
+            addr: ctx.address().recipient(),
+        });
+        conn.do_send(SetAddrSource(ctx.address().recipient()));
+    }
+
+    /// Add addresses which a peer sent, except stale ones and ones which lack required services.
+    /// Address pool evicts its worst addresses when it is full.
+    fn add_learned_addrs(&mut self, addrs: Vec<(u32, Address)>, now: u64)
+    {
+        let horizon = now.saturating_sub(self.addr_horizon.as_secs());
+        for (ts, addr) in addrs {
+            if (ts as u64) < horizon {
+                debug!("Ignore an address which is seen {} secs ago", now.saturating_sub(ts as u64));
+                continue;
+            }
+            if !has_services(addr.services, self.required_services) {
+                continue;
+            }
+            // A timestamp in the future is not trusted.
+            let seen = cmp::min(ts as u64, now);
+            match addr.socket_addr() {
+                Ok(a) if self.is_acceptable_addr(&a) => self.addr_pool.add(a, addr.services, seen),
+                _ => debug!("Ignore an address which is not routable"),
+            }
+        }
+    }
+
+    /// Addresses with their timestamps chosen at random from the address pool.
+    fn sample_addrs(&mut self, max: usize) -> Vec<(u32, Address)>
+    {
+        let max = cmp::min(max, MAX_ADDRS_IN_MSG);
+        self.addr_pool
+            .sample(max, &mut self.rng)
+            .into_iter()
+            .map(|(addr, entry)| (entry.last_seen as u32, Address::new(&addr, entry.services)))
+            .collect()
+    }
+
+    fn report_handshake<S>(&self, socket: &HandshakedSocket<S>)
+    {
+        self.events.on_event(Event::HandshakeCompleted {
+            peer: socket.peer_addr(),
+            user_agent: socket.remote_user_agent().into(),
+            start_height: socket.remote_start_height(),
+        });
+    }
+
+    fn is_eligible(&self, addr: &SocketAddr, now: Instant) -> bool
+    {
+        is_eligible(self.addr_history.get(addr), self.banned.get(&addr.ip()).cloned(), now)
+    }
+
+    fn record_attempt(&mut self, addr: SocketAddr, now: Instant)
+    {
+        if let Some(history) = self.addr_history.get_mut(&addr) {
+            history.last_attempt = now;
+        }
+    }
+
+    fn record_failure(&mut self, addr: SocketAddr, now: Instant)
+    {
+        record_failure(&mut self.addr_history, addr, now);
+    }
+
+    fn pool_stats(&self, now: Instant) -> PoolStats
+    {
+        let banned = self.banned.values().filter(|until| now < **until).count();
+        let backoff = self.addr_history
+            .iter()
+            .filter(|&(addr, history)| !self.banned.contains_key(&addr.ip()) && !is_eligible(Some(history), None, now))
+            .count();
+        PoolStats {
+            active: self.connection_pool.len(),
+            backoff,
+            banned,
+        }
+    }
+
+    /// Whether an address from DNS seeds or peers is worth keeping in address pool.
+    fn is_acceptable_addr(&self, addr: &SocketAddr) -> bool
+    {
+        if let Some(listen_addr) = self.listen_addr {
+            let is_ours = *addr == listen_addr
+                || (addr.port() == listen_addr.port() && self.external_ips.contains(&addr.ip()));
+            if is_ours {
+                return false;
+            }
+        }
+        is_routable(addr) || (self.allow_private_addrs && addr.port() != 0 && is_local(&addr.ip()))
+    }
+
+    fn is_dialable(&self, addr: &SocketAddr) -> bool
+    {
+        let connected = self.connection_pool.values().map(|entry| &entry.socket_addr);
+        // Nodes on a local network are not diverse anyway.
+        let max_per_netgroup = if self.allow_private_addrs && is_local(&addr.ip()) {
+            usize::max_value()
+        } else {
+            self.max_connections_per_netgroup
+        };
+        is_dialable(addr, connected, max_per_netgroup)
+    }
+
+    fn accept_connection(&mut self, socket: Socket<TcpStream>, ctx: &mut Context<Self>)
+    {
+        if self.shutting_down {
+            return;
+        }
+        if self.max_inbound_connections <= self.num_inbound_connections() {
+            info!("Too many inbound connections. Drop connection");
+            return;
+        }
+        let socket_addr = match socket.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                info!("Fail to get peer address : {:?}", e);
+                return;
+            },
+        };
+        if !is_eligible(None, self.banned.get(&socket_addr.ip()).cloned(), Instant::now()) {
+            info!("Peer {} is banned. Drop connection", socket_addr);
+            return;
+        }
+
+        let config = self.handshake_config();
+        let f = socket
+            .accept_handshake(config)
+            .into_actor(self)
+            .map(move |socket, actor, ctx| {
+                if actor.shutting_down {
+                    return;
+                }
+                // Other peers may connect while handshake.
+                if actor.max_inbound_connections <= actor.num_inbound_connections() {
+                    info!("Too many inbound connections. Drop connection");
+                    return;
+                }
+
+                let services = socket.remote_services();
+                actor.report_handshake(&socket);
+                let conn = Connection::start_actor(socket);
+                actor.setup_connection(&conn, ctx);
+                let entry = PoolEntry {
+                    socket_addr,
+                    services,
+                    inbound: true,
+                    median_ping: None,
+                };
+                let _ = actor.connection_pool.insert(conn.clone(), entry);
+                actor.publish(PoolEvent::ConnectionEstablished(conn, socket_addr));
+            })
+            .map_err(|err, _actor, _ctx| {
+                info!("Fail to accept connection : {:?}", err);
+            });
+        ctx.spawn(f);
+    }
+
+    fn handshake_config(&self) -> HandshakeConfig
+    {
+        let start_height = {
+            let lock = self.blockchain.lock().unwrap();
+            let active_chain = lock.active_chain();
+            let start_height = active_chain.latest_block().height();
+            start_height
+        };
+        HandshakeConfig {
+            user_agent: self.user_agent.clone(),
+            protocol_version: self.protocol_version,
+            services: self.services,
+            relay: self.relay,
+            start_height: start_height as i32,
+            local_nonces: self.local_nonces.clone(),
+            ..HandshakeConfig::default()
         }
     }
 
+    fn num_inbound_connections(&self) -> usize
+    {
+        self.connection_pool.values().filter(|entry| entry.inbound).count()
+    }
+
     fn has_enough_connection(&self) -> bool
     {
-        self.water_line <= self.connection_pool.len()
+        let num_outbound = self.connection_pool.len() - self.num_inbound_connections();
+        self.water_line <= num_outbound
     }
 
-    fn feed_initial_addrs(&mut self, ctx: &mut Context<Self>)
+    fn subscribe(&mut self, addr: Recipient<PoolEvent>)
+    {
+        self.subscribers.push(Subscriber { addr, failures: 0 });
+    }
+
+    fn publish(&mut self, event: PoolEvent)
     {
-        let seeds = match self.network {
-            Network::Bitcoin => &BITCOIN_DNS_SEEDS[..],
-            Network::Testnet => &TESTNET_DNS_SEEDS[..],
-            Network::Regtest => return,
+        self.events.on_event(match event {
+            PoolEvent::ConnectionEstablished(_, addr) => Event::ConnectionOpened(addr),
+            PoolEvent::ConnectionLost(addr) => Event::ConnectionClosed(addr),
+            PoolEvent::ConnectionBanned(addr) => Event::ConnectionBanned(addr),
+        });
+        for subscriber in self.subscribers.iter_mut() {
+            match subscriber.addr.do_send(event.clone()) {
+                Ok(()) => subscriber.failures = 0,
+                Err(e) => {
+                    debug!("Fail to send PoolEvent : {:?}", e);
+                    subscriber.failures += 1;
+                },
+            }
+        }
+        self.subscribers.retain(|s| s.failures < MAX_DELIVERY_FAILURES);
+    }
+
+    fn load_addrs(&mut self)
+    {
+        let path = match self.addr_file {
+            Some(ref path) => path,
+            None => return,
         };
-        let f = query_dns_seeds(&seeds)
+        match AddrManager::load(path, ADDR_POOL_SIZE) {
+            Ok(addrs) => {
+                info!("Load {} addresses from {:?}", addrs.len(), path);
+                self.addr_pool = addrs;
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => warn!("Could not load addresses from {:?} : {:?}", path, e),
+        }
+    }
+
+    /// Save address pool if it changed since the last save. A failed save is retried next time.
+    fn save_addrs(&mut self)
+    {
+        if !self.addr_pool.is_modified() {
+            return;
+        }
+        if let Some(ref path) = self.addr_file {
+            if let Err(e) = self.addr_pool.save(path) {
+                warn!("Could not save addresses to {:?} : {:?}", path, e);
+            }
+        }
+    }
+
+    fn feed_initial_addrs(&mut self, ctx: &mut Context<Self>)
+    {
+        // Services of bootstrap addresses are checked while handshake.
+        let now = unix_time();
+        for addr in self.bootstrap_addrs.iter() {
+            self.addr_pool.add(*addr, 0, now);
+        }
+
+        // `health_check` may call this again before the previous query finishes.
+        if self.dns_seeds.is_empty() || self.querying_dns_seeds {
+            return;
+        }
+        self.querying_dns_seeds = true;
+
+        let f = self.query_seeds()
             .into_actor(self)
             .map(|ips, actor, _ctx| {
-                let port = match actor.network {
-                    Network::Bitcoin => BITCOIN_PORT,
-                    Network::Testnet => TESTNET_PORT,
-                    Network::Regtest => unreachable!(),
-                };
-                for ip in ips {
-                    actor.addr_pool.push(SocketAddr::new(ip, port));
+                actor.querying_dns_seeds = false;
+                if ips.is_empty() {
+                    // Next `health_check` will retry.
+                    info!("No DNS seed answers");
+                    return;
                 }
+                actor.add_seed_ips(ips);
             })
-            .map_err(|e, _actor, ctx| {
+            .map_err(|e, actor, _ctx| {
+                // Next `health_check` will retry.
                 info!("Could not query dns seed : {:?}", e);
-                ctx.stop();
+                actor.querying_dns_seeds = false;
             });
-        ctx.wait(f);
+        // Use `spawn` instead of `wait` so that a slow DNS query does not block
+        // `health_check` and other messages.
+        ctx.spawn(f);
+    }
+
+    fn query_seeds(&self) -> Box<Future<Item = Vec<IpAddr>, Error = ResolveError>>
+    {
+        let (config, opts) = self.resolver_config.clone().unwrap_or_else(system_resolver_config);
+        (self.seed_query)(self.dns_seeds.clone(), config, opts)
+    }
+
+    fn add_seed_ips(&mut self, mut ips: Vec<IpAddr>)
+    {
+        self.rng.shuffle(&mut ips);
+        let port = default_port(self.network);
+        // DNS seeds return only full nodes.
+        let now = unix_time();
+        for ip in ips {
+            let addr = SocketAddr::new(ip, port);
+            if self.is_acceptable_addr(&addr) {
+                self.addr_pool.add(addr, NODE_NETWORK, now);
+            }
+        }
     }
 }
 
@@ -180,14 +907,17 @@ impl Handler<AddrsResponse> for ConnectionPool
 
     fn handle(&mut self, msg: AddrsResponse, _ctx: &mut Context<Self>)
     {
-        for (_ts, addr) in msg.0 {
-            if self.addr_pool.len() > ADDR_POOL_SIZE {
-                return;
-            }
-            if let Ok(a) = addr.socket_addr() {
-                self.addr_pool.push(a);
-            }
-        }
+        self.add_learned_addrs(msg.0, unix_time());
+    }
+}
+
+impl Handler<SampleAddrs> for ConnectionPool
+{
+    type Result = MessageResult<SampleAddrs>;
+
+    fn handle(&mut self, msg: SampleAddrs, _ctx: &mut Context<Self>) -> MessageResult<SampleAddrs>
+    {
+        MessageResult(self.sample_addrs(msg.max))
     }
 }
 
@@ -197,11 +927,19 @@ impl Handler<GetConnections> for ConnectionPool
 
     fn handle(&mut self, msg: GetConnections, _ctx: &mut Context<Self>) -> MessageResult<GetConnections>
     {
-        let iter = self.connection_pool
+        // Connections are being disconnected.
+        if self.shutting_down {
+            return MessageResult(Vec::new());
+        }
+        let mut candidates: Vec<_> = self.connection_pool
             .iter()
-            .filter(|addr| !msg.except.contains(addr))
-            .cloned();
-        let vec = sample_iter(&mut self.rng, iter, msg.num).unwrap_or_else(|v| v);
+            .filter(|&(addr, entry)| !msg.except.contains(addr) && has_services(entry.services, msg.services))
+            .map(|(addr, entry)| (addr.clone(), entry.median_ping))
+            .collect();
+        // Prefer peers which respond `ping` faster. Peers which are not measured yet come last in random order.
+        self.rng.shuffle(&mut candidates);
+        candidates.sort_by_key(|&(_, ping)| (ping.is_none(), ping));
+        let vec = candidates.into_iter().take(msg.num).map(|(addr, _)| addr).collect();
         MessageResult(vec)
     }
 }
@@ -212,21 +950,967 @@ impl Handler<BanConnection> for ConnectionPool
 
     fn handle(&mut self, msg: BanConnection, _ctx: &mut Context<Self>)
     {
-        if let Some(conn) = self.connection_pool.take(&msg.conn) {
-            // Even if it fail to send Disconnect message, if all Addr are dropped, underlying
-            // Connection will stop.
-            conn.do_send(Disconnect());
-        }
+        self.ban_connection(&msg.conn);
     }
 }
 
-fn query_dns_seeds(seeds: &'static [&'static str]) -> Box<Future<Item = Vec<IpAddr>, Error = ResolveError>>
+impl Handler<Shutdown> for ConnectionPool
 {
-    let f = ResolverFuture::new(ResolverConfig::google(), ResolverOpts::default())
-        .and_then(move |resolver| {
-            let resolve_fut_iter = seeds.iter().map(move |seed| resolver.lookup_ip(*seed));
-            ::futures::future::join_all(resolve_fut_iter)
+    type Result = ResponseActFuture<Self, (), ()>;
+
+    fn handle(&mut self, _msg: Shutdown, ctx: &mut Context<Self>) -> Self::Result
+    {
+        info!("Shutdown connection pool");
+        self.shutting_down = true;
+        ctx.cancel_future(self.health_check_handle);
+        self.save_addrs();
+        let disconnects: Vec<_> = self.connection_pool
+            .drain()
+            .map(|(conn, _entry)| {
+                conn.send(Disconnect())
+                    .timeout(SHUTDOWN_TIMEOUT)
+                    .then(|_res| Ok::<(), ()>(()))
+            })
+            .collect();
+        let f = join_all(disconnects)
+            .into_actor(self)
+            .map(|_, _actor, ctx| ctx.stop());
+        Box::new(f)
+    }
+}
+
+impl Handler<GetPoolStats> for ConnectionPool
+{
+    type Result = MessageResult<GetPoolStats>;
+
+    fn handle(&mut self, _msg: GetPoolStats, _ctx: &mut Context<Self>) -> MessageResult<GetPoolStats>
+    {
+        MessageResult(self.pool_stats(Instant::now()))
+    }
+}
+
+impl StreamHandler<Socket<TcpStream>, Error> for ConnectionPool
+{
+    fn handle(&mut self, socket: Socket<TcpStream>, ctx: &mut Context<Self>)
+    {
+        self.accept_connection(socket, ctx);
+    }
+
+    fn error(&mut self, err: Error, _ctx: &mut Context<Self>) -> Running
+    {
+        info!("Fail to accept connection : {:?}", err);
+        Running::Continue
+    }
+
+    fn finished(&mut self, _ctx: &mut Context<Self>)
+    {
+        info!("Listener is closed");
+    }
+}
+
+impl Handler<SubscribePoolEvents> for ConnectionPool
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribePoolEvents, _ctx: &mut Context<Self>)
+    {
+        self.subscribe(msg.addr);
+    }
+}
+
+/// Network group of an address. Bitcoin core groups IPv4 addresses by /16 and IPv6 by /32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum NetGroup
+{
+    V4([u8; 2]),
+    V6([u16; 2]),
+}
+
+fn netgroup(addr: &SocketAddr) -> NetGroup
+{
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            NetGroup::V4([o[0], o[1]])
+        },
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            NetGroup::V6([s[0], s[1]])
+        },
+    }
+}
+
+/// Check whether we can establish a new connection to `addr` or not.
+/// We do not connect to the same address twice, and keep connections to the same network group less
+/// than `max_per_netgroup`.
+fn is_dialable<'a, I>(addr: &SocketAddr, connected: I, max_per_netgroup: usize) -> bool
+where I: Iterator<Item = &'a SocketAddr>
+{
+    let group = netgroup(addr);
+    let mut num_same_group = 0;
+    for connected_addr in connected {
+        if connected_addr == addr {
+            return false;
+        }
+        if netgroup(connected_addr) == group {
+            num_same_group += 1;
+        }
+    }
+    num_same_group < max_per_netgroup
+}
+
+/// Check whether `addr` can be reached on the public internet.
+/// Loopback, private, link-local, multicast, unspecified and broadcast addresses are rejected,
+/// as well as port 0. An IPv4-mapped IPv6 address is checked as IPv4.
+pub fn is_routable(addr: &SocketAddr) -> bool
+{
+    if addr.port() == 0 {
+        return false;
+    }
+    match addr.ip() {
+        IpAddr::V4(ip) => is_routable_v4(&ip),
+        IpAddr::V6(ip) => match ipv4_mapped(&ip) {
+            Some(v4) => is_routable_v4(&v4),
+            None => !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || is_local_v6(&ip)),
+        },
+    }
+}
+
+fn is_routable_v4(ip: &Ipv4Addr) -> bool
+{
+    !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_multicast() || ip.is_unspecified()
+        || ip.is_broadcast())
+}
+
+/// Loopback, private or link-local address, which is only reachable on a local network.
+fn is_local(ip: &IpAddr) -> bool
+{
+    match *ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => match ipv4_mapped(&ip) {
+            Some(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local(),
+            None => ip.is_loopback() || is_local_v6(&ip),
+        },
+    }
+}
+
+/// Unique local (fc00::/7) or link-local (fe80::/10) address.
+fn is_local_v6(ip: &Ipv6Addr) -> bool
+{
+    let first = ip.segments()[0];
+    first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+}
+
+fn ipv4_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr>
+{
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, _, _] => ip.to_ipv4(),
+        _ => None,
+    }
+}
+
+/// How long we wait before dialing an address again after `failures` failures in a row.
+/// It starts from `MIN_BACKOFF` and doubles on every failure, up to `MAX_BACKOFF`.
+fn backoff(failures: u32) -> Duration
+{
+    if failures == 0 {
+        return Duration::from_secs(0);
+    }
+    let exp = cmp::min(failures - 1, 16);
+    cmp::min(MIN_BACKOFF * (1 << exp), MAX_BACKOFF)
+}
+
+/// Check whether an address can be dialed at `now` from its history and ban.
+fn is_eligible(history: Option<&AddrHistory>, banned_until: Option<Instant>, now: Instant) -> bool
+{
+    if let Some(until) = banned_until {
+        if now < until {
+            return false;
+        }
+    }
+    match history {
+        None => true,
+        Some(history) => history.last_attempt + backoff(history.failures) <= now,
+    }
+}
+
+/// Count a failure of `addr`. If `addr_history` gets longer than `MAX_ADDR_HISTORY`, the history which is
+/// attempted least recently is dropped, whose backoff is most likely expired already.
+fn record_failure(addr_history: &mut HashMap<SocketAddr, AddrHistory>, addr: SocketAddr, now: Instant)
+{
+    {
+        let history = addr_history.entry(addr).or_insert(AddrHistory {
+            failures: 0,
+            last_attempt: now,
+        });
+        history.failures = history.failures.saturating_add(1);
+        history.last_attempt = now;
+    }
+    if addr_history.len() > MAX_ADDR_HISTORY {
+        let oldest = addr_history
+            .iter()
+            .min_by_key(|&(_, history)| history.last_attempt)
+            .map(|(addr, _)| *addr);
+        if let Some(oldest) = oldest {
+            addr_history.remove(&oldest);
+        }
+    }
+}
+
+fn unix_time() -> u64
+{
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Check whether `services` contains all of `required` services.
+fn has_services(services: u64, required: u64) -> bool
+{
+    services & required == required
+}
+
+fn default_dns_seeds(network: Network) -> Vec<String>
+{
+    let seeds: &[&str] = match network {
+        Network::Bitcoin => &BITCOIN_DNS_SEEDS,
+        Network::Testnet => &TESTNET_DNS_SEEDS,
+        Network::Regtest => &[],
+    };
+    seeds.iter().map(|s| s.to_string()).collect()
+}
+
+fn default_port(network: Network) -> u16
+{
+    match network {
+        Network::Bitcoin => BITCOIN_PORT,
+        Network::Testnet => TESTNET_PORT,
+        Network::Regtest => REGTEST_PORT,
+    }
+}
+
+/// Query all DNS seeds concurrently.
+/// A seed which does not answer in `DNS_SEED_TIMEOUT` is skipped, so returned addresses may be
+/// partial or even empty. They are de-duplicated.
+fn query_dns_seeds(
+    seeds: Vec<String>,
+    config: ResolverConfig,
+    opts: ResolverOpts,
+) -> Box<Future<Item = Vec<IpAddr>, Error = ResolveError>>
+{
+    let f = ResolverFuture::new(config, opts)
+        .and_then(move |resolver| {
+            let resolve_fut_iter = seeds.into_iter().map(move |seed| {
+                let lookup = resolver.lookup_ip(seed.as_str());
+                Timeout::new(lookup, DNS_SEED_TIMEOUT).then(move |res| match res {
+                    Ok(ips) => Ok::<_, ResolveError>(ips.iter().collect::<Vec<_>>()),
+                    Err(e) => {
+                        info!("Skip DNS seed {} : {:?}", seed, e);
+                        Ok(Vec::new())
+                    },
+                })
+            });
+            ::futures::future::join_all(resolve_fut_iter)
         })
-        .map(|vec_ips| vec_ips.iter().flat_map(|ips| ips.iter()).collect::<Vec<_>>());
+        .map(|vec_ips| {
+            let mut ips: Vec<_> = vec_ips.into_iter().flat_map(|ips| ips).collect();
+            ips.sort();
+            ips.dedup();
+            ips
+        });
     Box::new(f)
 }
+
+/// Resolver config of the system.
+/// If the system config is not available, public resolvers are used.
+fn system_resolver_config() -> (ResolverConfig, ResolverOpts)
+{
+    match read_system_conf() {
+        Ok(conf) => conf,
+        Err(e) => {
+            info!("Could not read system resolver config : {:?}", e);
+            (ResolverConfig::google(), ResolverOpts::default())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::{cell::{Cell, RefCell}, rc::Rc};
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::{message::NetworkMessage, message_network::VersionMessage};
+    use futures::Stream;
+
+    use blockchain::BlockData;
+    use connection::message::Message;
+
+    use connection::socket::{begin_handshake_on, NODE_NETWORK_LIMITED, NODE_WITNESS};
+    use testing::{duplex, dummy_addrs, dummy_version_msg, loopback_peer, ScriptedPeer};
+
+    #[test]
+    fn filter_connections_by_services()
+    {
+        let full_node = NODE_NETWORK | NODE_WITNESS;
+        let pruned_node = NODE_NETWORK_LIMITED | NODE_WITNESS;
+
+        assert!(has_services(full_node, 0));
+        assert!(has_services(full_node, NODE_NETWORK));
+        assert!(has_services(full_node, NODE_NETWORK | NODE_WITNESS));
+        assert!(!has_services(pruned_node, NODE_NETWORK));
+        assert!(has_services(pruned_node, NODE_NETWORK_LIMITED));
+        assert!(!has_services(0, NODE_WITNESS));
+    }
+
+    #[test]
+    fn do_not_dial_connected_peer()
+    {
+        let connected: Vec<SocketAddr> = vec!["1.2.3.4:8333".parse().unwrap()];
+
+        assert!(!is_dialable(&"1.2.3.4:8333".parse().unwrap(), connected.iter(), 2));
+        assert!(is_dialable(&"5.6.7.8:8333".parse().unwrap(), connected.iter(), 2));
+
+        // Another port of the same host is another peer, but it is in the same network group.
+        assert!(is_dialable(&"1.2.3.4:18333".parse().unwrap(), connected.iter(), 2));
+        assert!(!is_dialable(&"1.2.3.4:18333".parse().unwrap(), connected.iter(), 1));
+    }
+
+    #[test]
+    fn limit_connections_per_netgroup()
+    {
+        let connected: Vec<SocketAddr> = vec![
+            "1.2.3.4:8333".parse().unwrap(),
+            "1.2.5.6:8333".parse().unwrap(),
+            "[2001:db8::1]:8333".parse().unwrap(),
+        ];
+
+        assert!(!is_dialable(&"1.2.7.8:8333".parse().unwrap(), connected.iter(), 2));
+        assert!(is_dialable(&"1.2.7.8:8333".parse().unwrap(), connected.iter(), 3));
+        assert!(is_dialable(&"1.3.7.8:8333".parse().unwrap(), connected.iter(), 2));
+        assert!(is_dialable(&"[2001:db9::1]:8333".parse().unwrap(), connected.iter(), 2));
+        assert!(!is_dialable(&"[2001:db8::2]:8333".parse().unwrap(), connected.iter(), 1));
+
+        // Only one peer in each /16 by default.
+        let max = DEFAULT_MAX_CONNECTIONS_PER_NETGROUP;
+        assert!(!is_dialable(&"1.2.200.1:8333".parse().unwrap(), connected[..1].iter(), max));
+        assert!(is_dialable(&"1.3.200.1:8333".parse().unwrap(), connected[..1].iter(), max));
+    }
+
+    #[test]
+    fn reject_unroutable_addresses()
+    {
+        let table = [
+            ("8.8.8.8:8333", true),
+            ("1.2.3.4:18333", true),
+            ("1.2.3.4:0", false),
+            ("127.0.0.1:8333", false),
+            ("10.0.0.1:8333", false),
+            ("172.16.5.4:8333", false),
+            ("192.168.1.1:8333", false),
+            ("169.254.0.1:8333", false),
+            ("224.0.0.1:8333", false),
+            ("0.0.0.0:8333", false),
+            ("255.255.255.255:8333", false),
+            ("[2001:4860:4860::8888]:8333", true),
+            ("[::1]:8333", false),
+            ("[::]:8333", false),
+            ("[fe80::1]:8333", false),
+            ("[fd00::1]:8333", false),
+            ("[ff02::1]:8333", false),
+            ("[::ffff:8.8.8.8]:8333", true),
+            ("[::ffff:192.168.1.1]:8333", false),
+        ];
+        for &(addr, expected) in table.iter() {
+            assert_eq!(is_routable(&addr.parse().unwrap()), expected, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn allow_private_addresses_only_when_configured()
+    {
+        let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Regtest)));
+        let mut pool = ConnectionPool::new(Network::Regtest, 0, 0, false, blockchain);
+        let private = "192.168.1.1:18444".parse().unwrap();
+        assert!(!pool.is_acceptable_addr(&private));
+
+        pool.set_allow_private_addrs(true);
+        assert!(pool.is_acceptable_addr(&private));
+        assert!(!pool.is_acceptable_addr(&"224.0.0.1:18444".parse().unwrap()));
+
+        // Our own addresses are never accepted.
+        pool.set_listen_addr("192.168.1.1:18444".parse().unwrap());
+        pool.external_ips.insert("1.2.3.4".parse().unwrap());
+        assert!(!pool.is_acceptable_addr(&private));
+        assert!(!pool.is_acceptable_addr(&"1.2.3.4:18444".parse().unwrap()));
+        assert!(pool.is_acceptable_addr(&"1.2.3.4:8333".parse().unwrap()));
+    }
+
+    fn stub_seed_query(
+        seeds: Vec<String>,
+        config: ResolverConfig,
+        _opts: ResolverOpts,
+    ) -> Box<Future<Item = Vec<IpAddr>, Error = ResolveError>>
+    {
+        assert_eq!(seeds, vec!["seed.example.com".to_string()]);
+        assert!(config.name_servers().is_empty());
+        let ips = vec!["8.8.8.8".parse().unwrap(), "192.168.1.1".parse().unwrap()];
+        Box::new(::futures::future::ok(ips))
+    }
+
+    #[test]
+    fn query_custom_dns_seeds_with_custom_resolver()
+    {
+        let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Testnet)));
+        let mut pool = ConnectionPool::new(Network::Testnet, 0, 0, false, blockchain)
+            .with_dns_seeds(vec!["seed.example.com".to_string()])
+            .with_resolver_config(ResolverConfig::new(), ResolverOpts::default());
+        pool.seed_query = stub_seed_query;
+
+        let ips = pool.query_seeds().wait().unwrap();
+        pool.add_seed_ips(ips);
+
+        // A private address from seeds is not accepted.
+        assert_eq!(pool.addr_pool.len(), 1);
+        let entry = pool.addr_pool.get(&"8.8.8.8:18333".parse().unwrap()).unwrap();
+        assert_eq!(entry.services, NODE_NETWORK);
+    }
+
+    #[test]
+    fn ignore_stale_addrs_and_addrs_without_required_services()
+    {
+        let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Bitcoin)));
+        let mut pool = ConnectionPool::new(Network::Bitcoin, 0, NODE_NETWORK, false, blockchain);
+        let now = 1_000_000;
+        let fresh: SocketAddr = "8.8.8.8:8333".parse().unwrap();
+        let stale: SocketAddr = "8.8.4.4:8333".parse().unwrap();
+        let limited: SocketAddr = "1.1.1.1:8333".parse().unwrap();
+        let future: SocketAddr = "1.0.0.1:8333".parse().unwrap();
+        let addrs = vec![
+            (now - 60, Address::new(&fresh, NODE_NETWORK)),
+            (now - 3 * 60 * 60 - 1, Address::new(&stale, NODE_NETWORK)),
+            (now - 60, Address::new(&limited, NODE_NETWORK_LIMITED)),
+            (now + 60, Address::new(&future, NODE_NETWORK)),
+        ];
+        pool.add_learned_addrs(addrs, now as u64);
+
+        assert_eq!(pool.addr_pool.len(), 2);
+        assert_eq!(pool.addr_pool.get(&fresh).unwrap().last_seen, (now - 60) as u64);
+        assert_eq!(pool.addr_pool.get(&future).unwrap().last_seen, now as u64);
+    }
+
+    #[test]
+    fn sample_addrs_with_their_timestamps()
+    {
+        let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Bitcoin)));
+        let mut pool = ConnectionPool::new(Network::Bitcoin, 0, 0, false, blockchain);
+        for i in 1..11 {
+            let addr = SocketAddr::new([8, 8, 8, i].into(), 8333);
+            pool.addr_pool.add(addr, NODE_NETWORK, 1000 + i as u64);
+        }
+
+        let sampled = pool.sample_addrs(3);
+        assert_eq!(sampled.len(), 3);
+        for (ts, addr) in sampled {
+            let socket_addr = addr.socket_addr().unwrap();
+            assert_eq!(ts as u64, pool.addr_pool.get(&socket_addr).unwrap().last_seen);
+            assert_eq!(addr.services, NODE_NETWORK);
+        }
+        assert_eq!(pool.sample_addrs(MAX_ADDRS_IN_MSG * 2).len(), 10);
+    }
+
+    #[test]
+    fn dial_bootstrap_addrs_before_others()
+    {
+        let bootstrap = "1.2.3.4:18444".parse().unwrap();
+        let learned = "5.6.7.8:18444".parse().unwrap();
+        let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Regtest)));
+        let mut pool =
+            ConnectionPool::new(Network::Regtest, 0, 0, false, blockchain).with_bootstrap_addrs(vec![bootstrap]);
+        assert!(pool.dns_seeds.is_empty());
+        pool.addr_pool.record_success(learned, NODE_NETWORK, unix_time());
+
+        let now = Instant::now();
+        assert_eq!(pool.next_addr(now), Some(bootstrap));
+
+        // Other addresses are dialed while bootstrap addresses are not available.
+        pool.dialing.insert(bootstrap);
+        assert_eq!(pool.next_addr(now), Some(learned));
+        pool.dialing.clear();
+        pool.record_failure(bootstrap, now);
+        assert_eq!(pool.next_addr(now), Some(learned));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max()
+    {
+        assert_eq!(backoff(0), Duration::from_secs(0));
+        assert_eq!(backoff(1), Duration::from_secs(60));
+        assert_eq!(backoff(2), Duration::from_secs(120));
+        assert_eq!(backoff(3), Duration::from_secs(240));
+        assert_eq!(backoff(6), Duration::from_secs(1920));
+        assert_eq!(backoff(7), MAX_BACKOFF);
+        assert_eq!(backoff(u32::max_value()), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn drop_least_recently_attempted_history()
+    {
+        let now = Instant::now();
+        let mut addr_history = HashMap::new();
+        let addr = |i: usize| SocketAddr::from(([1, 2, (i >> 8) as u8, i as u8], 8333));
+        for i in 0..MAX_ADDR_HISTORY {
+            record_failure(&mut addr_history, addr(i), now + Duration::from_secs(i as u64));
+        }
+        // Another failure of a known address does not drop anything.
+        record_failure(&mut addr_history, addr(0), now + Duration::from_secs(MAX_ADDR_HISTORY as u64));
+        assert_eq!(addr_history.len(), MAX_ADDR_HISTORY);
+        assert_eq!(addr_history[&addr(0)].failures, 2);
+
+        // `addr(1)` is attempted least recently now.
+        record_failure(&mut addr_history, addr(MAX_ADDR_HISTORY), now + Duration::from_secs(2000));
+        assert_eq!(addr_history.len(), MAX_ADDR_HISTORY);
+        assert!(!addr_history.contains_key(&addr(1)));
+        assert!(addr_history.contains_key(&addr(0)));
+        assert!(addr_history.contains_key(&addr(MAX_ADDR_HISTORY)));
+    }
+
+    #[test]
+    fn eligibility_of_addresses()
+    {
+        let now = Instant::now();
+        let history = AddrHistory {
+            failures: 2,
+            last_attempt: now,
+        };
+
+        // No history means no failure.
+        assert!(is_eligible(None, None, now));
+
+        // Backoff expires just at `last_attempt + backoff`.
+        assert!(!is_eligible(Some(&history), None, now));
+        assert!(!is_eligible(Some(&history), None, now + Duration::from_secs(119)));
+        assert!(is_eligible(Some(&history), None, now + Duration::from_secs(120)));
+
+        // Ban expires just at `banned_until` even if there is no failure.
+        let until = now + DEFAULT_BAN_DURATION;
+        assert!(!is_eligible(None, Some(until), now));
+        assert!(!is_eligible(None, Some(until), until - Duration::from_secs(1)));
+        assert!(is_eligible(None, Some(until), until));
+
+        // Both of them must be expired.
+        assert!(!is_eligible(Some(&history), Some(now + Duration::from_secs(10)), now + Duration::from_secs(60)));
+        assert!(!is_eligible(Some(&history), Some(now + Duration::from_secs(200)), now + Duration::from_secs(120)));
+        assert!(is_eligible(Some(&history), Some(now + Duration::from_secs(10)), now + Duration::from_secs(120)));
+    }
+
+    // Record `PoolEvent`s. It bans an established connection, then stops the system when it is banned.
+    struct EventRecorder
+    {
+        events: Rc<RefCell<Vec<(&'static str, SocketAddr)>>>,
+        pool: Addr<ConnectionPool>,
+    }
+
+    impl Actor for EventRecorder
+    {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<PoolEvent> for EventRecorder
+    {
+        type Result = ();
+
+        fn handle(&mut self, event: PoolEvent, _ctx: &mut Context<Self>)
+        {
+            match event {
+                PoolEvent::ConnectionEstablished(conn, addr) => {
+                    self.events.borrow_mut().push(("established", addr));
+                    self.pool.do_send(BanConnection { conn });
+                },
+                PoolEvent::ConnectionLost(addr) => self.events.borrow_mut().push(("lost", addr)),
+                PoolEvent::ConnectionBanned(addr) => {
+                    self.events.borrow_mut().push(("banned", addr));
+                    System::current().stop();
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn subscriber_receives_pool_events()
+    {
+        // A scripted peer listens for a connection from the pool.
+        let (listen_addr, peer) = loopback_peer(Network::Regtest, |peer| {
+            peer.handshake(0).run_and_serve(|_msg| Vec::new())
+        });
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events2 = events.clone();
+
+        System::run(move || {
+            Arbiter::spawn(peer);
+
+            let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Regtest)));
+            let pool = ConnectionPool::create(move |ctx| {
+                let mut pool = ConnectionPool::new(Network::Regtest, 0, 0, false, blockchain);
+                pool.add_connection(&listen_addr, ctx);
+                pool
+            });
+            let recorder = EventRecorder {
+                events: events2,
+                pool: pool.clone(),
+            }.start();
+            pool.do_send(SubscribePoolEvents {
+                addr: recorder.recipient(),
+            });
+        });
+
+        assert_eq!(*events.borrow(), vec![("established", listen_addr), ("banned", listen_addr)]);
+    }
+
+    // Wait for `num` connections, then record addresses of connections which `GetConnections` returns.
+    struct ServicesFilterChecker
+    {
+        pool: Addr<ConnectionPool>,
+        num: usize,
+        services: u64,
+        established: Vec<(Addr<Connection>, SocketAddr)>,
+        returned: Rc<RefCell<Vec<SocketAddr>>>,
+    }
+
+    impl Actor for ServicesFilterChecker
+    {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<PoolEvent> for ServicesFilterChecker
+    {
+        type Result = ();
+
+        fn handle(&mut self, event: PoolEvent, ctx: &mut Context<Self>)
+        {
+            match event {
+                PoolEvent::ConnectionEstablished(conn, addr) => self.established.push((conn, addr)),
+                _ => return,
+            }
+            if self.established.len() < self.num {
+                return;
+            }
+            let req = GetConnections {
+                num: self.num,
+                except: Vec::new(),
+                services: self.services,
+            };
+            let f = self.pool
+                .send(req)
+                .into_actor(self)
+                .map(|conns, actor, _ctx| {
+                    for conn in conns {
+                        let &(_, addr) = actor.established.iter().find(|&&(ref c, _)| *c == conn).unwrap();
+                        actor.returned.borrow_mut().push(addr);
+                    }
+                    System::current().stop();
+                })
+                .map_err(|e, _actor, _ctx| panic!("Pool is stopped : {:?}", e));
+            ctx.spawn(f);
+        }
+    }
+
+    #[test]
+    fn get_only_connections_to_peers_with_requested_services()
+    {
+        let peer_with = |services| {
+            loopback_peer(Network::Regtest, move |peer| {
+                let version = VersionMessage {
+                    services,
+                    ..dummy_version_msg(0)
+                };
+                peer.handshake_with(version).run_and_serve(|_msg| Vec::new())
+            })
+        };
+        let (full_addr, full_peer) = peer_with(NODE_NETWORK | NODE_WITNESS);
+        let (pruned_addr, pruned_peer) = peer_with(NODE_NETWORK_LIMITED | NODE_WITNESS);
+        let returned = Rc::new(RefCell::new(Vec::new()));
+        let returned2 = returned.clone();
+
+        System::run(move || {
+            Arbiter::spawn(full_peer);
+            Arbiter::spawn(pruned_peer);
+
+            let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Regtest)));
+            let pool = ConnectionPool::create(move |ctx| {
+                let mut pool = ConnectionPool::new(Network::Regtest, 0, 0, false, blockchain);
+                pool.set_allow_private_addrs(true);
+                pool.add_connection(&full_addr, ctx);
+                pool.add_connection(&pruned_addr, ctx);
+                pool
+            });
+            let checker = ServicesFilterChecker {
+                pool: pool.clone(),
+                num: 2,
+                services: NODE_NETWORK,
+                established: Vec::new(),
+                returned: returned2,
+            }.start();
+            pool.do_send(SubscribePoolEvents {
+                addr: checker.recipient(),
+            });
+        });
+
+        assert_eq!(*returned.borrow(), vec![full_addr]);
+    }
+
+    #[test]
+    fn connect_to_loopback_peers_on_different_ports()
+    {
+        let peer = || loopback_peer(Network::Regtest, |peer| peer.handshake(0).run_and_serve(|_msg| Vec::new()));
+        let (addr1, peer1) = peer();
+        let (addr2, peer2) = peer();
+        assert_eq!(addr1.ip(), addr2.ip());
+        let returned = Rc::new(RefCell::new(Vec::new()));
+        let returned2 = returned.clone();
+
+        System::run(move || {
+            Arbiter::spawn(peer1);
+            Arbiter::spawn(peer2);
+
+            let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Regtest)));
+            let pool = ConnectionPool::create(move |ctx| {
+                let mut pool = ConnectionPool::new(Network::Regtest, 0, 0, false, blockchain);
+                pool.set_allow_private_addrs(true);
+                pool.add_connection(&addr1, ctx);
+                pool.add_connection(&addr2, ctx);
+                pool
+            });
+            let checker = ServicesFilterChecker {
+                pool: pool.clone(),
+                num: 2,
+                services: 0,
+                established: Vec::new(),
+                returned: returned2,
+            }.start();
+            pool.do_send(SubscribePoolEvents {
+                addr: checker.recipient(),
+            });
+        });
+
+        let returned = returned.borrow();
+        assert_eq!(returned.len(), 2);
+        assert!(returned.contains(&addr1));
+        assert!(returned.contains(&addr2));
+    }
+
+    #[test]
+    fn connect_to_bootstrap_addrs_on_regtest()
+    {
+        let (listen_addr, peer) = loopback_peer(Network::Regtest, |peer| {
+            peer.handshake(0).run_and_serve(|_msg| Vec::new())
+        });
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events2 = events.clone();
+
+        System::run(move || {
+            Arbiter::spawn(peer);
+
+            // Regtest does not have DNS seeds, so the pool dials only bootstrap addresses.
+            let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Regtest)));
+            let pool = ConnectionPool::new(Network::Regtest, 0, 0, false, blockchain)
+                .with_bootstrap_addrs(vec![listen_addr])
+                .start();
+            let recorder = EventRecorder {
+                events: events2,
+                pool: pool.clone(),
+            }.start();
+            pool.do_send(SubscribePoolEvents {
+                addr: recorder.recipient(),
+            });
+        });
+
+        assert_eq!(*events.borrow(), vec![("established", listen_addr), ("banned", listen_addr)]);
+    }
+
+    #[test]
+    fn accept_inbound_connection_on_listener()
+    {
+        // Find a free port.
+        let listen_addr = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events2 = events.clone();
+        let remote_height = Rc::new(Cell::new(None));
+        let remote_height2 = remote_height.clone();
+
+        System::run(move || {
+            let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Regtest)));
+            let pool = ConnectionPool::new(Network::Regtest, 0, 0, false, blockchain)
+                .with_listener(listen_addr)
+                .start();
+            let recorder = EventRecorder {
+                events: events2,
+                pool: pool.clone(),
+            }.start();
+            pool.do_send(SubscribePoolEvents {
+                addr: recorder.recipient(),
+            });
+
+            // An outbound socket dials the pool, then keeps the connection until the pool closes it.
+            let peer = Socket::connect(&listen_addr, Network::Regtest)
+                .and_then(|socket| socket.begin_handshake_with_config(HandshakeConfig::default()))
+                .and_then(move |socket| {
+                    remote_height2.set(Some(socket.remote_start_height()));
+                    socket.recv_msg_stream().for_each(|_| Ok(())).then(|_| Ok(()))
+                })
+                .map_err(|e| panic!("Outbound socket fails : {:?}", e));
+            Arbiter::spawn(peer);
+        });
+
+        // The pool advertises the height of genesis block.
+        assert_eq!(remote_height.get(), Some(0));
+        let events = events.borrow();
+        assert_eq!(events.iter().map(|&(event, _)| event).collect::<Vec<_>>(), vec!["established", "banned"]);
+        assert!(events[0].1.ip().is_loopback());
+    }
+
+    #[test]
+    fn advertise_current_height_of_blockchain()
+    {
+        let advertised = Rc::new(Cell::new(None));
+        let advertised2 = advertised.clone();
+        // A peer which records `start_height` of our `version` message.
+        let (listen_addr, peer) = loopback_peer(Network::Regtest, move |peer| {
+            peer.run_and_serve(move |msg| match msg {
+                Message::Network(NetworkMessage::Version(v)) => {
+                    advertised2.set(Some(v.start_height));
+                    vec![NetworkMessage::Version(dummy_version_msg(0)).into()]
+                },
+                Message::Network(NetworkMessage::Verack) => vec![NetworkMessage::Verack.into()],
+                _ => Vec::new(),
+            })
+        });
+
+        System::run(move || {
+            Arbiter::spawn(peer);
+
+            let start = BlockData::new(genesis_block(Network::Regtest).header, 100);
+            let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, start)));
+            let pool = ConnectionPool::create(move |ctx| {
+                let mut pool = ConnectionPool::new(Network::Regtest, 0, 0, false, blockchain);
+                pool.add_connection(&listen_addr, ctx);
+                pool
+            });
+            let recorder = EventRecorder {
+                events: Rc::new(RefCell::new(Vec::new())),
+                pool: pool.clone(),
+            }.start();
+            pool.do_send(SubscribePoolEvents {
+                addr: recorder.recipient(),
+            });
+        });
+
+        assert_eq!(advertised.get(), Some(100));
+    }
+
+    #[test]
+    fn advertise_configured_user_agent_and_protocol_version()
+    {
+        let advertised = Rc::new(RefCell::new(None));
+        let advertised2 = advertised.clone();
+        // A peer which records our `version` message.
+        let (listen_addr, peer) = loopback_peer(Network::Regtest, move |peer| {
+            peer.run_and_serve(move |msg| match msg {
+                Message::Network(NetworkMessage::Version(v)) => {
+                    *advertised2.borrow_mut() = Some((v.user_agent, v.version));
+                    vec![NetworkMessage::Version(dummy_version_msg(0)).into()]
+                },
+                Message::Network(NetworkMessage::Verack) => vec![NetworkMessage::Verack.into()],
+                _ => Vec::new(),
+            })
+        });
+
+        System::run(move || {
+            Arbiter::spawn(peer);
+
+            let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Regtest)));
+            let pool = ConnectionPool::create(move |ctx| {
+                let mut pool = ConnectionPool::new(Network::Regtest, 0, 0, false, blockchain);
+                pool.set_user_agent("/embedder:1.0/".into());
+                pool.set_protocol_version(70012);
+                pool.add_connection(&listen_addr, ctx);
+                pool
+            });
+            let recorder = EventRecorder {
+                events: Rc::new(RefCell::new(Vec::new())),
+                pool: pool.clone(),
+            }.start();
+            pool.do_send(SubscribePoolEvents {
+                addr: recorder.recipient(),
+            });
+        });
+
+        assert_eq!(*advertised.borrow(), Some(("/embedder:1.0/".to_string(), 70012)));
+    }
+
+    #[test]
+    fn shutdown_disconnects_every_connection()
+    {
+        let num_closed = Rc::new(Cell::new(0));
+        let shutdown = Rc::new(Cell::new(false));
+        let (num_closed2, shutdown2) = (num_closed.clone(), shutdown.clone());
+
+        // Stop the system when shutdown completes and every peer observes EOF.
+        let (c, s) = (num_closed.clone(), shutdown.clone());
+        let stop_if_done = Rc::new(move || {
+            if c.get() == 2 && s.get() {
+                System::current().stop();
+            }
+        });
+
+        System::run(move || {
+            let (local_addr, peer_addr) = dummy_addrs();
+            let mut conns = Vec::new();
+            for _ in 0..2 {
+                let (local, remote) = duplex();
+                let (num_closed, stop_if_done) = (num_closed2.clone(), stop_if_done.clone());
+                let peer = ScriptedPeer::new(remote, Network::Regtest)
+                    .handshake(0)
+                    .run_and_serve(|_msg| Vec::new())
+                    .map(move |()| {
+                        num_closed.set(num_closed.get() + 1);
+                        stop_if_done();
+                    })
+                    .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+                Arbiter::spawn(peer);
+
+                let socket = Socket::new(local, Network::Regtest);
+                let conn = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr)
+                    .map(|socket| Connection::start_actor(socket));
+                conns.push(conn);
+            }
+
+            let f = join_all(conns)
+                .map_err(|e| panic!("Fail to handshake : {:?}", e))
+                .and_then(move |conns| {
+                    let pool = ConnectionPool::create(move |_ctx| {
+                        let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Regtest)));
+                        let mut pool = ConnectionPool::new(Network::Regtest, 0, 0, false, blockchain);
+                        for conn in conns {
+                            let entry = PoolEntry {
+                                socket_addr: peer_addr,
+                                services: 0,
+                                inbound: true,
+                                median_ping: None,
+                            };
+                            pool.connection_pool.insert(conn, entry);
+                        }
+                        pool
+                    });
+                    ConnectionPool::shutdown(pool)
+                })
+                .map(move |()| {
+                    shutdown2.set(true);
+                    stop_if_done();
+                });
+            Arbiter::spawn(f);
+        });
+
+        assert!(shutdown.get());
+        assert_eq!(num_closed.get(), 2);
+    }
+}
@@ -1,6 +0,0 @@
-#[derive(Debug, Fail)]
-pub enum ConnectionError
-{
-    #[fail(display = "Detect misbehavior peer")]
-    MisbehavePeer,
-}
@@ -0,0 +1,151 @@
+use bitcoin::network::{encodable::{ConsensusDecodable, ConsensusEncodable},
+                       message::{NetworkMessage, RawNetworkMessage}, serialize::{SimpleDecoder, SimpleEncoder}};
+use bitcoin::util::hash::Sha256dHash;
+
+use bloom::{BloomFilter, MerkleBlock};
+use connection::compact::{BlockTransactions, BlockTransactionsRequest, HeaderAndShortIds, SendCmpct};
+
+/// `MSG_FILTERED_BLOCK` inventory type (BIP 37).
+pub const MSG_FILTERED_BLOCK: u32 = 3;
+
+/// The min protocol version of peer which understands `sendheaders` message (BIP 130).
+pub const SEND_HEADERS_MIN_PROTOCOL_VERSION: u32 = 70012;
+
+/// A message of bitcoin protocol.
+/// `bitcoin` crate does not support some messages, so we define them here.
+#[derive(Debug, Clone)]
+pub enum Message
+{
+    Network(NetworkMessage),
+    /// `getdata` message with inventory types which `bitcoin` crate does not know.
+    GetDataRaw(Vec<RawInventory>),
+    FilterLoad(BloomFilter),
+    FilterAdd(Vec<u8>),
+    FilterClear,
+    MerkleBlock(MerkleBlock),
+    SendCmpct(SendCmpct),
+    CmpctBlock(HeaderAndShortIds),
+    GetBlockTxn(BlockTransactionsRequest),
+    BlockTxn(BlockTransactions),
+    Reject(Reject),
+    /// Ask peer to announce new blocks by `headers` message instead of `inv` (BIP 130).
+    SendHeaders,
+    /// Ask peer not to announce transactions whose fee rate is lower than this, in satoshis per 1000 bytes (BIP 133).
+    FeeFilter(u64),
+    /// A message of a command which we do not understand, e.g. `wtxidrelay`. Its payload is skipped.
+    Unknown(String),
+}
+
+impl Message
+{
+    /// Command name of this message. e.g. "version"
+    pub fn command(&self) -> String
+    {
+        match *self {
+            Message::Network(ref msg) => {
+                let raw = RawNetworkMessage {
+                    magic: 0,
+                    payload: msg.clone(),
+                };
+                raw.command()
+            },
+            Message::GetDataRaw(_) => "getdata".into(),
+            Message::FilterLoad(_) => "filterload".into(),
+            Message::FilterAdd(_) => "filteradd".into(),
+            Message::FilterClear => "filterclear".into(),
+            Message::MerkleBlock(_) => "merkleblock".into(),
+            Message::SendCmpct(_) => "sendcmpct".into(),
+            Message::CmpctBlock(_) => "cmpctblock".into(),
+            Message::GetBlockTxn(_) => "getblocktxn".into(),
+            Message::BlockTxn(_) => "blocktxn".into(),
+            Message::Reject(_) => "reject".into(),
+            Message::SendHeaders => "sendheaders".into(),
+            Message::FeeFilter(_) => "feefilter".into(),
+            Message::Unknown(ref command) => command.clone(),
+        }
+    }
+}
+
+impl From<NetworkMessage> for Message
+{
+    fn from(msg: NetworkMessage) -> Message
+    {
+        Message::Network(msg)
+    }
+}
+
+/// An inventory whose type is a raw number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawInventory
+{
+    pub inv_type: u32,
+    pub hash: Sha256dHash,
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for RawInventory
+{
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error>
+    {
+        self.inv_type.consensus_encode(s)?;
+        self.hash.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for RawInventory
+{
+    fn consensus_decode(d: &mut D) -> Result<RawInventory, D::Error>
+    {
+        Ok(RawInventory {
+            inv_type: ConsensusDecodable::consensus_decode(d)?,
+            hash: ConsensusDecodable::consensus_decode(d)?,
+        })
+    }
+}
+
+/// `reject` message (BIP 61).
+/// Peer sends it when it rejects a message we sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reject
+{
+    /// Command name of rejected message. e.g. "tx"
+    pub message: String,
+    pub ccode: u8,
+    pub reason: String,
+    /// Hash of rejected transaction or block.
+    /// Only `tx` and `block` rejections have it.
+    pub data: Option<Sha256dHash>,
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for Reject
+{
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error>
+    {
+        self.message.consensus_encode(s)?;
+        self.ccode.consensus_encode(s)?;
+        self.reason.consensus_encode(s)?;
+        match self.data {
+            Some(ref hash) => hash.consensus_encode(s),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for Reject
+{
+    fn consensus_decode(d: &mut D) -> Result<Reject, D::Error>
+    {
+        let message: String = ConsensusDecodable::consensus_decode(d)?;
+        let ccode = ConsensusDecodable::consensus_decode(d)?;
+        let reason = ConsensusDecodable::consensus_decode(d)?;
+        let data = match message.as_str() {
+            "tx" | "block" => Some(ConsensusDecodable::consensus_decode(d)?),
+            _ => None,
+        };
+        Ok(Reject {
+            message,
+            ccode,
+            reason,
+            data,
+        })
+    }
+}
@@ -0,0 +1,251 @@
+use std::time::{Duration, Instant};
+
+/// A kind of protocol violation by a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation
+{
+    /// Peer sends a message which we do not request. It may happen naturally after a race.
+    UnsolicitedMessage,
+    /// Peer sends a block header which can not be added to blockchain.
+    InvalidHeader,
+    /// Peer sends a block which fails validation.
+    InvalidBlock,
+    /// Peer sends a message whose checksum does not match.
+    ChecksumFailure,
+    /// Peer sends a message which exceeds the protocol limit of its command.
+    InvalidMessageSize,
+    /// Peer sends a message which is close to the protocol limit of its command.
+    /// It is not a violation by itself, so it weighs nothing by default.
+    NearLimitMessage,
+    /// Peer sends messages of a command faster than its rate limit allows.
+    /// Each dropped message weighs a little, so only a sustained flood reaches the threshold.
+    RateLimited,
+}
+
+/// Weights of violations and a threshold to ban a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MisbehaviorPolicy
+{
+    pub unsolicited_message: u32,
+    pub invalid_header: u32,
+    pub invalid_block: u32,
+    pub checksum_failure: u32,
+    pub invalid_message_size: u32,
+    pub near_limit_message: u32,
+    pub rate_limited: u32,
+    /// Peer is disconnected and banned when its score reaches this.
+    pub threshold: u32,
+    /// Score decreases by one every this duration.
+    pub decay_interval: Duration,
+}
+
+impl Default for MisbehaviorPolicy
+{
+    fn default() -> MisbehaviorPolicy
+    {
+        MisbehaviorPolicy {
+            unsolicited_message: 1,
+            invalid_header: 20,
+            invalid_block: 100,
+            checksum_failure: 100,
+            invalid_message_size: 20,
+            near_limit_message: 0,
+            rate_limited: 1,
+            threshold: 100,
+            decay_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl MisbehaviorPolicy
+{
+    pub fn weight(&self, violation: Violation) -> u32
+    {
+        match violation {
+            Violation::UnsolicitedMessage => self.unsolicited_message,
+            Violation::InvalidHeader => self.invalid_header,
+            Violation::InvalidBlock => self.invalid_block,
+            Violation::ChecksumFailure => self.checksum_failure,
+            Violation::InvalidMessageSize => self.invalid_message_size,
+            Violation::NearLimitMessage => self.near_limit_message,
+            Violation::RateLimited => self.rate_limited,
+        }
+    }
+}
+
+/// The number of violations of each kind since connection is established.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Violations
+{
+    pub unsolicited_message: u32,
+    pub invalid_header: u32,
+    pub invalid_block: u32,
+    pub checksum_failure: u32,
+    pub invalid_message_size: u32,
+    pub near_limit_message: u32,
+    pub rate_limited: u32,
+    pub last_violation: Option<Instant>,
+}
+
+impl Violations
+{
+    fn record(&mut self, violation: Violation, now: Instant)
+    {
+        let count = match violation {
+            Violation::UnsolicitedMessage => &mut self.unsolicited_message,
+            Violation::InvalidHeader => &mut self.invalid_header,
+            Violation::InvalidBlock => &mut self.invalid_block,
+            Violation::ChecksumFailure => &mut self.checksum_failure,
+            Violation::InvalidMessageSize => &mut self.invalid_message_size,
+            Violation::NearLimitMessage => &mut self.near_limit_message,
+            Violation::RateLimited => &mut self.rate_limited,
+        };
+        *count = count.saturating_add(1);
+        self.last_violation = Some(now);
+    }
+}
+
+/// Misbehavior score of a peer, which decays over time.
+#[derive(Debug, Clone, Copy)]
+pub struct MisbehaviorScore
+{
+    score: u32,
+    // When `score` is decayed last time.
+    decayed_at: Instant,
+    violations: Violations,
+}
+
+impl MisbehaviorScore
+{
+    pub fn new(now: Instant) -> MisbehaviorScore
+    {
+        MisbehaviorScore {
+            score: 0,
+            decayed_at: now,
+            violations: Violations::default(),
+        }
+    }
+
+    /// Add a weight of `violation` to the score.
+    /// Returns true if the score reaches the threshold of `policy`.
+    pub fn report(&mut self, violation: Violation, policy: &MisbehaviorPolicy, now: Instant) -> bool
+    {
+        self.decay(policy, now);
+        self.score = self.score.saturating_add(policy.weight(violation));
+        self.violations.record(violation, now);
+        policy.threshold <= self.score
+    }
+
+    pub fn score(&mut self, policy: &MisbehaviorPolicy, now: Instant) -> u32
+    {
+        self.decay(policy, now);
+        self.score
+    }
+
+    pub fn violations(&self) -> Violations
+    {
+        self.violations
+    }
+
+    fn decay(&mut self, policy: &MisbehaviorPolicy, now: Instant)
+    {
+        let interval = duration_as_millis(policy.decay_interval);
+        if interval == 0 || now < self.decayed_at {
+            return;
+        }
+        let steps = duration_as_millis(now - self.decayed_at) / interval;
+        if steps == 0 {
+            return;
+        }
+        self.score = self.score.saturating_sub(saturate_u32(steps));
+        // Keep the remainder so that frequent queries do not stop decay.
+        self.decayed_at += policy.decay_interval * saturate_u32(steps);
+    }
+}
+
+pub(super) fn duration_as_millis(d: Duration) -> u64
+{
+    d.as_secs() * 1000 + d.subsec_millis() as u64
+}
+
+fn saturate_u32(n: u64) -> u32
+{
+    if n > u32::max_value() as u64 {
+        u32::max_value()
+    } else {
+        n as u32
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn benign_violation_does_not_reach_threshold()
+    {
+        let policy = MisbehaviorPolicy::default();
+        let now = Instant::now();
+        let mut score = MisbehaviorScore::new(now);
+
+        assert!(!score.report(Violation::UnsolicitedMessage, &policy, now));
+        assert_eq!(score.score(&policy, now), 1);
+        assert_eq!(score.violations().unsolicited_message, 1);
+    }
+
+    #[test]
+    fn repeated_invalid_headers_reach_threshold()
+    {
+        let policy = MisbehaviorPolicy::default();
+        let now = Instant::now();
+        let mut score = MisbehaviorScore::new(now);
+
+        for _ in 0..4 {
+            assert!(!score.report(Violation::InvalidHeader, &policy, now));
+        }
+        assert!(score.report(Violation::InvalidHeader, &policy, now));
+        assert_eq!(score.violations().invalid_header, 5);
+        assert_eq!(score.violations().last_violation, Some(now));
+    }
+
+    #[test]
+    fn score_decays_over_time()
+    {
+        let policy = MisbehaviorPolicy::default();
+        let now = Instant::now();
+        let mut score = MisbehaviorScore::new(now);
+        score.report(Violation::InvalidHeader, &policy, now);
+
+        assert_eq!(score.score(&policy, now + Duration::from_secs(59)), 20);
+        assert_eq!(score.score(&policy, now + Duration::from_secs(90)), 19);
+        assert_eq!(score.score(&policy, now + Duration::from_secs(120)), 18);
+        assert_eq!(score.score(&policy, now + Duration::from_secs(60 * 60)), 0);
+
+        // Decayed score needs more violations to reach threshold again.
+        let later = now + Duration::from_secs(60 * 60);
+        for _ in 0..4 {
+            assert!(!score.report(Violation::InvalidHeader, &policy, later));
+        }
+        assert!(score.report(Violation::InvalidHeader, &policy, later));
+    }
+
+    #[test]
+    fn near_limit_messages_are_counted_but_weigh_nothing_by_default()
+    {
+        let now = Instant::now();
+        let mut score = MisbehaviorScore::new(now);
+        for _ in 0..1000 {
+            assert!(!score.report(Violation::NearLimitMessage, &MisbehaviorPolicy::default(), now));
+        }
+        assert_eq!(score.score(&MisbehaviorPolicy::default(), now), 0);
+        assert_eq!(score.violations().near_limit_message, 1000);
+
+        let strict = MisbehaviorPolicy {
+            near_limit_message: 10,
+            ..MisbehaviorPolicy::default()
+        };
+        assert!(!score.report(Violation::NearLimitMessage, &strict, now));
+        assert_eq!(score.score(&strict, now), 10);
+    }
+}
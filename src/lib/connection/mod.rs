@@ -1,8 +1,16 @@
+#[cfg(feature = "actix-net")]
 mod connection;
-mod error;
+
+pub mod addr_manager;
+pub mod codec;
+pub mod compact;
+pub mod message;
+pub mod misbehavior;
+pub mod rate_limit;
 
 pub mod socket;
+#[cfg(feature = "actix-net")]
 pub mod connection_pool;
 
+#[cfg(feature = "actix-net")]
 pub use self::connection::*;
-pub use self::error::ConnectionError;
@@ -0,0 +1,226 @@
+use std::time::{Duration, Instant};
+
+use bitcoin::network::message::NetworkMessage;
+
+use connection::{message::Message, misbehavior::duration_as_millis};
+
+/// At most `capacity` messages per `per`. A burst of `capacity` messages is allowed after a quiet period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit
+{
+    pub capacity: u32,
+    pub per: Duration,
+}
+
+impl RateLimit
+{
+    pub fn per_minute(capacity: u32) -> RateLimit
+    {
+        RateLimit {
+            capacity,
+            per: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Rate limits of messages which peer sends without our request.
+/// `None` means the command is not limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimits
+{
+    pub addr: Option<RateLimit>,
+    pub inv: Option<RateLimit>,
+}
+
+impl Default for RateLimits
+{
+    fn default() -> RateLimits
+    {
+        RateLimits {
+            addr: Some(RateLimit::per_minute(10)),
+            inv: Some(RateLimit::per_minute(100)),
+        }
+    }
+}
+
+/// The number of messages which are dropped since they exceed rate limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimitedMessages
+{
+    pub addr: u64,
+    pub inv: u64,
+}
+
+/// Tokens refill continuously at the rate of a `RateLimit`, and each message takes one.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket
+{
+    tokens: u32,
+    // When `tokens` is refilled last time.
+    refilled_at: Instant,
+}
+
+impl TokenBucket
+{
+    /// A bucket which is full of tokens.
+    pub fn new(limit: &RateLimit, now: Instant) -> TokenBucket
+    {
+        TokenBucket {
+            tokens: limit.capacity,
+            refilled_at: now,
+        }
+    }
+
+    /// Take a token. Returns false if no token is left.
+    pub fn take(&mut self, limit: &RateLimit, now: Instant) -> bool
+    {
+        self.refill(limit, now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant)
+    {
+        let per = duration_as_millis(limit.per);
+        let capacity = limit.capacity as u64;
+        if per == 0 {
+            self.tokens = limit.capacity;
+            return;
+        }
+        if now <= self.refilled_at {
+            return;
+        }
+        let added = duration_as_millis(now - self.refilled_at) * capacity / per;
+        if added == 0 {
+            return;
+        }
+        if capacity <= self.tokens as u64 + added {
+            self.tokens = limit.capacity;
+            self.refilled_at = now;
+        } else {
+            self.tokens += added as u32;
+            // Keep the remainder so that frequent messages do not stop refilling.
+            self.refilled_at += Duration::from_millis(added * per / capacity);
+        }
+    }
+}
+
+/// Token buckets of each limited command of a connection.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimiter
+{
+    limits: RateLimits,
+    addr: Option<TokenBucket>,
+    inv: Option<TokenBucket>,
+    dropped: RateLimitedMessages,
+}
+
+impl RateLimiter
+{
+    pub fn new(limits: RateLimits, now: Instant) -> RateLimiter
+    {
+        RateLimiter {
+            limits,
+            addr: limits.addr.map(|limit| TokenBucket::new(&limit, now)),
+            inv: limits.inv.map(|limit| TokenBucket::new(&limit, now)),
+            dropped: RateLimitedMessages::default(),
+        }
+    }
+
+    /// Replace limits. Buckets are refilled, but counters of dropped messages are kept.
+    pub fn set_limits(&mut self, limits: RateLimits, now: Instant)
+    {
+        let dropped = self.dropped;
+        *self = RateLimiter::new(limits, now);
+        self.dropped = dropped;
+    }
+
+    pub fn limits(&self) -> RateLimits
+    {
+        self.limits
+    }
+
+    pub fn dropped(&self) -> RateLimitedMessages
+    {
+        self.dropped
+    }
+
+    /// Take a token for `msg`. Returns false if `msg` should be dropped.
+    /// Messages of commands without limit are always allowed.
+    pub fn allow(&mut self, msg: &Message, now: Instant) -> bool
+    {
+        let (limit, bucket, dropped) = match *msg {
+            Message::Network(NetworkMessage::Addr(_)) => (self.limits.addr, &mut self.addr, &mut self.dropped.addr),
+            Message::Network(NetworkMessage::Inv(_)) => (self.limits.inv, &mut self.inv, &mut self.dropped.inv),
+            _ => return true,
+        };
+        let allowed = match (limit, bucket.as_mut()) {
+            (Some(limit), Some(bucket)) => bucket.take(&limit, now),
+            _ => true,
+        };
+        if !allowed {
+            *dropped += 1;
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_and_refills_over_time()
+    {
+        let limit = RateLimit::per_minute(10);
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&limit, now);
+
+        for _ in 0..10 {
+            assert!(bucket.take(&limit, now));
+        }
+        assert!(!bucket.take(&limit, now));
+
+        // One token per 6 seconds.
+        assert!(!bucket.take(&limit, now + Duration::from_secs(5)));
+        assert!(bucket.take(&limit, now + Duration::from_secs(6)));
+        assert!(!bucket.take(&limit, now + Duration::from_secs(7)));
+        assert!(bucket.take(&limit, now + Duration::from_secs(12)));
+
+        // Tokens never exceed the capacity.
+        let later = now + Duration::from_secs(60 * 60);
+        for _ in 0..10 {
+            assert!(bucket.take(&limit, later));
+        }
+        assert!(!bucket.take(&limit, later));
+    }
+
+    #[test]
+    fn limiter_counts_dropped_messages_per_command()
+    {
+        let limits = RateLimits {
+            addr: Some(RateLimit::per_minute(2)),
+            inv: None,
+        };
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(limits, now);
+        let addr: Message = NetworkMessage::Addr(Vec::new()).into();
+        let inv: Message = NetworkMessage::Inv(Vec::new()).into();
+
+        let allowed = (0..5).filter(|_| limiter.allow(&addr, now)).count();
+        assert_eq!(allowed, 2);
+        assert!((0..1000).all(|_| limiter.allow(&inv, now)));
+        assert!(limiter.allow(&NetworkMessage::Ping(1).into(), now));
+        assert_eq!(limiter.dropped(), RateLimitedMessages { addr: 3, inv: 0 });
+
+        // New limits refill buckets.
+        limiter.set_limits(RateLimits::default(), now);
+        assert!(limiter.allow(&addr, now));
+        assert_eq!(limiter.dropped().addr, 3);
+        assert_eq!(limiter.limits(), RateLimits::default());
+    }
+}
@@ -1,19 +1,131 @@
-use std::{io::Cursor, net::SocketAddr, time::{SystemTime, UNIX_EPOCH}};
-use bitcoin::network::{address::Address, constants::{Network, PROTOCOL_VERSION}, encodable::ConsensusDecodable,
-                       message::{CommandString, NetworkMessage, RawNetworkMessage}, message_network::VersionMessage,
-                       serialize::{serialize, Error as BitcoinSerializeError, RawDecoder}};
-use bitcoin::util::hash::Sha256dHash;
-
-use futures::{Future, IntoFuture, Sink, Stream};
-use tokio::{codec::{Encoder, FramedWrite}, io::{shutdown, AsyncRead, AsyncWrite, ReadHalf, Shutdown, WriteHalf},
-            net::TcpStream};
-use bytes::BytesMut;
-use failure::Error;
+use std::{collections::HashSet, io, net::SocketAddr, sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+use bitcoin::network::{address::Address, constants::{Network, PROTOCOL_VERSION}, message::NetworkMessage,
+                       message_network::VersionMessage};
 
-use connection::error::ConnectionError;
+use futures::{future, Future, IntoFuture, Sink, Stream, future::Either};
+use tokio::{codec::{Decoder, FramedRead, FramedWrite}, io::{shutdown, AsyncRead, AsyncWrite, ReadHalf, Shutdown, WriteHalf},
+            net::{TcpListener, TcpStream}};
+use bytes::BytesMut;
+use connection::{codec::{encode, BtcCodec, RAW_NETWORK_MESSAGE_HEADER_SIZE}, message::Message};
+use error::Error;
 
 pub const USER_AGENT: &str = "bitcoinrs v0.0";
 
+/// Service bits which a node advertises in `version` and `addr` messages.
+pub const NODE_NETWORK: u64 = 1;
+pub const NODE_WITNESS: u64 = 1 << 3;
+pub const NODE_NETWORK_LIMITED: u64 = 1 << 10;
+
+/// Peers announcing an older protocol version than this are rejected by default.
+pub const MIN_PROTOCOL_VERSION: u32 = 70001;
+
+/// Parameters of our `version` message.
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig
+{
+    /// BIP 14 format. e.g. "/bitcoinrs:0.0/"
+    pub user_agent: String,
+    pub protocol_version: u32,
+    pub services: u64,
+    /// Services which remote peer must advertise when we begin handshake.
+    /// e.g. `NODE_WITNESS` to download witness blocks.
+    pub required_services: u64,
+    /// Oldest protocol version which remote peer may announce.
+    pub min_protocol_version: u32,
+    pub relay: bool,
+    pub start_height: i32,
+    /// Nonce of our `version` message. A random nonce is used for each handshake if `None`.
+    /// Since self connection is detected by nonces, fix it only to test handshakes.
+    pub nonce: Option<u64>,
+    /// Nonces of our outgoing `version` messages.
+    /// Share it between outbound and inbound handshakes to detect connecting to ourself.
+    pub local_nonces: LocalNonces,
+}
+
+impl Default for HandshakeConfig
+{
+    fn default() -> HandshakeConfig
+    {
+        HandshakeConfig {
+            user_agent: USER_AGENT.into(),
+            protocol_version: PROTOCOL_VERSION,
+            services: 0,
+            required_services: 0,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            relay: false,
+            start_height: 0,
+            nonce: None,
+            local_nonces: LocalNonces::default(),
+        }
+    }
+}
+
+impl HandshakeConfig
+{
+    pub fn with_user_agent<S: Into<String>>(mut self, user_agent: S) -> HandshakeConfig
+    {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    pub fn with_protocol_version(mut self, protocol_version: u32) -> HandshakeConfig
+    {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    pub fn with_services(mut self, services: u64) -> HandshakeConfig
+    {
+        self.services = services;
+        self
+    }
+
+    pub fn with_relay(mut self, relay: bool) -> HandshakeConfig
+    {
+        self.relay = relay;
+        self
+    }
+
+    pub fn with_start_height(mut self, start_height: i32) -> HandshakeConfig
+    {
+        self.start_height = start_height;
+        self
+    }
+
+    pub fn with_nonce(mut self, nonce: u64) -> HandshakeConfig
+    {
+        self.nonce = Some(nonce);
+        self
+    }
+
+    fn version_nonce(&self) -> u64
+    {
+        self.nonce.unwrap_or_else(::rand::random)
+    }
+}
+
+/// A set of nonces of our `version` messages which are waiting for handshake to complete.
+#[derive(Debug, Clone, Default)]
+pub struct LocalNonces(Arc<Mutex<HashSet<u64>>>);
+
+impl LocalNonces
+{
+    fn insert(&self, nonce: u64)
+    {
+        self.0.lock().unwrap().insert(nonce);
+    }
+
+    fn remove(&self, nonce: u64)
+    {
+        self.0.lock().unwrap().remove(&nonce);
+    }
+
+    fn contains(&self, nonce: u64) -> bool
+    {
+        self.0.lock().unwrap().contains(&nonce)
+    }
+}
+
 #[derive(Debug)]
 pub struct Socket<S>
 {
@@ -21,8 +133,25 @@ pub struct Socket<S>
     network: Network,
 }
 
+/// What remote peer tells about itself in its `version` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo
+{
+    pub addr: SocketAddr,
+    pub protocol_version: u32,
+    pub services: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    pub relay: bool,
+}
+
 #[derive(Debug)]
-pub struct HandshakedSocket<S>(Socket<S>);
+pub struct HandshakedSocket<S>
+{
+    socket: Socket<S>,
+    remote_version: VersionMessage,
+    peer_addr: SocketAddr,
+}
 
 impl Socket<TcpStream>
 {
@@ -34,6 +163,25 @@ impl Socket<TcpStream>
             .map_err(|e| Error::from(e))
     }
 
+    /// Listen for incoming connections on `bind`.
+    /// If it fails to bind, returned stream yields an error.
+    pub fn listen(bind: SocketAddr, network: Network) -> impl Stream<Item = Self, Error = Error>
+    {
+        TcpListener::bind(&bind)
+            .into_future()
+            .map(|listener| listener.incoming())
+            .flatten_stream()
+            .map(move |socket| Socket::new(socket, network))
+            .map_err(Error::from)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr>
+    {
+        self.socket.peer_addr()
+    }
+
+    #[deprecated(note = "use `begin_handshake_with_config` instead")]
+    #[allow(deprecated)]
     pub fn begin_handshake(
         self,
         start_height: i32,
@@ -44,10 +192,20 @@ impl Socket<TcpStream>
         begin_handshake(self, start_height, services, relay)
     }
 
-    // TODO
-    pub fn reply_handshake(self) -> Result<HandshakedSocket<TcpStream>, Error>
+    pub fn begin_handshake_with_config(
+        self,
+        config: HandshakeConfig,
+    ) -> impl Future<Item = HandshakedSocket<TcpStream>, Error = Error>
+    {
+        begin_handshake_with_config(self, config)
+    }
+
+    pub fn accept_handshake(
+        self,
+        config: HandshakeConfig,
+    ) -> impl Future<Item = HandshakedSocket<TcpStream>, Error = Error>
     {
-        unimplemented!();
+        accept_handshake(self, config)
     }
 }
 
@@ -58,6 +216,11 @@ impl<S> Socket<S>
         Socket { socket, network }
     }
 
+    pub fn network(&self) -> Network
+    {
+        self.network
+    }
+
     fn breakdown(self) -> (S, Network)
     {
         (self.socket, self.network)
@@ -77,9 +240,10 @@ impl<S> Socket<S>
         shutdown(self.socket)
     }
 
-    pub fn send_msg(self, msg: NetworkMessage) -> impl Future<Item = Self, Error = Error>
+    pub fn send_msg<M: Into<Message>>(self, msg: M) -> impl Future<Item = Self, Error = Error>
     where S: AsyncWrite
     {
+        let msg = msg.into();
         debug!("Send a message {:?}", msg);
         let (socket, network) = self.breakdown();
         let serialized = encode(msg, network.clone());
@@ -90,87 +254,205 @@ impl<S> Socket<S>
             .map(move |socket| Socket::new(socket, network))
     }
 
-    pub fn send_msg_sink(self) -> impl Sink<SinkItem = NetworkMessage, SinkError = Error>
+    pub fn send_msg_sink(self) -> impl Sink<SinkItem = Message, SinkError = Error>
     where S: AsyncWrite
     {
         let (socket, network) = self.breakdown();
-        let encoder = BtcEncoder { network };
-        FramedWrite::new(socket, encoder)
+        FramedWrite::new(socket, BtcCodec::new(network))
     }
 
-    pub fn recv_msg(self) -> impl Future<Item = (NetworkMessage, Self), Error = Error>
+    pub fn recv_msg(self) -> impl Future<Item = (Message, Self), Error = Error>
     where S: AsyncRead
     {
+        self.recv_sized_msg().map(|(msg, _size, socket)| (msg, socket))
+    }
+
+    /// Receive a message with its size in bytes including a header.
+    pub fn recv_sized_msg(self) -> impl Future<Item = (Message, usize, Self), Error = Error>
+    where S: AsyncRead
+    {
+        // Read exactly one message so that nothing after it is lost when the socket is returned.
         let (socket, network) = self.breakdown();
-        let network2 = network.clone();
+        let codec = BtcCodec::new(network.clone());
         let header_buf: [u8; RAW_NETWORK_MESSAGE_HEADER_SIZE] = [0; RAW_NETWORK_MESSAGE_HEADER_SIZE];
 
         ::tokio::io::read_exact(socket, header_buf)
             .map_err(Error::from)
-            .and_then(move |(socket, bytes)| {
-                let header = decode_msg_header(&bytes, &network)?;
-                Ok((socket, header))
+            .and_then(move |(socket, header)| {
+                let size = codec.frame_size(&header)?;
+                Ok((socket, header, size, codec))
             })
-            .and_then(|(socket, header)| {
-                let mut buf = Vec::with_capacity(header.payload_size as usize);
-                buf.resize(header.payload_size as usize, 0);
-                ::tokio::io::read_exact(socket, buf)
+            .and_then(|(socket, header, size, codec)| {
+                let payload = vec![0; size - RAW_NETWORK_MESSAGE_HEADER_SIZE];
+                ::tokio::io::read_exact(socket, payload)
                     .map_err(Error::from)
-                    .map(|(socket, bytes)| (socket, bytes, header))
+                    .map(move |(socket, payload)| (socket, header, payload, codec))
             })
-            .and_then(move |(socket, bytes, header)| {
-                let msg = decode_and_check_msg_payload(&bytes, &header)?;
-                Ok((msg, Socket::new(socket, network2)))
+            .and_then(move |(socket, header, payload, mut codec)| {
+                let mut frame = BytesMut::with_capacity(header.len() + payload.len());
+                frame.extend_from_slice(&header);
+                frame.extend_from_slice(&payload);
+                let (msg, size) = codec.decode(&mut frame)?.expect("A whole frame is read");
+                Ok((msg, size, Socket::new(socket, network)))
             })
     }
 
-    pub fn recv_msg_stream(self) -> impl Stream<Item = NetworkMessage, Error = Error>
+    pub fn recv_msg_stream(self) -> impl Stream<Item = Message, Error = Error>
+    where S: AsyncRead
+    {
+        self.recv_sized_msg_stream().map(|(msg, _size)| msg)
+    }
+
+    /// Unlike `recv_msg`, a read buffer is reused across messages.
+    pub fn recv_sized_msg_stream(self) -> impl Stream<Item = (Message, usize), Error = Error>
     where S: AsyncRead
     {
-        ::futures::stream::unfold(self, |s| Some(s.recv_msg()))
+        let (socket, network) = self.breakdown();
+        FramedRead::new(socket, BtcCodec::new(network))
     }
 }
 
 impl<S> HandshakedSocket<S>
 {
+    /// `version` message which remote peer sent while handshake.
+    pub fn remote_version(&self) -> &VersionMessage
+    {
+        &self.remote_version
+    }
+
+    pub fn network(&self) -> Network
+    {
+        self.socket.network()
+    }
+
+    /// Address of remote peer which is given to handshake.
+    pub fn peer_addr(&self) -> SocketAddr
+    {
+        self.peer_addr
+    }
+
+    /// `start_height` which remote peer advertised while handshake.
+    /// It is an estimation of the height of remote chain.
+    pub fn remote_start_height(&self) -> i32
+    {
+        self.remote_version.start_height
+    }
+
+    pub fn remote_user_agent(&self) -> &str
+    {
+        &self.remote_version.user_agent
+    }
+
+    /// Services which remote peer advertised while handshake.
+    pub fn remote_services(&self) -> u64
+    {
+        self.remote_version.services
+    }
+
+    pub fn peer_info(&self) -> PeerInfo
+    {
+        PeerInfo {
+            addr: self.peer_addr,
+            protocol_version: self.remote_version.version,
+            services: self.remote_version.services,
+            user_agent: self.remote_version.user_agent.clone(),
+            start_height: self.remote_version.start_height,
+            relay: self.remote_version.relay,
+        }
+    }
+
     pub fn split(self) -> (HandshakedSocket<ReadHalf<S>>, HandshakedSocket<WriteHalf<S>>)
     where S: AsyncRead + AsyncWrite
     {
-        let (r, w) = self.0.split();
-        (HandshakedSocket(r), HandshakedSocket(w))
+        let HandshakedSocket {
+            socket,
+            remote_version,
+            peer_addr,
+        } = self;
+        let (r, w) = socket.split();
+        let r = HandshakedSocket {
+            socket: r,
+            remote_version: remote_version.clone(),
+            peer_addr,
+        };
+        let w = HandshakedSocket {
+            socket: w,
+            remote_version,
+            peer_addr,
+        };
+        (r, w)
     }
 
     pub fn shutdown(self) -> Shutdown<S>
     where S: AsyncWrite
     {
-        self.0.shutdown()
+        self.socket.shutdown()
     }
 
-    pub fn send_msg(self, msg: NetworkMessage) -> impl Future<Item = Self, Error = Error>
+    /// Erase the type of underlying stream.
+    pub fn into_boxed_write(self) -> HandshakedSocket<Box<AsyncWrite>>
+    where S: AsyncWrite + 'static
+    {
+        let HandshakedSocket {
+            socket,
+            remote_version,
+            peer_addr,
+        } = self;
+        let (s, network) = socket.breakdown();
+        HandshakedSocket {
+            socket: Socket::new(Box::new(s) as Box<AsyncWrite>, network),
+            remote_version,
+            peer_addr,
+        }
+    }
+
+    pub fn send_msg<M: Into<Message>>(self, msg: M) -> impl Future<Item = Self, Error = Error>
     where S: AsyncWrite
     {
-        self.0.send_msg(msg).map(|s| HandshakedSocket(s))
+        let (remote_version, peer_addr) = (self.remote_version, self.peer_addr);
+        self.socket.send_msg(msg).map(move |socket| {
+            HandshakedSocket {
+                socket,
+                remote_version,
+                peer_addr,
+            }
+        })
     }
 
-    pub fn send_msg_sink(self) -> impl Sink<SinkItem = NetworkMessage, SinkError = Error>
+    pub fn send_msg_sink(self) -> impl Sink<SinkItem = Message, SinkError = Error>
     where S: AsyncWrite
     {
-        self.0.send_msg_sink()
+        self.socket.send_msg_sink()
     }
 
-    pub fn recv_msg(self) -> impl Future<Item = (NetworkMessage, Self), Error = Error>
+    pub fn recv_msg(self) -> impl Future<Item = (Message, Self), Error = Error>
     where S: AsyncRead
     {
-        self.0.recv_msg().map(|(msg, socket)| (msg, HandshakedSocket(socket)))
+        let (remote_version, peer_addr) = (self.remote_version, self.peer_addr);
+        self.socket.recv_msg().map(move |(msg, socket)| {
+            let socket = HandshakedSocket {
+                socket,
+                remote_version,
+                peer_addr,
+            };
+            (msg, socket)
+        })
     }
 
-    pub fn recv_msg_stream(self) -> impl Stream<Item = NetworkMessage, Error = Error>
+    pub fn recv_msg_stream(self) -> impl Stream<Item = Message, Error = Error>
     where S: AsyncRead
     {
-        self.0.recv_msg_stream()
+        self.socket.recv_msg_stream()
+    }
+
+    pub fn recv_sized_msg_stream(self) -> impl Stream<Item = (Message, usize), Error = Error>
+    where S: AsyncRead
+    {
+        self.socket.recv_sized_msg_stream()
     }
 }
 
+#[deprecated(note = "use `begin_handshake_with_config` instead")]
 pub fn begin_handshake(
     socket: Socket<TcpStream>,
     start_height: i32,
@@ -178,170 +460,569 @@ pub fn begin_handshake(
     relay: bool,
 ) -> impl Future<Item = HandshakedSocket<TcpStream>, Error = Error>
 {
-    version_msg(&socket.socket, start_height, services, relay)
+    let config = HandshakeConfig::default()
+        .with_start_height(start_height)
+        .with_services(services)
+        .with_relay(relay);
+    begin_handshake_with_config(socket, config)
+}
+
+pub fn begin_handshake_with_config(
+    socket: Socket<TcpStream>,
+    config: HandshakeConfig,
+) -> impl Future<Item = HandshakedSocket<TcpStream>, Error = Error>
+{
+    tcp_addrs(&socket)
+        .into_future()
+        .and_then(move |(local_addr, peer_addr)| begin_handshake_on(socket, config, local_addr, peer_addr))
+}
+
+/// Reply handshake which is started by remote peer.
+pub fn accept_handshake(
+    socket: Socket<TcpStream>,
+    config: HandshakeConfig,
+) -> impl Future<Item = HandshakedSocket<TcpStream>, Error = Error>
+{
+    tcp_addrs(&socket)
         .into_future()
-        .and_then(|v| socket.send_msg(NetworkMessage::Version(v)))
-        .and_then(|socket| socket.recv_msg())
-        .and_then(|(msg, socket)| {
+        .and_then(move |(local_addr, peer_addr)| accept_handshake_on(socket, config, local_addr, peer_addr))
+}
+
+fn tcp_addrs(socket: &Socket<TcpStream>) -> Result<(SocketAddr, SocketAddr), Error>
+{
+    let local_addr = socket.socket.local_addr()?;
+    let peer_addr = socket.socket.peer_addr()?;
+    Ok((local_addr, peer_addr))
+}
+
+/// Begin handshake on any kind of stream.
+/// `local_addr` and `peer_addr` are only used to build our `version` message.
+pub fn begin_handshake_on<S>(
+    socket: Socket<S>,
+    config: HandshakeConfig,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+) -> impl Future<Item = HandshakedSocket<S>, Error = Error>
+where S: AsyncRead + AsyncWrite
+{
+    // Random nonce is used to detect connecting to ourself.
+    let nonce = config.version_nonce();
+    let version = version_msg(&config, nonce, &local_addr, &peer_addr);
+    let (required_services, min_version) = (config.required_services, config.min_protocol_version);
+    let local_nonces = config.local_nonces.clone();
+    local_nonces.insert(nonce);
+    let f = socket
+        .send_msg(NetworkMessage::Version(version))
+        .and_then(move |socket| recv_remote_version(socket, peer_addr))
+        .and_then(move |(remote_v, verack_received, socket)| {
+            check_remote_version_msg(&remote_v, nonce)
+                .and_then(|()| check_remote_protocol_version(&remote_v, min_version, peer_addr))
+                .and_then(|()| check_remote_services(&remote_v, required_services, peer_addr))
+                .map(|()| (remote_v, verack_received, socket))
+        })
+        .and_then(|(remote_v, verack_received, socket)| {
+            socket
+                .send_msg(NetworkMessage::Verack)
+                .map(move |socket| (remote_v, verack_received, socket))
+        })
+        .and_then(move |(remote_v, verack_received, socket)| {
+            let f = if verack_received {
+                Either::A(future::ok(socket))
+            } else {
+                Either::B(recv_verack(socket, peer_addr))
+            };
+            f.map(move |socket| {
+                HandshakedSocket {
+                    socket,
+                    remote_version: remote_v,
+                    peer_addr,
+                }
+            })
+        });
+    f.then(move |res| {
+        local_nonces.remove(nonce);
+        res
+    })
+}
+
+/// Reply handshake on any kind of stream.
+/// Wait for peer's `version`, then reply our `version` and `verack`.
+pub fn accept_handshake_on<S>(
+    socket: Socket<S>,
+    config: HandshakeConfig,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+) -> impl Future<Item = HandshakedSocket<S>, Error = Error>
+where S: AsyncRead + AsyncWrite
+{
+    let nonce = config.version_nonce();
+    let version = version_msg(&config, nonce, &local_addr, &peer_addr);
+    let min_version = config.min_protocol_version;
+    recv_known_msg(socket)
+        .and_then(move |(msg, socket)| {
             match msg {
-                NetworkMessage::Version(v) => Ok((v, socket)),
-                msg => {
-                    info!("Fail to handshake. Expect Version msg but found {:?}", msg);
-                    bail!(ConnectionError::MisbehavePeer);
-                },
+                Message::Network(NetworkMessage::Version(v)) => Ok((v, socket)),
+                msg => Err(unexpected_handshake_msg("version", &msg, peer_addr)),
             }
         })
-        .and_then(|(remote_v, socket)| check_remote_version_msg(remote_v).map(|()| socket))
-        .and_then(|socket| socket.send_msg(NetworkMessage::Verack))
-        .and_then(|socket| socket.recv_msg())
-        .and_then(|(msg, socket)| {
+        .and_then(move |(remote_v, socket)| {
+            // Remote peer may be ourself which is dialing out.
+            if config.local_nonces.contains(remote_v.nonce) {
+                info!("Detect connection to ourself");
+                return Err(Error::SelfConnection);
+            }
+            check_remote_protocol_version(&remote_v, min_version, peer_addr).map(|()| (remote_v, socket))
+        })
+        .and_then(move |(remote_v, socket)| {
+            socket
+                .send_msg(NetworkMessage::Version(version))
+                .and_then(|socket| socket.send_msg(NetworkMessage::Verack))
+                .map(|socket| (remote_v, socket))
+        })
+        .and_then(move |(remote_v, socket)| {
+            recv_verack(socket, peer_addr).map(move |socket| {
+                HandshakedSocket {
+                    socket,
+                    remote_version: remote_v,
+                    peer_addr,
+                }
+            })
+        })
+}
+
+/// Receive `version` of remote peer.
+/// Some implementations send `verack` before `version`, so returned `bool` tells whether `verack` is already received.
+fn recv_remote_version<S>(
+    socket: Socket<S>,
+    peer_addr: SocketAddr,
+) -> impl Future<Item = (VersionMessage, bool, Socket<S>), Error = Error>
+where S: AsyncRead
+{
+    recv_known_msg(socket).and_then(move |(msg, socket)| {
+        match msg {
+            Message::Network(NetworkMessage::Version(v)) => Either::A(future::ok((v, false, socket))),
+            Message::Network(NetworkMessage::Verack) => {
+                Either::B(recv_known_msg(socket).and_then(move |(msg, socket)| {
+                    match msg {
+                        Message::Network(NetworkMessage::Version(v)) => Ok((v, true, socket)),
+                        msg => Err(unexpected_handshake_msg("version", &msg, peer_addr)),
+                    }
+                }))
+            },
+            msg => Either::A(future::err(unexpected_handshake_msg("version", &msg, peer_addr))),
+        }
+    })
+}
+
+fn recv_verack<S>(socket: Socket<S>, peer_addr: SocketAddr) -> impl Future<Item = Socket<S>, Error = Error>
+where S: AsyncRead
+{
+    recv_known_msg(socket).and_then(move |(msg, socket)| {
+        match msg {
+            Message::Network(NetworkMessage::Verack) => Ok(socket),
+            msg => Err(unexpected_handshake_msg("verack", &msg, peer_addr)),
+        }
+    })
+}
+
+/// Receive a next message, skipping messages which we do not understand.
+/// Recent peers send e.g. `wtxidrelay` and `sendaddrv2` between `version` and `verack`.
+fn recv_known_msg<S>(socket: Socket<S>) -> impl Future<Item = (Message, Socket<S>), Error = Error>
+where S: AsyncRead
+{
+    future::loop_fn(socket, |socket| {
+        socket.recv_msg().map(|(msg, socket)| {
             match msg {
-                NetworkMessage::Verack => Ok(HandshakedSocket(socket)),
-                msg => {
-                    info!("Fail to handshake. Expect Verack msg but found {:?}", msg);
-                    bail!(ConnectionError::MisbehavePeer);
+                Message::Unknown(command) => {
+                    debug!("Skip {} message during handshake", command);
+                    future::Loop::Continue(socket)
                 },
+                msg => future::Loop::Break((msg, socket)),
             }
         })
+    })
 }
 
-fn version_msg(socket: &TcpStream, start_height: i32, services: u64, relay: bool) -> Result<VersionMessage, Error>
+fn version_msg(
+    config: &HandshakeConfig,
+    nonce: u64,
+    local_addr: &SocketAddr,
+    peer_addr: &SocketAddr,
+) -> VersionMessage
 {
     let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
-    let sender = Address::new(&socket.local_addr()?, services.clone());
-    let receiver = Address::new(&socket.peer_addr()?, services.clone());
-    Ok(VersionMessage {
-        version: PROTOCOL_VERSION,
-        services,
+    let sender = Address::new(local_addr, config.services);
+    let receiver = Address::new(peer_addr, config.services);
+    VersionMessage {
+        version: config.protocol_version,
+        services: config.services,
         timestamp: ts,
         receiver,
         sender,
-        nonce: 0,
-        user_agent: USER_AGENT.into(),
-        start_height,
-        relay,
-    })
+        nonce,
+        user_agent: config.user_agent.clone(),
+        start_height: config.start_height,
+        relay: config.relay,
+    }
 }
 
-fn check_remote_version_msg(_version: VersionMessage) -> Result<(), Error>
+fn unexpected_handshake_msg(expected: &str, msg: &Message, peer: SocketAddr) -> Error
 {
-    // Currently does not check anything
+    info!("Fail to handshake. Expect {} msg but found {:?}", expected, msg);
+    Error::HandshakeFailed {
+        peer,
+        reason: format!("expect {} but receive {}", expected, msg.command()),
+    }
+}
+
+/// `nonce` is a nonce of our `version` message.
+fn check_remote_version_msg(version: &VersionMessage, nonce: u64) -> Result<(), Error>
+{
+    if version.nonce == nonce {
+        info!("Detect connection to ourself");
+        return Err(Error::SelfConnection);
+    }
     Ok(())
 }
 
-fn encode(msg: NetworkMessage, network: Network) -> Vec<u8>
+fn check_remote_protocol_version(version: &VersionMessage, min: u32, peer: SocketAddr) -> Result<(), Error>
 {
-    let msg = RawNetworkMessage {
-        magic: network.magic(),
-        payload: msg,
-    };
-    serialize(&msg).unwrap() // Never fail
+    if version.version < min {
+        info!("Peer {} announces obsolete protocol version {}", peer, version.version);
+        return Err(Error::ObsoleteProtocolVersion {
+            peer,
+            version: version.version,
+            min,
+        });
+    }
+    Ok(())
 }
 
-struct BtcEncoder
+fn check_remote_services(version: &VersionMessage, required: u64, peer: SocketAddr) -> Result<(), Error>
 {
-    pub network: Network,
+    if version.services & required != required {
+        info!("Peer {} does not advertise required services {:#x}", peer, required);
+        return Err(Error::MissingServices {
+            peer,
+            required,
+            services: version.services,
+        });
+    }
+    Ok(())
 }
 
-impl Encoder for BtcEncoder
+#[cfg(test)]
+mod tests
 {
-    type Item = NetworkMessage;
-    type Error = Error;
-    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error>
+    use super::*;
+    use tokio::runtime::current_thread::Runtime;
+    use testing::{duplex, dummy_addrs, ScriptedPeer};
+
+    fn dummy_version_msg(nonce: u64) -> VersionMessage
     {
-        let encoded = encode(item, self.network.clone());
-        dst.extend_from_slice(encoded.as_slice());
-        Ok(())
+        let addr = Address::new(&"127.0.0.1:8333".parse().unwrap(), 0);
+        VersionMessage {
+            version: PROTOCOL_VERSION,
+            services: 0,
+            timestamp: 0,
+            receiver: addr.clone(),
+            sender: addr,
+            nonce,
+            user_agent: USER_AGENT.into(),
+            start_height: 0,
+            relay: false,
+        }
     }
-}
 
+    #[test]
+    fn detect_self_connection_by_nonce()
+    {
+        let err = check_remote_version_msg(&dummy_version_msg(42), 42).unwrap_err();
+        match err {
+            Error::SelfConnection => {},
+            other => panic!("Unexpected error : {:?}", other),
+        }
 
-const RAW_NETWORK_MESSAGE_HEADER_SIZE: usize = 24;
+        assert!(check_remote_version_msg(&dummy_version_msg(43), 42).is_ok());
+    }
 
-struct RawNetworkMessageHeader
-{
-    command_name: CommandString,
-    payload_size: u32,
-    checksum: [u8; 4],
-}
+    #[test]
+    fn handshake_with_scripted_peer()
+    {
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let peer = ScriptedPeer::new(remote, Network::Bitcoin).handshake(42);
+        let socket = Socket::new(local, Network::Bitcoin);
+        let handshake = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr);
 
-/// # Panic
-/// If length of `src` is not 24 bytes.
-fn decode_msg_header(src: &[u8], network: &Network) -> Result<RawNetworkMessageHeader, Error>
-{
-    assert!(src.len() == RAW_NETWORK_MESSAGE_HEADER_SIZE);
+        let (socket, _) = handshake.join(peer.run()).wait().unwrap();
+        assert_eq!(socket.remote_version().start_height, 42);
+    }
+
+    // Payload of our `version` message which is built from `config`, as bytes on the wire.
+    fn sent_version_payload(config: HandshakeConfig) -> Vec<u8>
+    {
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let socket = Socket::new(local, Network::Bitcoin);
+        let mut runtime = Runtime::new().unwrap();
+        // Peer never responds, so handshake keeps waiting while we read our `version`.
+        runtime.spawn(begin_handshake_on(socket, config, local_addr, peer_addr).then(|_| Ok(())));
 
-    debug!("Decode message header");
+        let read = ::tokio::io::read_exact(remote, [0u8; RAW_NETWORK_MESSAGE_HEADER_SIZE]).and_then(|(remote, header)| {
+            assert_eq!(&header[4..16], b"version\0\0\0\0\0");
+            let len = header[16..20]
+                .iter()
+                .rev()
+                .fold(0, |len, byte| len << 8 | *byte as usize);
+            ::tokio::io::read_exact(remote, vec![0u8; len])
+        });
+        let (_remote, payload) = runtime.block_on(read).unwrap();
+        payload
+    }
 
-    let mut decoder = RawDecoder::new(Cursor::new(src));
+    #[test]
+    fn version_message_on_wire_reflects_config()
+    {
+        let config = HandshakeConfig::default()
+            .with_user_agent("/test:1.0/")
+            .with_protocol_version(70015)
+            .with_services(NODE_NETWORK | NODE_WITNESS)
+            .with_relay(true)
+            .with_start_height(540_000)
+            .with_nonce(0x0102_0304_0506_0708);
+        let payload = sent_version_payload(config);
 
-    let magic = u32::consensus_decode(&mut decoder)?;
-    if magic != network.magic() {
-        return Err(Error::from(BitcoinSerializeError::UnexpectedNetworkMagic {
-            expected: network.magic(),
-            actual: magic,
-        }));
+        assert_eq!(&payload[0..4], &[0x7f, 0x11, 0x01, 0x00]);
+        assert_eq!(&payload[4..12], &[0x09, 0, 0, 0, 0, 0, 0, 0]);
+        // Timestamp and 26 bytes of both addresses come before nonce.
+        assert_eq!(&payload[72..80], &[0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+        // User agent is prefixed by its length as a var int.
+        assert_eq!(payload[80], 10);
+        assert_eq!(&payload[81..91], b"/test:1.0/");
+        assert_eq!(&payload[91..95], &[0x60, 0x3d, 0x08, 0x00]);
+        assert_eq!(&payload[95..], &[1]);
     }
 
-    let command_name = CommandString::consensus_decode(&mut decoder)?;
-    let payload_size = u32::consensus_decode(&mut decoder)?;
-    let checksum = <[u8; 4]>::consensus_decode(&mut decoder)?;
+    #[test]
+    fn long_user_agent_has_multi_byte_length_prefix()
+    {
+        let user_agent: String = ::std::iter::repeat('a').take(300).collect();
+        let payload = sent_version_payload(HandshakeConfig::default().with_user_agent(user_agent.clone()));
 
-    Ok(RawNetworkMessageHeader {
-        command_name,
-        payload_size,
-        checksum,
-    })
-}
+        assert_eq!(&payload[80..83], &[0xfd, 0x2c, 0x01]);
+        assert_eq!(&payload[83..383], user_agent.as_bytes());
+        assert_eq!(payload.len(), 383 + 4 + 1);
+    }
 
-/// # Panic
-/// If length of `src` is not `header.payload_size`.
-fn decode_and_check_msg_payload(src: &[u8], header: &RawNetworkMessageHeader) -> Result<NetworkMessage, Error>
-{
-    assert!(src.len() as u32 == header.payload_size);
-
-    let mut decoder = RawDecoder::new(Cursor::new(src));
-
-    // Check a checksum
-    let expected_checksum = sha2_checksum(&src);
-    if expected_checksum != header.checksum {
-        warn!("bad checksum");
-        return Err(Error::from(BitcoinSerializeError::InvalidChecksum {
-            expected: expected_checksum,
-            actual: header.checksum,
-        }));
-    }
-
-    let msg = match &header.command_name.0[..] {
-        "version" => NetworkMessage::Version(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "verack" => NetworkMessage::Verack,
-        "addr" => NetworkMessage::Addr(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "inv" => NetworkMessage::Inv(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "getdata" => NetworkMessage::GetData(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "notfound" => NetworkMessage::NotFound(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "getblocks" => NetworkMessage::GetBlocks(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "getheaders" => NetworkMessage::GetHeaders(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "mempool" => NetworkMessage::MemPool,
-        "block" => NetworkMessage::Block(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "headers" => NetworkMessage::Headers(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "getaddr" => NetworkMessage::GetAddr,
-        "ping" => NetworkMessage::Ping(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "pong" => NetworkMessage::Pong(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "tx" => NetworkMessage::Tx(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "alert" => NetworkMessage::Alert(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        cmd => {
-            warn!("unrecognized network command : {}", cmd);
-            return Err(Error::from(BitcoinSerializeError::UnrecognizedNetworkCommand(
-                cmd.into(),
-            )));
-        },
-    };
-
-    Ok(msg)
-}
+    #[test]
+    fn handshake_fails_if_peer_does_not_send_version()
+    {
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+            .expect("version")
+            .send(NetworkMessage::Verack)
+            .send(NetworkMessage::Verack);
+        let socket = Socket::new(local, Network::Bitcoin);
+        let handshake = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr);
 
-fn sha2_checksum(data: &[u8]) -> [u8; 4]
-{
-    let checksum = Sha256dHash::from_data(data);
-    [checksum[0], checksum[1], checksum[2], checksum[3]]
+        match handshake.join(peer.run()).wait().map(|_| ()) {
+            Err(Error::HandshakeFailed { peer, ref reason }) => {
+                assert_eq!(peer, peer_addr);
+                assert_eq!(reason, "expect version but receive verack");
+            },
+            other => panic!("Unexpected result : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshake_error_can_be_downcast_from_failure_error()
+    {
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+            .expect("version")
+            .send(NetworkMessage::Ping(1));
+        let socket = Socket::new(local, Network::Bitcoin);
+        let handshake = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr);
+
+        let err: ::failure::Error = handshake.join(peer.run()).wait().map(|_| ()).unwrap_err().into();
+        assert!(err.to_string().contains(&peer_addr.to_string()), "{}", err);
+        match err.downcast_ref::<Error>() {
+            Some(&Error::HandshakeFailed { ref reason, .. }) => assert_eq!(reason, "expect version but receive ping"),
+            other => panic!("Unexpected error : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accept_handshake_from_outbound_socket()
+    {
+        // Find a free port.
+        let addr = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let inbound = Socket::listen(addr, Network::Regtest)
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(socket, _)| {
+                let config = HandshakeConfig {
+                    start_height: 1,
+                    ..HandshakeConfig::default()
+                };
+                socket.unwrap().accept_handshake(config)
+            });
+        let outbound = Socket::connect(&addr, Network::Regtest).and_then(|socket| {
+            let config = HandshakeConfig {
+                start_height: 2,
+                ..HandshakeConfig::default()
+            };
+            socket.begin_handshake_with_config(config)
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let (inbound, outbound) = runtime.block_on(inbound.join(outbound)).unwrap();
+        assert_eq!(inbound.remote_version().start_height, 2);
+        assert_eq!(outbound.remote_version().start_height, 1);
+    }
+
+    #[test]
+    fn accept_handshake_detects_self_connection()
+    {
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        // Both sides share the same nonces as if they were the same node.
+        let config = HandshakeConfig::default();
+        let outbound = begin_handshake_on(Socket::new(local, Network::Bitcoin), config.clone(), local_addr, peer_addr);
+        let inbound = accept_handshake_on(Socket::new(remote, Network::Bitcoin), config, peer_addr, local_addr);
+
+        match inbound.join(outbound).wait().map(|_| ()) {
+            Err(Error::SelfConnection) => {},
+            other => panic!("Unexpected result : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshake_fails_if_peer_lacks_required_services()
+    {
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let peer = ScriptedPeer::new(remote, Network::Bitcoin).handshake_with(dummy_version_msg(1));
+        let config = HandshakeConfig {
+            services: NODE_WITNESS,
+            required_services: NODE_WITNESS,
+            ..HandshakeConfig::default()
+        };
+        let handshake = begin_handshake_on(Socket::new(local, Network::Bitcoin), config, local_addr, peer_addr);
+
+        match handshake.join(peer.run()).wait().map(|_| ()) {
+            Err(Error::MissingServices { peer, required, services }) => {
+                assert_eq!((peer, required, services), (peer_addr, NODE_WITNESS, 0))
+            },
+            other => panic!("Unexpected result : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshake_with_peer_which_has_required_services()
+    {
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let mut version = dummy_version_msg(1);
+        version.services = NODE_NETWORK | NODE_WITNESS;
+        let peer = ScriptedPeer::new(remote, Network::Bitcoin).handshake_with(version);
+        let config = HandshakeConfig {
+            required_services: NODE_WITNESS,
+            ..HandshakeConfig::default()
+        };
+        let handshake = begin_handshake_on(Socket::new(local, Network::Bitcoin), config, local_addr, peer_addr);
+
+        let (socket, _) = handshake.join(peer.run()).wait().unwrap();
+        assert_eq!(socket.remote_services(), NODE_NETWORK | NODE_WITNESS);
+    }
+
+    #[test]
+    fn handshake_when_peer_sends_verack_before_version()
+    {
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let mut version = dummy_version_msg(1);
+        version.user_agent = "/Satoshi:0.16.0/".into();
+        version.services = NODE_NETWORK;
+        version.start_height = 7;
+        let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+            .expect("version")
+            .send(NetworkMessage::Verack)
+            .send(NetworkMessage::Version(version))
+            .expect("verack");
+        let socket = Socket::new(local, Network::Bitcoin);
+        let handshake = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr);
+
+        let (socket, _) = handshake.join(peer.run()).wait().unwrap();
+        let expected = PeerInfo {
+            addr: peer_addr,
+            protocol_version: PROTOCOL_VERSION,
+            services: NODE_NETWORK,
+            user_agent: "/Satoshi:0.16.0/".into(),
+            start_height: 7,
+            relay: false,
+        };
+        assert_eq!(socket.peer_info(), expected);
+    }
+
+    #[test]
+    fn handshake_fails_if_peer_announces_obsolete_protocol_version()
+    {
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let mut version = dummy_version_msg(1);
+        version.version = 0;
+        let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+            .expect("version")
+            .send(NetworkMessage::Version(version));
+        let socket = Socket::new(local, Network::Bitcoin);
+        let handshake = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr);
+
+        match handshake.join(peer.run()).wait().map(|_| ()) {
+            Err(Error::ObsoleteProtocolVersion { peer, version, min }) => {
+                assert_eq!((peer, version, min), (peer_addr, 0, MIN_PROTOCOL_VERSION))
+            },
+            other => panic!("Unexpected result : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accept_handshake_rejects_obsolete_protocol_version()
+    {
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let mut version = dummy_version_msg(1);
+        version.version = 60000;
+        let peer = ScriptedPeer::new(remote, Network::Bitcoin).send(NetworkMessage::Version(version));
+        let socket = Socket::new(local, Network::Bitcoin);
+        let handshake = accept_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr);
+
+        match handshake.join(peer.run()).wait().map(|_| ()) {
+            Err(Error::ObsoleteProtocolVersion { version, .. }) => assert_eq!(version, 60000),
+            other => panic!("Unexpected result : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshake_skips_unknown_messages_before_verack()
+    {
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+            .expect("version")
+            .send(NetworkMessage::Version(dummy_version_msg(1)))
+            .send(Message::Unknown("wtxidrelay".into()))
+            .send(Message::Unknown("sendaddrv2".into()))
+            .send(NetworkMessage::Verack)
+            .expect("verack");
+        let socket = Socket::new(local, Network::Bitcoin);
+        let handshake = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr);
+
+        let (socket, _) = handshake.join(peer.run()).wait().unwrap();
+        assert_eq!(socket.peer_addr(), peer_addr);
+    }
 }
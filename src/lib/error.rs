@@ -0,0 +1,169 @@
+use std::{io, net::SocketAddr};
+
+use bitcoin::network::{constants::Network, serialize::{BitcoinHash, Error as BitcoinSerializeError}};
+use bitcoin::util::hash::Sha256dHash;
+#[cfg(feature = "actix-net")]
+use actix::MailboxError;
+
+use blockchain::BlockAddError;
+
+/// An error type which is used across this crate.
+///
+/// Every variant is `Send + 'static`, so it can be passed between actors.
+#[derive(Debug, Fail)]
+pub enum Error
+{
+    #[fail(display = "Peer {} misbehaves : {}", peer, reason)]
+    MisbehavingPeer
+    {
+        peer: SocketAddr,
+        reason: String,
+    },
+
+    #[fail(display = "Fail to handshake with {} : {}", peer, reason)]
+    HandshakeFailed
+    {
+        peer: SocketAddr,
+        reason: String,
+    },
+
+    #[fail(display = "Peer {} does not advertise required services {:#x}", peer, required)]
+    MissingServices
+    {
+        peer: SocketAddr,
+        required: u64,
+        services: u64,
+    },
+
+    #[fail(display = "Peer {} announces protocol version {} but min is {}", peer, version, min)]
+    ObsoleteProtocolVersion
+    {
+        peer: SocketAddr,
+        version: u32,
+        min: u32,
+    },
+
+    #[fail(display = "Connect to ourself")]
+    SelfConnection,
+
+    #[fail(display = "Timeout")]
+    Timeout,
+
+    #[fail(display = "Actor is already stopped")]
+    ActorStopped,
+
+    #[fail(display = "Invalid block header {}", _0)]
+    InvalidBlockHeader(Sha256dHash),
+
+    #[fail(display = "Invalid proof of work of block header {}", _0)]
+    InvalidProofOfWork(Sha256dHash),
+
+    #[fail(display = "Difficulty of block header {} does not follow adjustment", _0)]
+    InvalidDifficulty(Sha256dHash),
+
+    #[fail(display = "Invalid timestamp of block header {}", _0)]
+    InvalidTimestamp(Sha256dHash),
+
+    #[fail(display = "Transactions do not match merkle root of block {}", _0)]
+    InvalidMerkleRoot(Sha256dHash),
+
+    #[fail(display = "Peer does not have block {}", _0)]
+    BlockNotFound(Sha256dHash),
+
+    #[fail(display = "Block {} is not a known checkpoint of {:?}", _0, _1)]
+    UnknownCheckpoint(Sha256dHash, Network),
+
+    #[fail(display = "Block {} contradicts a checkpoint", _0)]
+    CheckpointMismatch(Sha256dHash),
+
+    #[fail(display = "Active chain does not have a block at height {}", _0)]
+    HeightOutOfRange(u32),
+
+    #[fail(display = "Block at height {} is replaced by a reorg", _0)]
+    ActiveChainChanged(u32),
+
+    #[fail(display = "Peer is on {:?} but blockchain is on {:?}", peer, chain)]
+    NetworkMismatch
+    {
+        peer: Network,
+        chain: Network,
+    },
+
+    #[fail(display = "Peer sends too many headers beyond its start height {}", _0)]
+    TooManyHeaders(i32),
+
+    #[fail(display = "Peer sends {} orphan headers, more than the orphan pool holds", _0)]
+    TooManyOrphans(usize),
+
+    #[fail(display = "Payload of {} message has {} bytes but max is {}", command, size, max)]
+    OversizedPayload
+    {
+        command: String,
+        size: u32,
+        max: u32,
+    },
+
+    #[fail(display = "Payload of {} message has invalid size {}", command, size)]
+    InvalidPayloadSize
+    {
+        command: String,
+        size: u32,
+    },
+
+    #[fail(display = "{} message has {} entries but max is {}", command, count, max)]
+    TooManyEntries
+    {
+        command: String,
+        count: u64,
+        max: u64,
+    },
+
+    #[fail(display = "Fail to decode a message : {}", _0)]
+    Decode(BitcoinSerializeError),
+
+    #[fail(display = "IO error : {}", _0)]
+    Io(#[cause] io::Error),
+}
+
+impl From<io::Error> for Error
+{
+    fn from(e: io::Error) -> Error
+    {
+        Error::Io(e)
+    }
+}
+
+impl From<BitcoinSerializeError> for Error
+{
+    fn from(e: BitcoinSerializeError) -> Error
+    {
+        Error::Decode(e)
+    }
+}
+
+#[cfg(feature = "actix-net")]
+impl From<MailboxError> for Error
+{
+    fn from(e: MailboxError) -> Error
+    {
+        match e {
+            MailboxError::Timeout => Error::Timeout,
+            MailboxError::Closed => Error::ActorStopped,
+        }
+    }
+}
+
+impl From<BlockAddError> for Error
+{
+    fn from(e: BlockAddError) -> Error
+    {
+        match e {
+            BlockAddError::NotFoundPrevBlock(header) => Error::InvalidBlockHeader(header.bitcoin_hash()),
+            BlockAddError::InvalidPoW(header) => Error::InvalidProofOfWork(header.bitcoin_hash()),
+            BlockAddError::InvalidTimestamp(header) => Error::InvalidTimestamp(header.bitcoin_hash()),
+            BlockAddError::InvalidMerkleRoot(header) => Error::InvalidMerkleRoot(header.bitcoin_hash()),
+            BlockAddError::InvalidDifficulty(header) => Error::InvalidDifficulty(header.bitcoin_hash()),
+            BlockAddError::CheckpointMismatch(header) => Error::CheckpointMismatch(header.bitcoin_hash()),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Hooks to observe what happens in the node, e.g. for metrics.
+//!
+//! `EventSink` is a plain trait object, so it is available without `actix-net` feature.
+
+use std::{net::SocketAddr, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+
+use bitcoin::util::hash::Sha256dHash;
+
+use connection::misbehavior::Violation;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event
+{
+    HandshakeCompleted
+    {
+        peer: SocketAddr,
+        user_agent: String,
+        start_height: i32,
+    },
+    /// A batch of headers is added to blockchain.
+    HeadersApplied
+    {
+        count: usize,
+        tip_height: u32,
+    },
+    /// A block body is stored. `size` is in bytes.
+    BlockStored
+    {
+        hash: Sha256dHash,
+        height: u32,
+        size: usize,
+    },
+    /// Active chain switches to another branch. `depth` is the number of disconnected blocks.
+    Reorg
+    {
+        depth: u32,
+    },
+    Misbehavior
+    {
+        peer: SocketAddr,
+        violation: Violation,
+    },
+    ConnectionOpened(SocketAddr),
+    ConnectionClosed(SocketAddr),
+    ConnectionBanned(SocketAddr),
+    SyncCompleted
+    {
+        tip_height: u32,
+    },
+}
+
+/// Receiver of `Event`s.
+///
+/// `on_event` is called on the thread which causes the event, so it should return quickly.
+pub trait EventSink: Send + Sync
+{
+    fn on_event(&self, ev: Event);
+}
+
+/// A sink which discards every event.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopSink;
+
+impl EventSink for NoopSink
+{
+    fn on_event(&self, _ev: Event) {}
+}
+
+/// Default sink of components which accept `EventSink`.
+pub fn noop_sink() -> Arc<EventSink>
+{
+    Arc::new(NoopSink)
+}
+
+/// A sink which counts events by kind.
+#[derive(Debug, Default)]
+pub struct CountingSink
+{
+    handshakes: AtomicUsize,
+    headers_applied: AtomicUsize,
+    blocks_stored: AtomicUsize,
+    reorgs: AtomicUsize,
+    misbehaviors: AtomicUsize,
+    connections_opened: AtomicUsize,
+    connections_closed: AtomicUsize,
+    connections_banned: AtomicUsize,
+    syncs_completed: AtomicUsize,
+}
+
+/// Numbers of events which `CountingSink` received so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EventCounts
+{
+    pub handshakes: usize,
+    /// Total number of headers, not batches.
+    pub headers_applied: usize,
+    pub blocks_stored: usize,
+    pub reorgs: usize,
+    pub misbehaviors: usize,
+    pub connections_opened: usize,
+    pub connections_closed: usize,
+    pub connections_banned: usize,
+    pub syncs_completed: usize,
+}
+
+impl CountingSink
+{
+    pub fn new() -> CountingSink
+    {
+        CountingSink::default()
+    }
+
+    pub fn counts(&self) -> EventCounts
+    {
+        EventCounts {
+            handshakes: self.handshakes.load(Ordering::Relaxed),
+            headers_applied: self.headers_applied.load(Ordering::Relaxed),
+            blocks_stored: self.blocks_stored.load(Ordering::Relaxed),
+            reorgs: self.reorgs.load(Ordering::Relaxed),
+            misbehaviors: self.misbehaviors.load(Ordering::Relaxed),
+            connections_opened: self.connections_opened.load(Ordering::Relaxed),
+            connections_closed: self.connections_closed.load(Ordering::Relaxed),
+            connections_banned: self.connections_banned.load(Ordering::Relaxed),
+            syncs_completed: self.syncs_completed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl EventSink for CountingSink
+{
+    fn on_event(&self, ev: Event)
+    {
+        let (counter, n) = match ev {
+            Event::HandshakeCompleted { .. } => (&self.handshakes, 1),
+            Event::HeadersApplied { count, .. } => (&self.headers_applied, count),
+            Event::BlockStored { .. } => (&self.blocks_stored, 1),
+            Event::Reorg { .. } => (&self.reorgs, 1),
+            Event::Misbehavior { .. } => (&self.misbehaviors, 1),
+            Event::ConnectionOpened(_) => (&self.connections_opened, 1),
+            Event::ConnectionClosed(_) => (&self.connections_closed, 1),
+            Event::ConnectionBanned(_) => (&self.connections_banned, 1),
+            Event::SyncCompleted { .. } => (&self.syncs_completed, 1),
+        };
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn counting_sink_counts_headers_not_batches()
+    {
+        let sink = CountingSink::new();
+        let peer = "127.0.0.1:8333".parse().unwrap();
+        sink.on_event(Event::HeadersApplied {
+            count: 2000,
+            tip_height: 2000,
+        });
+        sink.on_event(Event::HeadersApplied {
+            count: 10,
+            tip_height: 2010,
+        });
+        sink.on_event(Event::ConnectionOpened(peer));
+        sink.on_event(Event::ConnectionBanned(peer));
+
+        let counts = sink.counts();
+        assert_eq!(counts.headers_applied, 2010);
+        assert_eq!(counts.connections_opened, 1);
+        assert_eq!(counts.connections_banned, 1);
+        assert_eq!(counts.handshakes, 0);
+    }
+}
@@ -1,10 +1,14 @@
 extern crate bitcoin;
+extern crate crypto;
 extern crate futures;
 extern crate tokio;
+extern crate tokio_threadpool;
+#[cfg(feature = "actix-net")]
 extern crate trust_dns_resolver;
 
 extern crate rand;
 extern crate bytes;
+#[cfg(feature = "actix-net")]
 extern crate actix;
 #[macro_use]
 extern crate log;
@@ -13,6 +17,17 @@ extern crate failure;
 #[macro_use]
 extern crate failure_derive;
 
+pub mod bloom;
+pub mod witness;
+pub mod error;
 pub mod connection;
 pub mod blockchain;
+pub mod events;
 pub mod process;
+#[cfg(feature = "actix-net")]
+pub mod node;
+
+#[cfg(test)]
+mod testing;
+
+pub use error::Error;
@@ -0,0 +1,606 @@
+use std::{net::SocketAddr, sync::{Arc, Mutex}};
+
+use actix::prelude::*;
+use bitcoin::network::{constants::Network, serialize::BitcoinHash};
+use futures::{future, Stream, future::Either, sync::mpsc};
+
+use blockchain::{BlockChain, BlockData};
+use connection::{connection_pool::{ConnectionPool, GetConnections, PoolEvent, Shutdown, SubscribePoolEvents,
+                                   DEFAULT_WATER_LINE},
+                 Connection};
+use process::listen::{ListenConnection, ListenNewBlocks, NewBlockEvent, SubscribeNewBlock};
+use process::sync_blockchain::{InFlightHeaders, SyncBlockChain, SyncBlockChainResult};
+
+/// The number of events which are buffered for each subscriber.
+/// A subscriber which falls behind more than this is dropped, and its stream ends.
+pub const EVENT_BUFFER_SIZE: usize = 1000;
+
+/// A change which `SpvNode` publishes to subscribers.
+///
+/// When the tip changes after initial sync, `BlockDisconnected`s from the old tip come first,
+/// then `BlockConnected`s from the fork point, and finally `NewTip` or `Reorg`.
+/// Initial sync publishes only `NewTip`, since it would connect the whole chain.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainEvent
+{
+    /// A block is connected to active chain.
+    BlockConnected(BlockData),
+    /// A block is disconnected from active chain by a reorg.
+    BlockDisconnected(BlockData),
+    /// The tip of active chain moves forward.
+    NewTip(BlockData),
+    /// The previous tip is disconnected from active chain.
+    /// `depth` is the number of disconnected blocks.
+    Reorg
+    {
+        depth: u32,
+        tip: BlockData,
+    },
+    /// The number of established connections is changed.
+    PeerCount(usize),
+}
+
+#[derive(Message)]
+/// Start to receive `ChainEvent`s. Use `SpvNode::subscribe` to get them as a `Stream`.
+pub struct SubscribeChainEvents(pub mpsc::Sender<ChainEvent>);
+
+/// A headers only node which ties `ConnectionPool`, `SyncBlockChain` and `ListenNewBlocks` together.
+///
+/// When it starts, it runs a connection pool and syncs headers from the first established connection.
+/// If the connection fails, sync is resumed from another connection of the pool.
+/// After sync completes, it keeps following the tip by announcements from every connection.
+pub struct SpvNode
+{
+    network: Network,
+    blockchain: Arc<Mutex<BlockChain>>,
+    water_line: usize,
+    services: u64,
+    bootstrap_addrs: Vec<SocketAddr>,
+    dns_seeds: Option<Vec<String>>, // `None` means the default seeds of `network`
+
+    pool: Option<Addr<ConnectionPool>>,
+    // Missing headers are requested from the first connection of the listener.
+    listener: Option<(Addr<ListenNewBlocks>, Addr<Connection>)>,
+    in_flight: InFlightHeaders,
+    // A connection which we sync headers from now.
+    syncing: Option<Addr<Connection>>,
+    // Connections which fail to sync. They are not used for sync again.
+    failed: Vec<Addr<Connection>>,
+    synced: bool,
+    peers: usize,
+    tip: BlockData,
+    subscribers: Vec<mpsc::Sender<ChainEvent>>,
+}
+
+impl SpvNode
+{
+    /// Create a node which starts from the genesis block of `network`.
+    pub fn new(network: Network) -> SpvNode
+    {
+        SpvNode::with_blockchain(BlockChain::new(network))
+    }
+
+    /// Create a node which starts from `blockchain`, e.g. a restored one.
+    pub fn with_blockchain(blockchain: BlockChain) -> SpvNode
+    {
+        let network = blockchain.network();
+        let tip = blockchain.active_chain().latest_block().clone();
+        SpvNode {
+            network,
+            blockchain: Arc::new(Mutex::new(blockchain)),
+            water_line: DEFAULT_WATER_LINE,
+            services: 0,
+            bootstrap_addrs: Vec::new(),
+            dns_seeds: None,
+
+            pool: None,
+            listener: None,
+            in_flight: InFlightHeaders::new(),
+            syncing: None,
+            failed: Vec::new(),
+            synced: false,
+            peers: 0,
+            tip,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Start from `block` instead of the genesis block. Blocks before it are never requested.
+    pub fn with_start_block(self, block: BlockData) -> SpvNode
+    {
+        let blockchain = BlockChain::with_start(self.network, block);
+        SpvNode {
+            tip: blockchain.active_chain().latest_block().clone(),
+            blockchain: Arc::new(Mutex::new(blockchain)),
+            ..self
+        }
+    }
+
+    /// Set the number of outbound connections which the pool keeps.
+    pub fn with_water_line(mut self, water_line: usize) -> SpvNode
+    {
+        self.water_line = water_line;
+        self
+    }
+
+    /// Set services which we advertise in `version` messages.
+    pub fn with_services(mut self, services: u64) -> SpvNode
+    {
+        self.services = services;
+        self
+    }
+
+    /// Set static peers which are dialed first.
+    pub fn with_bootstrap_addrs(mut self, addrs: Vec<SocketAddr>) -> SpvNode
+    {
+        self.bootstrap_addrs = addrs;
+        self
+    }
+
+    /// Replace the default DNS seeds of the network. An empty list disables DNS seeds.
+    pub fn with_dns_seeds(mut self, seeds: Vec<String>) -> SpvNode
+    {
+        self.dns_seeds = Some(seeds);
+        self
+    }
+
+    /// Blockchain which the node keeps updated.
+    pub fn blockchain(&self) -> Arc<Mutex<BlockChain>>
+    {
+        self.blockchain.clone()
+    }
+
+    /// Subscribe `ChainEvent`s of `node`.
+    /// The stream ends when the node stops, or when the subscriber falls behind by `EVENT_BUFFER_SIZE` events.
+    pub fn subscribe(node: &Addr<SpvNode>) -> impl Stream<Item = ChainEvent, Error = ()>
+    {
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER_SIZE);
+        node.do_send(SubscribeChainEvents(tx));
+        rx
+    }
+
+    /// Send `event` to every subscriber without waiting. A subscriber whose buffer is full is dropped.
+    fn publish(&mut self, event: ChainEvent)
+    {
+        let mut subscribers = Vec::with_capacity(self.subscribers.len());
+        for mut subscriber in self.subscribers.drain(..) {
+            match subscriber.try_send(event.clone()) {
+                Ok(()) => subscribers.push(subscriber),
+                Err(ref e) if e.is_full() => warn!("Drop a subscriber which does not keep up with chain events"),
+                Err(_) => {}, // Subscriber is gone
+            }
+        }
+        self.subscribers = subscribers;
+    }
+
+    /// Publish events if the tip of active chain is changed.
+    fn update_tip(&mut self)
+    {
+        let (tip, events) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            let tip = *blockchain.active_chain().latest_block();
+            if tip.bitcoin_hash() == self.tip.bitcoin_hash() {
+                return;
+            }
+            (tip, tip_events(&blockchain, &self.tip, self.synced))
+        };
+        info!("New tip : height {}, hash {}", tip.height(), tip.bitcoin_hash());
+        self.tip = tip;
+        for event in events {
+            self.publish(event);
+        }
+    }
+
+    fn sync_from(&mut self, conn: Addr<Connection>, ctx: &mut Context<Self>)
+    {
+        self.syncing = Some(conn.clone());
+        SyncBlockChain::new(self.blockchain.clone(), self.in_flight.clone(), conn, ctx.address().recipient()).start();
+    }
+
+    /// Resume sync from another connection of the pool.
+    /// If there is no other connection, sync is resumed when a new connection is established.
+    fn retry_sync(&mut self, ctx: &mut Context<Self>)
+    {
+        if let Some(conn) = self.syncing.take() {
+            self.failed.push(conn);
+        }
+        self.failed.retain(|conn| conn.connected());
+        let pool = match self.pool {
+            Some(ref pool) => pool,
+            None => return,
+        };
+        let req = GetConnections {
+            num: 1,
+            except: self.failed.clone(),
+            services: 0,
+        };
+        let f = pool.send(req)
+            .into_actor(self)
+            .map(|conns, actor, ctx| {
+                // A new connection may already start to sync while waiting.
+                if actor.syncing.is_some() || actor.synced {
+                    return;
+                }
+                match conns.into_iter().next() {
+                    Some(conn) => actor.sync_from(conn, ctx),
+                    None => info!("No connection to sync from. Wait for a new connection"),
+                }
+            })
+            .map_err(|e, _actor, _ctx| info!("Connection pool is stopped : {:?}", e));
+        ctx.spawn(f);
+    }
+
+    /// Listen announcements from `conn` as well.
+    fn listen(&mut self, conn: Addr<Connection>, ctx: &mut Context<Self>)
+    {
+        match self.listener {
+            Some((ref listener, _)) => listener.do_send(ListenConnection(conn)),
+            None => {
+                let listener = ListenNewBlocks::start_actor(self.blockchain.clone(), conn.clone());
+                listener.do_send(SubscribeNewBlock {
+                    addr: ctx.address().recipient(),
+                });
+                self.listener = Some((listener, conn));
+            },
+        }
+    }
+
+    /// If the connection which the listener requests headers from is lost,
+    /// start a new listener on the remaining connections.
+    fn replace_lost_listener(&mut self, ctx: &mut Context<Self>)
+    {
+        let is_lost = match self.listener {
+            Some((_, ref conn)) => !conn.connected(),
+            None => false,
+        };
+        let pool = match self.pool {
+            Some(ref pool) if is_lost => pool,
+            _ => return,
+        };
+        self.listener = None;
+        let req = GetConnections {
+            num: self.water_line,
+            except: Vec::new(),
+            services: 0,
+        };
+        let f = pool.send(req)
+            .into_actor(self)
+            .map(|conns, actor, ctx| {
+                for conn in conns {
+                    actor.listen(conn, ctx);
+                }
+            })
+            .map_err(|e, _actor, _ctx| info!("Connection pool is stopped : {:?}", e));
+        ctx.spawn(f);
+    }
+}
+
+impl Actor for SpvNode
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context)
+    {
+        let mut pool = ConnectionPool::new(self.network, self.services, 0, false, self.blockchain.clone())
+            .with_bootstrap_addrs(self.bootstrap_addrs.clone());
+        if let Some(seeds) = self.dns_seeds.clone() {
+            pool = pool.with_dns_seeds(seeds);
+        }
+        pool.set_water_line(self.water_line);
+        let pool = pool.start();
+        pool.do_send(SubscribePoolEvents {
+            addr: ctx.address().recipient(),
+        });
+        self.pool = Some(pool);
+    }
+}
+
+impl Handler<SubscribeChainEvents> for SpvNode
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeChainEvents, _ctx: &mut Context<Self>)
+    {
+        self.subscribers.push(msg.0);
+    }
+}
+
+impl Handler<PoolEvent> for SpvNode
+{
+    type Result = ();
+
+    fn handle(&mut self, event: PoolEvent, ctx: &mut Context<Self>)
+    {
+        match event {
+            PoolEvent::ConnectionEstablished(conn, _) => {
+                self.peers += 1;
+                self.publish(ChainEvent::PeerCount(self.peers));
+                self.listen(conn.clone(), ctx);
+                if self.syncing.is_none() && !self.synced {
+                    self.sync_from(conn, ctx);
+                }
+            },
+            PoolEvent::ConnectionLost(_) | PoolEvent::ConnectionBanned(_) => {
+                self.peers = self.peers.saturating_sub(1);
+                self.publish(ChainEvent::PeerCount(self.peers));
+                self.replace_lost_listener(ctx);
+            },
+        }
+    }
+}
+
+impl Handler<SyncBlockChainResult> for SpvNode
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SyncBlockChainResult, ctx: &mut Context<Self>)
+    {
+        match msg {
+            SyncBlockChainResult::Complete(stats) => {
+                info!("Complete to sync headers : {:?}", stats);
+                self.syncing = None;
+                self.update_tip();
+                self.synced = true;
+            },
+            SyncBlockChainResult::Cancelled(_) => self.syncing = None,
+            SyncBlockChainResult::Error(stats, e) => {
+                info!("Fail to sync headers : {:?} {:?}", e, stats);
+                // Headers received so far are kept, so another connection resumes from there.
+                self.update_tip();
+                self.retry_sync(ctx);
+            },
+        }
+    }
+}
+
+impl Handler<NewBlockEvent> for SpvNode
+{
+    type Result = ();
+
+    fn handle(&mut self, _event: NewBlockEvent, _ctx: &mut Context<Self>)
+    {
+        self.update_tip();
+    }
+}
+
+impl Handler<Shutdown> for SpvNode
+{
+    type Result = ResponseActFuture<Self, (), ()>;
+
+    /// Shut down the connection pool, then stop the node.
+    fn handle(&mut self, _msg: Shutdown, _ctx: &mut Context<Self>) -> Self::Result
+    {
+        let f = match self.pool.take() {
+            Some(pool) => Either::A(ConnectionPool::shutdown(pool)),
+            None => Either::B(future::ok(())),
+        };
+        Box::new(f.into_actor(self).map(|(), _actor, ctx| ctx.stop()))
+    }
+}
+
+/// Events of the move of active chain from `old_tip`, which must differ from the current tip.
+/// If `per_block` is false, only the last `NewTip` or `Reorg` is made.
+fn tip_events(blockchain: &BlockChain, old_tip: &BlockData, per_block: bool) -> Vec<ChainEvent>
+{
+    let active_chain = blockchain.active_chain();
+    let tip = *active_chain.latest_block();
+    let depth = active_chain.reorg_depth(old_tip);
+    let mut events = Vec::new();
+    if per_block {
+        let fork_height = old_tip.height() - depth;
+        let disconnected = (fork_height + 1..old_tip.height() + 1)
+            .rev()
+            .filter_map(|height| active_chain.ancestor_at_height(old_tip, height));
+        events.extend(disconnected.map(ChainEvent::BlockDisconnected));
+        let connected = active_chain.iter_from(fork_height + 1);
+        events.extend(connected.map(|block| ChainEvent::BlockConnected(*block)));
+    }
+    if depth > 0 {
+        events.push(ChainEvent::Reorg { depth, tip });
+    } else {
+        events.push(ChainEvent::NewTip(tip));
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    use bitcoin::blockdata::block::{BlockHeader, LoneBlockHeader};
+    use bitcoin::network::{encodable::VarInt, message::NetworkMessage};
+    use bitcoin::util::hash::Sha256dHash;
+    use futures::Future;
+
+    use connection::message::Message;
+    use testing::{loopback_peer, mine, MIN_DIFFICULTY_BITS};
+
+    // `n` headers after `prev`. Timestamps are `prev.time + 1`, `prev.time + 2`, ...
+    fn dummy_headers(prev: &BlockHeader, n: usize) -> Vec<BlockHeader>
+    {
+        let mut prev = *prev;
+        let mut headers = Vec::with_capacity(n);
+        for _ in 0..n {
+            let mut header = BlockHeader {
+                version: 1,
+                prev_blockhash: prev.bitcoin_hash(),
+                merkle_root: Sha256dHash::default(),
+                time: prev.time + 1,
+                bits: MIN_DIFFICULTY_BITS,
+                nonce: 0,
+            };
+            mine(&mut header);
+            prev = header;
+            headers.push(header);
+        }
+        headers
+    }
+
+    fn headers_msg(headers: &[BlockHeader]) -> Message
+    {
+        let lone_headers = headers
+            .iter()
+            .map(|h| {
+                LoneBlockHeader {
+                    header: *h,
+                    tx_count: VarInt(0),
+                }
+            })
+            .collect();
+        NetworkMessage::Headers(lone_headers).into()
+    }
+
+    // A peer which knows all but the last of `headers` when we sync, then announces the last one.
+    // Later `getheaders` are responded with every header after the first known locator hash.
+    fn announcing_server(start: BlockHeader, headers: Vec<BlockHeader>) -> impl FnMut(Message) -> Vec<Message>
+    {
+        let mut hashes = vec![start.bitcoin_hash()];
+        hashes.extend(headers.iter().map(|h| h.bitcoin_hash()));
+        let mut announced = false;
+        move |msg| {
+            let req = match msg {
+                Message::Network(NetworkMessage::GetHeaders(req)) => req,
+                _ => return Vec::new(),
+            };
+            if !announced {
+                announced = true;
+                let (synced, new) = headers.split_at(headers.len() - 1);
+                return vec![headers_msg(synced), headers_msg(new)];
+            }
+            let pos = req.locator_hashes
+                .iter()
+                .filter_map(|h| hashes.iter().position(|known| known == h))
+                .next();
+            match pos {
+                Some(pos) => vec![headers_msg(&headers[pos..])],
+                None => vec![headers_msg(&[])],
+            }
+        }
+    }
+
+    #[test]
+    fn publish_disconnected_blocks_before_connected_ones()
+    {
+        let mut start = BlockHeader {
+            version: 1,
+            prev_blockhash: Sha256dHash::default(),
+            merkle_root: Sha256dHash::default(),
+            time: 1,
+            bits: MIN_DIFFICULTY_BITS,
+            nonce: 0,
+        };
+        mine(&mut start);
+        let mut blockchain = BlockChain::with_start(Network::Regtest, BlockData::new(start, 0));
+        let branch_a = dummy_headers(&start, 2);
+        // Another version makes another block on the same parent.
+        let mut fork = dummy_headers(&start, 1)[0];
+        fork.version = 2;
+        mine(&mut fork);
+        let mut branch_b = vec![fork];
+        branch_b.extend(dummy_headers(&fork, 2));
+
+        blockchain.try_add(branch_a[0]).unwrap();
+        let a1 = *blockchain.active_chain().latest_block();
+        blockchain.try_add(branch_a[1]).unwrap();
+        let old_tip = *blockchain.active_chain().latest_block();
+        for header in branch_b.iter() {
+            blockchain.try_add(*header).unwrap();
+        }
+
+        let block = |header: &BlockHeader| *blockchain.active_chain().get_by_hash(&header.bitcoin_hash()).unwrap();
+        let tip = block(&branch_b[2]);
+        let expected = vec![
+            ChainEvent::BlockDisconnected(old_tip),
+            ChainEvent::BlockDisconnected(a1),
+            ChainEvent::BlockConnected(block(&branch_b[0])),
+            ChainEvent::BlockConnected(block(&branch_b[1])),
+            ChainEvent::BlockConnected(tip),
+            ChainEvent::Reorg { depth: 2, tip },
+        ];
+        assert_eq!(tip_events(&blockchain, &old_tip, true), expected);
+        assert_eq!(tip_events(&blockchain, &old_tip, false), vec![ChainEvent::Reorg { depth: 2, tip }]);
+    }
+
+    #[test]
+    fn drop_subscriber_which_falls_behind()
+    {
+        let mut node = SpvNode::new(Network::Regtest);
+        let (slow, _slow_rx) = mpsc::channel(0);
+        let (gone, gone_rx) = mpsc::channel(10);
+        let (fast, fast_rx) = mpsc::channel(10);
+        node.subscribers = vec![slow, gone, fast];
+        drop(gone_rx);
+
+        // A sender can always send one more message than the buffer.
+        node.publish(ChainEvent::PeerCount(1));
+        assert_eq!(node.subscribers.len(), 2);
+        node.publish(ChainEvent::PeerCount(2));
+        assert_eq!(node.subscribers.len(), 1);
+
+        let events: Vec<_> = fast_rx.take(2).collect().wait().unwrap();
+        assert_eq!(events, vec![ChainEvent::PeerCount(1), ChainEvent::PeerCount(2)]);
+    }
+
+    #[test]
+    fn sync_headers_then_follow_announced_block()
+    {
+        let mut start = BlockHeader {
+            version: 1,
+            prev_blockhash: Sha256dHash::default(),
+            merkle_root: Sha256dHash::default(),
+            time: 1,
+            bits: MIN_DIFFICULTY_BITS,
+            nonce: 0,
+        };
+        mine(&mut start);
+        let headers = dummy_headers(&start, 11);
+        let (listen_addr, peer) = loopback_peer(Network::Regtest, move |peer| {
+            peer.handshake(10).run_and_serve(announcing_server(start, headers))
+        });
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events2 = events.clone();
+
+        let node = SpvNode::new(Network::Regtest)
+            .with_start_block(BlockData::new(start, 0))
+            .with_water_line(1)
+            .with_bootstrap_addrs(vec![listen_addr])
+            .with_dns_seeds(Vec::new());
+        let blockchain = node.blockchain();
+
+        System::run(move || {
+            Arbiter::spawn(peer);
+
+            let node = node.start();
+            let node2 = node.clone();
+            let f = SpvNode::subscribe(&node)
+                .take_while(move |event| {
+                    events2.borrow_mut().push(event.clone());
+                    match *event {
+                        ChainEvent::NewTip(ref tip) => Ok(tip.height() < 11),
+                        _ => Ok(true),
+                    }
+                })
+                .for_each(|_| Ok(()))
+                .then(move |_| node2.send(Shutdown))
+                .then(|res| {
+                    assert!(res.is_ok(), "Fail to shutdown : {:?}", res);
+                    System::current().stop();
+                    Ok::<(), ()>(())
+                });
+            Arbiter::spawn(f);
+        });
+
+        let events = events.borrow();
+        match events[0] {
+            ChainEvent::PeerCount(1) => {},
+            ref other => panic!("Unexpected first event : {:?}", other),
+        }
+        match events[events.len() - 1] {
+            ChainEvent::NewTip(ref tip) => assert_eq!(tip.height(), 11),
+            ref other => panic!("Unexpected last event : {:?}", other),
+        }
+        assert_eq!(blockchain.lock().unwrap().active_chain().len(), 12);
+    }
+}
@@ -0,0 +1,390 @@
+use std::{collections::{HashMap, VecDeque}, time::Duration};
+
+use actix::prelude::*;
+use bitcoin::blockdata::block::Block;
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+use futures::{Future, Stream, sync::oneshot};
+
+use blockchain::{BlockData, FullBlockData};
+use connection::{connection_pool::{ConnectionPool, GetConnections}, socket::NODE_NETWORK, Connection};
+use error::Error;
+use process::request_blocks::{block_stream, block_stream_unchecked};
+
+/// The max number of blocks which are requested from one peer at once.
+pub const MAX_BLOCKS_PER_PEER: usize = 500;
+
+/// The max number of connections which `download_blocks` uses.
+pub const MAX_DOWNLOAD_PEERS: usize = 8;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DownloadError
+{
+    /// Every connection fails before all blocks are downloaded.
+    /// `missing` is the number of blocks which are not downloaded.
+    Exhausted
+    {
+        missing: usize,
+    },
+    /// `ConnectionPool` is already stopped.
+    PoolStopped,
+    /// Downloading is aborted before it completes, e.g. the system is shutting down.
+    Aborted,
+}
+
+/// Download bodies of `blocks` from connections of `pool`, and return them ordered by height.
+///
+/// Blocks are split into chunks of up to `MAX_BLOCKS_PER_PEER`, and each connection downloads one chunk
+/// at a time. If a connection does not complete its chunk in `timeout`, or it sends an invalid block,
+/// the connection is given up and the chunk is downloaded by another one.
+/// Responses of a given up connection are ignored even if they arrive later.
+pub fn download_blocks(
+    pool: &Addr<ConnectionPool>,
+    blocks: Vec<BlockData>,
+    timeout: Duration,
+) -> impl Future<Item = Vec<FullBlockData>, Error = DownloadError>
+{
+    let req = GetConnections {
+        num: MAX_DOWNLOAD_PEERS,
+        except: Vec::new(),
+        services: NODE_NETWORK,
+    };
+    pool.send(req)
+        .map_err(|_e| DownloadError::PoolStopped)
+        .and_then(move |conns| download_blocks_from(conns, blocks, timeout))
+}
+
+/// Same as `download_blocks`, but uses given connections.
+pub fn download_blocks_from(
+    conns: Vec<Addr<Connection>>,
+    blocks: Vec<BlockData>,
+    timeout: Duration,
+) -> impl Future<Item = Vec<FullBlockData>, Error = DownloadError>
+{
+    download_in_chunks(conns, blocks, MAX_BLOCKS_PER_PEER, timeout, true)
+}
+
+/// Same as `download_blocks_from`, but transactions are not checked against merkle roots of headers.
+/// Only for blocks whose bodies are dummy, e.g. in tests. A peer can send any transactions.
+pub fn download_blocks_from_unchecked(
+    conns: Vec<Addr<Connection>>,
+    blocks: Vec<BlockData>,
+    timeout: Duration,
+) -> impl Future<Item = Vec<FullBlockData>, Error = DownloadError>
+{
+    download_in_chunks(conns, blocks, MAX_BLOCKS_PER_PEER, timeout, false)
+}
+
+fn download_in_chunks(
+    conns: Vec<Addr<Connection>>,
+    mut blocks: Vec<BlockData>,
+    chunk_size: usize,
+    timeout: Duration,
+    check_merkle_root: bool,
+) -> impl Future<Item = Vec<FullBlockData>, Error = DownloadError>
+{
+    blocks.sort_by_key(|b| b.height());
+    blocks.dedup_by_key(|b| b.bitcoin_hash());
+
+    let mut targets = HashMap::new();
+    let mut chunks = Vec::new();
+    for (idx, chunk) in blocks.chunks(chunk_size).enumerate() {
+        for block in chunk {
+            targets.insert(block.bitcoin_hash(), block.height());
+        }
+        chunks.push(Chunk {
+            remaining: chunk.iter().map(|b| b.bitcoin_hash()).collect(),
+            assigned: None,
+        });
+    }
+
+    let (tx, rx) = oneshot::channel();
+    BlockDownloader {
+        timeout,
+        check_merkle_root,
+        idle: conns,
+        targets,
+        pending: (0..chunks.len()).collect(),
+        chunks,
+        attempt: 0,
+        downloaded: Vec::new(),
+        done: Some(tx),
+    }.start();
+    rx.map_err(|_canceled| DownloadError::Aborted).and_then(|res| res)
+}
+
+struct Chunk
+{
+    // Blocks of this chunk which do not arrive yet.
+    remaining: Vec<Sha256dHash>,
+    // A connection which downloads this chunk now, and the id of the attempt.
+    assigned: Option<(Addr<Connection>, usize)>,
+}
+
+struct BlockDownloader
+{
+    timeout: Duration,
+    check_merkle_root: bool,
+
+    // Connections which do not download any chunk now.
+    idle: Vec<Addr<Connection>>,
+    // Height of each block to download.
+    targets: HashMap<Sha256dHash, u32>,
+    chunks: Vec<Chunk>,
+    // Indexes of chunks which are not assigned to any connection.
+    pending: VecDeque<usize>,
+    attempt: usize,
+    downloaded: Vec<FullBlockData>,
+    done: Option<oneshot::Sender<Result<Vec<FullBlockData>, DownloadError>>>,
+}
+
+impl Actor for BlockDownloader
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context)
+    {
+        if self.chunks.is_empty() {
+            return self.finish(Ok(Vec::new()), ctx);
+        }
+        self.assign_chunks(ctx);
+    }
+}
+
+impl BlockDownloader
+{
+    /// Assign pending chunks to idle connections.
+    fn assign_chunks(&mut self, ctx: &mut Context<Self>)
+    {
+        while !self.pending.is_empty() && !self.idle.is_empty() {
+            let idx = self.pending.pop_front().unwrap();
+            let conn = self.idle.pop().unwrap();
+            self.assign(idx, conn, ctx);
+        }
+
+        let is_downloading = self.chunks.iter().any(|c| c.assigned.is_some());
+        if !self.pending.is_empty() && !is_downloading {
+            let missing = self.chunks.iter().map(|c| c.remaining.len()).sum();
+            self.finish(Err(DownloadError::Exhausted { missing }), ctx);
+        }
+    }
+
+    fn assign(&mut self, idx: usize, conn: Addr<Connection>, ctx: &mut Context<Self>)
+    {
+        self.attempt += 1;
+        let attempt = self.attempt;
+        self.chunks[idx].assigned = Some((conn.clone(), attempt));
+
+        let block_hashes = self.chunks[idx].remaining.clone();
+        let blocks: Box<Stream<Item = Block, Error = Error>> = if self.check_merkle_root {
+            Box::new(block_stream(&conn, block_hashes, self.timeout))
+        } else {
+            Box::new(block_stream_unchecked(&conn, block_hashes, self.timeout))
+        };
+        let f = blocks
+            .into_actor(self)
+            .map(move |block, actor, ctx| actor.handle_block(idx, attempt, block, ctx))
+            .map_err(move |e, actor, ctx| {
+                info!("Fail to download blocks : {}", e);
+                actor.give_up(idx, attempt, ctx);
+            })
+            .finish();
+        ctx.spawn(f);
+    }
+
+    fn is_current(&self, idx: usize, attempt: usize) -> bool
+    {
+        match self.chunks[idx].assigned {
+            Some((_, a)) => a == attempt,
+            None => false,
+        }
+    }
+
+    /// Give up the connection which downloads chunk `idx`, and download the chunk by another connection.
+    /// It is ignored if `attempt` is already finished.
+    fn give_up(&mut self, idx: usize, attempt: usize, ctx: &mut Context<Self>)
+    {
+        if !self.is_current(idx, attempt) {
+            return;
+        }
+        info!("Give up downloading {} blocks from a peer", self.chunks[idx].remaining.len());
+        self.chunks[idx].assigned = None;
+        self.pending.push_back(idx);
+        self.assign_chunks(ctx);
+    }
+
+    /// Take a block which `attempt` downloads for chunk `idx`.
+    /// It is ignored if `attempt` is already given up.
+    fn handle_block(&mut self, idx: usize, attempt: usize, block: Block, ctx: &mut Context<Self>)
+    {
+        if !self.is_current(idx, attempt) {
+            return;
+        }
+        let hash = block.bitcoin_hash();
+        let height = match self.targets.get(&hash) {
+            Some(&height) => height,
+            None => return,
+        };
+        let pos = match self.chunks[idx].remaining.iter().position(|h| *h == hash) {
+            Some(pos) => pos,
+            None => return,
+        };
+
+        self.chunks[idx].remaining.remove(pos);
+        self.downloaded.push(FullBlockData::new(block, height));
+
+        if self.chunks[idx].remaining.is_empty() {
+            // The connection is free to download the next chunk.
+            if let Some((conn, _)) = self.chunks[idx].assigned.take() {
+                self.idle.push(conn);
+            }
+            self.pending.retain(|i| *i != idx);
+            if self.chunks.iter().all(|c| c.remaining.is_empty()) {
+                let mut blocks: Vec<_> = self.downloaded.drain(..).collect();
+                blocks.sort_by_key(|b| b.height);
+                return self.finish(Ok(blocks), ctx);
+            }
+            self.assign_chunks(ctx);
+        }
+    }
+
+    fn finish(&mut self, res: Result<Vec<FullBlockData>, DownloadError>, ctx: &mut Context<Self>)
+    {
+        if let Some(done) = self.done.take() {
+            let _ = done.send(res);
+        }
+        ctx.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use bitcoin::network::{constants::Network, message::NetworkMessage,
+                           message_blockdata::{InvType, Inventory}};
+
+    use testing::{self, blocks_server, connect_scripted_peers, duplex, run_in_system, MemoryStream, PeerFuture,
+                  ScriptedPeer};
+
+    // `testing::synthetic_blocks` with their heights.
+    fn synthetic_blocks(len: u32) -> Vec<FullBlockData>
+    {
+        testing::synthetic_blocks(len)
+            .into_iter()
+            .zip(1..)
+            .map(|(block, height)| FullBlockData::new(block, height))
+            .collect()
+    }
+
+    // Run `download_in_chunks` against scripted peers, in order of connections.
+    fn run_download(
+        peers: Vec<(MemoryStream, PeerFuture)>,
+        blocks: Vec<BlockData>,
+        chunk_size: usize,
+    ) -> Result<Vec<FullBlockData>, DownloadError>
+    {
+        run_in_system(move || {
+            connect_scripted_peers(peers, Network::Regtest).and_then(move |mut conns| {
+                // `BlockDownloader` takes idle connections from the back.
+                conns.reverse();
+                download_in_chunks(conns, blocks, chunk_size, Duration::from_millis(500), true)
+            })
+        })
+    }
+
+    fn block_data(blocks: &[FullBlockData]) -> Vec<BlockData>
+    {
+        blocks
+            .iter()
+            .map(|b| BlockData::new(b.block.header, b.height))
+            .collect()
+    }
+
+    #[test]
+    fn retry_chunk_on_another_peer_when_one_fails_halfway()
+    {
+        let blocks = synthetic_blocks(6);
+        let bodies: Vec<_> = blocks.iter().map(|b| b.block.clone()).collect();
+
+        // The first peer serves only one block of its chunk, then closes the connection.
+        let (local1, remote1) = duplex();
+        let first = bodies[0].clone();
+        let peer1 = ScriptedPeer::new(remote1, Network::Regtest)
+            .handshake(0)
+            .expect("getdata")
+            .send(NetworkMessage::Block(first))
+            .run()
+            .map(|_socket| ());
+
+        let (local2, remote2) = duplex();
+        let peer2 = ScriptedPeer::new(remote2, Network::Regtest)
+            .handshake(0)
+            .run_and_serve(blocks_server(bodies.clone()));
+
+        let peers: Vec<(MemoryStream, PeerFuture)> = vec![(local1, Box::new(peer1)), (local2, Box::new(peer2))];
+        let mut requested = block_data(&blocks);
+        requested.reverse();
+        let downloaded = run_download(peers, requested, 2).unwrap();
+
+        let heights: Vec<_> = downloaded.iter().map(|b| b.height).collect();
+        assert_eq!(heights, vec![1, 2, 3, 4, 5, 6]);
+        let downloaded_bodies: Vec<_> = downloaded.into_iter().map(|b| b.block).collect();
+        assert_eq!(downloaded_bodies, bodies);
+    }
+
+    #[test]
+    fn fail_when_every_peer_fails()
+    {
+        let blocks = synthetic_blocks(3);
+
+        let (local, remote) = duplex();
+        let peer = ScriptedPeer::new(remote, Network::Regtest)
+            .handshake(0)
+            .expect("getdata")
+            .send(NetworkMessage::Block(blocks[0].block.clone()))
+            .run_and_serve(|_msg| Vec::new());
+
+        let peers: Vec<(MemoryStream, PeerFuture)> = vec![(local, Box::new(peer))];
+        let res = run_download(peers, block_data(&blocks), MAX_BLOCKS_PER_PEER);
+        assert_eq!(res.unwrap_err(), DownloadError::Exhausted { missing: 2 });
+    }
+
+    #[test]
+    fn late_notfound_of_given_up_peer_does_not_give_up_next_peer()
+    {
+        let blocks = synthetic_blocks(2);
+        let bodies: Vec<_> = blocks.iter().map(|b| b.block.clone()).collect();
+
+        // The first peer answers `notfound` after it is given up, while the second peer downloads the chunk.
+        let (local1, remote1) = duplex();
+        let invs = bodies
+            .iter()
+            .map(|b| Inventory {
+                inv_type: InvType::Block,
+                hash: b.bitcoin_hash(),
+            })
+            .collect();
+        let peer1 = ScriptedPeer::new(remote1, Network::Regtest)
+            .handshake(0)
+            .expect("getdata")
+            .wait(Duration::from_millis(700))
+            .send(NetworkMessage::NotFound(invs))
+            .run_and_serve(|_msg| Vec::new());
+
+        let (local2, remote2) = duplex();
+        let peer2 = ScriptedPeer::new(remote2, Network::Regtest)
+            .handshake(0)
+            .expect("getdata")
+            .wait(Duration::from_millis(300))
+            .send(NetworkMessage::Block(bodies[0].clone()))
+            .send(NetworkMessage::Block(bodies[1].clone()))
+            .run_and_serve(|_msg| Vec::new());
+
+        let peers: Vec<(MemoryStream, PeerFuture)> = vec![(local1, Box::new(peer1)), (local2, Box::new(peer2))];
+        let downloaded = run_download(peers, block_data(&blocks), MAX_BLOCKS_PER_PEER).unwrap();
+        let downloaded_bodies: Vec<_> = downloaded.into_iter().map(|b| b.block).collect();
+        assert_eq!(downloaded_bodies, bodies);
+    }
+}
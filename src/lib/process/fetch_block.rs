@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use actix::prelude::*;
+use bitcoin::blockdata::block::Block;
+use bitcoin::util::hash::Sha256dHash;
+use futures::{future::{self, Either, Loop}, Future};
+
+use connection::{connection_pool::{ConnectionPool, GetConnections}, socket::NODE_NETWORK, Connection};
+use error::Error;
+use process::request_blocks::request_blocks;
+
+/// The max number of connections which `fetch_block` tries.
+pub const MAX_FETCH_ATTEMPTS: usize = 3;
+
+#[derive(Debug)]
+pub enum FetchError
+{
+    /// Every connection fails to serve the block. Reasons are in order of attempts.
+    /// It is empty if there is no connection.
+    Exhausted(Vec<Error>),
+    /// `ConnectionPool` is already stopped.
+    PoolStopped,
+}
+
+/// Fetch a single block by hash from connections of `pool`.
+///
+/// Connections are tried one by one until one of them serves a valid block.
+/// Each connection is given up after `timeout`.
+pub fn fetch_block(
+    pool: &Addr<ConnectionPool>,
+    hash: Sha256dHash,
+    timeout: Duration,
+) -> impl Future<Item = Block, Error = FetchError>
+{
+    let req = GetConnections {
+        num: MAX_FETCH_ATTEMPTS,
+        except: Vec::new(),
+        services: NODE_NETWORK,
+    };
+    pool.send(req)
+        .map_err(|_e| FetchError::PoolStopped)
+        .and_then(move |conns| fetch_block_from(conns, hash, timeout))
+}
+
+/// Same as `fetch_block`, but tries given connections in order.
+///
+/// Each attempt is a separate request, so a late response from a connection which is already given up
+/// is neither taken as the block nor blamed on the next connection.
+pub fn fetch_block_from(
+    conns: Vec<Addr<Connection>>,
+    hash: Sha256dHash,
+    timeout: Duration,
+) -> impl Future<Item = Block, Error = FetchError>
+{
+    future::loop_fn((conns.into_iter(), Vec::new()), move |(mut conns, mut failures)| {
+        let conn = match conns.next() {
+            Some(conn) => conn,
+            None => return Either::A(future::err(FetchError::Exhausted(failures))),
+        };
+        let f = request_blocks(&conn, vec![hash], timeout).then(move |res| match res {
+            Ok(mut blocks) => Ok(Loop::Break(blocks.remove(0))),
+            Err(e) => {
+                info!("Fail to fetch block {} : {}", hash, e);
+                failures.push(e);
+                Ok(Loop::Continue((conns, failures)))
+            },
+        });
+        Either::B(f)
+    })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::{constants::Network, message::NetworkMessage,
+                           message_blockdata::{InvType, Inventory}, serialize::BitcoinHash};
+
+    use testing::{connect_scripted_peers, duplex, run_in_system, MemoryStream, PeerFuture, ScriptedPeer};
+
+    // Run `fetch_block_from` against scripted peers, in order.
+    fn run_fetch(peers: Vec<(MemoryStream, PeerFuture)>, hash: Sha256dHash) -> Result<Block, FetchError>
+    {
+        run_in_system(move || {
+            connect_scripted_peers(peers, Network::Bitcoin)
+                .and_then(move |conns| fetch_block_from(conns, hash, Duration::from_millis(500)))
+        })
+    }
+
+    fn scripted_peer(script: fn(ScriptedPeer<MemoryStream>) -> ScriptedPeer<MemoryStream>) -> (MemoryStream, PeerFuture)
+    {
+        let (local, remote) = duplex();
+        let peer = script(ScriptedPeer::new(remote, Network::Bitcoin).handshake(0));
+        (local, Box::new(peer.run_and_serve(|_msg| Vec::new())))
+    }
+
+    fn genesis_inv() -> Inventory
+    {
+        Inventory {
+            inv_type: InvType::Block,
+            hash: genesis_block(Network::Bitcoin).bitcoin_hash(),
+        }
+    }
+
+    #[test]
+    fn fall_back_to_next_peer_on_notfound()
+    {
+        let block = genesis_block(Network::Bitcoin);
+        let peer1 = scripted_peer(|peer| peer.expect("getdata").send(NetworkMessage::NotFound(vec![genesis_inv()])));
+        let peer2 = scripted_peer(|peer| {
+            peer.expect("getdata")
+                .send(NetworkMessage::Block(genesis_block(Network::Bitcoin)))
+        });
+
+        let fetched = run_fetch(vec![peer1, peer2], block.bitcoin_hash()).unwrap();
+        assert_eq!(fetched, block);
+    }
+
+    #[test]
+    fn reject_block_whose_transactions_do_not_match()
+    {
+        let hash = genesis_block(Network::Bitcoin).bitcoin_hash();
+        // Header is the same, but a transaction is added.
+        let peer1 = scripted_peer(|peer| {
+            let mut block = genesis_block(Network::Bitcoin);
+            let tx = block.txdata[0].clone();
+            block.txdata.push(tx);
+            peer.expect("getdata").send(NetworkMessage::Block(block))
+        });
+        // Peer never responds.
+        let peer2 = scripted_peer(|peer| peer.expect("getdata"));
+
+        match run_fetch(vec![peer1, peer2], hash) {
+            Err(FetchError::Exhausted(failures)) => match failures.as_slice() {
+                [Error::InvalidMerkleRoot(invalid), Error::Timeout] => assert_eq!(*invalid, hash),
+                failures => panic!("Unexpected failures : {:?}", failures),
+            },
+            other => panic!("Unexpected result : {:?}", other.map(|b| b.bitcoin_hash())),
+        }
+    }
+
+    #[test]
+    fn late_response_of_previous_peer_does_not_affect_next_peer()
+    {
+        let block = genesis_block(Network::Bitcoin);
+        // The first peer sends an invalid block after it is given up, while the second peer is being asked.
+        let peer1 = scripted_peer(|peer| {
+            let mut block = genesis_block(Network::Bitcoin);
+            let tx = block.txdata[0].clone();
+            block.txdata.push(tx);
+            peer.expect("getdata")
+                .wait(Duration::from_millis(700))
+                .send(NetworkMessage::Block(block))
+        });
+        let peer2 = scripted_peer(|peer| {
+            peer.expect("getdata")
+                .wait(Duration::from_millis(300))
+                .send(NetworkMessage::Block(genesis_block(Network::Bitcoin)))
+        });
+
+        let fetched = run_fetch(vec![peer1, peer2], block.bitcoin_hash()).unwrap();
+        assert_eq!(fetched, block);
+    }
+}
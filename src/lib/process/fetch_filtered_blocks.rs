@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use actix::prelude::*;
+use bitcoin::util::hash::Sha256dHash;
+use futures::Future;
+
+use connection::Connection;
+use error::Error;
+use process::request_blocks::{request_filtered_blocks, FilteredBlock};
+
+/// Headers of filtered blocks with their transactions which match a bloom filter.
+pub type FilteredBlocks = Vec<FilteredBlock>;
+
+/// Download `block_hashes` from `conn` as filtered blocks (BIP 37).
+/// A bloom filter must be loaded on `conn` by `LoadBloomFilter` before.
+///
+/// Each block is returned with the transactions which match the filter, in the order of `block_hashes`.
+/// Partial merkle trees are already verified against merkle roots of headers by `Connection`.
+/// `Connection` serves one request at a time, so do not call this in parallel on the same connection.
+pub fn get_filtered_blocks(
+    conn: Addr<Connection>,
+    block_hashes: Vec<Sha256dHash>,
+    timeout: Duration,
+) -> impl Future<Item = (Addr<Connection>, FilteredBlocks), Error = Error>
+{
+    request_filtered_blocks(&conn, block_hashes, timeout).map(move |blocks| (conn, blocks))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use bitcoin::blockdata::{block::BlockHeader, transaction::Transaction};
+    use bitcoin::network::{constants::Network, message::NetworkMessage, serialize::BitcoinHash};
+
+    use bloom::MerkleBlock;
+    use connection::message::Message;
+    use testing::{connect_scripted_peers, duplex, run_in_system, MemoryStream, PeerFuture, ScriptedPeer};
+
+    fn dummy_tx(lock_time: u32) -> Transaction
+    {
+        Transaction {
+            version: 1,
+            lock_time,
+            input: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    // A merkleblock of a block which has only `tx`.
+    fn single_tx_merkle_block(tx: &Transaction, matched: bool) -> MerkleBlock
+    {
+        let header = BlockHeader {
+            version: 1,
+            prev_blockhash: Sha256dHash::default(),
+            merkle_root: tx.bitcoin_hash(),
+            time: tx.lock_time,
+            bits: 0,
+            nonce: 0,
+        };
+        MerkleBlock {
+            header,
+            total_transactions: 1,
+            hashes: vec![tx.bitcoin_hash()],
+            flags: vec![matched as u8],
+        }
+    }
+
+    #[test]
+    fn collect_filtered_blocks_in_requested_order()
+    {
+        let (matched_tx, unmatched_tx) = (dummy_tx(1), dummy_tx(2));
+        let matched_block = single_tx_merkle_block(&matched_tx, true);
+        let unmatched_block = single_tx_merkle_block(&unmatched_tx, false);
+        let (matched_header, unmatched_header) = (matched_block.header, unmatched_block.header);
+        let hashes = vec![unmatched_header.bitcoin_hash(), matched_header.bitcoin_hash()];
+
+        let (local, remote) = duplex();
+        let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+            .handshake(0)
+            .run_and_serve(move |msg| match msg.command().as_str() {
+                // Blocks are served in another order than requested.
+                "getdata" => vec![
+                    Message::MerkleBlock(matched_block.clone()),
+                    NetworkMessage::Tx(matched_tx.clone()).into(),
+                    Message::MerkleBlock(unmatched_block.clone()),
+                ],
+                _ => Vec::new(),
+            });
+        let peers: Vec<(MemoryStream, PeerFuture)> = vec![(local, Box::new(peer))];
+
+        let (_conn, blocks) = run_in_system(move || {
+            connect_scripted_peers(peers, Network::Bitcoin)
+                .and_then(move |mut conns| get_filtered_blocks(conns.remove(0), hashes, Duration::from_secs(3)))
+        }).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0], (unmatched_header, Vec::new()));
+        assert_eq!(blocks[1], (matched_header, vec![dummy_tx(1)]));
+    }
+}
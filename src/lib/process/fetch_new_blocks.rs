@@ -0,0 +1,107 @@
+use std::{collections::HashSet, sync::{Arc, Mutex}, time::Duration};
+
+use actix::prelude::*;
+use bitcoin::blockdata::block::Block;
+use bitcoin::util::hash::Sha256dHash;
+use futures::Future;
+
+use blockchain::BlockChain;
+use connection::{BlockInvsResponse, Connection, GetBlockInvsRequest};
+use error::Error;
+use process::request_blocks::{request_blocks, single_response};
+
+/// Ask `conn` which blocks follow the active chain of `blockchain` by `getblocks`,
+/// then download the announced blocks which we do not know yet.
+///
+/// This is the flow before headers first sync, which some old peers still rely on.
+/// Blocks are returned in the order peer announces them, and are not added to `blockchain`.
+/// If peer does not respond `inv` within the request timeout of `conn`, there is no new block.
+/// If peer announces a block but does not have it, returned future fails with `Error::BlockNotFound`.
+pub fn fetch_new_blocks(
+    conn: Addr<Connection>,
+    blockchain: Arc<Mutex<BlockChain>>,
+    timeout: Duration,
+) -> impl Future<Item = (Addr<Connection>, Vec<Block>), Error = Error>
+{
+    let locator_hashes = blockchain.lock().unwrap().active_chain().locator_hashes_vec();
+    let (addr, invs) = single_response();
+    let conn2 = conn.clone();
+    conn.send(GetBlockInvsRequest { locator_hashes, addr })
+        .from_err()
+        .and_then(move |()| invs)
+        .and_then(move |res| {
+            let block_hashes = match res {
+                BlockInvsResponse::Invs(invs) => unknown_blocks(&blockchain, invs),
+                BlockInvsResponse::Timeout => Vec::new(),
+            };
+            request_blocks(&conn2, block_hashes, timeout)
+        })
+        .map(move |blocks| (conn, blocks))
+}
+
+/// Hashes of `invs` which are not in `blockchain`, without duplicates.
+/// Peer may announce blocks which we already know, e.g. when our tip is on a stale branch.
+fn unknown_blocks(blockchain: &Mutex<BlockChain>, invs: Vec<Sha256dHash>) -> Vec<Sha256dHash>
+{
+    let blockchain = blockchain.lock().unwrap();
+    let mut seen = HashSet::new();
+    invs.into_iter()
+        .filter(|hash| !blockchain.contains(hash) && seen.insert(*hash))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::{constants::Network, message::NetworkMessage,
+                           message_blockdata::{InvType, Inventory}, serialize::BitcoinHash};
+
+    use connection::message::Message;
+    use testing::{connect_scripted_peers, duplex, run_in_system, MemoryStream, PeerFuture, ScriptedPeer};
+
+    fn block_inv(block: &Block) -> Inventory
+    {
+        Inventory {
+            inv_type: InvType::Block,
+            hash: block.bitcoin_hash(),
+        }
+    }
+
+    #[test]
+    fn fetch_announced_blocks_which_we_do_not_know()
+    {
+        // Genesis blocks of other networks stand for new blocks, since they have valid merkle roots.
+        let known = genesis_block(Network::Bitcoin);
+        let (new1, new2) = (genesis_block(Network::Testnet), genesis_block(Network::Regtest));
+        let invs = vec![block_inv(&known), block_inv(&new1), block_inv(&new2)];
+        let blocks = vec![new1.clone(), new2.clone()];
+        let requested = Rc::new(RefCell::new(Vec::new()));
+        let requested2 = requested.clone();
+
+        let (local, remote) = duplex();
+        let peer = ScriptedPeer::new(remote, Network::Bitcoin)
+            .handshake(0)
+            .run_and_serve(move |msg| match msg {
+                Message::Network(NetworkMessage::GetBlocks(_)) => vec![NetworkMessage::Inv(invs.clone()).into()],
+                Message::Network(NetworkMessage::GetData(req)) => {
+                    requested2.borrow_mut().extend(req.iter().map(|inv| inv.hash));
+                    // Blocks are served in another order than announced.
+                    blocks.iter().rev().map(|b| NetworkMessage::Block(b.clone()).into()).collect()
+                },
+                _ => Vec::new(),
+            });
+        let peers: Vec<(MemoryStream, PeerFuture)> = vec![(local, Box::new(peer))];
+
+        let (_conn, fetched) = run_in_system(move || {
+            let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Bitcoin)));
+            connect_scripted_peers(peers, Network::Bitcoin)
+                .and_then(move |mut conns| fetch_new_blocks(conns.remove(0), blockchain, Duration::from_secs(3)))
+        }).unwrap();
+        assert_eq!(fetched, vec![new1.clone(), new2.clone()]);
+        assert_eq!(*requested.borrow(), vec![new1.bitcoin_hash(), new2.bitcoin_hash()]);
+    }
+}
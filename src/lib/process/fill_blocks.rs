@@ -0,0 +1,273 @@
+use std::{ops::{Range, RangeInclusive}, sync::{Arc, Mutex}, time::Duration};
+
+use actix::prelude::*;
+use bitcoin::blockdata::block::Block;
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+use futures::{future::{self, Either}, stream, Future, Stream};
+
+use blockchain::{BlockChain, FullBlockData};
+use connection::Connection;
+use error::Error;
+use process::request_blocks::request_blocks;
+
+/// The max number of blocks which are requested by one `getdata` message.
+pub const MAX_BLOCKS_IN_MSG: usize = 16;
+
+/// If peer does not send all blocks of a batch in this duration, we give up the peer.
+const BATCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Download bodies of blocks in `range` of active chain, and return them ordered by height.
+///
+/// Heights are resolved to hashes when this function is called.
+/// If the active chain is reorganized and the block at some height is replaced before it arrives,
+/// returned future fails with `Error::ActiveChainChanged`.
+pub fn fill_blocks(
+    conn: Addr<Connection>,
+    blockchain: Arc<Mutex<BlockChain>>,
+    range: RangeInclusive<u32>,
+) -> Box<Future<Item = Vec<FullBlockData>, Error = Error>>
+{
+    let targets = {
+        let blockchain = blockchain.lock().unwrap();
+        let active_chain = blockchain.active_chain();
+        let mut targets = Vec::new();
+        for height in *range.start()..=*range.end() {
+            match active_chain.get_by_height(height) {
+                Some(block) => targets.push((height, block.bitcoin_hash())),
+                None => return Box::new(future::err(Error::HeightOutOfRange(height))),
+            }
+        }
+        targets
+    };
+
+    // Batches are requested one by one, in order of height.
+    let batches: Vec<Vec<_>> = targets.chunks(MAX_BLOCKS_IN_MSG).map(|batch| batch.to_vec()).collect();
+    let f = stream::iter_ok::<_, Error>(batches)
+        .and_then(move |batch| {
+            let blockchain = blockchain.clone();
+            let block_hashes = batch.iter().map(|&(_, hash)| hash).collect();
+            request_blocks(&conn, block_hashes, BATCH_TIMEOUT)
+                .and_then(move |blocks| with_heights(&blockchain, batch, blocks))
+        })
+        .concat2();
+    Box::new(f)
+}
+
+/// Same as `fill_blocks`, but takes a half-open range of heights, e.g. `540_000..540_100`,
+/// and returns `conn` with the blocks so that the caller can keep using it.
+pub fn download_block_range(
+    conn: Addr<Connection>,
+    blockchain: Arc<Mutex<BlockChain>>,
+    range: Range<u32>,
+) -> impl Future<Item = (Addr<Connection>, Vec<FullBlockData>), Error = Error>
+{
+    if range.start >= range.end {
+        return Either::A(future::ok((conn, Vec::new())));
+    }
+    let f = fill_blocks(conn.clone(), blockchain, range.start..=range.end - 1);
+    Either::B(f.map(move |blocks| (conn, blocks)))
+}
+
+/// Pair `blocks` with heights of `batch`, if they are still in the active chain.
+fn with_heights(
+    blockchain: &Mutex<BlockChain>,
+    batch: Vec<(u32, Sha256dHash)>,
+    blocks: Vec<Block>,
+) -> Result<Vec<FullBlockData>, Error>
+{
+    let blockchain = blockchain.lock().unwrap();
+    let active_chain = blockchain.active_chain();
+    batch
+        .into_iter()
+        .zip(blocks)
+        .map(|((height, hash), block)| match active_chain.get_by_height(height) {
+            Some(current) if current.bitcoin_hash() == hash => Ok(FullBlockData::new(block, height)),
+            _ => Err(Error::ActiveChainChanged(height)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use bitcoin::blockdata::{block::BlockHeader, constants::genesis_block};
+    use bitcoin::network::{constants::Network, message::NetworkMessage};
+
+    use connection::message::Message;
+    use testing::{self, connect_scripted_peers, duplex, mine, run_in_system, synthetic_blocks, MemoryStream, PeerFuture,
+                  ScriptedPeer};
+
+    fn blockchain_of(blocks: &[Block]) -> Arc<Mutex<BlockChain>>
+    {
+        let mut blockchain = BlockChain::new(Network::Regtest);
+        for block in blocks {
+            blockchain.try_add(block.header).unwrap();
+        }
+        Arc::new(Mutex::new(blockchain))
+    }
+
+    // A server function which responds `getdata` with `blocks`, in reverse order.
+    fn blocks_server(blocks: Vec<Block>) -> impl FnMut(Message) -> Vec<Message>
+    {
+        let mut server = testing::blocks_server(blocks);
+        move |msg| {
+            let mut replies = server(msg);
+            replies.reverse();
+            replies
+        }
+    }
+
+    // Run `fill_blocks` against a peer which serves `served` blocks.
+    // `after_start` is called just after `fill_blocks` is called.
+    fn run_fill<F>(
+        blockchain: Arc<Mutex<BlockChain>>,
+        served: Vec<Block>,
+        range: RangeInclusive<u32>,
+        after_start: F,
+    ) -> Result<Vec<FullBlockData>, Error>
+    where F: FnOnce() + 'static
+    {
+        run_with_peer(served, move |conn| {
+            let f = fill_blocks(conn, blockchain, range);
+            after_start();
+            f
+        })
+    }
+
+    // Run a future which `start` makes from a connection to a peer which serves `served` blocks.
+    fn run_with_peer<S, F>(served: Vec<Block>, start: S) -> Result<Vec<FullBlockData>, Error>
+    where
+        S: FnOnce(Addr<Connection>) -> F + 'static,
+        F: Future<Item = Vec<FullBlockData>, Error = Error> + 'static,
+    {
+        let (local, remote) = duplex();
+        let peer = ScriptedPeer::new(remote, Network::Regtest)
+            .handshake(0)
+            .run_and_serve(blocks_server(served));
+        let peers: Vec<(MemoryStream, PeerFuture)> = vec![(local, Box::new(peer))];
+
+        run_in_system(move || {
+            connect_scripted_peers(peers, Network::Regtest).and_then(move |mut conns| start(conns.remove(0)))
+        })
+    }
+
+    #[test]
+    fn fill_blocks_in_batches_ordered_by_height()
+    {
+        let blocks = synthetic_blocks(MAX_BLOCKS_IN_MSG as u32 + 4);
+        let blockchain = blockchain_of(&blocks);
+
+        let filled = run_fill(blockchain, blocks.clone(), 2..=20, || ()).unwrap();
+
+        let heights: Vec<_> = filled.iter().map(|b| b.height).collect();
+        assert_eq!(heights, (2..=20).collect::<Vec<_>>());
+        let bodies: Vec<_> = filled.into_iter().map(|b| b.block).collect();
+        assert_eq!(bodies, blocks[1..].to_vec());
+    }
+
+    #[test]
+    fn reject_block_with_corrupted_merkle_root()
+    {
+        let blocks = synthetic_blocks(5);
+        let blockchain = blockchain_of(&blocks);
+
+        // The header is kept, so the hash is still the requested one.
+        let mut served = blocks.clone();
+        let extra_tx = served[0].txdata[0].clone();
+        served[2].txdata.push(extra_tx);
+
+        match run_fill(blockchain, served, 1..=5, || ()) {
+            Err(Error::InvalidMerkleRoot(hash)) => assert_eq!(hash, blocks[2].bitcoin_hash()),
+            other => panic!("Unexpected result : {:?}", other.map(|blocks| blocks.len())),
+        }
+    }
+
+    #[test]
+    fn abort_on_reorg_while_downloading()
+    {
+        let blocks = synthetic_blocks(3);
+        let blockchain = blockchain_of(&blocks);
+
+        // A longer branch from the genesis block replaces all requested blocks.
+        let mut branch = synthetic_blocks(4);
+        for block in branch.iter_mut() {
+            block.header.version = 2;
+        }
+        let blockchain2 = blockchain.clone();
+        let reorg = move || {
+            let mut blockchain = blockchain2.lock().unwrap();
+            let mut prev = genesis_block(Network::Regtest).bitcoin_hash();
+            for block in branch {
+                let mut header = BlockHeader {
+                    prev_blockhash: prev,
+                    ..block.header
+                };
+                mine(&mut header);
+                prev = header.bitcoin_hash();
+                blockchain.try_add(header).unwrap();
+            }
+        };
+
+        let res = run_fill(blockchain, blocks, 1..=3, reorg);
+        match res {
+            Err(Error::ActiveChainChanged(_)) => {},
+            other => panic!("Unexpected result : {:?}", other.map(|blocks| blocks.len())),
+        }
+    }
+
+    #[test]
+    fn fail_on_height_beyond_active_chain()
+    {
+        let blocks = synthetic_blocks(3);
+        let blockchain = blockchain_of(&blocks);
+        match run_fill(blockchain, blocks, 2..=4, || ()) {
+            Err(Error::HeightOutOfRange(height)) => assert_eq!(height, 4),
+            other => panic!("Unexpected result : {:?}", other.map(|blocks| blocks.len())),
+        }
+    }
+
+    #[test]
+    fn download_half_open_range_up_to_tip()
+    {
+        let blocks = synthetic_blocks(MAX_BLOCKS_IN_MSG as u32 + 4);
+        let blockchain = blockchain_of(&blocks);
+
+        // The peer serves each batch in reverse order.
+        let downloaded = run_with_peer(blocks.clone(), move |conn| {
+            download_block_range(conn, blockchain, 3..21).map(|(_conn, blocks)| blocks)
+        }).unwrap();
+
+        let heights: Vec<_> = downloaded.iter().map(|b| b.height).collect();
+        assert_eq!(heights, (3..21).collect::<Vec<_>>());
+        let bodies: Vec<_> = downloaded.into_iter().map(|b| b.block).collect();
+        assert_eq!(bodies, blocks[2..].to_vec());
+    }
+
+    #[test]
+    fn download_empty_range()
+    {
+        let blocks = synthetic_blocks(3);
+        let blockchain = blockchain_of(&blocks);
+        let res = run_with_peer(blocks, move |conn| {
+            download_block_range(conn, blockchain, 2..2).map(|(_conn, blocks)| blocks)
+        });
+        assert_eq!(res.unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn download_range_beyond_tip()
+    {
+        let blocks = synthetic_blocks(3);
+        let blockchain = blockchain_of(&blocks);
+        let res = run_with_peer(blocks, move |conn| {
+            download_block_range(conn, blockchain, 2..5).map(|(_conn, blocks)| blocks)
+        });
+        match res {
+            Err(Error::HeightOutOfRange(height)) => assert_eq!(height, 4),
+            other => panic!("Unexpected result : {:?}", other.map(|blocks| blocks.len())),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::{cmp, sync::{Arc, Mutex}};
+
+use actix::prelude::*;
+use bitcoin::network::serialize::{serialize, BitcoinHash};
+use futures::{future::{self, Either, Loop}, Future};
+
+use blockchain::BlockChain;
+use connection::{Connection, GetPeerStartHeight};
+use error::Error;
+use process::fill_blocks::fill_blocks;
+use process::request_blocks::single_response;
+use process::sync_blockchain::{IbdProgress, InFlightHeaders, SyncBlockChain, SyncBlockChainResult};
+
+/// Progress is reported every time this number of blocks are downloaded.
+pub const BLOCKS_PER_PROGRESS: u32 = 100;
+
+/// Same as `initial_block_download_with_progress`, but without progress reports.
+pub fn initial_block_download(
+    conn: Addr<Connection>,
+    blockchain: Arc<Mutex<BlockChain>>,
+) -> impl Future<Item = IbdProgress, Error = Error>
+{
+    initial_block_download_with_progress(conn, blockchain, |_progress| ())
+}
+
+/// Sync headers from `conn`, then download transactions of blocks at the tip of the active chain which do not
+/// have them yet, e.g. blocks which are added by headers.
+///
+/// `progress` is called after each batch of headers and after every `BLOCKS_PER_PROGRESS` blocks.
+/// Returned future resolves to the last progress.
+pub fn initial_block_download_with_progress<F>(
+    conn: Addr<Connection>,
+    blockchain: Arc<Mutex<BlockChain>>,
+    progress: F,
+) -> impl Future<Item = IbdProgress, Error = Error>
+where F: Fn(IbdProgress) + Send + 'static
+{
+    let progress = Arc::new(Mutex::new(progress));
+    let progress2 = progress.clone();
+    let (notify, synced) = single_response::<SyncBlockChainResult>();
+    SyncBlockChain::new(blockchain.clone(), InFlightHeaders::new(), conn.clone(), notify)
+        .with_progress(move |p| (*progress2.lock().unwrap())(p))
+        .start();
+
+    synced
+        .and_then(|res| match res {
+            SyncBlockChainResult::Complete(stats) | SyncBlockChainResult::Cancelled(stats) => Ok(stats),
+            SyncBlockChainResult::Error(_stats, e) => Err(e),
+        })
+        .and_then({
+            let conn = conn.clone();
+            move |stats| conn.send(GetPeerStartHeight).from_err().map(move |height| (stats, height))
+        })
+        .and_then(move |(stats, best_known_height)| {
+            let (first, tip) = {
+                let blockchain = blockchain.lock().unwrap();
+                (first_height_without_body(&blockchain), blockchain.active_chain().latest_block().height())
+            };
+            let initial = IbdProgress {
+                headers_synced: stats.headers_contributed,
+                current_height: tip,
+                best_known_height,
+                blocks_downloaded: 0,
+                blocks_total: tip + 1 - first,
+                bytes_downloaded: 0,
+            };
+            download_bodies(conn, blockchain, initial, first, progress)
+        })
+}
+
+/// Download blocks from `first` to `progress.current_height` in rounds of `BLOCKS_PER_PROGRESS`.
+fn download_bodies<F>(
+    conn: Addr<Connection>,
+    blockchain: Arc<Mutex<BlockChain>>,
+    progress: IbdProgress,
+    first: u32,
+    report: Arc<Mutex<F>>,
+) -> impl Future<Item = IbdProgress, Error = Error>
+where F: Fn(IbdProgress) + Send + 'static
+{
+    let tip = progress.current_height;
+    future::loop_fn((first, progress), move |(start, progress)| {
+        if start > tip {
+            return Either::A(future::ok(Loop::Break(progress)));
+        }
+        let end = cmp::min(start + BLOCKS_PER_PROGRESS - 1, tip);
+        let blockchain = blockchain.clone();
+        let report = report.clone();
+        let f = fill_blocks(conn.clone(), blockchain.clone(), start..=end).and_then(move |blocks| {
+            let mut progress = progress;
+            {
+                let mut blockchain = blockchain.lock().unwrap();
+                for block in blocks {
+                    progress.bytes_downloaded += serialize(&block.block)?.len() as u64;
+                    blockchain.try_add_full_block(block.block)?;
+                    progress.blocks_downloaded += 1;
+                }
+            }
+            (*report.lock().unwrap())(progress.clone());
+            Ok::<_, Error>(Loop::Continue((end + 1, progress)))
+        });
+        Either::B(f)
+    })
+}
+
+/// The lowest height of the run of blocks at the tip of the active chain whose transactions are not kept.
+/// Returns the height next to the tip if the tip has its transactions.
+/// The first block of the active chain is never downloaded, since it is trusted, e.g. the genesis block.
+fn first_height_without_body(blockchain: &BlockChain) -> u32
+{
+    let active_chain = blockchain.active_chain();
+    let mut blocks = active_chain.iter();
+    let root = blocks.next().unwrap().height();
+    let mut first = active_chain.latest_block().height() + 1;
+    for block in blocks.rev() {
+        if active_chain.get_full_block(&block.bitcoin_hash()).is_some() {
+            break;
+        }
+        first = block.height();
+    }
+    cmp::max(first, root + 1)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    use bitcoin::blockdata::block::{Block, LoneBlockHeader};
+    use bitcoin::network::{constants::Network, encodable::VarInt, message::NetworkMessage};
+
+    use connection::{message::Message, socket::{begin_handshake_on, HandshakeConfig, Socket}};
+    use testing::{duplex, dummy_addrs, synthetic_blocks, ScriptedPeer};
+
+    // A server function which responds `getheaders` and `getdata` with `blocks` after the genesis block.
+    fn chain_server(blocks: Vec<Block>) -> impl FnMut(Message) -> Vec<Message>
+    {
+        let mut hashes = vec![blocks[0].header.prev_blockhash];
+        hashes.extend(blocks.iter().map(|b| b.bitcoin_hash()));
+        move |msg| {
+            match msg {
+                Message::Network(NetworkMessage::GetHeaders(req)) => {
+                    let pos = req.locator_hashes
+                        .iter()
+                        .filter_map(|h| hashes.iter().position(|known| known == h))
+                        .next()
+                        .unwrap_or(0);
+                    let headers = blocks[pos..]
+                        .iter()
+                        .map(|b| {
+                            LoneBlockHeader {
+                                header: b.header,
+                                tx_count: VarInt(0),
+                            }
+                        })
+                        .collect();
+                    vec![NetworkMessage::Headers(headers).into()]
+                },
+                Message::Network(NetworkMessage::GetData(invs)) => {
+                    invs.iter()
+                        .filter_map(|inv| blocks.iter().find(|b| b.bitcoin_hash() == inv.hash))
+                        .map(|b| NetworkMessage::Block(b.clone()).into())
+                        .collect()
+                },
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    #[test]
+    fn download_headers_and_blocks_with_progress()
+    {
+        let blocks = synthetic_blocks(BLOCKS_PER_PROGRESS + 20);
+        let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Regtest)));
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let result = Rc::new(RefCell::new(None));
+
+        let (local, remote) = duplex();
+        let (blockchain2, reports2, result2) = (blockchain.clone(), reports.clone(), result.clone());
+        let start_height = blocks.len() as i32;
+        let served = blocks.clone();
+        System::run(move || {
+            let peer = ScriptedPeer::new(remote, Network::Regtest)
+                .handshake(start_height)
+                .run_and_serve(chain_server(served))
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let (local_addr, peer_addr) = dummy_addrs();
+            let socket = Socket::new(local, Network::Regtest);
+            let f = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr)
+                .map_err(|e| panic!("Fail to handshake : {:?}", e))
+                .and_then(move |socket| {
+                    let conn = Connection::start_actor(socket);
+                    initial_block_download_with_progress(conn, blockchain2, move |p| reports2.lock().unwrap().push(p))
+                        .then(move |res| {
+                            *result2.borrow_mut() = Some(res);
+                            System::current().stop();
+                            Ok(())
+                        })
+                });
+            Arbiter::spawn(f);
+        });
+
+        let last = result.borrow_mut().take().unwrap().unwrap();
+        let total = blocks.len() as u32;
+        let bytes: u64 = blocks.iter().map(|b| serialize(b).unwrap().len() as u64).sum();
+        assert_eq!(last.current_height, total);
+        assert_eq!(last.best_known_height, total as i32);
+        assert_eq!(last.blocks_downloaded, total);
+        assert_eq!(last.blocks_total, total);
+        assert_eq!(last.bytes_downloaded, bytes);
+
+        // One report for headers, then one for each round of blocks.
+        let downloaded: Vec<_> = reports.lock().unwrap().iter().map(|p| p.blocks_downloaded).collect();
+        assert_eq!(downloaded, vec![0, BLOCKS_PER_PROGRESS, total]);
+
+        let blockchain = blockchain.lock().unwrap();
+        let active_chain = blockchain.active_chain();
+        for block in blocks.iter() {
+            assert_eq!(active_chain.get_full_block(&block.bitcoin_hash()).unwrap().block, *block);
+        }
+    }
+}
@@ -0,0 +1,528 @@
+use std::{collections::HashSet, sync::{Arc, Mutex}};
+
+use actix::prelude::*;
+use bitcoin::blockdata::block::LoneBlockHeader;
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+
+use blockchain::{BlockChain, BlockChainSnapshot};
+use events::{noop_sink, Event, EventSink};
+use connection::{misbehavior::Violation, Connection, GetHeadersRequest, HeadersResponse, InvFilter, PublishHeaders,
+                 PublishInv, ReportMisbehavior, SubscribeHeaders, SubscribeInv};
+use process::request_blocks::single_response;
+
+/// The max number of announced blocks which wait for `getheaders` responses.
+/// Peer responds at most 2000 headers to one `getheaders`, so more announcements would not be covered anyway.
+/// Further announcements are ignored until a response arrives.
+pub const MAX_REQUESTED_BLOCKS: usize = 2000;
+
+/// Keep a shared `BlockChain` updated from `inv` and `headers` announcements.
+///
+/// Headers which are announced by `headers` message are added directly.
+/// Only when they do not connect to our blockchain, `getheaders` is sent to fill the gap.
+/// `getheaders` is sent to the peer which announces blocks, and invalid headers are reported against it.
+///
+/// `ListenNewBlocks` may listen several connections. Even if the same block is announced by
+/// several connections, it is requested only once.
+pub struct ListenNewBlocks
+{
+    blockchain: Arc<Mutex<BlockChain>>,
+    connection: Addr<Connection>,
+    subscribers: Vec<Recipient<NewBlockEvent>>,
+
+    // Hashes of announced blocks which wait for a `getheaders` response. Up to `MAX_REQUESTED_BLOCKS`.
+    requested: HashSet<Sha256dHash>,
+    events: Arc<EventSink>,
+}
+
+#[derive(Message, Debug, Clone)]
+/// Published when the tip of active chain is changed.
+pub struct NewBlockEvent
+{
+    pub height: u32,
+    pub hash: Sha256dHash,
+    /// Whether the previous tip is disconnected from active chain or not.
+    pub reorg: bool,
+    /// Active chain just after the tip is changed.
+    pub snapshot: BlockChainSnapshot,
+}
+
+#[derive(Message)]
+pub struct SubscribeNewBlock
+{
+    pub addr: Recipient<NewBlockEvent>,
+}
+
+#[derive(Message)]
+/// Start to listen announcements from another connection as well.
+pub struct ListenConnection(pub Addr<Connection>);
+
+impl ListenNewBlocks
+{
+    pub fn new(blockchain: Arc<Mutex<BlockChain>>, connection: Addr<Connection>) -> ListenNewBlocks
+    {
+        ListenNewBlocks {
+            blockchain,
+            connection,
+            subscribers: Vec::new(),
+            requested: HashSet::new(),
+            events: noop_sink(),
+        }
+    }
+
+    /// Report applied headers and reorgs to `sink`.
+    pub fn with_events(mut self, sink: Arc<EventSink>) -> ListenNewBlocks
+    {
+        self.events = sink;
+        self
+    }
+
+    pub fn start_actor(blockchain: Arc<Mutex<BlockChain>>, connection: Addr<Connection>) -> Addr<ListenNewBlocks>
+    {
+        ListenNewBlocks::new(blockchain, connection).start()
+    }
+
+    /// Request headers which follow our active chain from `conn`.
+    fn request_getheaders(&mut self, conn: Addr<Connection>, ctx: &mut Context<Self>)
+    {
+        let locator_hashes = self.blockchain.lock().unwrap().active_chain().locator_hashes_vec();
+        let (addr, response) = single_response();
+        conn.do_send(GetHeadersRequest { locator_hashes, addr });
+        let f = response
+            .into_actor(self)
+            .map(move |res, actor, _ctx| actor.handle_headers_response(&conn, res))
+            .map_err(|_e, actor, _ctx| actor.requested.clear());
+        ctx.spawn(f);
+    }
+
+    fn handle_headers_response(&mut self, conn: &Addr<Connection>, res: HeadersResponse)
+    {
+        // Blocks which are still unknown are requested again when they are announced next time.
+        self.requested.clear();
+        if let HeadersResponse::Headers(headers) = res {
+            self.apply_headers(conn, headers);
+        }
+    }
+
+    fn subscribe(conn: &Addr<Connection>, ctx: &mut Context<Self>)
+    {
+        conn.do_send(SubscribeInv {
+            addr: ctx.address().recipient(),
+            filter: InvFilter::Block,
+        });
+        conn.do_send(SubscribeHeaders {
+            addr: ctx.address().recipient(),
+        });
+    }
+
+    /// Add `headers` which `conn` sends to blockchain, and publish `NewBlockEvent` if the tip is changed.
+    fn apply_headers(&mut self, conn: &Addr<Connection>, headers: Vec<LoneBlockHeader>)
+    {
+        let event = {
+            let mut blockchain = self.blockchain.lock().unwrap();
+            let old_tip = blockchain.active_chain().latest_block().clone();
+
+            let mut count = 0;
+            for lone_header in headers {
+                if blockchain.contains(&lone_header.header.bitcoin_hash()) {
+                    continue;
+                }
+                if let Err(e) = blockchain.try_add(lone_header.header) {
+                    info!("Peer sends invalid block header : {:?}", e);
+                    conn.do_send(ReportMisbehavior(Violation::InvalidHeader));
+                    return;
+                }
+                count += 1;
+            }
+
+            let active_chain = blockchain.active_chain();
+            let new_tip = active_chain.latest_block().clone();
+            if count > 0 {
+                self.events.on_event(Event::HeadersApplied {
+                    count,
+                    tip_height: new_tip.height(),
+                });
+            }
+            if new_tip.bitcoin_hash() == old_tip.bitcoin_hash() {
+                return;
+            }
+            let depth = active_chain.reorg_depth(&old_tip);
+            if depth > 0 {
+                self.events.on_event(Event::Reorg { depth });
+            }
+            NewBlockEvent {
+                height: new_tip.height(),
+                hash: new_tip.bitcoin_hash(),
+                reorg: !active_chain.contains(&old_tip),
+                snapshot: blockchain.freeze(),
+            }
+        };
+
+        info!("New tip : height {}, hash {}", event.height, event.hash);
+        self.publish(event);
+    }
+
+    fn publish(&mut self, event: NewBlockEvent)
+    {
+        self.subscribers.retain(|s| s.do_send(event.clone()).is_ok());
+    }
+}
+
+impl Actor for ListenNewBlocks
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context)
+    {
+        ListenNewBlocks::subscribe(&self.connection, ctx);
+    }
+}
+
+impl Handler<SubscribeNewBlock> for ListenNewBlocks
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeNewBlock, _ctx: &mut Context<Self>)
+    {
+        self.subscribers.push(msg.addr);
+    }
+}
+
+impl Handler<ListenConnection> for ListenNewBlocks
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: ListenConnection, ctx: &mut Context<Self>)
+    {
+        ListenNewBlocks::subscribe(&msg.0, ctx);
+    }
+}
+
+impl Handler<PublishInv> for ListenNewBlocks
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: PublishInv, ctx: &mut Context<Self>)
+    {
+        let mut has_new_block = false;
+        {
+            let blockchain = self.blockchain.lock().unwrap();
+            for inv in msg.invs {
+                // Only block inventories are subscribed.
+                if blockchain.contains(&inv.hash) || self.requested.contains(&inv.hash) {
+                    continue;
+                }
+                if self.requested.len() >= MAX_REQUESTED_BLOCKS {
+                    info!("Too many announced blocks wait for headers. Ignore the rest.");
+                    break;
+                }
+                self.requested.insert(inv.hash);
+                has_new_block = true;
+            }
+        }
+
+        if has_new_block {
+            self.request_getheaders(msg.conn, ctx);
+        }
+    }
+}
+
+impl Handler<PublishHeaders> for ListenNewBlocks
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: PublishHeaders, ctx: &mut Context<Self>)
+    {
+        let connects = match msg.headers.first() {
+            Some(lone_header) => self.blockchain.lock().unwrap().contains(&lone_header.header.prev_blockhash),
+            None => return,
+        };
+        if connects {
+            self.apply_headers(&msg.conn, msg.headers);
+        } else {
+            // We miss some blocks between our blockchain and announced headers.
+            self.request_getheaders(msg.conn, ctx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::{cell::{Cell, RefCell}, rc::Rc};
+
+    use bitcoin::blockdata::block::{BlockHeader, LoneBlockHeader};
+    use bitcoin::network::{constants::Network, encodable::VarInt, message::NetworkMessage,
+                           message_blockdata::{InvType, Inventory}};
+    use futures::Future;
+
+    use blockchain::BlockData;
+    use connection::{message::{Message, SEND_HEADERS_MIN_PROTOCOL_VERSION},
+                     socket::{begin_handshake_on, HandshakeConfig, Socket}, ClearBloomFilter};
+    use testing::{duplex, dummy_addrs, dummy_version_msg, mine, ScriptedPeer, MIN_DIFFICULTY_BITS};
+
+    fn dummy_header(prev_blockhash: Sha256dHash, time: u32) -> BlockHeader
+    {
+        let mut header = BlockHeader {
+            version: 1,
+            prev_blockhash,
+            merkle_root: Sha256dHash::default(),
+            time,
+            bits: MIN_DIFFICULTY_BITS,
+            nonce: 0,
+        };
+        mine(&mut header);
+        header
+    }
+
+    fn block_inv(hash: Sha256dHash) -> Message
+    {
+        let inv = Inventory {
+            inv_type: InvType::Block,
+            hash,
+        };
+        NetworkMessage::Inv(vec![inv]).into()
+    }
+
+    fn headers_msg(headers: &[BlockHeader]) -> Message
+    {
+        let lones = headers
+            .iter()
+            .map(|header| LoneBlockHeader {
+                header: *header,
+                tx_count: VarInt(0),
+            })
+            .collect();
+        NetworkMessage::Headers(lones).into()
+    }
+
+    // Collect `num` events, then stop the system.
+    struct Collector
+    {
+        events: Rc<RefCell<Vec<NewBlockEvent>>>,
+        num: usize,
+    }
+
+    impl Actor for Collector
+    {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<NewBlockEvent> for Collector
+    {
+        type Result = ();
+
+        fn handle(&mut self, event: NewBlockEvent, _ctx: &mut Context<Self>)
+        {
+            let mut events = self.events.borrow_mut();
+            events.push(event);
+            if events.len() == self.num {
+                System::current().stop();
+            }
+        }
+    }
+
+    #[test]
+    fn request_announced_block_only_once()
+    {
+        let start = dummy_header(Sha256dHash::default(), 1);
+        let block1 = dummy_header(start.bitcoin_hash(), 2);
+        let block2 = dummy_header(block1.bitcoin_hash(), 3);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+
+        let (local, remote) = duplex();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events2 = events.clone();
+        let num_getheaders = Rc::new(Cell::new(0));
+        let num_getheaders2 = num_getheaders.clone();
+
+        System::run(move || {
+            // Peer announces `block1` repeatedly, and then `block2` after `block1` is sent.
+            let peer = ScriptedPeer::new(remote, Network::Regtest)
+                .handshake(0)
+                .expect("filterclear")
+                .send(block_inv(block1.bitcoin_hash()))
+                .send(block_inv(block1.bitcoin_hash()))
+                .run_and_serve(move |msg| match msg {
+                    Message::Network(NetworkMessage::GetHeaders(_)) => {
+                        num_getheaders2.set(num_getheaders2.get() + 1);
+                        match num_getheaders2.get() {
+                            1 => vec![
+                                headers_msg(&[block1]),
+                                block_inv(block1.bitcoin_hash()),
+                                block_inv(block2.bitcoin_hash()),
+                            ],
+                            _ => vec![headers_msg(&[block2])],
+                        }
+                    },
+                    _ => Vec::new(),
+                })
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let collector = Collector {
+                events: events2,
+                num: 2,
+            }.start();
+            let (local_addr, peer_addr) = dummy_addrs();
+            let socket = Socket::new(local, Network::Regtest);
+            let f = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr)
+                .map_err(|e| panic!("Fail to handshake : {:?}", e))
+                .and_then(move |socket| {
+                    let conn = Connection::start_actor(socket);
+                    let listen = ListenNewBlocks::start_actor(blockchain, conn.clone());
+                    let req = SubscribeNewBlock {
+                        addr: collector.recipient(),
+                    };
+                    // `Connection` handles `SubscribeInv` before `ClearBloomFilter`.
+                    // So peer does not announce blocks before we subscribe them.
+                    listen
+                        .send(req)
+                        .map(move |()| conn.do_send(ClearBloomFilter))
+                        .map_err(|e| panic!("Fail to subscribe : {:?}", e))
+                });
+            Arbiter::spawn(f);
+        });
+
+        assert_eq!(num_getheaders.get(), 2);
+        let events = events.borrow();
+        let tips: Vec<_> = events.iter().map(|e| (e.height, e.hash, e.snapshot.len())).collect();
+        assert_eq!(tips, vec![(1, block1.bitcoin_hash(), 2), (2, block2.bitcoin_hash(), 3)]);
+        assert!(events.iter().all(|e| !e.reorg));
+    }
+
+    #[test]
+    fn request_headers_from_peer_which_announces_block()
+    {
+        let start = dummy_header(Sha256dHash::default(), 1);
+        let block1 = dummy_header(start.bitcoin_hash(), 2);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+
+        let (local1, remote1) = duplex();
+        let (local2, remote2) = duplex();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events2 = events.clone();
+
+        System::run(move || {
+            // The first peer is the main connection of `ListenNewBlocks`, but it announces nothing.
+            let peer1 = ScriptedPeer::new(remote1, Network::Regtest)
+                .handshake(0)
+                .expect("filterclear")
+                .run_and_serve(|msg| match msg {
+                    Message::Network(NetworkMessage::GetHeaders(_)) => panic!("Peer which does not announce is asked"),
+                    _ => Vec::new(),
+                })
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer1);
+            let peer2 = ScriptedPeer::new(remote2, Network::Regtest)
+                .handshake(0)
+                .expect("filterclear")
+                .send(block_inv(block1.bitcoin_hash()))
+                .run_and_serve(move |msg| match msg {
+                    Message::Network(NetworkMessage::GetHeaders(_)) => vec![headers_msg(&[block1])],
+                    _ => Vec::new(),
+                })
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer2);
+
+            let collector = Collector {
+                events: events2,
+                num: 1,
+            }.start();
+            let handshakes = vec![local1, local2].into_iter().map(|local| {
+                let (local_addr, peer_addr) = dummy_addrs();
+                let socket = Socket::new(local, Network::Regtest);
+                begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr)
+                    .map(|socket| Connection::start_actor(socket))
+            });
+            let f = ::futures::future::join_all(handshakes)
+                .map_err(|e| panic!("Fail to handshake : {:?}", e))
+                .and_then(move |conns| {
+                    let listen = ListenNewBlocks::start_actor(blockchain, conns[0].clone());
+                    listen.do_send(ListenConnection(conns[1].clone()));
+                    let req = SubscribeNewBlock {
+                        addr: collector.recipient(),
+                    };
+                    listen
+                        .send(req)
+                        .map(move |()| {
+                            for conn in conns {
+                                conn.do_send(ClearBloomFilter);
+                            }
+                        })
+                        .map_err(|e| panic!("Fail to subscribe : {:?}", e))
+                });
+            Arbiter::spawn(f);
+        });
+
+        let events = events.borrow();
+        let tips: Vec<_> = events.iter().map(|e| (e.height, e.hash)).collect();
+        assert_eq!(tips, vec![(1, block1.bitcoin_hash())]);
+    }
+
+    #[test]
+    fn apply_headers_announcement_without_getheaders()
+    {
+        let start = dummy_header(Sha256dHash::default(), 1);
+        let block1 = dummy_header(start.bitcoin_hash(), 2);
+        let block2 = dummy_header(block1.bitcoin_hash(), 3);
+        let block3 = dummy_header(block2.bitcoin_hash(), 4);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+
+        let (local, remote) = duplex();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events2 = events.clone();
+        let num_getheaders = Rc::new(Cell::new(0));
+        let num_getheaders2 = num_getheaders.clone();
+
+        System::run(move || {
+            let mut version = dummy_version_msg(0);
+            version.version = SEND_HEADERS_MIN_PROTOCOL_VERSION;
+
+            // Peer announces `block1` by `headers`, and then `block3` which does not connect to our blockchain.
+            let peer = ScriptedPeer::new(remote, Network::Regtest)
+                .handshake_with(version)
+                .expect("sendheaders")
+                .expect("filterclear")
+                .send(headers_msg(&[block1]))
+                .send(headers_msg(&[block3]))
+                .run_and_serve(move |msg| match msg {
+                    Message::Network(NetworkMessage::GetHeaders(_)) => {
+                        num_getheaders2.set(num_getheaders2.get() + 1);
+                        vec![headers_msg(&[block2, block3])]
+                    },
+                    _ => Vec::new(),
+                })
+                .map_err(|e| panic!("Scripted peer fails : {:?}", e));
+            Arbiter::spawn(peer);
+
+            let collector = Collector {
+                events: events2,
+                num: 2,
+            }.start();
+            let (local_addr, peer_addr) = dummy_addrs();
+            let socket = Socket::new(local, Network::Regtest);
+            let f = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr)
+                .map_err(|e| panic!("Fail to handshake : {:?}", e))
+                .and_then(move |socket| {
+                    let conn = Connection::start_actor(socket);
+                    let listen = ListenNewBlocks::start_actor(blockchain, conn.clone());
+                    let req = SubscribeNewBlock {
+                        addr: collector.recipient(),
+                    };
+                    listen
+                        .send(req)
+                        .map(move |()| conn.do_send(ClearBloomFilter))
+                        .map_err(|e| panic!("Fail to subscribe : {:?}", e))
+                });
+            Arbiter::spawn(f);
+        });
+
+        // Only the disconnected announcement needs `getheaders`.
+        assert_eq!(num_getheaders.get(), 1);
+        let events = events.borrow();
+        let tips: Vec<_> = events.iter().map(|e| (e.height, e.hash, e.snapshot.len())).collect();
+        assert_eq!(tips, vec![(1, block1.bitcoin_hash(), 2), (3, block3.bitcoin_hash(), 4)]);
+    }
+}
@@ -1 +1,17 @@
+#[cfg(feature = "actix-net")]
+pub mod download_blocks;
+#[cfg(feature = "actix-net")]
+pub mod fetch_block;
+#[cfg(feature = "actix-net")]
+pub mod fetch_filtered_blocks;
+#[cfg(feature = "actix-net")]
+pub mod fetch_new_blocks;
+#[cfg(feature = "actix-net")]
+pub mod fill_blocks;
+#[cfg(feature = "actix-net")]
+pub mod initial_block_download;
+#[cfg(feature = "actix-net")]
+pub mod listen;
+#[cfg(feature = "actix-net")]
+pub mod request_blocks;
 pub mod sync_blockchain;
@@ -0,0 +1,320 @@
+use std::{collections::{HashMap, HashSet}, time::Duration};
+
+use actix::prelude::*;
+use bitcoin::blockdata::{block::{Block, BlockHeader}, transaction::Transaction};
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+use futures::{sync::{mpsc, oneshot}, Future, Stream};
+
+use blockchain::validate_merkle_root;
+use connection::{misbehavior::Violation, BlockResponse, Connection, FilteredBlockResponse, GetBlocksRequest,
+                 GetFilteredBlocksRequest, ReportMisbehavior};
+use error::Error;
+
+/// A header of a filtered block with its transactions which match a bloom filter.
+pub type FilteredBlock = (BlockHeader, Vec<Transaction>);
+
+/// Request `block_hashes` from `conn` by one `getdata`, and stream blocks in the order they arrive.
+///
+/// The stream ends when every block arrives, or fails with the first error.
+///
+/// - `Error::BlockNotFound` if peer does not have a block.
+/// - `Error::InvalidMerkleRoot` if transactions of a block do not match its merkle root.
+///   `conn` is reported as misbehaving.
+/// - `Error::Timeout` if peer does not deliver every block in `timeout`.
+/// - `Error::ActorStopped` if `conn` is dropped.
+///
+/// Each call receives responses by its own recipient, so a late response to a previous request
+/// never reaches this one.
+pub fn block_stream(
+    conn: &Addr<Connection>,
+    block_hashes: Vec<Sha256dHash>,
+    timeout: Duration,
+) -> impl Stream<Item = Block, Error = Error>
+{
+    start(Full { check_merkle_root: true }, conn, block_hashes, timeout)
+}
+
+/// Same as `block_stream`, but transactions are not checked against merkle roots of headers.
+/// Only for blocks whose bodies are dummy, e.g. in tests. A peer can send any transactions.
+pub fn block_stream_unchecked(
+    conn: &Addr<Connection>,
+    block_hashes: Vec<Sha256dHash>,
+    timeout: Duration,
+) -> impl Stream<Item = Block, Error = Error>
+{
+    start(Full { check_merkle_root: false }, conn, block_hashes, timeout)
+}
+
+/// Same as `block_stream`, but returns blocks at once in the order of `block_hashes`.
+pub fn request_blocks(
+    conn: &Addr<Connection>,
+    block_hashes: Vec<Sha256dHash>,
+    timeout: Duration,
+) -> impl Future<Item = Vec<Block>, Error = Error>
+{
+    let blocks = block_stream(conn, block_hashes.clone(), timeout);
+    in_order(blocks, block_hashes, |block| block.bitcoin_hash())
+}
+
+/// Same as `request_blocks`, but requests filtered blocks (BIP 37).
+/// A bloom filter must be loaded on `conn` by `LoadBloomFilter` before.
+///
+/// Partial merkle trees are already verified against merkle roots of headers by `Connection`.
+/// `Connection` serves one request of filtered blocks at a time, so do not call this in parallel on the same
+/// connection.
+pub fn request_filtered_blocks(
+    conn: &Addr<Connection>,
+    block_hashes: Vec<Sha256dHash>,
+    timeout: Duration,
+) -> impl Future<Item = Vec<FilteredBlock>, Error = Error>
+{
+    let blocks = start(Filtered, conn, block_hashes.clone(), timeout);
+    in_order(blocks, block_hashes, |&(ref header, _)| header.bitcoin_hash())
+}
+
+/// A recipient for a request which is answered by a single message, e.g. `GetBlockInvsRequest`,
+/// and a future of the answer.
+/// The future fails with `Error::ActorStopped` if the recipient is dropped without an answer.
+pub fn single_response<M>() -> (Recipient<M>, impl Future<Item = M, Error = Error>)
+where M: Message<Result = ()> + Send + 'static
+{
+    let (tx, rx) = oneshot::channel();
+    let addr = SingleResponse { tx: Some(tx) }.start();
+    (addr.recipient(), rx.map_err(|_canceled| Error::ActorStopped))
+}
+
+/// Collect `blocks` in the order of `block_hashes`.
+fn in_order<S, F>(
+    blocks: S,
+    block_hashes: Vec<Sha256dHash>,
+    hash_of: F,
+) -> impl Future<Item = Vec<S::Item>, Error = Error>
+where
+    S: Stream<Error = Error>,
+    F: Fn(&S::Item) -> Sha256dHash,
+{
+    blocks
+        .fold(HashMap::new(), move |mut received, block| {
+            received.insert(hash_of(&block), block);
+            Ok::<_, Error>(received)
+        })
+        .and_then(move |mut received| {
+            block_hashes
+                .iter()
+                .map(|hash| received.remove(hash).ok_or(Error::BlockNotFound(*hash)))
+                .collect::<Result<Vec<_>, _>>()
+        })
+}
+
+fn start<K: Kind>(
+    kind: K,
+    conn: &Addr<Connection>,
+    mut block_hashes: Vec<Sha256dHash>,
+    timeout: Duration,
+) -> impl Stream<Item = K::Item, Error = Error>
+{
+    let mut seen = HashSet::new();
+    block_hashes.retain(|hash| seen.insert(*hash));
+
+    let (tx, rx) = mpsc::unbounded();
+    BlockRequest {
+        kind,
+        conn: conn.clone(),
+        remaining: block_hashes.len(),
+        block_hashes,
+        timeout,
+        tx: Some(tx),
+    }.start();
+    rx.then(|res| match res {
+        Ok(res) => res,
+        Err(()) => Err(Error::ActorStopped),
+    })
+}
+
+/// A kind of block data which is requested by `getdata`.
+trait Kind: 'static
+{
+    type Response: Message<Result = ()> + Send + 'static;
+    type Item: 'static;
+
+    fn send_request(
+        &self,
+        conn: &Addr<Connection>,
+        block_hashes: Vec<Sha256dHash>,
+        addr: Recipient<Self::Response>,
+    ) -> Box<Future<Item = (), Error = MailboxError>>;
+
+    /// Block data in a response, or the reason why the request fails.
+    fn accept(&self, res: Self::Response) -> Result<Self::Item, Error>;
+}
+
+struct Full
+{
+    check_merkle_root: bool,
+}
+
+impl Kind for Full
+{
+    type Response = BlockResponse;
+    type Item = Block;
+
+    fn send_request(
+        &self,
+        conn: &Addr<Connection>,
+        block_hashes: Vec<Sha256dHash>,
+        addr: Recipient<BlockResponse>,
+    ) -> Box<Future<Item = (), Error = MailboxError>>
+    {
+        Box::new(conn.send(GetBlocksRequest { block_hashes, addr }))
+    }
+
+    fn accept(&self, res: BlockResponse) -> Result<Block, Error>
+    {
+        match res {
+            BlockResponse::Found(block) => {
+                if self.check_merkle_root && !validate_merkle_root(&block) {
+                    return Err(Error::InvalidMerkleRoot(block.bitcoin_hash()));
+                }
+                Ok(block)
+            },
+            BlockResponse::NotFound(hash) => Err(Error::BlockNotFound(hash)),
+            BlockResponse::Timeout(_) => Err(Error::Timeout),
+        }
+    }
+}
+
+struct Filtered;
+
+impl Kind for Filtered
+{
+    type Response = FilteredBlockResponse;
+    type Item = FilteredBlock;
+
+    fn send_request(
+        &self,
+        conn: &Addr<Connection>,
+        block_hashes: Vec<Sha256dHash>,
+        addr: Recipient<FilteredBlockResponse>,
+    ) -> Box<Future<Item = (), Error = MailboxError>>
+    {
+        Box::new(conn.send(GetFilteredBlocksRequest { block_hashes, addr }))
+    }
+
+    fn accept(&self, res: FilteredBlockResponse) -> Result<FilteredBlock, Error>
+    {
+        Ok((res.header, res.txs))
+    }
+}
+
+/// Forwards responses of a single request to a channel, until every block arrives or the request fails.
+struct BlockRequest<K: Kind>
+{
+    kind: K,
+    conn: Addr<Connection>,
+    block_hashes: Vec<Sha256dHash>,
+    timeout: Duration,
+    // The number of blocks which do not arrive yet.
+    remaining: usize,
+    tx: Option<mpsc::UnboundedSender<Result<K::Item, Error>>>,
+}
+
+impl<K: Kind> Actor for BlockRequest<K>
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context)
+    {
+        if self.remaining == 0 {
+            self.tx = None;
+            return ctx.stop();
+        }
+        let block_hashes = self.block_hashes.clone();
+        let f = self.kind
+            .send_request(&self.conn, block_hashes, ctx.address().recipient())
+            .into_actor(self)
+            .map_err(|_e, actor, ctx| actor.fail(Error::ActorStopped, ctx));
+        ctx.spawn(f);
+        ctx.run_later(self.timeout, |actor, ctx| actor.fail(Error::Timeout, ctx));
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context)
+    {
+        // Stopped before every block arrives, e.g. the system is shutting down.
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.unbounded_send(Err(Error::ActorStopped));
+        }
+    }
+}
+
+impl<K: Kind> BlockRequest<K>
+{
+    fn forward(&mut self, item: K::Item, ctx: &mut Context<Self>)
+    {
+        let is_sent = match self.tx {
+            Some(ref tx) => tx.unbounded_send(Ok(item)).is_ok(),
+            None => false,
+        };
+        if !is_sent {
+            // Nobody waits for the rest.
+            self.tx = None;
+            return ctx.stop();
+        }
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.tx = None;
+            ctx.stop();
+        }
+    }
+
+    fn fail(&mut self, e: Error, ctx: &mut Context<Self>)
+    {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.unbounded_send(Err(e));
+        }
+        ctx.stop();
+    }
+}
+
+impl<K: Kind> Handler<K::Response> for BlockRequest<K>
+{
+    type Result = ();
+
+    fn handle(&mut self, res: K::Response, ctx: &mut Context<Self>)
+    {
+        match self.kind.accept(res) {
+            Ok(item) => self.forward(item, ctx),
+            Err(e) => {
+                if let Error::InvalidMerkleRoot(_) = e {
+                    self.conn.do_send(ReportMisbehavior(Violation::InvalidBlock));
+                }
+                self.fail(e, ctx);
+            },
+        }
+    }
+}
+
+struct SingleResponse<M>
+{
+    tx: Option<oneshot::Sender<M>>,
+}
+
+impl<M> Actor for SingleResponse<M>
+where M: Message<Result = ()> + Send + 'static
+{
+    type Context = Context<Self>;
+}
+
+impl<M> Handler<M> for SingleResponse<M>
+where M: Message<Result = ()> + Send + 'static
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: M, ctx: &mut Context<Self>)
+    {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(msg);
+        }
+        ctx.stop();
+    }
+}
@@ -0,0 +1,1087 @@
+use std::{collections::HashSet, sync::{Arc, Mutex}, time::Duration};
+
+use actix::prelude::*;
+use futures::{Future, sync::oneshot};
+use tokio_threadpool::ThreadPool;
+use bitcoin::blockdata::block::{BlockHeader, LoneBlockHeader};
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+
+use blockchain::{validate_headers, validate_headers_parallel, BlockChain, ValidationError};
+use error::Error;
+use events::{noop_sink, Event, EventSink};
+use connection::{misbehavior::Violation, Connection, Disconnect, GetHeadersRequest, GetNetwork, GetPeerStartHeight,
+                 HeadersResponse, ReportMisbehavior, MAX_HEADERS_IN_MSG};
+
+use super::{add_headers, IbdProgress, SyncStats};
+
+/// Interval to retry a request which another actor is already requesting.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// If peer does not respond `headers` message in this duration, we give up the peer.
+const HEADERS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A registry of `getheaders` requests which are in flight.
+///
+/// `SyncBlockChain` actors sharing the same `BlockChain` should share this as well.
+/// Then two actors never request headers after the same tip concurrently.
+#[derive(Debug, Clone, Default)]
+pub struct InFlightHeaders(Arc<Mutex<HashSet<Sha256dHash>>>);
+
+impl InFlightHeaders
+{
+    pub fn new() -> InFlightHeaders
+    {
+        InFlightHeaders::default()
+    }
+
+    /// Returns `false` if a request after given tip is already in flight.
+    fn register(&self, tip: Sha256dHash) -> bool
+    {
+        self.0.lock().unwrap().insert(tip)
+    }
+
+    fn release(&self, tip: &Sha256dHash)
+    {
+        self.0.lock().unwrap().remove(tip);
+    }
+}
+
+pub struct SyncBlockChain
+{
+    blockchain: Arc<Mutex<BlockChain>>,
+    in_flight: InFlightHeaders,
+    connection: Addr<Connection>,
+    notify: Recipient<SyncBlockChainResult>,
+
+    // Tip of locator of the request which waits for response.
+    requesting: Option<Sha256dHash>,
+    // Hashes of orphan headers from the peer which are not connected yet.
+    pending_orphans: Vec<Sha256dHash>,
+    stats: SyncStats,
+
+    progress: Option<Box<Fn(IbdProgress) + Send>>,
+    best_known_height: i32,
+    // Workers which hash headers of a batch. `None` hashes them on the actor's thread.
+    validation_pool: Option<Arc<ThreadPool>>,
+
+    cancel: Option<oneshot::Receiver<()>>,
+    events: Arc<EventSink>,
+}
+
+#[derive(Message)]
+pub enum SyncBlockChainResult
+{
+    Complete(SyncStats),
+    /// Sync is cancelled. Headers which are received so far are kept in blockchain.
+    Cancelled(SyncStats),
+    /// Sync fails by the peer. Headers which are received before the error are kept in blockchain,
+    /// so another `SyncBlockChain` on the same blockchain resumes from there.
+    /// A batch which has a header of invalid proof of work is rejected as a whole.
+    Error(SyncStats, Error),
+}
+
+impl SyncBlockChain
+{
+    pub fn new(
+        blockchain: Arc<Mutex<BlockChain>>,
+        in_flight: InFlightHeaders,
+        conn: Addr<Connection>,
+        notify: Recipient<SyncBlockChainResult>,
+    ) -> SyncBlockChain
+    {
+        SyncBlockChain {
+            blockchain,
+            in_flight,
+            connection: conn,
+            notify,
+
+            requesting: None,
+            pending_orphans: Vec::new(),
+            stats: SyncStats::default(),
+
+            progress: None,
+            best_known_height: 0,
+            validation_pool: None,
+
+            cancel: None,
+            events: noop_sink(),
+        }
+    }
+
+    /// Set a callback which is called every time a batch of headers is added.
+    pub fn with_progress<F>(mut self, progress: F) -> SyncBlockChain
+    where F: Fn(IbdProgress) + Send + 'static
+    {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Stop syncing when `cancel` fires.
+    /// It is checked after each batch of headers is added, so a request in flight is not wasted.
+    pub fn with_cancel(mut self, cancel: oneshot::Receiver<()>) -> SyncBlockChain
+    {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Validate proof of work of each batch of headers on workers of `pool`.
+    /// It can be shared by several `SyncBlockChain`s.
+    pub fn with_validation_pool(mut self, pool: Arc<ThreadPool>) -> SyncBlockChain
+    {
+        self.validation_pool = Some(pool);
+        self
+    }
+
+    /// Report applied headers, reorgs and completion to `sink`.
+    pub fn with_events(mut self, sink: Arc<EventSink>) -> SyncBlockChain
+    {
+        self.events = sink;
+        self
+    }
+
+    pub fn start_actor(
+        blockchain: Arc<Mutex<BlockChain>>,
+        in_flight: InFlightHeaders,
+        conn: Addr<Connection>,
+        notify: Recipient<SyncBlockChainResult>,
+    ) -> Addr<SyncBlockChain>
+    {
+        SyncBlockChain::new(blockchain, in_flight, conn, notify).start()
+    }
+
+    /// Make sure that peer is on the same network as blockchain before requesting anything.
+    /// Otherwise every header would be rejected as an orphan.
+    fn check_network_then_sync(&mut self, ctx: &mut Context<Self>)
+    {
+        let f = self.connection
+            .send(GetNetwork)
+            .into_actor(self)
+            .map(|peer, actor, ctx| {
+                let chain = actor.blockchain.lock().unwrap().network();
+                if peer != chain {
+                    info!("Peer is on {:?} but blockchain is on {:?}. Disconnect", peer, chain);
+                    actor.connection.do_send(Disconnect());
+                    return actor.notify_err(Error::NetworkMismatch { peer, chain }, ctx);
+                }
+                actor.fetch_best_known_height(ctx);
+                actor.request_getheaders(ctx);
+            })
+            .map_err(|e, actor, ctx| actor.notify_err(Error::from(e), ctx));
+        ctx.wait(f);
+    }
+
+    fn fetch_best_known_height(&mut self, ctx: &mut Context<Self>)
+    {
+        let f = self.connection
+            .send(GetPeerStartHeight)
+            .map_err(|_e| debug!("Connection is already dropped"))
+            .into_actor(self)
+            .map(|height, actor, _ctx| actor.best_known_height = height);
+        // Headers are checked against it, so wait before requesting headers.
+        ctx.wait(f);
+    }
+
+    fn report_progress(&self)
+    {
+        if let Some(ref progress) = self.progress {
+            let current_height = self.blockchain
+                .lock()
+                .unwrap()
+                .active_chain()
+                .latest_block()
+                .height();
+            progress(IbdProgress {
+                headers_synced: self.stats.headers_contributed,
+                current_height,
+                best_known_height: self.best_known_height,
+                blocks_downloaded: 0,
+                blocks_total: 0,
+                bytes_downloaded: 0,
+            });
+        }
+    }
+
+    fn request_getheaders(&mut self, ctx: &mut Context<Self>)
+    {
+        let locator_hashes = self.blockchain.lock().unwrap().active_chain().locator_hashes_vec();
+        let tip = locator_hashes[0];
+        if !self.in_flight.register(tip) {
+            // Another actor is requesting the same headers. Retry after they are added.
+            ctx.run_later(RETRY_INTERVAL, |actor, ctx| actor.request_getheaders(ctx));
+            return;
+        }
+        self.requesting = Some(tip);
+
+        ctx.run_later(HEADERS_TIMEOUT, move |actor, ctx| {
+            if actor.requesting == Some(tip) {
+                info!("Peer does not respond headers. Disconnect");
+                actor.connection.do_send(Disconnect());
+                actor.notify_err(Error::Timeout, ctx);
+            }
+        });
+
+        let addr = ctx.address().recipient();
+        let req = GetHeadersRequest { locator_hashes, addr };
+
+        let f = self.connection
+            .send(req)
+            .into_actor(self)
+            .map_err(|e, actor, ctx| {
+                debug!("Connection is already dropped");
+                actor.notify_err(Error::from(e), ctx);
+            });
+        // Stop task processing until successfully send a request
+        ctx.wait(f);
+    }
+
+    fn is_cancelled(&mut self) -> bool
+    {
+        match self.cancel.as_mut().map(|cancel| cancel.try_recv()) {
+            Some(Ok(Some(()))) => true,
+            // Sender is dropped, so nobody can cancel any more.
+            Some(Err(_)) => {
+                self.cancel = None;
+                false
+            },
+            _ => false,
+        }
+    }
+
+    fn release_request(&mut self)
+    {
+        if let Some(tip) = self.requesting.take() {
+            self.in_flight.release(&tip);
+        }
+    }
+
+    fn apply_headers(&mut self, headers: Vec<LoneBlockHeader>) -> Result<(), Error>
+    {
+        let blockchain = self.blockchain.clone();
+        let mut blockchain = blockchain.lock().unwrap();
+        let old_tip = *blockchain.active_chain().latest_block();
+        let contributed_before = self.stats.headers_contributed;
+
+        // A batch with invalid proof of work is rejected before it touches blockchain.
+        // Headers before one which fails contextual checks are kept, so they are reported as well.
+        let res = self.validate_batch(&blockchain, &headers)
+            .and_then(|()| self.add_headers(&mut blockchain, headers));
+
+        let count = self.stats.headers_contributed - contributed_before;
+        if count > 0 {
+            let active_chain = blockchain.active_chain();
+            self.events.on_event(Event::HeadersApplied {
+                count,
+                tip_height: active_chain.latest_block().height(),
+            });
+            let depth = active_chain.reorg_depth(&old_tip);
+            if depth > 0 {
+                self.events.on_event(Event::Reorg { depth });
+            }
+        }
+        res
+    }
+
+    /// Check proof of work, linkage and timestamps of the whole batch, which follows a block of `blockchain`.
+    ///
+    /// Linkage and timestamps are checked only within the batch here, so their failures are left to
+    /// `BlockChain::try_add`, which checks them against the full chain and keeps headers out of order as orphans.
+    /// A batch whose prev block is not known yet is left to it as well.
+    fn validate_batch(&self, blockchain: &BlockChain, headers: &[LoneBlockHeader]) -> Result<(), Error>
+    {
+        let new_headers: Vec<BlockHeader> = headers
+            .iter()
+            .map(|lone_header| lone_header.header)
+            .skip_while(|header| blockchain.contains(&header.bitcoin_hash()))
+            .collect();
+        let prev = match new_headers.first().and_then(|h| blockchain.find(&h.prev_blockhash)) {
+            Some(prev) => *prev,
+            None => return Ok(()),
+        };
+        let res = match self.validation_pool {
+            Some(ref pool) => validate_headers_parallel(&new_headers, &prev, pool),
+            None => validate_headers(&new_headers, &prev),
+        };
+        match res {
+            Err(ValidationError::InvalidProofOfWork(i)) => {
+                Err(Error::InvalidProofOfWork(new_headers[i].bitcoin_hash()))
+            },
+            Err(ValidationError::Disconnected(_)) | Err(ValidationError::InvalidTimestamp(_)) | Ok(_) => Ok(()),
+        }
+    }
+
+    fn add_headers(&mut self, blockchain: &mut BlockChain, headers: Vec<LoneBlockHeader>) -> Result<(), Error>
+    {
+        let orphans = add_headers(blockchain, headers, self.best_known_height, &mut self.stats)?;
+        self.pending_orphans.extend(orphans);
+
+        // Orphans are normal while several peers are syncing, but one peer should not overflow the pool.
+        self.pending_orphans.retain(|hash| !blockchain.contains(hash));
+        if self.pending_orphans.len() > blockchain.max_orphans() {
+            return Err(Error::TooManyOrphans(self.pending_orphans.len()));
+        }
+        Ok(())
+    }
+
+    /// Send error message and then stop actor.
+    fn notify_err(&mut self, err: Error, ctx: &mut Context<Self>)
+    {
+        let res = SyncBlockChainResult::Error(self.stats, err);
+        self.notify_then_stop(res, ctx);
+    }
+
+    /// Send complete message and then stop actor.
+    fn notify_complete(&mut self, ctx: &mut Context<Self>)
+    {
+        let tip_height = self.blockchain.lock().unwrap().active_chain().latest_block().height();
+        self.events.on_event(Event::SyncCompleted { tip_height });
+        let res = SyncBlockChainResult::Complete(self.stats);
+        self.notify_then_stop(res, ctx);
+    }
+
+    /// Send cancelled message and then stop actor.
+    fn notify_cancelled(&mut self, ctx: &mut Context<Self>)
+    {
+        info!("Sync is cancelled");
+        let res = SyncBlockChainResult::Cancelled(self.stats);
+        self.notify_then_stop(res, ctx);
+    }
+
+    fn notify_then_stop(&mut self, res: SyncBlockChainResult, ctx: &mut Context<Self>)
+    {
+        self.release_request();
+        let f = self.notify
+            .send(res)
+            .map_err(|_e| debug!("Caller already dropped"))
+            .into_actor(self)
+            .map(|(), _actor, ctx| ctx.stop());
+        ctx.wait(f);
+    }
+}
+
+impl Actor for SyncBlockChain
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context)
+    {
+        self.check_network_then_sync(ctx);
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context)
+    {
+        // Let other actors request headers instead of us.
+        self.release_request();
+    }
+}
+
+impl Handler<HeadersResponse> for SyncBlockChain
+{
+    type Result = ();
+    fn handle(&mut self, msg: HeadersResponse, ctx: &mut Context<Self>)
+    {
+        let headers = match msg {
+            HeadersResponse::Headers(headers) => headers,
+            HeadersResponse::Timeout => {
+                info!("Peer does not respond headers. Disconnect");
+                self.release_request();
+                self.connection.do_send(Disconnect());
+                return self.notify_err(Error::Timeout, ctx);
+            },
+        };
+
+        // Peer sends less than max headers only when it does not have more.
+        // A full batch which adds nothing leaves our locator as it is, so the next request would get the same.
+        let is_full = headers.len() == MAX_HEADERS_IN_MSG;
+        let contributed_before = self.stats.headers_contributed;
+        let res = self.apply_headers(headers);
+        let is_finish = !is_full || self.stats.headers_contributed == contributed_before;
+
+        // Release after headers are added so that other actors request next headers.
+        self.release_request();
+
+        if let Err(e) = res {
+            info!("Peer sends invalid block header : {:?}", e);
+            self.connection.do_send(ReportMisbehavior(Violation::InvalidHeader));
+            return self.notify_err(e, ctx);
+        }
+        self.report_progress();
+        if is_finish {
+            self.notify_complete(ctx);
+        } else if self.is_cancelled() {
+            self.notify_cancelled(ctx);
+        } else {
+            self.request_getheaders(ctx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::{cell::{Cell, RefCell}, rc::Rc, time::Instant};
+
+    use bitcoin::blockdata::block::BlockHeader;
+    use bitcoin::network::{constants::Network, encodable::VarInt, message::NetworkMessage};
+
+    use blockchain::{BlockChainSnapshot, BlockData};
+    use events::CountingSink;
+    use process::sync_blockchain::START_HEIGHT_MARGIN;
+    use connection::{message::Message, socket::{accept_handshake_on, begin_handshake_on, HandshakeConfig, Socket},
+                     SetHeaderSource, SetRequestTimeout, DEFAULT_REQUEST_TIMEOUT};
+    use connection::connection_pool::{ConnectionPool, PoolEvent, Shutdown, SubscribePoolEvents};
+    use futures::Stream;
+    use testing::{duplex, dummy_addrs, loopback_peer, mined_headers, MemoryStream, ScriptedPeer};
+
+    type PeerFuture = Box<Future<Item = (), Error = Error>>;
+
+    // Collect results, then stop the system when all `SyncBlockChain` actors finish.
+    struct Collector
+    {
+        results: Rc<RefCell<Vec<SyncBlockChainResult>>>,
+        num_actors: usize,
+    }
+
+    impl Actor for Collector
+    {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<SyncBlockChainResult> for Collector
+    {
+        type Result = ();
+
+        fn handle(&mut self, msg: SyncBlockChainResult, _ctx: &mut Context<Self>)
+        {
+            let mut results = self.results.borrow_mut();
+            results.push(msg);
+            if results.len() == self.num_actors {
+                System::current().stop();
+            }
+        }
+    }
+
+    // Timestamps of headers are `start_time`, `start_time + 1`, ...
+    fn lone_headers(headers: &[BlockHeader]) -> Vec<LoneBlockHeader>
+    {
+        headers
+            .iter()
+            .map(|h| {
+                LoneBlockHeader {
+                    header: *h,
+                    tx_count: VarInt(0),
+                }
+            })
+            .collect()
+    }
+
+    // A server function which responds `getheaders` like a node which has `headers` after `start`.
+    // If the node does not know any locator hash, it responds empty headers.
+    fn headers_server(start: BlockHeader, headers: Vec<BlockHeader>) -> impl FnMut(Message) -> Vec<Message>
+    {
+        let mut hashes = vec![start.bitcoin_hash()];
+        hashes.extend(headers.iter().map(|h| h.bitcoin_hash()));
+        move |msg| {
+            match msg {
+                Message::Network(NetworkMessage::GetHeaders(req)) => {
+                    let pos = req.locator_hashes
+                        .iter()
+                        .filter_map(|h| hashes.iter().position(|known| known == h))
+                        .next();
+                    let batch: Vec<_> = match pos {
+                        None => Vec::new(),
+                        Some(pos) => headers[pos..].iter().take(MAX_HEADERS_IN_MSG).cloned().collect(),
+                    };
+                    vec![NetworkMessage::Headers(lone_headers(&batch)).into()]
+                },
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    // Create a peer which advertises `start_height` and behaves as `script` after handshake.
+    fn scripted_peer<F, R>(start_height: i32, script: F) -> (MemoryStream, PeerFuture)
+    where
+        F: FnOnce(ScriptedPeer) -> R,
+        R: Future<Item = (), Error = Error> + 'static,
+    {
+        let (local, remote) = duplex();
+        let peer = script(ScriptedPeer::new(remote, Network::Regtest).handshake(start_height));
+        (local, Box::new(peer))
+    }
+
+    // Run `SyncBlockChain` actors on a shared blockchain against given peers.
+    fn run_sync(blockchain: Arc<Mutex<BlockChain>>, peers: Vec<(MemoryStream, PeerFuture)>) -> Vec<SyncBlockChainResult>
+    {
+        run_sync_with_events(blockchain, peers, noop_sink())
+    }
+
+    fn run_sync_with_events(
+        blockchain: Arc<Mutex<BlockChain>>,
+        peers: Vec<(MemoryStream, PeerFuture)>,
+        events: Arc<EventSink>,
+    ) -> Vec<SyncBlockChainResult>
+    {
+        run_sync_with(blockchain, peers, events, DEFAULT_REQUEST_TIMEOUT, Arc::default())
+    }
+
+    // Progress reports of every actor are pushed to `progress`.
+    fn run_sync_with(
+        blockchain: Arc<Mutex<BlockChain>>,
+        peers: Vec<(MemoryStream, PeerFuture)>,
+        events: Arc<EventSink>,
+        request_timeout: Duration,
+        progress: Arc<Mutex<Vec<IbdProgress>>>,
+    ) -> Vec<SyncBlockChainResult>
+    {
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let results2 = results.clone();
+
+        System::run(move || {
+            let collector = Collector {
+                results: results2,
+                num_actors: peers.len(),
+            }.start();
+            let in_flight = InFlightHeaders::new();
+
+            for (local, peer) in peers {
+                Arbiter::spawn(peer.map_err(|e| panic!("Scripted peer fails : {:?}", e)));
+
+                let (local_addr, peer_addr) = dummy_addrs();
+                let socket = Socket::new(local, Network::Regtest);
+                let blockchain = blockchain.clone();
+                let in_flight = in_flight.clone();
+                let notify = collector.clone().recipient();
+                let events = events.clone();
+                let progress = progress.clone();
+                let f = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr)
+                    .map(move |socket| {
+                        let conn = Connection::start_actor(socket);
+                        conn.do_send(SetRequestTimeout(request_timeout));
+                        SyncBlockChain::new(blockchain, in_flight, conn, notify)
+                            .with_events(events)
+                            .with_progress(move |p| progress.lock().unwrap().push(p))
+                            .start();
+                    })
+                    .map_err(|e| panic!("Fail to handshake : {:?}", e));
+                Arbiter::spawn(f);
+            }
+        });
+
+        let res = results.borrow_mut().drain(..).collect();
+        res
+    }
+
+    fn unwrap_stats(res: &SyncBlockChainResult) -> SyncStats
+    {
+        match *res {
+            SyncBlockChainResult::Complete(stats) => stats,
+            SyncBlockChainResult::Cancelled(_) => panic!("Sync is cancelled"),
+            SyncBlockChainResult::Error(_, ref e) => panic!("Fail to sync : {:?}", e),
+        }
+    }
+
+    #[test]
+    fn sync_blockchain_from_our_own_connection()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, MAX_HEADERS_IN_MSG + 10);
+        let mut served = BlockChain::with_start(Network::Regtest, BlockData::new(start, 0));
+        for header in headers.iter() {
+            served.try_add(*header).unwrap();
+        }
+        let served = Arc::new(Mutex::new(served));
+
+        // The other side is a `Connection` which serves headers of `served`.
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let config = HandshakeConfig {
+            start_height: headers.len() as i32,
+            ..HandshakeConfig::default()
+        };
+        let served2 = served.clone();
+        let server = accept_handshake_on(Socket::new(remote, Network::Regtest), config, peer_addr, local_addr)
+            .map(move |socket| Connection::start_actor(socket).do_send(SetHeaderSource(served2)));
+        let server: PeerFuture = Box::new(server);
+
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+        let results = run_sync(blockchain.clone(), vec![(local, server)]);
+
+        assert_eq!(unwrap_stats(&results[0]).headers_contributed, headers.len());
+        let synced = blockchain.lock().unwrap().active_chain().into_vec();
+        let expected = served.lock().unwrap().active_chain().into_vec();
+        assert_eq!(synced, expected);
+    }
+
+    #[test]
+    fn report_progress_after_each_batch()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, MAX_HEADERS_IN_MSG * 2 + 5);
+        let best_known_height = headers.len() as i32;
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+        let progress = Arc::new(Mutex::new(Vec::new()));
+
+        let peer = scripted_peer(best_known_height, |peer| {
+            peer.run_and_serve(headers_server(start, headers))
+        });
+        let results = run_sync_with(
+            blockchain,
+            vec![peer],
+            noop_sink(),
+            DEFAULT_REQUEST_TIMEOUT,
+            progress.clone(),
+        );
+
+        unwrap_stats(&results[0]);
+        let expected: Vec<_> = [MAX_HEADERS_IN_MSG, MAX_HEADERS_IN_MSG * 2, MAX_HEADERS_IN_MSG * 2 + 5]
+            .iter()
+            .map(|&n| {
+                IbdProgress {
+                    headers_synced: n,
+                    current_height: n as u32,
+                    best_known_height,
+                    blocks_downloaded: 0,
+                    blocks_total: 0,
+                    bytes_downloaded: 0,
+                }
+            })
+            .collect();
+        assert_eq!(*progress.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn sync_blockchain_with_scripted_peer()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, 3);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+
+        let peer = scripted_peer(3, |peer| {
+            peer.expect("getheaders")
+                .send(NetworkMessage::Headers(lone_headers(&headers)))
+                .run()
+                .map(|_| ())
+        });
+        let results = run_sync(blockchain.clone(), vec![peer]);
+
+        assert_eq!(unwrap_stats(&results[0]).headers_contributed, 3);
+        let blockchain = blockchain.lock().unwrap();
+        let active_chain = blockchain.active_chain();
+        assert_eq!(active_chain.len(), 4);
+        assert_eq!(active_chain.latest_block().header, headers[2]);
+    }
+
+    #[test]
+    fn sync_blockchain_finishes_when_full_batch_adds_nothing()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, MAX_HEADERS_IN_MSG);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+        for header in headers.iter() {
+            blockchain.lock().unwrap().try_add(*header).unwrap();
+        }
+
+        // Peer answers every `getheaders` with the same full batch which we already have.
+        let requests = Rc::new(Cell::new(0));
+        let requests2 = requests.clone();
+        let peer = scripted_peer(headers.len() as i32, move |peer| {
+            peer.run_and_serve(move |msg| {
+                match msg {
+                    Message::Network(NetworkMessage::GetHeaders(_)) => {
+                        requests2.set(requests2.get() + 1);
+                        vec![NetworkMessage::Headers(lone_headers(&headers)).into()]
+                    },
+                    _ => Vec::new(),
+                }
+            })
+        });
+        let results = run_sync(blockchain.clone(), vec![peer]);
+
+        let stats = unwrap_stats(&results[0]);
+        assert_eq!(stats.headers_contributed, 0);
+        assert_eq!(stats.duplicates_discarded, MAX_HEADERS_IN_MSG);
+        assert_eq!(requests.get(), 1);
+    }
+
+    #[test]
+    fn sync_blockchain_rejects_peer_on_another_network()
+    {
+        let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Testnet)));
+
+        // Peer is on regtest, so it should never be asked for headers.
+        let peer = scripted_peer(0, |peer| peer.run_and_serve(|_msg| Vec::new()));
+        let results = run_sync(blockchain.clone(), vec![peer]);
+
+        match results[0] {
+            SyncBlockChainResult::Error(stats, Error::NetworkMismatch { peer, chain }) => {
+                assert_eq!(stats, SyncStats::default());
+                assert_eq!(peer, Network::Regtest);
+                assert_eq!(chain, Network::Testnet);
+            },
+            _ => panic!("Sync should fail by network mismatch"),
+        }
+        assert_eq!(blockchain.lock().unwrap().active_chain().len(), 1);
+    }
+
+    #[test]
+    fn sync_blockchain_fails_when_peer_does_not_respond_headers()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+
+        // Peer receives `getheaders` but stays silent.
+        let peer = scripted_peer(10, |peer| peer.run_and_serve(|_msg| Vec::new()));
+        let begin = Instant::now();
+        let results = run_sync_with(
+            blockchain,
+            vec![peer],
+            noop_sink(),
+            Duration::from_millis(100),
+            Arc::default(),
+        );
+
+        match results[0] {
+            SyncBlockChainResult::Error(stats, Error::Timeout) => assert_eq!(stats, SyncStats::default()),
+            _ => panic!("Sync should fail by timeout"),
+        }
+        // Timeout of `Connection` fires much earlier than `HEADERS_TIMEOUT`.
+        assert!(begin.elapsed() < HEADERS_TIMEOUT);
+    }
+
+    #[test]
+    fn sync_blockchain_requests_next_batch_after_full_batch()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, MAX_HEADERS_IN_MSG + 1);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+
+        let (first, second) = headers.split_at(MAX_HEADERS_IN_MSG);
+        let peer = scripted_peer(headers.len() as i32, |peer| {
+            peer.expect("getheaders")
+                .send(NetworkMessage::Headers(lone_headers(first)))
+                .expect("getheaders")
+                .send(NetworkMessage::Headers(lone_headers(second)))
+                .run()
+                .map(|_| ())
+        });
+        run_sync(blockchain.clone(), vec![peer]);
+
+        let len = blockchain.lock().unwrap().active_chain().len();
+        assert_eq!(len, MAX_HEADERS_IN_MSG as u32 + 2);
+    }
+
+    #[test]
+    fn resume_sync_with_another_peer_after_failure()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, MAX_HEADERS_IN_MSG + 1000);
+        let new_blockchain = || {
+            let blockchain = BlockChain::with_start(Network::Regtest, BlockData::new(start, 0));
+            Arc::new(Mutex::new(blockchain))
+        };
+
+        // The first peer advertises nothing, so its second batch exceeds `START_HEIGHT_MARGIN`.
+        let blockchain = new_blockchain();
+        let (hs, num) = (headers.clone(), headers.len() as i32);
+        let bad_peer = scripted_peer(0, move |peer| peer.run_and_serve(headers_server(start, hs)));
+        let results = run_sync(blockchain.clone(), vec![bad_peer]);
+        match results[0] {
+            SyncBlockChainResult::Error(stats, Error::TooManyHeaders(0)) => {
+                assert_eq!(stats.headers_contributed, MAX_HEADERS_IN_MSG)
+            },
+            _ => panic!("The first peer should fail"),
+        }
+
+        // The second peer only needs to serve the rest.
+        let hs = headers.clone();
+        let good_peer = scripted_peer(num, move |peer| peer.run_and_serve(headers_server(start, hs)));
+        let results = run_sync(blockchain.clone(), vec![good_peer]);
+        assert_eq!(unwrap_stats(&results[0]).headers_contributed, 1000);
+
+        // The result is the same as syncing with a single good peer.
+        let reference = new_blockchain();
+        let single_peer = scripted_peer(num, move |peer| peer.run_and_serve(headers_server(start, headers)));
+        run_sync(reference.clone(), vec![single_peer]);
+
+        let resumed = blockchain.lock().unwrap().freeze();
+        let reference = reference.lock().unwrap().freeze();
+        let hashes = |snapshot: &BlockChainSnapshot| snapshot.iter().map(|b| b.bitcoin_hash()).collect::<Vec<_>>();
+        assert_eq!(resumed.len(), MAX_HEADERS_IN_MSG as u32 + 1001);
+        assert_eq!(hashes(&resumed), hashes(&reference));
+    }
+
+    #[test]
+    fn sync_resumes_from_fork_point_far_behind_our_tip()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let ours = mined_headers(start.bitcoin_hash(), start.time + 1, 600);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+        for header in ours.iter() {
+            blockchain.lock().unwrap().try_add(*header).unwrap();
+        }
+
+        // The peer only shares the first 100 headers with us, 500 blocks back from our tip.
+        let fork = ours[99];
+        let mut theirs = ours[..100].to_vec();
+        theirs.extend(mined_headers(fork.bitcoin_hash(), fork.time + 1000, 650));
+        let their_tip = theirs.last().unwrap().bitcoin_hash();
+
+        let num = theirs.len() as i32;
+        let peer = scripted_peer(num, move |peer| peer.run_and_serve(headers_server(start, theirs)));
+        let results = run_sync(blockchain.clone(), vec![peer]);
+
+        // Locator reaches a block shortly before the fork point, not the start block.
+        let stats = unwrap_stats(&results[0]);
+        assert_eq!(stats.headers_contributed, 650);
+        assert!(stats.duplicates_discarded < 100);
+        let blockchain = blockchain.lock().unwrap();
+        assert_eq!(blockchain.active_chain().latest_block().bitcoin_hash(), their_tip);
+    }
+
+    #[test]
+    fn report_applied_headers_and_reorg_to_event_sink()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let ours = mined_headers(start.bitcoin_hash(), start.time + 1, 5);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+        for header in ours.iter() {
+            blockchain.lock().unwrap().try_add(*header).unwrap();
+        }
+
+        // The peer has a longer branch from the second block, so our last 3 blocks are disconnected.
+        let mut theirs = ours[..2].to_vec();
+        theirs.extend(mined_headers(ours[1].bitcoin_hash(), ours[1].time + 1000, 10));
+
+        let sink = Arc::new(CountingSink::new());
+        let num = theirs.len() as i32;
+        let peer = scripted_peer(num, move |peer| peer.run_and_serve(headers_server(start, theirs)));
+        let results = run_sync_with_events(blockchain.clone(), vec![peer], sink.clone());
+        unwrap_stats(&results[0]);
+
+        let counts = sink.counts();
+        assert_eq!(counts.headers_applied, 10);
+        assert_eq!(counts.reorgs, 1);
+        assert_eq!(counts.syncs_completed, 1);
+    }
+
+    #[test]
+    fn sync_blockchain_with_two_peers_does_not_apply_duplicates()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, 3000);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+
+        // Both peers serve the first 2500 headers.
+        let short = headers[..2500].to_vec();
+        let long = headers.clone();
+        let peer1 = scripted_peer(2500, move |peer| peer.run_and_serve(headers_server(start, short)));
+        let peer2 = scripted_peer(3000, move |peer| peer.run_and_serve(headers_server(start, long)));
+        let results = run_sync(blockchain.clone(), vec![peer1, peer2]);
+
+        let stats: Vec<_> = results.iter().map(unwrap_stats).collect();
+        assert_eq!(stats.iter().map(|s| s.headers_contributed).sum::<usize>(), 3000);
+        assert_eq!(stats.iter().map(|s| s.duplicates_discarded).sum::<usize>(), 0);
+
+        let blockchain = blockchain.lock().unwrap();
+        assert_eq!(blockchain.active_chain().len(), 3001);
+        assert_eq!(blockchain.active_chain().latest_block().header, headers[2999]);
+    }
+
+    #[test]
+    fn sync_blockchain_assembles_headers_out_of_order()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, 5);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+
+        let mut reversed = headers.clone();
+        reversed.reverse();
+        let peer = scripted_peer(5, move |peer| {
+            peer.expect("getheaders")
+                .send(NetworkMessage::Headers(lone_headers(&reversed)))
+                .run()
+                .map(|_| ())
+        });
+        let results = run_sync(blockchain.clone(), vec![peer]);
+
+        let stats = unwrap_stats(&results[0]);
+        assert_eq!((stats.orphans_received, stats.headers_contributed), (4, 1));
+        let blockchain = blockchain.lock().unwrap();
+        assert_eq!(blockchain.orphan_count(), 0);
+        let synced: Vec<_> = blockchain.active_chain().iter().skip(1).map(|block| block.header).collect();
+        assert_eq!(synced, headers);
+    }
+
+    #[test]
+    fn sync_blockchain_fails_when_peer_overflows_orphan_pool()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, 5);
+        let mut blockchain = BlockChain::with_start(Network::Regtest, BlockData::new(start, 0));
+        blockchain.set_max_orphans(2);
+        let blockchain = Arc::new(Mutex::new(blockchain));
+
+        // The first header is missing, so the rest never connect.
+        let orphans = headers[1..].to_vec();
+        let peer = scripted_peer(5, move |peer| {
+            peer.expect("getheaders")
+                .send(NetworkMessage::Headers(lone_headers(&orphans)))
+                .run_and_serve(|_msg| Vec::new())
+        });
+        let results = run_sync(blockchain.clone(), vec![peer]);
+
+        match results[0] {
+            SyncBlockChainResult::Error(stats, Error::TooManyOrphans(n)) => {
+                assert_eq!(n, 4);
+                assert_eq!(stats.orphans_received, 4);
+            },
+            _ => panic!("Sync should fail by too many orphans"),
+        }
+        assert_eq!(blockchain.lock().unwrap().active_chain().len(), 1);
+    }
+
+    #[test]
+    fn sync_blockchain_aborts_on_too_many_headers()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, 10_000);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+
+        // Peer advertises nothing, but serves 10k linked headers.
+        let peer = scripted_peer(0, move |peer| peer.run_and_serve(headers_server(start, headers)));
+        let results = run_sync(blockchain.clone(), vec![peer]);
+
+        match results[0] {
+            SyncBlockChainResult::Error(stats, Error::TooManyHeaders(0)) => {
+                assert_eq!(stats.headers_contributed, START_HEIGHT_MARGIN as usize);
+            },
+            SyncBlockChainResult::Error(_, ref e) => panic!("Unexpected error : {:?}", e),
+            SyncBlockChainResult::Complete(_) | SyncBlockChainResult::Cancelled(_) => panic!("Sync should fail"),
+        }
+        let len = blockchain.lock().unwrap().active_chain().len();
+        assert_eq!(len, START_HEIGHT_MARGIN + 1);
+    }
+
+    // Start `SyncBlockChain` on an established connection and cancel it after the first batch.
+    // When it finishes, shut down the pool and then stop the system.
+    struct CancelAfterFirstBatch
+    {
+        blockchain: Arc<Mutex<BlockChain>>,
+        pool: Addr<ConnectionPool>,
+        results: Rc<RefCell<Vec<SyncBlockChainResult>>>,
+    }
+
+    impl Actor for CancelAfterFirstBatch
+    {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<PoolEvent> for CancelAfterFirstBatch
+    {
+        type Result = ();
+
+        fn handle(&mut self, event: PoolEvent, ctx: &mut Context<Self>)
+        {
+            if let PoolEvent::ConnectionEstablished(conn, _) = event {
+                let (cancel_tx, cancel_rx) = oneshot::channel();
+                let cancel_tx = Mutex::new(Some(cancel_tx));
+                let notify = ctx.address().recipient();
+                SyncBlockChain::new(self.blockchain.clone(), InFlightHeaders::new(), conn, notify)
+                    .with_cancel(cancel_rx)
+                    .with_progress(move |_progress| {
+                        if let Some(tx) = cancel_tx.lock().unwrap().take() {
+                            let _ = tx.send(());
+                        }
+                    })
+                    .start();
+            }
+        }
+    }
+
+    impl Handler<SyncBlockChainResult> for CancelAfterFirstBatch
+    {
+        type Result = ();
+
+        fn handle(&mut self, msg: SyncBlockChainResult, ctx: &mut Context<Self>)
+        {
+            self.results.borrow_mut().push(msg);
+            let f = self.pool
+                .send(Shutdown)
+                .map(|_| System::current().stop())
+                .map_err(|e| panic!("Fail to shutdown : {:?}", e))
+                .into_actor(self);
+            ctx.spawn(f);
+        }
+    }
+
+    #[test]
+    fn sync_blockchain_rejects_whole_batch_with_invalid_proof_of_work()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let mut headers = mined_headers(start.bitcoin_hash(), start.time + 1, 5);
+        while headers[2].bitcoin_hash().into_le() <= headers[2].target() {
+            headers[2].nonce += 1;
+        }
+        let bad_hash = headers[2].bitcoin_hash();
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+
+        let peer = scripted_peer(5, move |peer| {
+            peer.expect("getheaders")
+                .send(NetworkMessage::Headers(lone_headers(&headers)))
+                .run_and_serve(|_msg| Vec::new())
+        });
+        let results = run_sync(blockchain.clone(), vec![peer]);
+
+        match results[0] {
+            SyncBlockChainResult::Error(stats, Error::InvalidProofOfWork(hash)) => {
+                assert_eq!(hash, bad_hash);
+                assert_eq!(stats.headers_contributed, 0);
+            },
+            _ => panic!("Sync should fail by invalid proof of work"),
+        }
+        // Valid headers before the invalid one are not added either.
+        assert_eq!(blockchain.lock().unwrap().active_chain().len(), 1);
+    }
+
+    #[test]
+    fn shutdown_pool_while_syncing()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, MAX_HEADERS_IN_MSG * 3);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+        let num_headers = headers.len() as i32;
+        let (listen_addr, peer) = loopback_peer(Network::Regtest, move |peer| {
+            peer.handshake(num_headers).run_and_serve(headers_server(start, headers))
+        });
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let results2 = results.clone();
+        let blockchain2 = blockchain.clone();
+        let started_at = Instant::now();
+
+        System::run(move || {
+            Arbiter::spawn(peer);
+
+            let pool = ConnectionPool::new(Network::Regtest, 0, 0, false, blockchain2.clone())
+                .with_bootstrap_addrs(vec![listen_addr])
+                .start();
+            let canceller = CancelAfterFirstBatch {
+                blockchain: blockchain2,
+                pool: pool.clone(),
+                results: results2,
+            }.start();
+            pool.do_send(SubscribePoolEvents {
+                addr: canceller.recipient(),
+            });
+        });
+
+        assert!(started_at.elapsed() < Duration::from_secs(10));
+        match results.borrow()[0] {
+            SyncBlockChainResult::Cancelled(ref stats) => assert_eq!(stats.headers_contributed, MAX_HEADERS_IN_MSG),
+            _ => panic!("Sync should be cancelled"),
+        }
+        let len = blockchain.lock().unwrap().active_chain().len();
+        assert_eq!(len, MAX_HEADERS_IN_MSG as u32 + 1);
+    }
+}
@@ -0,0 +1,318 @@
+//! Sync headers of a blockchain with a peer.
+//!
+//! `sync_blockchain_on` works on a `HandshakedSocket` with futures only, so it is available without the
+//! `actix-net` feature. `SyncBlockChain` is an actor on a `Connection`, which shares a `BlockChain` with other
+//! peers.
+
+#[cfg(feature = "actix-net")]
+mod actor;
+
+use std::{cmp, sync::{Arc, Mutex}};
+
+use bitcoin::blockdata::block::LoneBlockHeader;
+use bitcoin::network::{message::NetworkMessage, message_blockdata::GetHeadersMessage, serialize::BitcoinHash};
+use bitcoin::util::hash::Sha256dHash;
+use futures::{future::{self, Loop}, Future};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use blockchain::{BlockAddResult, BlockChain};
+use connection::{codec::MAX_HEADERS_IN_MSG, message::Message, misbehavior::Violation, socket::HandshakedSocket};
+use error::Error;
+
+#[cfg(feature = "actix-net")]
+pub use self::actor::*;
+
+/// The number of headers which we accept beyond the `start_height` which peer advertised.
+/// New blocks may be mined after handshake, but not so many.
+const START_HEIGHT_MARGIN: u32 = 2000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IbdProgress
+{
+    /// The number of headers received so far.
+    pub headers_synced: usize,
+    /// Height of our latest block.
+    pub current_height: u32,
+    /// Estimated height of the best chain.
+    /// Remote peer's `start_height` is used.
+    pub best_known_height: i32,
+    /// The number of blocks whose transactions are downloaded so far.
+    /// Always 0 while headers are synced.
+    pub blocks_downloaded: u32,
+    /// The number of blocks whose transactions are going to be downloaded after headers are synced.
+    pub blocks_total: u32,
+    /// Serialized size of downloaded blocks.
+    pub bytes_downloaded: u64,
+}
+
+/// Statistics of a peer while syncing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncStats
+{
+    /// The number of headers which the peer added to blockchain.
+    pub headers_contributed: usize,
+    /// The number of headers which were already in blockchain.
+    pub duplicates_discarded: usize,
+    /// The number of headers which were kept as orphans since their prev block was not known yet.
+    pub orphans_received: usize,
+}
+
+/// Request headers from `socket` until peer sends less than `MAX_HEADERS_IN_MSG` headers,
+/// or a full batch which adds nothing, and add them to `blockchain`.
+///
+/// `ping` messages are answered while waiting for headers, and other messages are ignored.
+/// Unlike `SyncBlockChain`, this never times out, so wrap the returned future by a timer if peer may hang.
+/// Headers which are added before an error are kept in `blockchain`.
+///
+/// There is no `Connection` to report misbehavior to, so invalid headers fail with `Error::MisbehavingPeer`,
+/// and the caller decides whether to ban the peer.
+pub fn sync_blockchain_on<S>(
+    socket: HandshakedSocket<S>,
+    blockchain: Arc<Mutex<BlockChain>>,
+) -> impl Future<Item = (HandshakedSocket<S>, SyncStats), Error = Error>
+where S: AsyncRead + AsyncWrite + 'static
+{
+    let start_height = socket.remote_start_height();
+    future::loop_fn((socket, SyncStats::default()), move |(socket, stats)| {
+        let locator_hashes = blockchain.lock().unwrap().active_chain().locator_hashes_vec();
+        let get_headers = GetHeadersMessage::new(locator_hashes, Sha256dHash::default());
+        let blockchain = blockchain.clone();
+        socket
+            .send_msg(NetworkMessage::GetHeaders(get_headers))
+            .and_then(recv_headers)
+            .and_then(move |(headers, socket)| {
+                let contributed_before = stats.headers_contributed;
+                let mut stats = stats;
+                let is_full = headers.len() == MAX_HEADERS_IN_MSG as usize;
+                add_headers(&mut blockchain.lock().unwrap(), headers, start_height, &mut stats).map_err(|e| {
+                    Error::MisbehavingPeer {
+                        peer: socket.peer_addr(),
+                        reason: format!("{:?} : {}", Violation::InvalidHeader, e),
+                    }
+                })?;
+                // A full batch which adds nothing leaves our locator as it is, so the next request would get the same.
+                let is_last = !is_full || stats.headers_contributed == contributed_before;
+                if is_last {
+                    Ok::<_, Error>(Loop::Break((socket, stats)))
+                } else {
+                    Ok(Loop::Continue((socket, stats)))
+                }
+            })
+    })
+}
+
+/// Receive messages until `headers` arrives. `ping` is answered by `pong`.
+fn recv_headers<S>(
+    socket: HandshakedSocket<S>,
+) -> impl Future<Item = (Vec<LoneBlockHeader>, HandshakedSocket<S>), Error = Error>
+where S: AsyncRead + AsyncWrite + 'static
+{
+    future::loop_fn(socket, |socket| {
+        socket.recv_msg().and_then(|(msg, socket)| {
+            let f: Box<Future<Item = _, Error = Error>> = match msg {
+                Message::Network(NetworkMessage::Headers(headers)) => {
+                    Box::new(future::ok(Loop::Break((headers, socket))))
+                },
+                Message::Network(NetworkMessage::Ping(nonce)) => {
+                    Box::new(socket.send_msg(NetworkMessage::Pong(nonce)).map(Loop::Continue))
+                },
+                _ => Box::new(future::ok(Loop::Continue(socket))),
+            };
+            f
+        })
+    })
+}
+
+/// Add `headers` to `blockchain`, and returns hashes of headers which are kept as orphans.
+/// Fails if they are too far beyond `start_height` which peer advertised.
+fn add_headers(
+    blockchain: &mut BlockChain,
+    headers: Vec<LoneBlockHeader>,
+    start_height: i32,
+    stats: &mut SyncStats,
+) -> Result<Vec<Sha256dHash>, Error>
+{
+    let mut orphans = Vec::new();
+    let max_height = cmp::max(start_height, 0) as u32 + START_HEIGHT_MARGIN;
+    for lone_header in headers {
+        if blockchain.contains(&lone_header.header.bitcoin_hash()) {
+            stats.duplicates_discarded += 1;
+            continue;
+        }
+        // Peer can not have a chain much longer than it advertised.
+        let prev_height = blockchain.active_chain().height_of(&lone_header.header.prev_blockhash);
+        if prev_height.map(|h| h + 1 > max_height).unwrap_or(false) {
+            return Err(Error::TooManyHeaders(start_height));
+        }
+        match blockchain.try_add(lone_header.header)? {
+            BlockAddResult::Orphaned => {
+                stats.orphans_received += 1;
+                orphans.push(lone_header.header.bitcoin_hash());
+            },
+            BlockAddResult::AlreadyKnown(_) => stats.duplicates_discarded += 1,
+            _ => stats.headers_contributed += 1,
+        }
+    }
+    Ok(orphans)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use std::{cell::Cell, rc::Rc};
+
+    use bitcoin::blockdata::block::BlockHeader;
+    use bitcoin::network::{constants::Network, encodable::VarInt};
+    use tokio::runtime::current_thread::Runtime;
+
+    use blockchain::BlockData;
+    use connection::socket::{begin_handshake_on, HandshakeConfig, Socket};
+    use testing::{duplex, dummy_addrs, mined_headers, ScriptedPeer};
+
+    // A server function which responds `getheaders` with `headers` after the known locator hash.
+    // A `ping` is sent before every response.
+    fn headers_server(start: BlockHeader, headers: Vec<BlockHeader>) -> impl FnMut(Message) -> Vec<Message>
+    {
+        let mut hashes = vec![start.bitcoin_hash()];
+        hashes.extend(headers.iter().map(|h| h.bitcoin_hash()));
+        move |msg| {
+            match msg {
+                Message::Network(NetworkMessage::GetHeaders(req)) => {
+                    let pos = req.locator_hashes
+                        .iter()
+                        .filter_map(|h| hashes.iter().position(|known| known == h))
+                        .next()
+                        .unwrap_or(0);
+                    let end = cmp::min(pos + MAX_HEADERS_IN_MSG as usize, headers.len());
+                    let batch = headers[pos..end]
+                        .iter()
+                        .map(|header| {
+                            LoneBlockHeader {
+                                header: *header,
+                                tx_count: VarInt(0),
+                            }
+                        })
+                        .collect();
+                    vec![NetworkMessage::Ping(42).into(), NetworkMessage::Headers(batch).into()]
+                },
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    #[test]
+    fn sync_headers_on_socket_without_actor()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, MAX_HEADERS_IN_MSG as usize + 10);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let peer = ScriptedPeer::new(remote, Network::Regtest)
+            .handshake(headers.len() as i32)
+            .expect("getheaders")
+            .send(NetworkMessage::Ping(1))
+            .expect("pong")
+            .send(NetworkMessage::Headers(Vec::new()))
+            .run_and_serve(headers_server(start, headers.clone()));
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(peer.map_err(|e| panic!("Scripted peer fails : {:?}", e)));
+
+        let socket = Socket::new(local, Network::Regtest);
+        let f = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr)
+            .and_then(|socket| {
+                // The first `getheaders` is answered by `ping` and no headers, which finishes the sync.
+                sync_blockchain_on(socket, blockchain.clone())
+            })
+            .and_then(|(socket, stats)| {
+                assert_eq!(stats, SyncStats::default());
+                sync_blockchain_on(socket, blockchain.clone())
+            });
+        let (_socket, stats) = runtime.block_on(f).unwrap();
+
+        assert_eq!(stats.headers_contributed, headers.len());
+        assert_eq!(stats.duplicates_discarded, 0);
+        let blockchain = blockchain.lock().unwrap();
+        assert_eq!(blockchain.active_chain().latest_block().bitcoin_hash(), headers.last().unwrap().bitcoin_hash());
+    }
+    #[test]
+    fn fail_with_misbehavior_when_peer_sends_too_many_headers()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, START_HEIGHT_MARGIN as usize + 10);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+
+        // Peer advertises nothing, so its second batch exceeds `START_HEIGHT_MARGIN`.
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let peer = ScriptedPeer::new(remote, Network::Regtest)
+            .handshake(0)
+            .run_and_serve(headers_server(start, headers));
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(peer.map_err(|e| panic!("Scripted peer fails : {:?}", e)));
+
+        let socket = Socket::new(local, Network::Regtest);
+        let f = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr)
+            .and_then(|socket| sync_blockchain_on(socket, blockchain.clone()));
+        match runtime.block_on(f) {
+            Err(Error::MisbehavingPeer { peer, ref reason }) => {
+                assert_eq!(peer, peer_addr);
+                assert_eq!(reason, "InvalidHeader : Peer sends too many headers beyond its start height 0");
+            },
+            Err(e) => panic!("Unexpected error : {:?}", e),
+            Ok(_) => panic!("Sync should fail"),
+        }
+        assert_eq!(blockchain.lock().unwrap().active_chain().len(), START_HEIGHT_MARGIN + 1);
+    }
+
+    #[test]
+    fn finish_when_full_batch_adds_nothing()
+    {
+        let start = mined_headers(Sha256dHash::default(), 1, 1)[0];
+        let headers = mined_headers(start.bitcoin_hash(), start.time + 1, MAX_HEADERS_IN_MSG as usize);
+        let blockchain = Arc::new(Mutex::new(BlockChain::with_start(Network::Regtest, BlockData::new(start, 0))));
+        for header in headers.iter() {
+            blockchain.lock().unwrap().try_add(*header).unwrap();
+        }
+
+        // Peer answers every `getheaders` with the same full batch which we already have.
+        let (local, remote) = duplex();
+        let (local_addr, peer_addr) = dummy_addrs();
+        let batch: Vec<_> = headers
+            .iter()
+            .map(|header| {
+                LoneBlockHeader {
+                    header: *header,
+                    tx_count: VarInt(0),
+                }
+            })
+            .collect();
+        let requests = Rc::new(Cell::new(0));
+        let requests2 = requests.clone();
+        let peer = ScriptedPeer::new(remote, Network::Regtest)
+            .handshake(headers.len() as i32)
+            .run_and_serve(move |msg| {
+                match msg {
+                    Message::Network(NetworkMessage::GetHeaders(_)) => {
+                        requests2.set(requests2.get() + 1);
+                        vec![NetworkMessage::Headers(batch.clone()).into()]
+                    },
+                    _ => Vec::new(),
+                }
+            });
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(peer.map_err(|e| panic!("Scripted peer fails : {:?}", e)));
+
+        let socket = Socket::new(local, Network::Regtest);
+        let f = begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr)
+            .and_then(|socket| sync_blockchain_on(socket, blockchain.clone()));
+        let (_socket, stats) = runtime.block_on(f).unwrap();
+
+        assert_eq!(stats.headers_contributed, 0);
+        assert_eq!(stats.duplicates_discarded, headers.len());
+        assert_eq!(requests.get(), 1);
+    }
+}
@@ -0,0 +1,461 @@
+//! Utilities to test protocol logic without a live bitcoin node.
+
+use std::{cmp, collections::{HashMap, VecDeque}, io::{self, Read, Write}, net::SocketAddr, sync::{Arc, Mutex},
+          time::{Duration, Instant}};
+#[cfg(feature = "actix-net")]
+use std::{cell::RefCell, rc::Rc};
+
+#[cfg(feature = "actix-net")]
+use actix::{Addr, Arbiter, System};
+
+use bitcoin::blockdata::{block::{Block, BlockHeader}, constants::genesis_block};
+use bitcoin::network::{address::Address, constants::{Network, PROTOCOL_VERSION}, message::NetworkMessage,
+                       message_network::VersionMessage, serialize::BitcoinHash};
+use bitcoin::util::hash::{MerkleRoot, Sha256dHash};
+use futures::{future::{self, Loop}, stream, task::{self, Task}, Async, Future, Poll, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Delay;
+
+use connection::{message::Message, socket::{Socket, USER_AGENT}};
+#[cfg(feature = "actix-net")]
+use connection::{socket::{begin_handshake_on, HandshakeConfig}, Connection};
+use error::Error;
+
+/// `bits` of the easiest target, which regtest uses.
+/// Headers with this `bits` are accepted by regtest `BlockChain` after a few tries of `mine`.
+pub const MIN_DIFFICULTY_BITS: u32 = 0x207f_ffff;
+
+/// Increment `nonce` of `header` until its hash meets the target.
+/// It takes 2 tries on average with `MIN_DIFFICULTY_BITS`.
+pub fn mine(header: &mut BlockHeader)
+{
+    while header.bitcoin_hash().into_le() > header.target() {
+        header.nonce += 1;
+    }
+}
+
+/// `n` mined headers following `prev_hash`. Timestamps are `start_time`, `start_time + 1`, ...
+pub fn mined_headers(prev_hash: Sha256dHash, start_time: u32, n: usize) -> Vec<BlockHeader>
+{
+    let mut prev_hash = prev_hash;
+    let mut headers = Vec::with_capacity(n);
+    for i in 0..n {
+        let mut header = BlockHeader {
+            version: 1,
+            prev_blockhash: prev_hash,
+            merkle_root: Sha256dHash::default(),
+            time: start_time + i as u32,
+            bits: MIN_DIFFICULTY_BITS,
+            nonce: 0,
+        };
+        mine(&mut header);
+        prev_hash = header.bitcoin_hash();
+        headers.push(header);
+    }
+    headers
+}
+
+/// A chain of `len` mined blocks of regtest after the genesis block. Each block has a distinct coinbase.
+pub fn synthetic_blocks(len: u32) -> Vec<Block>
+{
+    let genesis = genesis_block(Network::Regtest);
+    let mut prev = genesis.header;
+    let mut blocks = Vec::new();
+    for i in 1..=len {
+        let mut coinbase = genesis.txdata[0].clone();
+        coinbase.lock_time = i;
+        let mut block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: prev.bitcoin_hash(),
+                merkle_root: Sha256dHash::default(),
+                time: prev.time + 600,
+                bits: MIN_DIFFICULTY_BITS,
+                nonce: 0,
+            },
+            txdata: vec![coinbase],
+        };
+        block.header.merkle_root = block.merkle_root();
+        mine(&mut block.header);
+        prev = block.header;
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// A server function for `ScriptedPeer::run_and_serve`, which responds `getdata` with `blocks` in requested order.
+pub fn blocks_server(blocks: Vec<Block>) -> impl FnMut(Message) -> Vec<Message>
+{
+    let blocks: HashMap<_, _> = blocks.into_iter().map(|b| (b.bitcoin_hash(), b)).collect();
+    move |msg| {
+        match msg {
+            Message::Network(NetworkMessage::GetData(invs)) => {
+                invs.iter()
+                    .filter_map(|inv| blocks.get(&inv.hash))
+                    .map(|b| NetworkMessage::Block(b.clone()).into())
+                    .collect()
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Create a pair of connected in-memory streams.
+/// Bytes written to one stream can be read from another.
+pub fn duplex() -> (MemoryStream, MemoryStream)
+{
+    pipes(Pipe::default(), Pipe::default())
+}
+
+/// Like `duplex`, but a write blocks while `capacity` bytes are not read yet, as a slow peer does.
+pub fn duplex_with_capacity(capacity: usize) -> (MemoryStream, MemoryStream)
+{
+    let pipe = || {
+        Pipe {
+            capacity: Some(capacity),
+            ..Pipe::default()
+        }
+    };
+    pipes(pipe(), pipe())
+}
+
+fn pipes(a: Pipe, b: Pipe) -> (MemoryStream, MemoryStream)
+{
+    let a = Arc::new(Mutex::new(a));
+    let b = Arc::new(Mutex::new(b));
+    let s1 = MemoryStream {
+        read: a.clone(),
+        write: b.clone(),
+    };
+    let s2 = MemoryStream { read: b, write: a };
+    (s1, s2)
+}
+
+/// Dummy addresses which are used to build `version` message.
+pub fn dummy_addrs() -> (SocketAddr, SocketAddr)
+{
+    ("127.0.0.1:8333".parse().unwrap(), "127.0.0.1:18333".parse().unwrap())
+}
+
+/// `version` message which a scripted peer sends.
+pub fn dummy_version_msg(start_height: i32) -> VersionMessage
+{
+    let (local_addr, peer_addr) = dummy_addrs();
+    VersionMessage {
+        version: PROTOCOL_VERSION,
+        services: 0,
+        timestamp: 0,
+        receiver: Address::new(&local_addr, 0),
+        sender: Address::new(&peer_addr, 0),
+        nonce: 0,
+        user_agent: USER_AGENT.into(),
+        start_height,
+        relay: false,
+    }
+}
+
+#[derive(Default)]
+struct Pipe
+{
+    buf: VecDeque<u8>,
+    closed: bool,
+    reader: Option<Task>,
+    // Max number of unread bytes. `None` if unlimited.
+    capacity: Option<usize>,
+    writer: Option<Task>,
+}
+
+impl Pipe
+{
+    fn notify_reader(&mut self)
+    {
+        if let Some(task) = self.reader.take() {
+            task.notify();
+        }
+    }
+
+    fn notify_writer(&mut self)
+    {
+        if let Some(task) = self.writer.take() {
+            task.notify();
+        }
+    }
+}
+
+pub struct MemoryStream
+{
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+impl Read for MemoryStream
+{
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize>
+    {
+        let mut pipe = self.read.lock().unwrap();
+        if pipe.buf.is_empty() {
+            if pipe.closed {
+                return Ok(0);
+            }
+            pipe.reader = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = cmp::min(dst.len(), pipe.buf.len());
+        for (d, b) in dst.iter_mut().zip(pipe.buf.drain(..n)) {
+            *d = b;
+        }
+        pipe.notify_writer();
+        Ok(n)
+    }
+}
+
+impl AsyncRead for MemoryStream {}
+
+impl Write for MemoryStream
+{
+    fn write(&mut self, src: &[u8]) -> io::Result<usize>
+    {
+        let mut pipe = self.write.lock().unwrap();
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let n = match pipe.capacity {
+            None => src.len(),
+            Some(capacity) => cmp::min(src.len(), capacity.saturating_sub(pipe.buf.len())),
+        };
+        if n == 0 && !src.is_empty() {
+            pipe.writer = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        pipe.buf.extend(&src[..n]);
+        pipe.notify_reader();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for MemoryStream
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error>
+    {
+        let mut pipe = self.write.lock().unwrap();
+        pipe.closed = true;
+        pipe.notify_reader();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for MemoryStream
+{
+    fn drop(&mut self)
+    {
+        let _ = self.shutdown();
+    }
+}
+
+enum Step
+{
+    Expect(&'static str),
+    Send(Message),
+    Wait(Duration),
+}
+
+/// A remote peer which behaves as a given script.
+/// It usually runs on `MemoryStream`, but any stream (e.g. `TcpStream`) can be used.
+///
+/// # Panic
+/// `run` panics if the peer receives a message which is not expected.
+pub struct ScriptedPeer<S = MemoryStream>
+{
+    socket: Socket<S>,
+    steps: Vec<Step>,
+}
+
+impl<S> ScriptedPeer<S>
+where S: AsyncRead + AsyncWrite + 'static
+{
+    pub fn new(stream: S, network: Network) -> ScriptedPeer<S>
+    {
+        ScriptedPeer {
+            socket: Socket::new(stream, network),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Expect to receive a message of given command.
+    pub fn expect(mut self, command: &'static str) -> ScriptedPeer<S>
+    {
+        self.steps.push(Step::Expect(command));
+        self
+    }
+
+    /// Send a given message.
+    pub fn send<M: Into<Message>>(mut self, msg: M) -> ScriptedPeer<S>
+    {
+        self.steps.push(Step::Send(msg.into()));
+        self
+    }
+
+    /// Wait for `duration` before the next step, e.g. to respond after the requester gives up.
+    pub fn wait(mut self, duration: Duration) -> ScriptedPeer<S>
+    {
+        self.steps.push(Step::Wait(duration));
+        self
+    }
+
+    /// Reply handshake which is started by remote.
+    pub fn handshake(self, start_height: i32) -> ScriptedPeer<S>
+    {
+        self.handshake_with(dummy_version_msg(start_height))
+    }
+
+    /// Reply handshake with a given `version` message.
+    pub fn handshake_with(self, version: VersionMessage) -> ScriptedPeer<S>
+    {
+        self.expect("version")
+            .send(NetworkMessage::Version(version))
+            .expect("verack")
+            .send(NetworkMessage::Verack)
+    }
+
+    /// Run the script. The returned socket can be used to continue conversation.
+    ///
+    /// On an unexpected message, the panic shows every expected command and the commands received so far.
+    pub fn run(self) -> impl Future<Item = Socket<S>, Error = Error>
+    {
+        let ScriptedPeer { socket, steps } = self;
+        let expected: Vec<&'static str> = steps
+            .iter()
+            .filter_map(|step| match *step {
+                Step::Expect(command) => Some(command),
+                Step::Send(_) | Step::Wait(_) => None,
+            })
+            .collect();
+        let state = (socket, steps.into_iter(), Vec::new());
+        future::loop_fn(state, move |(socket, mut steps, mut received)| {
+            let f: Box<Future<Item = _, Error = Error>> = match steps.next() {
+                None => Box::new(future::ok(Loop::Break(socket))),
+                Some(Step::Send(msg)) => {
+                    Box::new(socket.send_msg(msg).map(move |s| Loop::Continue((s, steps, received))))
+                },
+                Some(Step::Wait(duration)) => {
+                    let delay = Delay::new(Instant::now() + duration);
+                    Box::new(delay.then(move |_| Ok(Loop::Continue((socket, steps, received)))))
+                },
+                Some(Step::Expect(command)) => {
+                    let expected = expected.clone();
+                    Box::new(socket.recv_msg().map(move |(msg, s)| {
+                        received.push(msg.command());
+                        if msg.command() != command {
+                            panic!(
+                                "Expect {} message but receive {:?}\n  expected : {}\n  received : {}",
+                                command,
+                                msg,
+                                expected.join(", "),
+                                received.join(", ")
+                            );
+                        }
+                        Loop::Continue((s, steps, received))
+                    }))
+                },
+            };
+            f
+        })
+    }
+
+    /// Run the script, then keep replying every message by `server` until the stream is closed.
+    pub fn run_and_serve<F>(self, server: F) -> impl Future<Item = (), Error = Error>
+    where F: FnMut(Message) -> Vec<Message> + 'static
+    {
+        self.run().and_then(move |socket| {
+            future::loop_fn((socket, server), |(socket, mut server)| {
+                socket.recv_msg().then(move |res| {
+                    let f: Box<Future<Item = _, Error = Error>> = match res {
+                        // Remote closes the stream.
+                        Err(_) => Box::new(future::ok(Loop::Break(()))),
+                        Ok((msg, socket)) => {
+                            let replies = stream::iter_ok(server(msg));
+                            let f = replies.fold(socket, |socket, reply| socket.send_msg(reply));
+                            Box::new(f.map(move |socket| Loop::Continue((socket, server))))
+                        },
+                    };
+                    f
+                })
+            })
+        })
+    }
+}
+
+/// Listen on a loopback port, and run `script` on a scripted peer of the first incoming connection.
+/// Returns the address to dial, and a future which must be spawned in a running system.
+/// The future panics if accepting or the script fails.
+pub fn loopback_peer<F, R>(network: Network, script: F) -> (SocketAddr, impl Future<Item = (), Error = ()>)
+where
+    F: FnOnce(ScriptedPeer<TcpStream>) -> R + 'static,
+    R: Future<Item = (), Error = Error> + 'static,
+{
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let f = listener
+        .incoming()
+        .into_future()
+        .map_err(|(e, _)| panic!("Fail to accept : {:?}", e))
+        .and_then(move |(stream, _)| {
+            let peer = ScriptedPeer::new(stream.unwrap(), network);
+            script(peer).map_err(|e| panic!("Scripted peer fails : {:?}", e))
+        });
+    (addr, f)
+}
+
+/// A scripted peer to be run by `connect_scripted_peers`.
+pub type PeerFuture = Box<Future<Item = (), Error = Error>>;
+
+/// Spawn each scripted peer, and start a `Connection` actor on the other end of its stream after handshake.
+/// Connections are in order of `peers`. Must be called in a running system.
+/// The future panics if a handshake or a peer fails, so its error type is up to the caller.
+#[cfg(feature = "actix-net")]
+pub fn connect_scripted_peers<E>(
+    peers: Vec<(MemoryStream, PeerFuture)>,
+    network: Network,
+) -> impl Future<Item = Vec<Addr<Connection>>, Error = E>
+{
+    let handshakes: Vec<_> = peers
+        .into_iter()
+        .map(|(local, peer)| {
+            Arbiter::spawn(peer.map_err(|e| panic!("Scripted peer fails : {:?}", e)));
+            let (local_addr, peer_addr) = dummy_addrs();
+            let socket = Socket::new(local, network);
+            begin_handshake_on(socket, HandshakeConfig::default(), local_addr, peer_addr)
+                .map(|socket| Connection::start_actor(socket))
+        })
+        .collect();
+    future::join_all(handshakes).map_err(|e| panic!("Fail to handshake : {:?}", e))
+}
+
+/// Run a new system until the future which `start` makes completes, and returns its result.
+#[cfg(feature = "actix-net")]
+pub fn run_in_system<F, R>(start: F) -> Result<R::Item, R::Error>
+where
+    F: FnOnce() -> R + 'static,
+    R: Future + 'static,
+{
+    let result = Rc::new(RefCell::new(None));
+    let result2 = result.clone();
+
+    System::run(move || {
+        let f = start().then(move |res| {
+            *result2.borrow_mut() = Some(res);
+            System::current().stop();
+            Ok(())
+        });
+        Arbiter::spawn(f);
+    });
+
+    let res = result.borrow_mut().take().unwrap();
+    res
+}
@@ -0,0 +1,217 @@
+use bitcoin::blockdata::{block::Block, transaction::Transaction};
+use bitcoin::network::serialize::serialize;
+use bitcoin::util::hash::Sha256dHash;
+
+/// Service flag of a node which can serve witness data (BIP 144).
+pub const NODE_WITNESS: u64 = 1 << 3;
+/// A flag of inventory type which requests data with witnesses (BIP 144).
+pub const MSG_WITNESS_FLAG: u32 = 1 << 30;
+/// `MSG_WITNESS_BLOCK` inventory type (BIP 144).
+pub const MSG_WITNESS_BLOCK: u32 = 2 | MSG_WITNESS_FLAG;
+
+/// Max weight of a block (BIP 141).
+pub const MAX_BLOCK_WEIGHT: u32 = 4_000_000;
+/// Weight of the smallest transaction, which has 60 bytes without witness.
+pub const MIN_TRANSACTION_WEIGHT: u32 = 4 * 60;
+
+/// Prefix of the script of witness commitment output.
+/// OP_RETURN, push 36 bytes and 0xaa21a9ed (BIP 141).
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WitnessError
+{
+    /// Coinbase input must have a single 32 bytes witness reserved value.
+    BadWitnessReservedValue,
+    /// Witness commitment does not match transactions.
+    CommitmentMismatch,
+    /// Block has witnesses without a commitment.
+    UnexpectedWitness,
+}
+
+/// `wtxid` of a transaction. It is the same as `txid` if the transaction has no witness.
+pub fn wtxid(tx: &Transaction) -> Sha256dHash
+{
+    Sha256dHash::from_data(&serialize(tx).unwrap())
+}
+
+/// Whether any transaction of the block has witness data.
+pub fn has_witness(block: &Block) -> bool
+{
+    block
+        .txdata
+        .iter()
+        .any(|tx| tx.input.iter().any(|input| !input.witness.is_empty()))
+}
+
+/// Witness commitment in the coinbase transaction.
+/// If there are multiple commitments, the last one is used.
+pub fn witness_commitment(block: &Block) -> Option<Sha256dHash>
+{
+    let coinbase = block.txdata.first()?;
+    coinbase
+        .output
+        .iter()
+        .rev()
+        .map(|output| &output.script_pubkey[..])
+        .find(|script| script.len() >= 38 && script[..6] == WITNESS_COMMITMENT_HEADER)
+        .map(|script| Sha256dHash::from(&script[6..38]))
+}
+
+/// Merkle root of `wtxid`s. `wtxid` of the coinbase is regarded as zero.
+pub fn witness_merkle_root(block: &Block) -> Sha256dHash
+{
+    let mut hashes: Vec<_> = block.txdata.iter().skip(1).map(wtxid).collect();
+    hashes.insert(0, Sha256dHash::default());
+    merkle_root(hashes)
+}
+
+/// Verify witness commitment against transactions (BIP 141).
+/// A block without commitment must not have witness data.
+pub fn check_witness_commitment(block: &Block) -> Result<(), WitnessError>
+{
+    let commitment = match witness_commitment(block) {
+        Some(commitment) => commitment,
+        None if has_witness(block) => return Err(WitnessError::UnexpectedWitness),
+        None => return Ok(()),
+    };
+
+    // A commitment is found, so the block has the coinbase, but its input comes from peer as is.
+    let witness = match block.txdata.first().and_then(|coinbase| coinbase.input.first()) {
+        Some(input) => &input.witness,
+        None => return Err(WitnessError::BadWitnessReservedValue),
+    };
+    if witness.len() != 1 || witness[0].len() != 32 {
+        return Err(WitnessError::BadWitnessReservedValue);
+    }
+
+    let mut data = Vec::with_capacity(64);
+    data.extend_from_slice(&witness_merkle_root(block)[..]);
+    data.extend_from_slice(&witness[0]);
+    if Sha256dHash::from_data(&data) != commitment {
+        return Err(WitnessError::CommitmentMismatch);
+    }
+    Ok(())
+}
+
+fn merkle_root(mut hashes: Vec<Sha256dHash>) -> Sha256dHash
+{
+    if hashes.is_empty() {
+        return Sha256dHash::default();
+    }
+    while hashes.len() > 1 {
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| {
+                // The last hash is duplicated if the number of hashes is odd.
+                let right = pair.get(1).unwrap_or(&pair[0]);
+                let mut data = Vec::with_capacity(64);
+                data.extend_from_slice(&pair[0][..]);
+                data.extend_from_slice(&right[..]);
+                Sha256dHash::from_data(&data)
+            })
+            .collect();
+    }
+    hashes[0]
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bitcoin::blockdata::{constants::genesis_block, script::Script, transaction::TxOut};
+    use bitcoin::network::{constants::Network, serialize::{deserialize, BitcoinHash}};
+
+    // A block which has a segwit transaction and a valid witness commitment.
+    fn segwit_block() -> Block
+    {
+        let mut block = genesis_block(Network::Regtest);
+        let mut tx = block.txdata[0].clone();
+        tx.input[0].script_sig = Script::from(vec![0x51]);
+        tx.input[0].witness = vec![vec![0x01, 0x02], vec![0x03]];
+        block.txdata.push(tx);
+
+        let reserved_value = vec![0x42; 32];
+        block.txdata[0].input[0].witness = vec![reserved_value.clone()];
+
+        let mut data = witness_merkle_root(&block)[..].to_vec();
+        data.extend_from_slice(&reserved_value);
+        let mut script = WITNESS_COMMITMENT_HEADER.to_vec();
+        script.extend_from_slice(&Sha256dHash::from_data(&data)[..]);
+        block.txdata[0].output.push(TxOut {
+            value: 0,
+            script_pubkey: Script::from(script),
+        });
+        block
+    }
+
+    #[test]
+    fn witness_block_round_trip()
+    {
+        let block = segwit_block();
+        let decoded: Block = deserialize(&serialize(&block).unwrap()).unwrap();
+
+        assert!(has_witness(&decoded));
+        assert_eq!(decoded.txdata[1].input[0].witness, vec![vec![0x01, 0x02], vec![0x03]]);
+        assert_ne!(wtxid(&decoded.txdata[1]), decoded.txdata[1].bitcoin_hash());
+        assert_eq!(check_witness_commitment(&decoded), Ok(()));
+    }
+
+    #[test]
+    fn detect_bad_witness_commitment()
+    {
+        // Witness data is modified.
+        let mut block = segwit_block();
+        block.txdata[1].input[0].witness[1] = vec![0x04];
+        assert_eq!(check_witness_commitment(&block), Err(WitnessError::CommitmentMismatch));
+
+        // Witness reserved value is missing.
+        let mut block = segwit_block();
+        block.txdata[0].input[0].witness.clear();
+        assert_eq!(check_witness_commitment(&block), Err(WitnessError::BadWitnessReservedValue));
+
+        // Coinbase without input does not panic.
+        let mut block = segwit_block();
+        block.txdata[0].input.clear();
+        assert_eq!(check_witness_commitment(&block), Err(WitnessError::BadWitnessReservedValue));
+
+        // Witness data without commitment.
+        let mut block = genesis_block(Network::Regtest);
+        block.txdata[0].input[0].witness = vec![vec![0x01]];
+        assert_eq!(check_witness_commitment(&block), Err(WitnessError::UnexpectedWitness));
+
+        assert_eq!(check_witness_commitment(&genesis_block(Network::Regtest)), Ok(()));
+    }
+
+    // Mainnet block 000000000000000000000c835b2adcaedc20fdf6ee440009c249452c726dafae at height 702861.
+    // 2065 of its 2500 transactions have witnesses.
+    const MAINNET_SEGWIT_BLOCK: &[u8] = include_bytes!(concat!(
+        "../../test_data/",
+        "mainnet_block_000000000000000000000c835b2adcaedc20fdf6ee440009c249452c726dafae.raw"
+    ));
+
+    #[test]
+    fn verify_commitment_of_mainnet_segwit_block()
+    {
+        let block: Block = deserialize(MAINNET_SEGWIT_BLOCK).unwrap();
+        assert_eq!(
+            block.bitcoin_hash(),
+            Sha256dHash::from_hex("000000000000000000000c835b2adcaedc20fdf6ee440009c249452c726dafae").unwrap()
+        );
+        assert_eq!(block.txdata.len(), 2500);
+        assert!(has_witness(&block));
+        assert_eq!(check_witness_commitment(&block), Ok(()));
+        assert_eq!(serialize(&block).unwrap(), MAINNET_SEGWIT_BLOCK.to_vec());
+
+        // Witness data is not covered by merkle root, but by the commitment.
+        let mut tampered = block.clone();
+        let tx = tampered
+            .txdata
+            .iter_mut()
+            .skip(1)
+            .find(|tx| !tx.input[0].witness.is_empty())
+            .unwrap();
+        tx.input[0].witness[0].push(0);
+        assert_eq!(check_witness_commitment(&tampered), Err(WitnessError::CommitmentMismatch));
+    }
+}